@@ -0,0 +1,292 @@
+//! Serve pirates rpcs from a Bevy app, e.g. as the admin or control channel of a game server.
+//!
+//! [PiratesPlugin] runs an [RpcServer] on Bevy's [IoTaskPool]. Its state is shared with the
+//! app's systems as the [PiratesState] resource, so ordinary rpcs can read and change it. Rpcs
+//! that need the rest of the ECS world are added with [PiratesPlugin::add_event_rpc] instead:
+//! each call is delivered to the app's systems as an [RpcCall] event, and answered with
+//! [RpcCall::respond]
+//!
+//! ```rust,ignore
+//! let mut pirates = PiratesPlugin::new(
+//!     Arc::new(Mutex::new(AdminState::default())),
+//!     TransportConfig::default(),
+//!     "0.0.0.0:5959",
+//! );
+//! pirates.server_mut().add_rpc(Box::new(rpcs::SetMotd::server()));
+//! pirates.add_event_rpc::<(), u32>(AdminRpc::PlayerCount);
+//!
+//! App::new()
+//!     .add_plugins((DefaultPlugins, pirates))
+//!     .add_systems(Update, player_count)
+//!     .run();
+//!
+//! fn player_count(mut calls: EventReader<RpcCall<(), u32>>, players: Query<&Player>) {
+//!     for call in calls.read() {
+//!         call.respond(Ok(players.iter().count() as u32));
+//!     }
+//! }
+//! ```
+use bevy_app::{App, Plugin, PreUpdate};
+use bevy_ecs::prelude::*;
+use bevy_tasks::{IoTaskPool, TaskPool};
+use log::error;
+use pirates::error::{RpcError, RpcResult};
+use pirates::{RpcImpl, RpcName, RpcServer, RpcType, TransportConfig};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long an [RpcCall] waits for a response by default, see
+/// [PiratesPlugin::set_response_timeout]
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The state of the [PiratesPlugin]'s server, shared with the app's systems
+#[derive(Resource)]
+pub struct PiratesState<S: Send + 'static>(pub Arc<Mutex<S>>);
+
+/// A call to an rpc added with [PiratesPlugin::add_event_rpc], to answer with [Self::respond].
+/// The client waits for the answer until the plugin's response timeout, so answer within a frame
+/// or two; calls no system answers fail once their event is dropped
+#[derive(Event)]
+pub struct RpcCall<Q: Send + Sync + 'static, R: Send + 'static> {
+    pub query: Q,
+    responder: Mutex<Option<Sender<RpcResult<R>>>>,
+}
+
+impl<Q: Send + Sync + 'static, R: Send + 'static> RpcCall<Q, R> {
+    /// Answer the call with [result]. Only the first answer is sent, later ones are ignored
+    pub fn respond(&self, result: RpcResult<R>) {
+        if let Some(responder) = self.responder.lock().unwrap().take() {
+            // The call may have timed out already, nobody is left to tell
+            let _ = responder.send(result);
+        }
+    }
+}
+
+/// Calls received by the server for [forward_calls] to send as events
+#[derive(Resource)]
+struct PendingCalls<Q: Send + Sync + 'static, R: Send + 'static> {
+    calls: Mutex<Receiver<RpcCall<Q, R>>>,
+}
+
+fn forward_calls<Q: Send + Sync + 'static, R: Send + 'static>(
+    pending: Res<PendingCalls<Q, R>>,
+    mut events: EventWriter<RpcCall<Q, R>>,
+) {
+    events.send_batch(pending.calls.lock().unwrap().try_iter());
+}
+
+type AppSetup = Box<dyn FnOnce(&mut App) + Send + Sync>;
+
+/// Runs an [RpcServer] as part of a Bevy app, see [crate]
+pub struct PiratesPlugin<S, Name>
+where
+    S: Send + 'static,
+    Name: RpcName + Send + Sync + 'static,
+{
+    state: Arc<Mutex<S>>,
+    listen_on: String,
+    /// Taken when the plugin is built
+    server: Mutex<Option<RpcServer<S, Name>>>,
+    setups: Mutex<Vec<AppSetup>>,
+    response_timeout: Duration,
+}
+
+impl<S, Name> PiratesPlugin<S, Name>
+where
+    S: Send + 'static,
+    Name: RpcName + Send + Sync + 'static,
+{
+    /// A plugin serving on [listen_on] with no rpcs yet
+    pub fn new(
+        state: Arc<Mutex<S>>,
+        transport_config: TransportConfig,
+        listen_on: impl Into<String>,
+    ) -> Self {
+        Self {
+            server: Mutex::new(Some(RpcServer::new(state.clone(), transport_config))),
+            state,
+            listen_on: listen_on.into(),
+            setups: Mutex::new(Vec::new()),
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+
+    /// The server, to add rpcs, interceptors or an authenticator to before the app starts
+    pub fn server_mut(&mut self) -> &mut RpcServer<S, Name> {
+        self.server
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .expect("The server is only taken when the plugin is built")
+    }
+
+    /// Fail [RpcCall]s that aren't answered within [response_timeout]
+    pub fn set_response_timeout(&mut self, response_timeout: Duration) {
+        self.response_timeout = response_timeout;
+    }
+
+    /// Serve the rpc [name] by sending each call to the app's systems as an [RpcCall] event.
+    /// While a call waits for its answer, the server handles nothing else
+    pub fn add_event_rpc<Q, R>(&mut self, name: Name)
+    where
+        Q: RpcType + Send + Sync,
+        R: RpcType + Send + Sync,
+    {
+        let (calls, pending) = mpsc::channel();
+        let response_timeout = self.response_timeout;
+        let rpc_name = name.to_string();
+        let rpc_impl: RpcImpl<Name, S, Q, R> = RpcImpl::new(
+            name,
+            Box::new(move |_state, query| {
+                let (responder, response) = mpsc::channel();
+                let call = RpcCall {
+                    query,
+                    responder: Mutex::new(Some(responder)),
+                };
+                calls
+                    .send(call)
+                    .map_err(|_| RpcError::Custom(String::from("The app has stopped")))?;
+                match response.recv_timeout(response_timeout) {
+                    Ok(result) => result,
+                    Err(RecvTimeoutError::Timeout) => Err(RpcError::Custom(format!(
+                        "{} was not answered within {:?}",
+                        rpc_name, response_timeout
+                    ))),
+                    Err(RecvTimeoutError::Disconnected) => Err(RpcError::Custom(format!(
+                        "{} was dropped without an answer",
+                        rpc_name
+                    ))),
+                }
+            }),
+        );
+        self.server_mut().add_rpc(Box::new(rpc_impl));
+        let pending = Mutex::new(pending);
+        self.setups.get_mut().unwrap().push(Box::new(move |app| {
+            app.add_event::<RpcCall<Q, R>>()
+                .insert_resource(PendingCalls { calls: pending })
+                .add_systems(PreUpdate, forward_calls::<Q, R>);
+        }));
+    }
+}
+
+impl<S, Name> Plugin for PiratesPlugin<S, Name>
+where
+    S: Send + 'static,
+    Name: RpcName + Send + Sync + 'static,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PiratesState(self.state.clone()));
+        for setup in self.setups.lock().unwrap().drain(..) {
+            setup(app);
+        }
+        let server = match self.server.lock().unwrap().take() {
+            Some(server) => Arc::new(server),
+            None => panic!("PiratesPlugin was built twice"),
+        };
+        let listen_on = self.listen_on.clone();
+        // The server needs tokio's reactor, so runs on a runtime of its own, taking one of the
+        // pool's threads (so the pool must be multi threaded)
+        IoTaskPool::get_or_init(TaskPool::new)
+            .spawn(async move {
+                match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => {
+                        if let Err(e) = runtime.block_on(server.serve(listen_on)) {
+                            error!("Could not serve pirates rpcs: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Could not start the pirates runtime: {}", e),
+                }
+            })
+            .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pirates::{Rpc, RpcClient};
+    use serde::{Deserialize, Serialize};
+    use std::fmt::Formatter;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    enum GameRpc {
+        GetScore,
+        Frame,
+    }
+
+    impl std::fmt::Display for GameRpc {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    impl RpcName for GameRpc {}
+
+    #[derive(Resource, Default)]
+    struct Frames(u64);
+
+    fn count_frames(mut frames: ResMut<Frames>) {
+        frames.0 += 1;
+    }
+
+    fn answer_frame(mut calls: EventReader<RpcCall<(), u64>>, frames: Res<Frames>) {
+        for call in calls.read() {
+            call.respond(Ok(frames.0));
+        }
+    }
+
+    #[test]
+    fn state_and_event_rpcs() {
+        // A free port, as the plugin binds the address itself
+        let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = probe.local_addr().unwrap().to_string();
+        drop(probe);
+        let mut pirates = PiratesPlugin::new(
+            Arc::new(Mutex::new(7u64)),
+            TransportConfig::default(),
+            addr.clone(),
+        );
+        pirates.server_mut().add_rpc(Box::new(RpcImpl::<_, u64, (), u64>::new(
+            GameRpc::GetScore,
+            Box::new(|score: &mut u64, ()| Ok(*score)),
+        )));
+        pirates.add_event_rpc::<(), u64>(GameRpc::Frame);
+        let mut app = App::new();
+        app.init_resource::<Frames>()
+            .add_plugins(pirates)
+            .add_systems(PreUpdate, count_frames)
+            .add_systems(bevy_app::Update, answer_frame);
+        *app.world().resource::<PiratesState<u64>>().0.lock().unwrap() = 8;
+
+        let client = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let get_score = RpcClient::new(Rpc::<_, (), u64>::new(GameRpc::GetScore));
+                let frame = RpcClient::new(Rpc::<_, (), u64>::new(GameRpc::Frame));
+                let mut transport = loop {
+                    match get_score.connect(&addr).await {
+                        Ok(transport) => break transport,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+                let score = get_score.call((), &mut transport).await.unwrap();
+                let frame = frame.call((), &mut transport).await.unwrap();
+                (score, frame)
+            })
+        });
+        while !client.is_finished() {
+            app.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (score, frame) = client.join().unwrap();
+        assert_eq!(score, 8);
+        assert!(frame > 0);
+    }
+}
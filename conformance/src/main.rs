@@ -0,0 +1,462 @@
+//! Conformance suite for the pirates wire protocol, so other implementations of it (e.g. a Python
+//! or embedded C client) can check they interoperate.
+//!
+//! `pirates-conformance server` serves the suite's rpcs in every wire format, on consecutive
+//! ports from `--port`: pickle, postcard, debug json lines, then pickle again requiring
+//! authentication. `pirates-conformance client` runs every case against a server, whether ours
+//! or another implementation's, and exits non-zero if any fail. To check a client, point it at
+//! our server and compare against the cases in [cases]
+use clap::{arg, value_parser};
+use pirates::admin::{self, AdminQuery};
+use pirates::error::{RpcError, RpcResult};
+use pirates::tls::{rustls, TlsClientConfig};
+use pirates::{
+    Identity, Rpc, RpcClient, RpcDefinition, RpcName, RpcServer, RpcType, TokenAuthenticator,
+    TokenCredentials, TransportConfig, TransportWireConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Formatter;
+use std::sync::{Arc, Mutex};
+
+/// Token of the admin rpcs, and of the client authenticating on the authenticated port
+const TOKEN: &str = "conformance";
+
+#[tokio::main]
+async fn main() {
+    let cmd = clap::Command::new("pirates-conformance")
+        .bin_name("pirates-conformance")
+        .subcommand_required(true)
+        .arg(
+            arg!(--host <HOST> "host to serve on or connect to")
+                .default_value("127.0.0.1")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--port <PORT> "first of the suite's four ports")
+                .default_value("5900")
+                .value_parser(value_parser!(u16)),
+        )
+        .subcommand(clap::Command::new("server").about("Serve the conformance rpcs"))
+        .subcommand(
+            clap::Command::new("client")
+                .about("Run the conformance cases against a server")
+                .arg(
+                    arg!(--wire <WIRE> "only check one wire format").value_parser([
+                        "pickle",
+                        "postcard",
+                        "debug-json-lines",
+                    ]),
+                ),
+        )
+        .get_matches();
+    let host = cmd.get_one::<String>("host").unwrap().clone();
+    let port = *cmd.get_one::<u16>("port").unwrap();
+
+    match cmd.subcommand() {
+        Some(("server", _)) => server(&host, port).await,
+        Some(("client", sub_match)) => {
+            let only_wire = sub_match.get_one::<String>("wire").cloned();
+            let passed = client(&host, port, only_wire).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        _ => {}
+    }
+}
+
+/// The formats of the suite's ports, in order
+fn wire_formats() -> Vec<TransportWireConfig> {
+    vec![
+        TransportWireConfig::default(),
+        TransportWireConfig::Postcard,
+        TransportWireConfig::DebugJsonLines,
+    ]
+}
+
+fn transport_config(wire_config: TransportWireConfig) -> TransportConfig {
+    TransportConfig {
+        wire_config,
+        ..Default::default()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+pub enum ConformanceRpc {
+    Echo,
+    EchoRecord,
+    EchoBytes,
+    Ping,
+    Fail,
+    Unavailable,
+    /// Never served, for the unknown rpc case
+    Unregistered,
+}
+impl std::fmt::Display for ConformanceRpc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl RpcName for ConformanceRpc {}
+
+/// A payload with nested, optional and collection fields
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u32,
+    pub label: String,
+    pub tags: Vec<String>,
+    pub score: Option<f64>,
+    pub nested: Option<Box<Record>>,
+}
+
+pub struct ConformanceState {}
+
+mod rpcs {
+    use crate::{ConformanceRpc, ConformanceState, Record};
+    use pirates::error::{RpcError, RpcResult};
+
+    pub struct Echo {}
+    #[pirates::rpc_definition]
+    impl Echo {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::Echo
+        }
+        fn implement(_state: &mut ConformanceState, query: String) -> RpcResult<String> {
+            Ok(query)
+        }
+    }
+
+    pub struct EchoRecord {}
+    #[pirates::rpc_definition]
+    impl EchoRecord {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::EchoRecord
+        }
+        fn implement(_state: &mut ConformanceState, query: Record) -> RpcResult<Record> {
+            Ok(query)
+        }
+    }
+
+    pub struct EchoBytes {}
+    #[pirates::rpc_definition]
+    impl EchoBytes {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::EchoBytes
+        }
+        fn implement(_state: &mut ConformanceState, query: Vec<u8>) -> RpcResult<Vec<u8>> {
+            Ok(query)
+        }
+    }
+
+    pub struct Ping {}
+    #[pirates::rpc_definition]
+    impl Ping {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::Ping
+        }
+        fn implement(_state: &mut ConformanceState, _query: ()) -> RpcResult<()> {
+            Ok(())
+        }
+    }
+
+    pub struct Fail {}
+    #[pirates::rpc_definition]
+    impl Fail {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::Fail
+        }
+        fn implement(_state: &mut ConformanceState, query: String) -> RpcResult<()> {
+            Err(RpcError::Custom(query))
+        }
+    }
+
+    pub struct Unavailable {}
+    #[pirates::rpc_definition]
+    impl Unavailable {
+        fn name() -> ConformanceRpc {
+            ConformanceRpc::Unavailable
+        }
+        fn implement(_state: &mut ConformanceState, query: String) -> RpcResult<()> {
+            Err(RpcError::Unavailable(query))
+        }
+    }
+}
+
+fn conformance_server(
+    transport_config: TransportConfig,
+) -> RpcServer<ConformanceState, ConformanceRpc> {
+    let state_ref = Arc::new(Mutex::new(ConformanceState {}));
+    let mut server = RpcServer::new(state_ref, transport_config);
+    server.add_rpc(Box::new(rpcs::Echo::server()));
+    server.add_rpc(Box::new(rpcs::EchoRecord::server()));
+    server.add_rpc(Box::new(rpcs::EchoBytes::server()));
+    server.add_rpc(Box::new(rpcs::Ping::server()));
+    server.add_rpc(Box::new(rpcs::Fail::server()));
+    server.add_rpc(Box::new(rpcs::Unavailable::server()));
+    server.enable_admin(TOKEN);
+    server
+}
+
+async fn server(host: &str, port: u16) {
+    let mut servers = tokio::task::JoinSet::new();
+    for (wire_config, port) in wire_formats().into_iter().zip(port..) {
+        let addr = format!("{}:{}", host, port);
+        println!("Serving {} on {}", wire_config.format_name(), addr);
+        let server = Arc::new(conformance_server(transport_config(wire_config)));
+        servers.spawn(async move { server.serve(addr).await.unwrap().join().await });
+    }
+    let mut authenticated = conformance_server(TransportConfig::default());
+    let mut authenticator = TokenAuthenticator::new();
+    authenticator.add_token(TOKEN, Identity::new("conformance"));
+    authenticated.set_authenticator(Box::new(authenticator));
+    let addr = format!("{}:{}", host, port + 3);
+    println!(
+        "Serving pickle, authenticated with token {:?}, on {}",
+        TOKEN, addr
+    );
+    let authenticated = Arc::new(authenticated);
+    servers.spawn(async move { authenticated.serve(addr).await.unwrap().join().await });
+    while servers.join_next().await.is_some() {}
+}
+
+/// Where a case connects, and how
+#[derive(Clone)]
+struct Target {
+    addr: String,
+    transport_config: TransportConfig,
+}
+
+impl Target {
+    fn client<Q: RpcType, R: RpcType>(
+        &self,
+        rpc: Rpc<ConformanceRpc, Q, R>,
+    ) -> RpcClient<ConformanceRpc, Q, R> {
+        let mut client = RpcClient::new(rpc);
+        client.set_transport_config(self.transport_config.clone());
+        client
+    }
+
+    async fn call<Q: RpcType, R: RpcType>(
+        &self,
+        rpc: Rpc<ConformanceRpc, Q, R>,
+        query: Q,
+    ) -> RpcResult<R> {
+        let client = self.client(rpc);
+        let mut transport = client.connect(&self.addr).await?;
+        client.call(query, &mut transport).await
+    }
+}
+
+type CaseResult = Result<(), String>;
+
+fn expect_eq<T: PartialEq + std::fmt::Debug>(expected: T, got: T) -> CaseResult {
+    if expected == got {
+        Ok(())
+    } else {
+        Err(format!("Expected {:?}, got {:?}", expected, got))
+    }
+}
+
+/// Check [result] is an error relayed from the server, retryable if [retryable]
+fn expect_remote<T: std::fmt::Debug>(result: RpcResult<T>, retryable: bool) -> CaseResult {
+    match result {
+        Err(RpcError::Remote(remote_error)) if remote_error.retryable == retryable => Ok(()),
+        other => Err(format!(
+            "Expected a remote error with retryable = {}, got {:?}",
+            retryable, other
+        )),
+    }
+}
+
+fn failed(e: RpcError) -> String {
+    e.to_string()
+}
+
+async fn query_response(target: Target) -> CaseResult {
+    let query = String::from("Ahoy, ünïcödé ☠");
+    let response = target.call(rpcs::Echo::client(), query.clone()).await;
+    expect_eq(query, response.map_err(failed)?)
+}
+
+async fn structured_payload(target: Target) -> CaseResult {
+    let record = Record {
+        id: u32::MAX,
+        label: String::from("outer"),
+        tags: vec![String::from("a"), String::new()],
+        score: Some(-1.5),
+        nested: Some(Box::new(Record {
+            id: 0,
+            label: String::from("inner"),
+            tags: vec![],
+            score: None,
+            nested: None,
+        })),
+    };
+    let response = target
+        .call(rpcs::EchoRecord::client(), record.clone())
+        .await;
+    expect_eq(record, response.map_err(failed)?)
+}
+
+async fn binary_payload(target: Target) -> CaseResult {
+    // Every byte value, so isn't valid UTF-8
+    let bytes: Vec<u8> = (0..=255).collect();
+    let response = target.call(rpcs::EchoBytes::client(), bytes.clone()).await;
+    expect_eq(bytes, response.map_err(failed)?)
+}
+
+async fn empty_payload(target: Target) -> CaseResult {
+    target.call(rpcs::Ping::client(), ()).await.map_err(failed)
+}
+
+async fn persistent_connection(target: Target) -> CaseResult {
+    let client = target.client(rpcs::Echo::client());
+    let mut transport = client.connect(&target.addr).await.map_err(failed)?;
+    for i in 0..3 {
+        let response = client.call(i.to_string(), &mut transport).await;
+        expect_eq(i.to_string(), response.map_err(failed)?)?;
+    }
+    Ok(())
+}
+
+async fn rpc_error(target: Target) -> CaseResult {
+    let result = target
+        .call(rpcs::Fail::client(), String::from("Failed on purpose"))
+        .await;
+    expect_remote(result, false)
+}
+
+async fn retryable_error(target: Target) -> CaseResult {
+    let result = target
+        .call(rpcs::Unavailable::client(), String::from("Busy on purpose"))
+        .await;
+    expect_remote(result, true)
+}
+
+async fn unknown_rpc(target: Target) -> CaseResult {
+    let rpc: Rpc<ConformanceRpc, (), ()> = Rpc::new(ConformanceRpc::Unregistered);
+    expect_remote(target.call(rpc, ()).await, false)
+}
+
+async fn malformed_query(target: Target) -> CaseResult {
+    // Not a string, or anything else, in any of the wire formats
+    let query_bytes = [0xff, 0x00, 0x13, 0x37];
+    let client = target.client(rpcs::Echo::client());
+    let mut transport = client.connect(&target.addr).await.map_err(failed)?;
+    let result = transport
+        .send_query(&query_bytes, &ConformanceRpc::Echo)
+        .await;
+    expect_remote(result, false)?;
+    // The connection is still usable afterwards
+    let response = client.call(String::from("after"), &mut transport).await;
+    expect_eq(String::from("after"), response.map_err(failed)?)
+}
+
+async fn heartbeat(target: Target) -> CaseResult {
+    let client = target.client(rpcs::Ping::client());
+    let mut transport = client.connect(&target.addr).await.map_err(failed)?;
+    transport.heartbeat().await.map_err(failed)?;
+    client.call((), &mut transport).await.map_err(failed)
+}
+
+async fn admin_rpc(target: Target) -> CaseResult {
+    let mut client = RpcClient::new(admin::dump_stats());
+    client.set_transport_config(target.transport_config.clone());
+    let mut transport = client.connect(&target.addr).await.map_err(failed)?;
+    client
+        .call(AdminQuery::new(TOKEN, ()), &mut transport)
+        .await
+        .map_err(failed)?;
+    let result = client
+        .call(AdminQuery::new("wrong token", ()), &mut transport)
+        .await;
+    expect_remote(result, false)
+}
+
+async fn starttls_refused(target: Target) -> CaseResult {
+    // Our server serves plaintext, so refuses the upgrade and the client carries on without TLS
+    let tls_config = TlsClientConfig::new(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth(),
+    ));
+    let client = target.client(rpcs::Ping::client());
+    let mut transport = client
+        .connect_starttls(&target.addr, &tls_config)
+        .await
+        .map_err(failed)?;
+    expect_eq(false, transport.internal_transport().is_tls())?;
+    client.call((), &mut transport).await.map_err(failed)
+}
+
+async fn authentication(target: Target) -> CaseResult {
+    match target.call(rpcs::Ping::client(), ()).await {
+        Err(RpcError::Remote(_)) => {}
+        other => return Err(format!("Expected to be refused, got {:?}", other)),
+    }
+    let mut client = target.client(rpcs::Ping::client());
+    client.set_authenticator(Arc::new(TokenCredentials::new(TOKEN)));
+    let mut transport = client.connect(&target.addr).await.map_err(failed)?;
+    client.call((), &mut transport).await.map_err(failed)
+}
+
+type Case = fn(Target) -> std::pin::Pin<Box<dyn std::future::Future<Output = CaseResult>>>;
+
+/// Every case run against each wire format
+fn cases() -> Vec<(&'static str, Case)> {
+    vec![
+        ("query and response", |t| Box::pin(query_response(t))),
+        ("structured payload", |t| Box::pin(structured_payload(t))),
+        ("binary payload", |t| Box::pin(binary_payload(t))),
+        ("empty payload", |t| Box::pin(empty_payload(t))),
+        ("persistent connection", |t| {
+            Box::pin(persistent_connection(t))
+        }),
+        ("rpc error", |t| Box::pin(rpc_error(t))),
+        ("retryable error", |t| Box::pin(retryable_error(t))),
+        ("unknown rpc", |t| Box::pin(unknown_rpc(t))),
+        ("malformed query", |t| Box::pin(malformed_query(t))),
+        ("heartbeat", |t| Box::pin(heartbeat(t))),
+        ("admin rpc", |t| Box::pin(admin_rpc(t))),
+        ("starttls refused", |t| Box::pin(starttls_refused(t))),
+    ]
+}
+
+/// Run the cases against the server on [host], true if all passed
+async fn client(host: &str, port: u16, only_wire: Option<String>) -> bool {
+    let mut runs = Vec::new();
+    for (wire_config, port) in wire_formats().into_iter().zip(port..) {
+        if only_wire
+            .as_ref()
+            .is_some_and(|only| only != wire_config.format_name())
+        {
+            continue;
+        }
+        let target = Target {
+            addr: format!("{}:{}", host, port),
+            transport_config: transport_config(wire_config),
+        };
+        for (name, case) in cases() {
+            runs.push((target.clone(), name, case));
+        }
+    }
+    if only_wire.as_ref().is_none_or(|only| only == "pickle") {
+        let target = Target {
+            addr: format!("{}:{}", host, port + 3),
+            transport_config: TransportConfig::default(),
+        };
+        runs.push((target, "authentication", |t| Box::pin(authentication(t))));
+    }
+
+    let mut failures = 0;
+    for (target, name, case) in runs {
+        let format = target.transport_config.wire_config.format_name();
+        match case(target).await {
+            Ok(()) => println!("PASS {} / {}", format, name),
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {} / {}: {}", format, name, e);
+            }
+        }
+    }
+    println!("{} failed", failures);
+    failures == 0
+}
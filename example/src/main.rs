@@ -1,5 +1,5 @@
 use clap::{arg, value_parser};
-use pirates::{call_client, RpcDefinition, RpcName, RpcServer, TransportConfig};
+use pirates::{ClientEnv, RpcDefinition, RpcName, RpcServer, TransportConfig};
 use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
 use std::sync::{Arc, Mutex};
@@ -7,7 +7,11 @@ use tokio;
 
 #[tokio::main]
 async fn main() {
-    let addr = "127.0.0.1:5858";
+    // Clients connect to PIRATES_CLIENT_ADDR when it's set anyway, so serve there too
+    let addr = ClientEnv::from_env()
+        .addr
+        .unwrap_or_else(|| String::from("127.0.0.1:5858"));
+    let addr = addr.as_str();
     let cmd = clap::Command::new("example")
         .bin_name("pirate_example")
         .subcommand_required(true)
@@ -70,7 +74,9 @@ async fn server(addr: &str) {
     server.add_rpc(Box::new(rpcs::AddName::server()));
     server.add_rpc(Box::new(rpcs::GetNames::server()));
     println!("Serving on {}!", addr);
-    server.serve(addr).await;
+    if let Err(e) = Arc::new(server).serve(addr).await {
+        eprintln!("Could not serve: {}", e);
+    }
 }
 
 enum CliSelection {
@@ -85,15 +91,18 @@ async fn client(addr: &str, selection: CliSelection) {
     }
 }
 
+pirates::rpc_client_bundle! {
+    struct NamesClient {
+        add_name: rpcs::AddName,
+        get_names: rpcs::GetNames,
+    }
+}
+
 async fn add_name_cli(addr: &str, name: String) {
-    call_client(addr, name, rpcs::AddName::client())
-        .await
-        .unwrap();
+    NamesClient::new(addr).add_name(name).await.unwrap();
 }
 async fn print_names_cli(addr: &str) {
-    let names = call_client(addr, (), rpcs::GetNames::client())
-        .await
-        .unwrap();
+    let names = NamesClient::new(addr).get_names(()).await.unwrap();
     for name in names {
         println!("{}", name);
     }
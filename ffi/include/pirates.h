@@ -0,0 +1,72 @@
+/*
+ * C API of pirates, for embedding a pirates server or client in applications not written in
+ * Rust. Link against the pirates_ffi library built from this crate.
+ *
+ * Rpcs are named by strings and take and return json documents. Every function returning an
+ * int32_t returns PIRATES_OK on success, or PIRATES_ERROR with the reason left for
+ * pirates_last_error.
+ */
+#ifndef PIRATES_H
+#define PIRATES_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PIRATES_OK 0
+#define PIRATES_ERROR -1
+
+typedef struct PiratesServer pirates_server;
+typedef struct PiratesResponse pirates_response;
+
+/*
+ * Implementation of an rpc: handle query_json and set response with pirates_response_set_json
+ * or pirates_response_set_error. Callbacks run one at a time, on one of pirates' threads.
+ */
+typedef void (*pirates_rpc_fn)(void *user_data, const char *query_json, pirates_response *response);
+
+/* The reason the last call on this thread that failed did, valid until another fails */
+const char *pirates_last_error(void);
+
+/* Free a string returned by pirates */
+void pirates_string_free(char *s);
+
+/* Respond with the json document json */
+void pirates_response_set_json(pirates_response *response, const char *json);
+
+/* Fail the call with message, seen by the client as a remote error */
+void pirates_response_set_error(pirates_response *response, const char *message);
+
+/* A new server with no rpcs, to free with pirates_server_free */
+pirates_server *pirates_server_new(void);
+
+/*
+ * Serve the rpc name with rpc, passing it user_data on every call. Must be called before
+ * pirates_server_serve
+ */
+int32_t pirates_server_add_rpc_json(pirates_server *server, const char *name, pirates_rpc_fn rpc,
+                                    void *user_data);
+
+/* Start serving on addr (e.g. "0.0.0.0:5959") in the background, returning once listening */
+int32_t pirates_server_serve(pirates_server *server, const char *addr);
+
+/* The port server is listening on, or 0 if it isn't serving */
+uint16_t pirates_server_port(const pirates_server *server);
+
+/* Stop server if it is serving, and free it */
+void pirates_server_free(pirates_server *server);
+
+/*
+ * Call the rpc name on the server at addr with query_json, blocking until it responds. On
+ * success response_json is set to the response, to free with pirates_string_free
+ */
+int32_t pirates_client_call(const char *addr, const char *name, const char *query_json,
+                            char **response_json);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PIRATES_H */
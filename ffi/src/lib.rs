@@ -0,0 +1,383 @@
+//! A C ABI for embedding a pirates server or client in non-Rust applications, built as the
+//! `pirates_ffi` dynamic (or static) library and declared in `include/pirates.h`.
+//!
+//! Rpcs are named by strings and take and return json documents, carried over the default
+//! pickle wire format. Names and payloads are made like those of Rust rpcs, so a C++ server can
+//! serve a Rust client whose rpc names are unit enum variants and whose types are plain structs,
+//! and the other way around
+//!
+//! ```c,ignore
+//! static void echo(void *user_data, const char *query_json, pirates_response *response) {
+//!     pirates_response_set_json(response, query_json);
+//! }
+//!
+//! pirates_server *server = pirates_server_new();
+//! pirates_server_add_rpc_json(server, "Echo", echo, NULL);
+//! pirates_server_serve(server, "127.0.0.1:5959");
+//!
+//! char *response_json;
+//! if (pirates_client_call("127.0.0.1:5959", "Echo", "\"ahoy\"", &response_json) == 0) {
+//!     printf("%s\n", response_json);
+//!     pirates_string_free(response_json);
+//! } else {
+//!     printf("Call failed: %s\n", pirates_last_error());
+//! }
+//! pirates_server_free(server);
+//! ```
+//!
+//! Every function returning an [i32] returns [PIRATES_OK] on success, or [PIRATES_ERROR] with
+//! the reason left for [pirates_last_error]
+use pirates::error::{RpcError, RpcResult};
+use pirates::{Rpc, RpcClient, RpcImpl, RpcServer, ServerHandle, TransportConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::runtime::Runtime;
+
+pub const PIRATES_OK: i32 = 0;
+pub const PIRATES_ERROR: i32 = -1;
+
+/// Rpc names given as strings, on the wire as a unit enum variant of the same name would be
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+struct FfiRpcName(String);
+
+impl Display for FfiRpcName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl pirates::RpcName for FfiRpcName {}
+
+/// The runtime every server and client call runs on, started on first use
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("pirates-ffi")
+            .enable_all()
+            .build()
+            .expect("Failed to start the pirates runtime")
+    })
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: impl Display) {
+    let message =
+        CString::new(message.to_string().replace('\0', "\\0")).expect("Nul bytes were replaced");
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+/// Run [f], returning [PIRATES_OK] if it succeeds and otherwise recording its error
+fn status(f: impl FnOnce() -> Result<(), String>) -> i32 {
+    match f() {
+        Ok(()) => PIRATES_OK,
+        Err(e) => {
+            set_last_error(e);
+            PIRATES_ERROR
+        }
+    }
+}
+
+/// # Safety
+/// [s] must be null or a nul terminated string
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is null", what));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| format!("{} is not utf-8: {}", what, e))
+}
+
+/// The reason the last call on this thread that failed did, valid until another fails
+#[no_mangle]
+pub extern "C" fn pirates_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+/// Free a string returned by pirates
+///
+/// # Safety
+/// [s] must be null or a string returned by pirates, not already freed
+#[no_mangle]
+pub unsafe extern "C" fn pirates_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Where an rpc callback sets its response, see [pirates_server_add_rpc_json]
+pub struct PiratesResponse {
+    result: Option<Result<String, String>>,
+}
+
+/// Respond with the json document [json]
+///
+/// # Safety
+/// [response] must be the response passed to the running callback, [json] a nul terminated
+/// string
+#[no_mangle]
+pub unsafe extern "C" fn pirates_response_set_json(
+    response: *mut PiratesResponse,
+    json: *const c_char,
+) {
+    if let Some(response) = response.as_mut() {
+        response.result = Some(str_arg(json, "json").map(String::from));
+    }
+}
+
+/// Fail the call with [message], seen by the client as a remote error
+///
+/// # Safety
+/// [response] must be the response passed to the running callback, [message] a nul terminated
+/// string
+#[no_mangle]
+pub unsafe extern "C" fn pirates_response_set_error(
+    response: *mut PiratesResponse,
+    message: *const c_char,
+) {
+    if let Some(response) = response.as_mut() {
+        let message = str_arg(message, "message").map_or_else(|e| e, String::from);
+        response.result = Some(Err(message));
+    }
+}
+
+/// Implementation of an rpc: handle [query_json] and set [response] with
+/// [pirates_response_set_json] or [pirates_response_set_error]
+pub type PiratesRpcFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    query_json: *const c_char,
+    response: *mut PiratesResponse,
+);
+
+/// The user data handed to an rpc callback. Pirates never touches it, and callbacks run one at a
+/// time, so it is up to the host to make it safe to use from pirates' threads
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// A server, configured until served with [pirates_server_serve]
+pub struct PiratesServer {
+    server: Option<RpcServer<(), FfiRpcName>>,
+    handle: Option<ServerHandle>,
+}
+
+/// A new server with no rpcs, to free with [pirates_server_free]
+#[no_mangle]
+pub extern "C" fn pirates_server_new() -> *mut PiratesServer {
+    let server = RpcServer::new(Arc::new(Mutex::new(())), TransportConfig::default());
+    Box::into_raw(Box::new(PiratesServer {
+        server: Some(server),
+        handle: None,
+    }))
+}
+
+fn call_json(rpc: PiratesRpcFn, user_data: &UserData, query: Value) -> RpcResult<Value> {
+    let query_json = CString::new(query.to_string()).expect("Json escapes nul bytes");
+    let mut response = PiratesResponse { result: None };
+    unsafe { rpc(user_data.get(), query_json.as_ptr(), &mut response) };
+    match response.result {
+        Some(Ok(json)) => serde_json::from_str(&json)
+            .map_err(|e| RpcError::Custom(format!("Rpc responded with invalid json: {}", e))),
+        Some(Err(message)) => Err(RpcError::Custom(message)),
+        None => Err(RpcError::Custom(String::from("Rpc set no response"))),
+    }
+}
+
+/// Serve the rpc [name] with [rpc], passing it [user_data] on every call. Must be called before
+/// [pirates_server_serve]
+///
+/// # Safety
+/// [server] must come from [pirates_server_new], [name] be a nul terminated string, and [rpc]
+/// safe to call with [user_data] from any thread
+#[no_mangle]
+pub unsafe extern "C" fn pirates_server_add_rpc_json(
+    server: *mut PiratesServer,
+    name: *const c_char,
+    rpc: PiratesRpcFn,
+    user_data: *mut c_void,
+) -> i32 {
+    status(|| {
+        let server = server.as_mut().ok_or("server is null")?;
+        let name = FfiRpcName(str_arg(name, "name")?.to_string());
+        let user_data = UserData(user_data);
+        let rpc_impl: RpcImpl<FfiRpcName, (), Value, Value> = RpcImpl::new(
+            name,
+            Box::new(move |_state, query| call_json(rpc, &user_data, query)),
+        );
+        server
+            .server
+            .as_mut()
+            .ok_or("Rpcs can't be added to a server already serving")?
+            .add_rpc(Box::new(rpc_impl));
+        Ok(())
+    })
+}
+
+/// Start serving on [addr] (e.g. "0.0.0.0:5959") in the background, returning once listening
+///
+/// # Safety
+/// [server] must come from [pirates_server_new] and [addr] be a nul terminated string
+#[no_mangle]
+pub unsafe extern "C" fn pirates_server_serve(
+    server: *mut PiratesServer,
+    addr: *const c_char,
+) -> i32 {
+    status(|| {
+        let server = server.as_mut().ok_or("server is null")?;
+        let addr = str_arg(addr, "addr")?;
+        let rpc_server = Arc::new(server.server.take().ok_or("Server is already serving")?);
+        let handle = runtime()
+            .block_on(rpc_server.spawn(addr))
+            .map_err(|e| format!("Could not serve on {}: {}", addr, e))?;
+        server.handle = Some(handle);
+        Ok(())
+    })
+}
+
+/// The port [server] is listening on, e.g. to find the port picked when serving on port 0, or 0
+/// if it isn't serving
+///
+/// # Safety
+/// [server] must come from [pirates_server_new]
+#[no_mangle]
+pub unsafe extern "C" fn pirates_server_port(server: *const PiratesServer) -> u16 {
+    server
+        .as_ref()
+        .and_then(|server| server.handle.as_ref())
+        .map_or(0, |handle| handle.local_addr().port())
+}
+
+/// Stop [server] if it is serving, and free it
+///
+/// # Safety
+/// [server] must be null or come from [pirates_server_new], not already freed
+#[no_mangle]
+pub unsafe extern "C" fn pirates_server_free(server: *mut PiratesServer) {
+    if server.is_null() {
+        return;
+    }
+    let server = Box::from_raw(server);
+    if let Some(handle) = server.handle {
+        handle.shutdown();
+    }
+}
+
+/// Call the rpc [name] on the server at [addr] with [query_json], blocking until it responds.
+/// On success [response_json] is set to the response, to free with [pirates_string_free]
+///
+/// # Safety
+/// [addr], [name] and [query_json] must be nul terminated strings, and [response_json] point to
+/// where the response can be written
+#[no_mangle]
+pub unsafe extern "C" fn pirates_client_call(
+    addr: *const c_char,
+    name: *const c_char,
+    query_json: *const c_char,
+    response_json: *mut *mut c_char,
+) -> i32 {
+    status(|| {
+        let addr = str_arg(addr, "addr")?;
+        let name = FfiRpcName(str_arg(name, "name")?.to_string());
+        let query: Value = serde_json::from_str(str_arg(query_json, "query_json")?)
+            .map_err(|e| format!("query_json is not valid json: {}", e))?;
+        if response_json.is_null() {
+            return Err(String::from("response_json is null"));
+        }
+        let client = RpcClient::new(Rpc::<FfiRpcName, Value, Value>::new(name));
+        let response = runtime()
+            .block_on(async {
+                let mut transport = client.connect(addr).await?;
+                client.call(query, &mut transport).await
+            })
+            .map_err(|e| e.to_string())?;
+        let response = CString::new(response.to_string()).expect("Json escapes nul bytes");
+        *response_json = response.into_raw();
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn shout(
+        user_data: *mut c_void,
+        query_json: *const c_char,
+        response: *mut PiratesResponse,
+    ) {
+        *(user_data as *mut u32) += 1;
+        let query: Value =
+            serde_json::from_str(CStr::from_ptr(query_json).to_str().unwrap()).unwrap();
+        match query.as_str() {
+            Some(query) => {
+                let json = CString::new(Value::from(query.to_uppercase()).to_string()).unwrap();
+                pirates_response_set_json(response, json.as_ptr());
+            }
+            None => pirates_response_set_error(response, c"Expected a string".as_ptr()),
+        }
+    }
+
+    #[test]
+    fn server_and_client() {
+        let mut calls = 0u32;
+        unsafe {
+            let server = pirates_server_new();
+            let rpc_status = pirates_server_add_rpc_json(
+                server,
+                c"Shout".as_ptr(),
+                shout,
+                &mut calls as *mut u32 as *mut c_void,
+            );
+            assert_eq!(rpc_status, PIRATES_OK);
+            assert_eq!(
+                pirates_server_serve(server, c"127.0.0.1:0".as_ptr()),
+                PIRATES_OK
+            );
+            let addr = CString::new(format!("127.0.0.1:{}", pirates_server_port(server))).unwrap();
+
+            let mut response_json = std::ptr::null_mut();
+            let call_status = pirates_client_call(
+                addr.as_ptr(),
+                c"Shout".as_ptr(),
+                c"\"ahoy\"".as_ptr(),
+                &mut response_json,
+            );
+            assert_eq!(call_status, PIRATES_OK);
+            assert_eq!(CStr::from_ptr(response_json).to_str().unwrap(), "\"AHOY\"");
+            pirates_string_free(response_json);
+
+            let call_status = pirates_client_call(
+                addr.as_ptr(),
+                c"Shout".as_ptr(),
+                c"3".as_ptr(),
+                &mut response_json,
+            );
+            assert_eq!(call_status, PIRATES_ERROR);
+            let error = CStr::from_ptr(pirates_last_error()).to_str().unwrap();
+            assert!(error.contains("Expected a string"), "{}", error);
+
+            // Adding rpcs once serving is refused
+            let rpc_status =
+                pirates_server_add_rpc_json(server, c"Late".as_ptr(), shout, std::ptr::null_mut());
+            assert_eq!(rpc_status, PIRATES_ERROR);
+            pirates_server_free(server);
+        }
+        assert_eq!(calls, 2);
+    }
+}
@@ -1,6 +1,9 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, AttributeArgs, ImplItem, ImplItemMethod, ItemImpl, ReturnType, Type};
+use syn::{
+    parse_macro_input, parse_quote, AttributeArgs, Data, DeriveInput, Fields, ImplItem,
+    ImplItemMethod, ItemImpl, Meta, NestedMeta, ReturnType, Type,
+};
 
 /*
 The macro takes:
@@ -22,6 +25,10 @@ and generates an extra impl block:
             RpcImpl::new(Self::name(), Box::new(Self::implement))
         }
     }
+
+and as `#[rpc_definition(register)]`, registers it for RpcServer::with_registered_rpcs:
+
+    inventory::submit! { RpcRegistration::of::<RPCIMPL, NAME, STATE, QUERY, RESPONSE>() }
 */
 
 fn find_fn_by_name<'a, 'b>(name: &'b str, items: &'a Vec<ImplItem>) -> Option<&'a ImplItemMethod> {
@@ -68,7 +75,12 @@ fn unpack_rpcresult_type(in_: &Type) -> &Type {
 
 #[proc_macro_attribute]
 pub fn rpc_definition(args: TokenStream, item: TokenStream) -> TokenStream {
-    let _args = parse_macro_input!(args as AttributeArgs);
+    let args = parse_macro_input!(args as AttributeArgs);
+    // `#[rpc_definition(register)]` registers the rpc for RpcServer::with_registered_rpcs
+    let register = args.iter().any(|arg| match arg {
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("register") => true,
+        _ => panic!("Unexpected argument, only 'register' is accepted"),
+    });
     let mut output_tokens = item.clone();
     eprintln!("Original Tokens:\n\n{:?}\n.\n.\n", item);
     let item = parse_macro_input!(item as ItemImpl);
@@ -131,5 +143,82 @@ pub fn rpc_definition(args: TokenStream, item: TokenStream) -> TokenStream {
     .into();
 
     output_tokens.extend(new_block);
+    if register {
+        let registration: TokenStream = quote! {
+            pirates::inventory::submit! {
+                pirates::registry::RpcRegistration::of::<
+                    #ty_rpc_impl, #ty_name, #ty_state, #ty_query, #ty_response
+                >()
+            }
+        }
+        .into();
+        output_tokens.extend(registration);
+    }
     output_tokens
 }
+
+/*
+The derive takes any (possibly generic) type:
+
+    #[derive(RpcType)]
+    struct QUERY<T> { ... }
+
+and marks it as a wire type, requiring its type parameters be wire types too:
+
+    impl<T: pirates::RpcType> pirates::RpcType for QUERY<T> {}
+*/
+#[proc_macro_derive(RpcType)]
+pub fn derive_rpc_type(item: TokenStream) -> TokenStream {
+    let mut item = parse_macro_input!(item as DeriveInput);
+    for param in item.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(pirates::RpcType));
+    }
+    let ty = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    quote! {
+        impl #impl_generics pirates::RpcType for #ty #ty_generics #where_clause {}
+    }
+    .into()
+}
+
+/*
+The derive takes an enum of unit variants:
+
+    #[derive(RpcNameList)]
+    enum NAME { A, B }
+
+and lists them, so servers can check each has an implementation:
+
+    impl pirates::RpcNameList for NAME {
+        fn all() -> Vec<Self> {
+            vec![Self::A, Self::B]
+        }
+    }
+*/
+#[proc_macro_derive(RpcNameList)]
+pub fn derive_rpc_name_list(item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as DeriveInput);
+    let variants = match &item.data {
+        Data::Enum(data_enum) => &data_enum.variants,
+        _ => panic!("RpcNameList can only be derived for enums"),
+    };
+    let variants = variants.iter().map(|variant| {
+        if !matches!(variant.fields, Fields::Unit) {
+            panic!(
+                "RpcNameList needs unit variants, {} has fields",
+                variant.ident
+            )
+        }
+        &variant.ident
+    });
+    let ty = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    quote! {
+        impl #impl_generics pirates::RpcNameList for #ty #ty_generics #where_clause {
+            fn all() -> std::vec::Vec<Self> {
+                std::vec![#(Self::#variants),*]
+            }
+        }
+    }
+    .into()
+}
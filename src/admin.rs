@@ -0,0 +1,183 @@
+//! Reserved admin RPCs for controlling a running [crate::RpcServer] with any pirates client.
+//!
+//! The set is disabled until [crate::RpcServer::enable_admin] is called with a token, and every
+//! call must carry that same token in its [AdminQuery]. The exception is [health], which every
+//! server answers for every client, so orchestrators and load balancers can probe it, and
+//! [reflection], open to every client of a server that enables it with
+//! [crate::RpcServer::set_reflection].
+//!
+//! ```rust,ignore
+//! let status = call_client(addr, (), pirates::admin::health()).await?;
+//! let token = String::from("hunter2");
+//! let stats = call_client(addr, AdminQuery::new(&token, ()), pirates::admin::dump_stats()).await?;
+//! call_client(addr, AdminQuery::new(&token, ()), pirates::admin::shutdown()).await?;
+//! ```
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use crate::stats::ServerStats;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Names of the reserved admin RPCs. These travel in their own namespace on the wire so they can
+/// never clash with the names of a server's own RPCs
+#[derive(Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum AdminRpcName {
+    Shutdown,
+    Drain,
+    DumpStats,
+    SetMaintenance,
+    DumpState,
+    /// Open to every client, needing no token, see [crate::schema]
+    #[cfg(feature = "schema")]
+    Schema,
+    UpdateConfig,
+    /// Open to every client, needing no token, see [health]
+    Health,
+    /// Open to every client, needing no token, see [reflection]
+    Reflection,
+}
+impl Display for AdminRpcName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Admin::{:?}", self)
+    }
+}
+impl RpcName for AdminRpcName {
+    const RESERVED: bool = true;
+}
+
+/// Query for all admin RPCs, the [token] must match the one given to [crate::RpcServer::enable_admin]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminQuery<T> {
+    pub token: String,
+    pub body: T,
+}
+
+impl<T> AdminQuery<T> {
+    pub fn new(token: &str, body: T) -> Self {
+        Self {
+            token: token.to_string(),
+            body,
+        }
+    }
+
+    pub(crate) fn authorise(self, expected_token: &str) -> RpcResult<T> {
+        if constant_time_eq(self.token.as_bytes(), expected_token.as_bytes()) {
+            Ok(self.body)
+        } else {
+            Err(RpcError::Custom(String::from("Admin token rejected")))
+        }
+    }
+}
+
+/// Whether [a] and [b] are equal, taking a time that depends only on their lengths, so a guessed
+/// token doesn't give away how much of it was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    a.len() == b.len() && std::hint::black_box(diff) == 0
+}
+
+/// Body of [set_maintenance], [rpc] is the [std::fmt::Display] form of the target RPC's name.
+/// [retry_after_ms] is how long the maintenance is expected to take, told to clients calling it
+/// meanwhile, see [crate::error::RpcError::Maintenance]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SetMaintenance {
+    pub rpc: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// Body of [update_config]: the settings to change on a running server, those left [None] being
+/// kept. Durations are in milliseconds, with 0 disabling those that are optional. Timeouts of
+/// [crate::TransportConfig] apply to connections opened from then on, see
+/// [crate::ServerHandle::update_config]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    pub rcv_timeout_ms: Option<u64>,
+    pub idle_timeout_ms: Option<u64>,
+    pub write_timeout_ms: Option<u64>,
+    /// See [crate::RpcServer::set_lock_timeout]
+    pub lock_timeout_ms: Option<u64>,
+    /// The most verbose level logged, by its name (e.g. "debug" or "off"), as with
+    /// [log::set_max_level]. This is set for the whole process, not just the server
+    pub max_log_level: Option<String>,
+}
+
+/// Response of [health]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    /// Accepting connections and answering calls
+    Serving,
+    /// Asked to shut down or drain, so best not sent new work
+    Stopping,
+}
+
+/// Check a server is up and whether it is stopping. Served by every [crate::RpcServer], even
+/// without [crate::RpcServer::enable_admin] or authentication, with no token needed
+pub fn health() -> Rpc<AdminRpcName, (), HealthStatus> {
+    Rpc::new(AdminRpcName::Health)
+}
+
+/// Response of [reflection]: what a server offers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reflection {
+    /// The [std::fmt::Display] forms of the names of the server's rpcs, sorted
+    pub rpcs: Vec<String>,
+    /// The wire format the connection is in, as [crate::TransportWireConfig::format_name]
+    pub wire_format: String,
+}
+
+/// List the rpcs a server has registered, for generic tooling to discover what it offers. Only
+/// served once enabled with [crate::RpcServer::set_reflection], but then with no token needed
+pub fn reflection() -> Rpc<AdminRpcName, (), Reflection> {
+    Rpc::new(AdminRpcName::Reflection)
+}
+
+/// Stop the server: connections close once their calls in flight are answered, then `serve`
+/// returns
+pub fn shutdown() -> Rpc<AdminRpcName, AdminQuery<()>, ()> {
+    Rpc::new(AdminRpcName::Shutdown)
+}
+
+/// Stop accepting new connections and let `serve` return, handing back the connections still
+/// open to wait on with [crate::Connections::join]
+pub fn drain() -> Rpc<AdminRpcName, AdminQuery<()>, ()> {
+    Rpc::new(AdminRpcName::Drain)
+}
+
+/// Fetch the server's [ServerStats]
+pub fn dump_stats() -> Rpc<AdminRpcName, AdminQuery<()>, ServerStats> {
+    Rpc::new(AdminRpcName::DumpStats)
+}
+
+/// Fetch the server's state, if dumps are enabled with [crate::RpcServer::enable_state_dump],
+/// as [T]: any type of the dump's shape, such as a copy of the server's state type
+pub fn dump_state<T: RpcType>() -> Rpc<AdminRpcName, AdminQuery<()>, T> {
+    Rpc::new(AdminRpcName::DumpState)
+}
+
+/// Toggle maintenance mode for one RPC. Calls to an RPC in maintenance are rejected
+pub fn set_maintenance() -> Rpc<AdminRpcName, AdminQuery<SetMaintenance>, ()> {
+    Rpc::new(AdminRpcName::SetMaintenance)
+}
+
+/// Change timeouts and log levels of the running server, see [ConfigUpdate]
+pub fn update_config() -> Rpc<AdminRpcName, AdminQuery<ConfigUpdate>, ()> {
+    Rpc::new(AdminRpcName::UpdateConfig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_compared() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter"));
+        assert!(!constant_time_eq(b"", b"hunter2"));
+        assert!(AdminQuery::new("hunter2", ()).authorise("hunter2").is_ok());
+        assert!(AdminQuery::new("guess", ()).authorise("hunter2").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::interceptor::{CallInfo, Interceptor};
+use crate::{Bytes, OwnedBytes};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Who a client authenticated as, attached to its connection and seen by interceptors as
+/// [crate::CallInfo::identity], and by handlers with [caller]. [roles] decide what it may call
+/// under an [AccessPolicy]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub name: String,
+    pub roles: BTreeSet<String>,
+}
+
+impl Identity {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            roles: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_role(&mut self, role: impl Into<String>) {
+        self.roles.insert(role.into());
+    }
+}
+
+thread_local! {
+    /// Who made the call being handled on this thread, see [caller]
+    static CALLER: std::cell::RefCell<Option<Identity>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Who made the call being handled, for handlers to authorize by. Only set while a handler runs
+/// under the state's lock, so the futures of async, streaming and uploading rpcs should read it
+/// before they're returned
+pub fn caller() -> Option<Identity> {
+    CALLER.with(|caller| caller.borrow().clone())
+}
+
+/// Run [f], a call made by [identity], on this thread
+pub(crate) fn with_caller<T>(identity: Option<&Identity>, f: impl FnOnce() -> T) -> T {
+    let previous = CALLER.with(|caller| caller.replace(identity.cloned()));
+    let t = f();
+    CALLER.with(|caller| *caller.borrow_mut() = previous);
+    t
+}
+
+/// Server side of the authentication handshake run as a client connects, see
+/// [crate::RpcServer::set_authenticator]. The client asks for a [Self::challenge], answers it
+/// with its [ClientAuthenticator], and the server checks the answer with [Self::authenticate].
+/// Queries on a connection are refused with [RpcError::Unauthenticated] until it succeeds
+pub trait Authenticator: Send + Sync {
+    /// Challenge sent to a client starting the handshake, e.g. a nonce for it to sign. Empty by
+    /// default
+    fn challenge(&self) -> OwnedBytes {
+        OwnedBytes::new()
+    }
+
+    /// Check a client's [response] to [challenge], returning who it is or
+    /// [RpcError::Unauthenticated]
+    fn authenticate(&self, challenge: Bytes, response: Bytes) -> RpcResult<Identity>;
+}
+
+/// Client side of the authentication handshake, see [crate::RpcClient::set_authenticator]
+pub trait ClientAuthenticator: Send + Sync {
+    /// Answer the server's [challenge]
+    fn respond(&self, challenge: Bytes) -> RpcResult<OwnedBytes>;
+}
+
+/// Authenticates every client as "anonymous" on the server, and answers every challenge with
+/// nothing on the client
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn authenticate(&self, _challenge: Bytes, _response: Bytes) -> RpcResult<Identity> {
+        Ok(Identity::new("anonymous"))
+    }
+}
+
+impl ClientAuthenticator for NoAuth {
+    fn respond(&self, _challenge: Bytes) -> RpcResult<OwnedBytes> {
+        Ok(OwnedBytes::new())
+    }
+}
+
+/// Authenticates clients presenting one of a set of tokens, each standing for an [Identity].
+/// Tokens are sent as they are, so only use this over TLS or a trusted network
+#[derive(Clone, Default)]
+pub struct TokenAuthenticator {
+    tokens: HashMap<String, Identity>,
+}
+
+impl TokenAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Authenticate clients presenting [token] as [identity]
+    pub fn add_token(&mut self, token: impl Into<String>, identity: Identity) {
+        self.tokens.insert(token.into(), identity);
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn authenticate(&self, _challenge: Bytes, response: Bytes) -> RpcResult<Identity> {
+        std::str::from_utf8(response)
+            .ok()
+            .and_then(|token| self.tokens.get(token))
+            .cloned()
+            .ok_or_else(|| RpcError::Unauthenticated(String::from("Unknown token")))
+    }
+}
+
+/// Client side of [TokenAuthenticator], presenting [token]
+#[derive(Clone)]
+pub struct TokenCredentials {
+    token: String,
+}
+
+impl TokenCredentials {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl ClientAuthenticator for TokenCredentials {
+    fn respond(&self, _challenge: Bytes) -> RpcResult<OwnedBytes> {
+        Ok(self.token.as_bytes().to_vec())
+    }
+}
+
+/// Role based access control: which roles may call which rpcs. Added to a server as an
+/// interceptor with [crate::RpcServer::add_interceptor], it rejects calls before dispatch with
+/// [RpcError::PermissionDenied] unless the caller's [Identity] has a role allowed the rpc, so
+/// needs an [Authenticator] on the server too
+///
+/// ```rust,ignore
+/// let mut policy = AccessPolicy::new();
+/// policy.allow("reader", RpcId::GetNames);
+/// policy.allow("writer", RpcId::GetNames);
+/// policy.allow("writer", RpcId::AddName);
+/// server.add_interceptor(Box::new(policy));
+/// ```
+pub struct AccessPolicy<Name: RpcName> {
+    allowed: HashMap<String, HashSet<Name>>,
+}
+
+impl<Name: RpcName> Default for AccessPolicy<Name> {
+    fn default() -> Self {
+        Self {
+            allowed: HashMap::new(),
+        }
+    }
+}
+
+impl<Name: RpcName> AccessPolicy<Name> {
+    /// A policy allowing nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow identities with [role] to call [rpc]
+    pub fn allow(&mut self, role: impl Into<String>, rpc: Name) {
+        self.allowed.entry(role.into()).or_default().insert(rpc);
+    }
+
+    /// Whether [identity] may call [rpc]
+    pub fn is_allowed(&self, identity: &Identity, rpc: &Name) -> bool {
+        identity.roles.iter().any(|role| {
+            self.allowed
+                .get(role)
+                .is_some_and(|allowed| allowed.contains(rpc))
+        })
+    }
+}
+
+impl<Name: RpcName + Send + Sync> Interceptor<Name> for AccessPolicy<Name> {
+    fn before_call(&self, call: &CallInfo<Name>) -> RpcResult<()> {
+        match call.identity {
+            Some(identity) if self.is_allowed(identity, call.name) => Ok(()),
+            identity => Err(RpcError::PermissionDenied {
+                rpc: call.name.to_string(),
+                identity: identity.map(|identity| identity.name.clone()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+    use crate::tests::HelloWorldRpcName;
+
+    #[test]
+    fn access_policy() {
+        let mut policy = AccessPolicy::new();
+        policy.allow("reader", HelloWorldRpcName::GetI);
+        policy.allow("writer", HelloWorldRpcName::GetI);
+        policy.allow("writer", HelloWorldRpcName::IncrI);
+        let mut reader = Identity::new("alice");
+        reader.add_role("reader");
+
+        let call = |name, identity| {
+            policy.before_call(&CallInfo {
+                name: &name,
+                query_bytes: &[],
+                identity,
+                metadata: &Metadata::new(),
+            })
+        };
+        assert!(call(HelloWorldRpcName::GetI, Some(&reader)).is_ok());
+        match call(HelloWorldRpcName::IncrI, Some(&reader)) {
+            Err(RpcError::PermissionDenied { rpc, identity }) => {
+                assert_eq!(rpc, HelloWorldRpcName::IncrI.to_string());
+                assert_eq!(identity.as_deref(), Some("alice"));
+            }
+            other => panic!("Expected PermissionDenied, got {:?}", other),
+        }
+        // Unauthenticated callers have no roles
+        assert!(call(HelloWorldRpcName::GetI, None).is_err());
+    }
+
+    #[test]
+    fn token_authenticator() {
+        let mut authenticator = TokenAuthenticator::new();
+        authenticator.add_token("s3cret", Identity::new("alice"));
+        let challenge = authenticator.challenge();
+
+        let response = TokenCredentials::new("s3cret").respond(&challenge).unwrap();
+        let identity = authenticator.authenticate(&challenge, &response).unwrap();
+        assert_eq!(identity, Identity::new("alice"));
+
+        let response = TokenCredentials::new("guess").respond(&challenge).unwrap();
+        match authenticator.authenticate(&challenge, &response) {
+            Err(RpcError::Unauthenticated(_)) => {}
+            other => panic!("Expected Unauthenticated, got {:?}", other),
+        }
+    }
+}
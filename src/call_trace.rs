@@ -0,0 +1,220 @@
+//! Per call timing of a server's phases, exported as a chrome://tracing (or Perfetto) trace to
+//! see where latency goes inside it (Enable the "call_trace" feature).
+//!
+//! Set a [TraceRecorder] with [crate::RpcServer::set_trace_recorder], then write out what it has
+//! recorded with [TraceRecorder::write_chrome_trace]. Each call is a span named after its rpc,
+//! split into the [Phase]s it went through, with a row per connection
+//!
+//! ```rust,ignore
+//! let recorder = Arc::new(TraceRecorder::new());
+//! server.set_trace_recorder(recorder.clone());
+//! // ... serve for a while
+//! recorder.write_chrome_trace(std::fs::File::create("pirates-trace.json")?)?;
+//! ```
+use std::time::{Duration, Instant};
+
+/// One stage of handling a call
+#[cfg_attr(not(feature = "call_trace"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Decoding the query's frame, from its bytes arriving (so not counting time waiting for the
+    /// client to send it)
+    Receive,
+    /// Waiting for the lock on the server's state
+    LockWait,
+    Deserialize,
+    /// Running the rpc's implementation
+    Handler,
+    Serialize,
+    /// Sending the response to the client
+    Send,
+}
+
+impl Phase {
+    #[cfg_attr(not(feature = "call_trace"), allow(dead_code))]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Receive => "receive",
+            Self::LockWait => "lock wait",
+            Self::Deserialize => "deserialize",
+            Self::Handler => "handler",
+            Self::Serialize => "serialize",
+            Self::Send => "send",
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "call_trace"), allow(dead_code))]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Span {
+    pub phase: Phase,
+    pub start: Instant,
+    pub duration: Duration,
+}
+
+#[cfg(feature = "call_trace")]
+thread_local! {
+    /// Spans of the call being traced on this thread, if any
+    static CURRENT: std::cell::RefCell<Option<Vec<Span>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Run [f] as [phase] of the call being traced on this thread, if any
+#[inline]
+pub(crate) fn phase<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "call_trace")]
+    if CURRENT.with(|current| current.borrow().is_some()) {
+        let start = Instant::now();
+        let t = f();
+        let span = Span {
+            phase,
+            start,
+            duration: start.elapsed(),
+        };
+        CURRENT.with(|current| current.borrow_mut().as_mut().map(|spans| spans.push(span)));
+        return t;
+    }
+    let _ = phase;
+    f()
+}
+
+/// Trace the phases of [f], a call run on this thread, returning them with its result
+#[cfg(feature = "call_trace")]
+pub(crate) fn traced<T>(f: impl FnOnce() -> T) -> (T, Vec<Span>) {
+    CURRENT.with(|current| *current.borrow_mut() = Some(Vec::new()));
+    let t = f();
+    let spans = CURRENT.with(|current| current.borrow_mut().take());
+    (t, spans.unwrap_or_default())
+}
+
+#[cfg(feature = "call_trace")]
+pub use recorder::TraceRecorder;
+
+#[cfg(feature = "call_trace")]
+mod recorder {
+    use super::Span;
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// Records the timing of calls for [Self::write_chrome_trace], up to [Self::max_events]
+    /// events, after which further calls are dropped
+    pub struct TraceRecorder {
+        pub max_events: usize,
+        epoch: Instant,
+        events: Mutex<Vec<TraceEvent>>,
+        next_connection: AtomicU64,
+    }
+
+    /// A complete ("X") event of the chrome trace event format
+    #[derive(Clone, Debug, Serialize)]
+    struct TraceEvent {
+        name: String,
+        cat: &'static str,
+        ph: &'static str,
+        ts: u64,
+        dur: u64,
+        pid: u32,
+        tid: u64,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ChromeTrace<'a> {
+        trace_events: &'a [TraceEvent],
+        display_time_unit: &'static str,
+    }
+
+    impl Default for TraceRecorder {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TraceRecorder {
+        pub fn new() -> Self {
+            Self {
+                max_events: 1_000_000,
+                epoch: Instant::now(),
+                events: Mutex::new(Vec::new()),
+                next_connection: AtomicU64::new(1),
+            }
+        }
+
+        /// Number of events recorded so far
+        pub fn len(&self) -> usize {
+            self.events.lock().unwrap().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Forget everything recorded so far
+        pub fn clear(&self) {
+            self.events.lock().unwrap().clear();
+        }
+
+        /// Write what has been recorded as a JSON trace, to open in chrome://tracing or Perfetto
+        pub fn write_chrome_trace(&self, writer: impl std::io::Write) -> std::io::Result<()> {
+            let events = self.events.lock().unwrap();
+            let trace = ChromeTrace {
+                trace_events: &events,
+                display_time_unit: "ms",
+            };
+            serde_json::to_writer(writer, &trace).map_err(std::io::Error::other)
+        }
+
+        /// Id of a new connection, its row in the trace
+        pub(crate) fn connection_id(&self) -> u64 {
+            self.next_connection.fetch_add(1, Ordering::Relaxed)
+        }
+
+        /// Record a call to [rpc] on connection [connection_id], made of [spans]
+        pub(crate) fn record_call(&self, rpc: &str, connection_id: u64, spans: &[Span]) {
+            let (Some(first), Some(last)) = (spans.first(), spans.last()) else {
+                return;
+            };
+            let mut events = self.events.lock().unwrap();
+            if events.len() + spans.len() + 1 > self.max_events {
+                return;
+            }
+            let call_end = last.start + last.duration;
+            events.push(self.event(
+                rpc.to_string(),
+                "call",
+                connection_id,
+                first.start,
+                call_end,
+            ));
+            for span in spans {
+                let end = span.start + span.duration;
+                let name = span.phase.name().to_string();
+                events.push(self.event(name, "phase", connection_id, span.start, end));
+            }
+        }
+
+        fn event(
+            &self,
+            name: String,
+            cat: &'static str,
+            tid: u64,
+            start: Instant,
+            end: Instant,
+        ) -> TraceEvent {
+            TraceEvent {
+                name,
+                cat,
+                ph: "X",
+                ts: micros(start.saturating_duration_since(self.epoch)),
+                dur: micros(end.saturating_duration_since(start)),
+                pid: std::process::id(),
+                tid,
+            }
+        }
+    }
+
+    fn micros(duration: Duration) -> u64 {
+        duration.as_micros().min(u64::MAX as u128) as u64
+    }
+}
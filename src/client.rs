@@ -1,19 +1,536 @@
+use crate::auth::ClientAuthenticator;
 use crate::core::{Rpc, RpcName, RpcType};
-use crate::error::{into_rpc_result_transport, RpcError, RpcResult};
+use crate::error::{RpcError, RpcResult};
+use crate::interceptor::{CallOutcome, ClientCallInfo, ClientInterceptor};
+use crate::memory::MemoryTransport;
+use crate::metadata::Metadata;
+use crate::resolver::{Resolver, SystemResolver, CONNECTION_ATTEMPT_DELAY};
+use crate::stats::RpcStats;
+use crate::streaming::{ResponseChunks, ValuesBody};
+use crate::tasks;
 use crate::transport::{
-    InternalTransport, TcpTransport, Transport, TransportConfig, TransportError,
+    InternalTransport, QueryOptions, StreamTransport, TcpTransport, Transport, TransportConfig,
+    TransportError, TransportWireConfig, VersionCheck,
 };
+use crate::udp::UdpTransport;
+use crate::OwnedBytes;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+use tokio::sync::{mpsc, oneshot};
+
+/// Hooks an [RpcClient] calls as its connectivity changes, so applications can log or alert on
+/// degraded connectivity rather than only seeing the final error. All methods default to no-ops
+pub trait ClientEvents: Send + Sync {
+    /// A connection to [addr] was established
+    fn on_connect(&self, _addr: &str) {}
+    /// A call is about to be retried after failing with [error], [attempt] counts from 1
+    fn on_retry(&self, _attempt: u32, _error: &RpcError) {}
+    /// No response arrived within [timeout]
+    fn on_timeout(&self, _timeout: Duration) {}
+    /// A dropped connection to [addr] was re-established
+    fn on_reconnect(&self, _addr: &str) {}
+}
+
+/// How [RpcClient::call_retrying] retries calls failing with a transient error, see
+/// [RpcError::is_retryable]. The first retry waits [Self::initial_backoff], doubling with each
+/// further retry up to [Self::max_backoff]. Each wait is cut short by a random fraction of up to
+/// [Self::jitter], so clients failing together don't all retry together
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first included
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// From 0, always waiting the full backoff, to 1
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before [retry], counting from 1
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let doublings = retry.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random_fraction())
+    }
+}
+
+/// Uniform in [0, 1), good enough to spread retries but no more
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Client settings overridden by environment variables, so that CLIs built on pirates are all
+/// configured the same way. Read once per process, by [RpcClient::new]. Unparseable values are
+/// logged and ignored. Named apart from the `PIRATES_SERVER_` overrides of a server's config, so
+/// a server and its clients can share an environment
+#[derive(Clone, Debug, Default)]
+pub struct ClientEnv {
+    /// `PIRATES_CLIENT_ADDR`: the server address connected to, in place of the one given
+    pub addr: Option<String>,
+    /// `PIRATES_CLIENT_TIMEOUT_MS`: the [TransportConfig::rcv_timeout]
+    pub timeout: Option<Duration>,
+    /// `PIRATES_CLIENT_WIRE_FORMAT`: the [TransportConfig::wire_config], by
+    /// [TransportWireConfig::format_name]
+    pub wire_config: Option<TransportWireConfig>,
+}
+
+impl ClientEnv {
+    /// The overrides set in the process's environment now
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let timeout =
+            var("PIRATES_CLIENT_TIMEOUT_MS").and_then(|timeout_ms| match timeout_ms.parse() {
+                Ok(timeout_ms) => Some(Duration::from_millis(timeout_ms)),
+                Err(e) => {
+                    log::warn!("Ignoring PIRATES_CLIENT_TIMEOUT_MS={}: {}", timeout_ms, e);
+                    None
+                }
+            });
+        let wire_config = var("PIRATES_CLIENT_WIRE_FORMAT").and_then(|wire_format| {
+            let wire_config = TransportWireConfig::from_format_name(&wire_format);
+            if wire_config.is_none() {
+                log::warn!(
+                    "Ignoring PIRATES_CLIENT_WIRE_FORMAT={}: unknown format",
+                    wire_format
+                );
+            }
+            wire_config
+        });
+        Self {
+            addr: var("PIRATES_CLIENT_ADDR").filter(|addr| !addr.is_empty()),
+            timeout,
+            wire_config,
+        }
+    }
+
+    fn process() -> Arc<Self> {
+        static PROCESS: OnceLock<Arc<ClientEnv>> = OnceLock::new();
+        PROCESS.get_or_init(|| Arc::new(Self::from_env())).clone()
+    }
+
+    fn addr<'a>(&'a self, addr: &'a str) -> &'a str {
+        self.addr.as_deref().unwrap_or(addr)
+    }
+
+    fn apply(&self, transport_config: &mut TransportConfig) {
+        if let Some(timeout) = self.timeout {
+            transport_config.rcv_timeout = timeout;
+        }
+        if let Some(wire_config) = &self.wire_config {
+            transport_config.wire_config = wire_config.clone();
+        }
+    }
+}
 
 /// An [RpcClient] encapsulates an Rpc and allows it to be called, providing a [Transport]
 /// a convenience function, [call_client] is provided which wraps this type and uses the
-/// [TcpTransport] transport
+/// [TcpTransport] transport. Clones are cheap, and a [SharedTransport] lets clones in many
+/// tasks call over one connection. The process's [ClientEnv] overrides its settings
+#[derive(Clone)]
 pub struct RpcClient<Name: RpcName, Q: RpcType, R: RpcType> {
     rpc: Rpc<Name, Q, R>,
+    /// Shared by clones, see [Self::stats]
+    stats: Arc<Mutex<RpcStats>>,
+    env: Option<Arc<ClientEnv>>,
+    events: Option<Arc<dyn ClientEvents>>,
+    interceptors: Vec<Arc<dyn ClientInterceptor<Name>>>,
+    authenticator: Option<Arc<dyn ClientAuthenticator>>,
+    resolver: Arc<dyn Resolver>,
+    transport_config: TransportConfig,
+    retry_policy: RetryPolicy,
+    #[cfg(feature = "schema")]
+    schema_check: bool,
+    #[cfg(feature = "type_hash")]
+    type_hash: std::sync::OnceLock<Option<u64>>,
 }
 
-impl<'de, Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
+impl<Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
     pub fn new(rpc: Rpc<Name, Q, R>) -> Self {
-        Self { rpc }
+        Self {
+            rpc,
+            stats: Arc::new(Mutex::new(RpcStats::default())),
+            env: Some(ClientEnv::process()),
+            events: None,
+            interceptors: Vec::new(),
+            authenticator: None,
+            resolver: Arc::new(SystemResolver),
+            transport_config: TransportConfig::default(),
+            retry_policy: RetryPolicy::default(),
+            #[cfg(feature = "schema")]
+            schema_check: false,
+            #[cfg(feature = "type_hash")]
+            type_hash: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Counts, error rate and latencies of the calls made by this client and its clones so far,
+    /// e.g. to base timeouts on [crate::LatencyHistogram::quantile]. Calls failing before they
+    /// were sent, say to serialise their query, aren't counted
+    pub fn stats(&self) -> RpcStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Send connectivity events for this client's calls to [events]
+    pub fn set_events(&mut self, events: Arc<dyn ClientEvents>) {
+        self.events = Some(events);
+    }
+
+    /// Run [interceptor] around every call this client and its clones make from now on, bar
+    /// streaming, uploading and shared calls. See [ClientInterceptor]
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn ClientInterceptor<Name>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Authenticate every new connection made with [Self::connect] with [authenticator], for
+    /// servers requiring it, see [crate::Authenticator]
+    pub fn set_authenticator(&mut self, authenticator: Arc<dyn ClientAuthenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Resolve the addresses passed to [Self::connect] with [resolver], rather than the system's
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Use [transport_config] for connections made with [Self::connect], rather than the default
+    pub fn set_transport_config(&mut self, transport_config: TransportConfig) {
+        self.transport_config = transport_config;
+    }
+
+    /// Retry [Self::call_retrying] under [retry_policy], rather than the default
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Override this client's settings with [env] rather than the process's [ClientEnv], or not at
+    /// all with [None]
+    pub fn set_env(&mut self, env: Option<ClientEnv>) {
+        self.env = env.map(Arc::new);
+    }
+
+    /// [addr], unless overridden by the [ClientEnv]
+    fn addr<'a>(&'a self, addr: &'a str) -> &'a str {
+        match &self.env {
+            Some(env) => env.addr(addr),
+            None => addr,
+        }
+    }
+
+    /// The [TransportConfig] of new connections, with the [ClientEnv]'s overrides
+    fn connection_config(&self) -> TransportConfig {
+        let mut transport_config = self.transport_config.clone();
+        if let Some(env) = &self.env {
+            env.apply(&mut transport_config);
+        }
+        transport_config
+    }
+
+    /// Run [Self::check_schema] on every new connection made with [Self::connect]
+    #[cfg(feature = "schema")]
+    pub fn set_schema_check(&mut self, enabled: bool) {
+        self.schema_check = enabled;
+    }
+
+    /// Fetch the server's schema for this client's rpc and check it matches the client's own
+    /// query and response types, failing with [RpcError::SchemaMismatch] if not. See [crate::schema]
+    #[cfg(feature = "schema")]
+    pub async fn check_schema(
+        &self,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<()> {
+        use crate::schema::RpcSchema;
+        let query_bytes = transport.config.wire_config.serialize(&())?;
+        let schema_bytes = transport
+            .send_reserved_query(&query_bytes, &crate::admin::AdminRpcName::Schema)
+            .await?;
+        let schemas: std::collections::HashMap<String, RpcSchema> =
+            transport.config.wire_config.deserialize(&schema_bytes)?;
+        // Traced only now, as a schema can't be held across an await
+        let local = RpcSchema::of::<Q, R>()?;
+        let rpc = self.rpc.name.to_string();
+        match schemas.get(&rpc) {
+            Some(server) => local
+                .check_compatible(server)
+                .map_err(|reason| RpcError::SchemaMismatch { rpc, reason }),
+            None => Err(RpcError::SchemaMismatch {
+                rpc,
+                reason: String::from("not served by the server"),
+            }),
+        }
+    }
+
+    /// Connect to the server at [addr] with a [TcpTransport]. Where [addr] resolves to several
+    /// addresses they are tried "happy eyeballs" style, alternating between IPv6 and IPv4 with a
+    /// new attempt started every [CONNECTION_ATTEMPT_DELAY] until one connects
+    pub async fn connect(&self, addr: &str) -> RpcResult<Transport<TcpTransport, Name>> {
+        let addr = self.addr(addr);
+        let tcp_transport = self.tcp_transport(addr).await?;
+        self.finish_connect(tcp_transport).await
+    }
+
+    /// [Self::connect] over TLS, see [crate::tls]. The handshake counts towards
+    /// [TransportConfig::connect_timeout]
+    #[cfg(feature = "transport_tls")]
+    pub async fn connect_tls(
+        &self,
+        addr: &str,
+        tls_config: &crate::tls::TlsClientConfig,
+    ) -> RpcResult<Transport<crate::tls::TlsTransport, Name>> {
+        let addr = self.addr(addr);
+        let tcp_stream = self.connect_tcp(addr).await?;
+        let mut tls_transport = self
+            .within_connect_timeout(addr, crate::tls::connect(tls_config, addr, tcp_stream))
+            .await?;
+        if let Some(keepalive) = self.transport_config.keepalive {
+            tls_transport.set_keepalive(keepalive)?;
+        }
+        tls_transport.set_write_timeout(self.transport_config.write_timeout);
+        self.finish_connect(tls_transport).await
+    }
+
+    /// [Self::connect] over a WebSocket, opened on [path] of the server at [addr], see
+    /// [crate::websocket]. The handshake counts towards [TransportConfig::connect_timeout]
+    #[cfg(feature = "transport_websocket")]
+    pub async fn connect_websocket(
+        &self,
+        addr: &str,
+        path: &str,
+    ) -> RpcResult<Transport<crate::websocket::WebSocketTransport, Name>> {
+        let addr = self.addr(addr);
+        let tcp_stream = self.connect_tcp(addr).await?;
+        if let Some(keepalive) = self.transport_config.keepalive {
+            crate::transport::set_keepalive(&tcp_stream, keepalive)?;
+        }
+        let mut websocket_transport = self
+            .within_connect_timeout(addr, crate::websocket::connect(addr, path, tcp_stream))
+            .await?;
+        websocket_transport.set_write_timeout(self.transport_config.write_timeout);
+        self.finish_connect(websocket_transport).await
+    }
+
+    /// Call the server at [addr] over UDP, resending each request every [retransmit_interval]
+    /// until it's answered or [TransportConfig::rcv_timeout] is up, see [crate::udp]
+    pub async fn connect_udp(
+        &self,
+        addr: &str,
+        retransmit_interval: Duration,
+    ) -> RpcResult<Transport<UdpTransport, Name>> {
+        let addr = self.addr(addr);
+        let connect_error = |e: std::io::Error| {
+            RpcError::TransportError(TransportError::ConnectError(e.to_string()))
+        };
+        let peer = tokio::net::lookup_host(addr)
+            .await
+            .map_err(connect_error)?
+            .next()
+            .ok_or_else(|| {
+                RpcError::TransportError(TransportError::ConnectError(format!(
+                    "{} resolved to no addresses",
+                    addr
+                )))
+            })?;
+        let local: std::net::SocketAddr = match peer {
+            std::net::SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+            std::net::SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = tokio::net::UdpSocket::bind(local)
+            .await
+            .map_err(connect_error)?;
+        socket.connect(peer).await.map_err(connect_error)?;
+        if let Some(events) = &self.events {
+            events.on_connect(addr);
+        }
+        self.finish_connect(UdpTransport::new(socket, retransmit_interval))
+            .await
+    }
+
+    /// [Self::connect] with every frame encrypted under the pre-shared [key], see
+    /// [crate::encryption]
+    #[cfg(feature = "transport_encryption")]
+    pub async fn connect_encrypted(
+        &self,
+        addr: &str,
+        key: [u8; 32],
+    ) -> RpcResult<Transport<crate::encryption::EncryptedTransport<TcpTransport>, Name>> {
+        let tcp_transport = self.tcp_transport(self.addr(addr)).await?;
+        self.finish_connect(crate::encryption::EncryptedTransport::client(
+            tcp_transport,
+            key,
+        ))
+        .await
+    }
+
+    /// Connect to the server at [addr] in plaintext and upgrade the connection to TLS if the
+    /// server supports it, see [crate::RpcServer::serve_starttls]. Use [Self::connect_tls]
+    /// where TLS is required, as a server (or attacker) can always decline the upgrade
+    #[cfg(feature = "transport_tls")]
+    pub async fn connect_starttls(
+        &self,
+        addr: &str,
+        tls_config: &crate::tls::TlsClientConfig,
+    ) -> RpcResult<Transport<crate::tls::MaybeTlsTransport, Name>> {
+        use crate::tls::MaybeTlsTransport;
+        let addr = self.addr(addr);
+        let mut transport: Transport<TcpTransport, Name> =
+            Transport::new(self.tcp_transport(addr).await?, self.connection_config());
+        let internal_transport = if transport.start_tls().await? {
+            let tcp_stream = transport.into_internal_transport().into_stream();
+            let mut tls_transport = self
+                .within_connect_timeout(addr, crate::tls::connect(tls_config, addr, tcp_stream))
+                .await?;
+            tls_transport.set_write_timeout(self.transport_config.write_timeout);
+            MaybeTlsTransport::Tls(Box::new(tls_transport))
+        } else {
+            log::info!("Server at {} declined to upgrade to TLS", addr);
+            MaybeTlsTransport::Plain(transport.into_internal_transport())
+        };
+        self.finish_connect(internal_transport).await
+    }
+
+    /// [Self::connect_tls] using the platform's TLS stack, see [crate::native_tls]
+    #[cfg(feature = "transport_native_tls")]
+    pub async fn connect_native_tls(
+        &self,
+        addr: &str,
+        tls_config: &crate::native_tls::NativeTlsClientConfig,
+    ) -> RpcResult<Transport<crate::native_tls::NativeTlsTransport, Name>> {
+        let addr = self.addr(addr);
+        let tcp_stream = self.connect_tcp(addr).await?;
+        if let Some(keepalive) = self.transport_config.keepalive {
+            crate::transport::set_keepalive(&tcp_stream, keepalive)?;
+        }
+        let tls_stream = self
+            .within_connect_timeout(
+                addr,
+                crate::native_tls::connect(tls_config, addr, tcp_stream),
+            )
+            .await?;
+        let mut native_tls_transport = StreamTransport::new(tls_stream);
+        native_tls_transport.set_write_timeout(self.transport_config.write_timeout);
+        self.finish_connect(native_tls_transport).await
+    }
+
+    /// Use [stream], already connected to a server over any duplex byte stream (e.g. a channel
+    /// of a multiplexed tunnel), for calls. Runs the same authentication and checks as
+    /// [Self::connect]
+    pub async fn over_stream<S>(&self, stream: S) -> RpcResult<Transport<StreamTransport<S>, Name>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+    {
+        let mut stream_transport = StreamTransport::new(stream);
+        stream_transport.set_write_timeout(self.transport_config.write_timeout);
+        self.finish_connect(stream_transport).await
+    }
+
+    /// Use [transport], one end of an in-process connection, for calls, see [crate::memory]. Runs
+    /// the same authentication and checks as [Self::connect]
+    pub async fn over_memory(
+        &self,
+        transport: MemoryTransport,
+    ) -> RpcResult<Transport<MemoryTransport, Name>> {
+        self.finish_connect(transport).await
+    }
+
+    async fn tcp_transport(&self, addr: &str) -> RpcResult<TcpTransport> {
+        let mut tcp_transport = TcpTransport::new(self.connect_tcp(addr).await?);
+        if let Some(keepalive) = self.transport_config.keepalive {
+            tcp_transport.set_keepalive(keepalive)?;
+        }
+        tcp_transport.set_write_timeout(self.transport_config.write_timeout);
+        Ok(tcp_transport)
+    }
+
+    async fn connect_tcp(&self, addr: &str) -> RpcResult<tokio::net::TcpStream> {
+        let connect_fut =
+            crate::resolver::connect(self.resolver.as_ref(), addr, CONNECTION_ATTEMPT_DELAY);
+        let tcp_stream = self.within_connect_timeout(addr, connect_fut).await?;
+        if let Some(events) = &self.events {
+            events.on_connect(addr);
+        }
+        Ok(tcp_stream)
+    }
+
+    async fn within_connect_timeout<T>(
+        &self,
+        addr: &str,
+        connect_fut: impl std::future::Future<Output = std::io::Result<T>>,
+    ) -> RpcResult<T> {
+        let connect_result = match self.transport_config.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect_fut)
+                .await
+                .map_err(|_| {
+                    RpcError::TransportError(TransportError::ConnectError(format!(
+                        "Timed out after {:?} connecting to {}",
+                        connect_timeout, addr
+                    )))
+                })?,
+            None => connect_fut.await,
+        };
+        connect_result
+            .map_err(|e| RpcError::TransportError(TransportError::ConnectError(format!("{}", e))))
+    }
+
+    async fn finish_connect<I: InternalTransport + Send>(
+        &self,
+        internal_transport: I,
+    ) -> RpcResult<Transport<I, Name>> {
+        let mut transport = Transport::new(internal_transport, self.connection_config());
+        if let Some(authenticator) = &self.authenticator {
+            transport.authenticate(authenticator.as_ref()).await?;
+        }
+        #[cfg(feature = "schema")]
+        if self.schema_check {
+            self.check_schema(&mut transport).await?;
+        }
+        Ok(transport)
+    }
+
+    pub(crate) fn fire_reconnect(&self, addr: &str) {
+        if let Some(events) = &self.events {
+            events.on_reconnect(addr);
+        }
+    }
+
+    /// Hash of this client's types, sent with each query. Types that can't be traced are sent
+    /// without one, skipping the server's check
+    #[cfg(feature = "type_hash")]
+    fn type_hash(&self) -> Option<u64> {
+        *self
+            .type_hash
+            .get_or_init(|| match crate::type_hash::type_hash::<Name, Q, R>() {
+                Ok(type_hash) => Some(type_hash),
+                Err(e) => {
+                    log::warn!("Sending queries without a type hash: {}", e);
+                    None
+                }
+            })
+    }
+
+    #[cfg(not(feature = "type_hash"))]
+    fn type_hash(&self) -> Option<u64> {
+        None
     }
 
     /// Call the rpc, using the specified [Transport] to connect to the server
@@ -22,40 +539,471 @@ impl<'de, Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
         query: Q,
         transport: &mut Transport<impl InternalTransport, Name>,
     ) -> RpcResult<R> {
+        let (response, _) = self
+            .call_with_options(query, QueryOptions::default(), transport)
+            .await?;
+        Ok(response)
+    }
+
+    /// [Self::connect] to [addr] and [Self::call], connecting afresh to try again for as long as
+    /// the call fails with a retryable error, under the client's [RetryPolicy]. Only for
+    /// idempotent rpcs, as a call whose response was lost may have been made already; retry
+    /// others with [Self::call_idempotent]
+    pub async fn call_retrying(&self, addr: &str, query: Q) -> RpcResult<R> {
+        let mut retry = 0;
+        loop {
+            let result = match self.connect(addr).await {
+                Ok(mut transport) => self.call(query.clone(), &mut transport).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if e.is_retryable() && retry + 1 < self.retry_policy.max_attempts => {
+                    retry += 1;
+                    log::debug!("Retrying {} after: {}", self.rpc.name, e);
+                    if let Some(events) = &self.events {
+                        events.on_retry(retry, &e);
+                    }
+                    tokio::time::sleep(self.retry_policy.backoff(retry)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// [Self::call], also returning the version of the server's state the call left, see
+    /// [crate::RpcServer::state_version]. Given an [expected_version], the call is only made if
+    /// the state is still at it, failing with [RpcError::Conflict] otherwise; so a value read
+    /// with one call can be written back with the next without holding any lock in between
+    pub async fn call_versioned(
+        &self,
+        query: Q,
+        expected_version: Option<u64>,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<(R, u64)> {
+        let version_check = match expected_version {
+            Some(expected_version) => VersionCheck::Expect(expected_version),
+            None => VersionCheck::Report,
+        };
+        let options = QueryOptions {
+            version_check: Some(version_check),
+            ..QueryOptions::default()
+        };
+        let (response, version) = self.call_with_options(query, options, transport).await?;
+        Ok((response, version.unwrap_or_default()))
+    }
+
+    /// [Self::call] with an idempotency [key], so if the call is retried (say after its response
+    /// was lost) a server with [crate::RpcServer::enable_idempotency] answers with the response
+    /// of the first call rather than making it again. Keys are scoped to the rpc and the
+    /// client's identity, use a fresh one for each distinct operation, such as a
+    /// [crate::idempotency::RequestId]
+    pub async fn call_idempotent(
+        &self,
+        query: Q,
+        key: impl Into<String>,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        let options = QueryOptions {
+            idempotency_key: Some(key.into()),
+            ..QueryOptions::default()
+        };
+        let (response, _) = self.call_with_options(query, options, transport).await?;
+        Ok(response)
+    }
+
+    /// [Self::call], sending [metadata] along with the query, see [Metadata]
+    pub async fn call_with_metadata(
+        &self,
+        query: Q,
+        metadata: Metadata,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        let options = QueryOptions {
+            metadata,
+            ..QueryOptions::default()
+        };
+        let (response, _) = self.call_with_options(query, options, transport).await?;
+        Ok(response)
+    }
+
+    /// Send [query] with [options], run through the client's interceptors
+    async fn call_with_options(
+        &self,
+        query: Q,
+        mut options: QueryOptions,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<(R, Option<u64>)> {
         let query_bytes = transport.config.wire_config.serialize(&query)?;
-        let result_bytes = transport.send_query(&query_bytes, &self.rpc.name).await?;
-        let result = transport.config.wire_config.deserialize(&result_bytes);
-        into_rpc_result_transport(result)
+        let started = Instant::now();
+        let mut call = ClientCallInfo {
+            name: &self.rpc.name,
+            query_bytes: &query_bytes,
+            metadata: &mut options.metadata,
+        };
+        let before = self
+            .interceptors
+            .iter()
+            .try_for_each(|interceptor| interceptor.before_call(&mut call));
+        let sent = match before {
+            Ok(()) => {
+                transport
+                    .send_query_with_options(
+                        &query_bytes,
+                        &self.rpc.name,
+                        self.type_hash(),
+                        &options,
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let (result, version) = match sent {
+            Ok((result_bytes, version)) => (Ok(result_bytes), version),
+            Err(e) => (Err(e), None),
+        };
+        let call = ClientCallInfo {
+            name: &self.rpc.name,
+            query_bytes: &query_bytes,
+            metadata: &mut options.metadata,
+        };
+        let result = self.intercepted(&call, result, started);
+        let response = self.response_of_result(result, &transport.config, started)?;
+        Ok((response, version))
+    }
+
+    /// [result] of [call] as the interceptors see it, rewriting any error
+    fn intercepted(
+        &self,
+        call: &ClientCallInfo<Name>,
+        result: RpcResult<OwnedBytes>,
+        started: Instant,
+    ) -> RpcResult<OwnedBytes> {
+        if self.interceptors.is_empty() {
+            return result;
+        }
+        let outcome = CallOutcome {
+            duration: started.elapsed(),
+            result: result.as_deref(),
+        };
+        for interceptor in &self.interceptors {
+            interceptor.after_call(call, &outcome);
+        }
+        result.map_err(|e| {
+            self.interceptors
+                .iter()
+                .fold(e, |e, interceptor| interceptor.map_error(call, e))
+        })
+    }
+
+    /// Call a streaming rpc (see [crate::streaming]), returning its response's chunks to read as
+    /// they arrive. Its stats count the time to the first chunk
+    pub async fn call_streaming<'a, I: InternalTransport>(
+        &self,
+        query: Q,
+        transport: &'a mut Transport<I, Name>,
+    ) -> RpcResult<ResponseChunks<'a, I, Name>> {
+        let query_bytes = transport.config.wire_config.serialize(&query)?;
+        let started = Instant::now();
+        let chunks = transport
+            .send_streaming_query(&query_bytes, &self.rpc.name, self.type_hash())
+            .await;
+        self.stats
+            .lock()
+            .unwrap()
+            .record(chunks.is_ok(), started.elapsed());
+        chunks
+    }
+
+    /// Call an uploading rpc (see [crate::streaming]) with [query], followed by the rest of the
+    /// request read from [body] until its end, or until the rpc has read all it needs
+    pub async fn call_uploading(
+        &self,
+        query: Q,
+        mut body: impl AsyncRead + Unpin,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        let query_bytes = transport.config.wire_config.serialize(&query)?;
+        let started = Instant::now();
+        let result = transport
+            .send_uploading_query(&query_bytes, &self.rpc.name, self.type_hash(), &mut body)
+            .await;
+        self.response_of_result(result, &transport.config, started)
+    }
+
+    /// [Self::call_uploading] with the rest of the request made of [values], for the rpc to read
+    /// one at a time with [crate::streaming::RequestReader::receive_value]. Each is serialised
+    /// once the server is ready for more, so they needn't all be in memory at once
+    pub async fn call_uploading_values<I>(
+        &self,
+        query: Q,
+        values: I,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R>
+    where
+        I: IntoIterator,
+        I::IntoIter: Unpin,
+        I::Item: RpcType,
+    {
+        let body = ValuesBody::new(values.into_iter(), transport.config.wire_config.clone());
+        self.call_uploading(query, body, transport).await
+    }
+
+    /// Send [query] without waiting for the rpc to be called, for notifications whose response
+    /// and errors the caller has no use for. The server answers only with an acknowledgement on
+    /// receiving the query, before making the call, which is read before the next frame is sent
+    /// over [transport] so connections stay in lockstep. The server still makes one call at a
+    /// time per connection, so the response to the next one waits on the oneway call. Interceptors
+    /// aren't run for oneway calls
+    pub async fn call_oneway(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<()> {
+        let query_bytes = transport.config.wire_config.serialize(&query)?;
+        let started = Instant::now();
+        let sent = transport
+            .send_oneway_query(&query_bytes, &self.rpc.name, self.type_hash())
+            .await;
+        self.stats
+            .lock()
+            .unwrap()
+            .record(sent.is_ok(), started.elapsed());
+        sent
+    }
+
+    /// [Self::call] over a connection shared with other tasks, see [SharedTransport]
+    pub async fn call_shared(&self, query: Q, transport: &SharedTransport<Name>) -> RpcResult<R>
+    where
+        Name: Send + Sync + 'static,
+    {
+        let query_bytes = transport.config.wire_config.serialize(&query)?;
+        let started = Instant::now();
+        let result = transport
+            .send_query(query_bytes, self.rpc.name.clone(), self.type_hash())
+            .await;
+        self.response_of_result(result, &transport.config, started)
+    }
+
+    /// The response to a call sent at [started], recorded in [Self::stats]
+    fn response_of_result(
+        &self,
+        result: RpcResult<OwnedBytes>,
+        config: &TransportConfig,
+        started: Instant,
+    ) -> RpcResult<R> {
+        let response = self.parse_response(result, config);
+        self.stats
+            .lock()
+            .unwrap()
+            .record(response.is_ok(), started.elapsed());
+        response
+    }
+
+    fn parse_response(
+        &self,
+        result: RpcResult<OwnedBytes>,
+        config: &TransportConfig,
+    ) -> RpcResult<R> {
+        let result_bytes = match result {
+            Ok(result_bytes) => result_bytes,
+            Err(e) => {
+                if let (
+                    Some(events),
+                    RpcError::TransportError(TransportError::ReceiveTimeout(timeout)),
+                ) = (&self.events, &e)
+                {
+                    events.on_timeout(*timeout);
+                }
+                return Err(e);
+            }
+        };
+        config
+            .wire_config
+            .deserialize_payload(&result_bytes, config.schema_compatibility)
+            .map_err(|e| RpcError::ParseError {
+                expected: std::any::type_name::<R>(),
+                received_bytes: result_bytes.len(),
+                reason: e.to_string(),
+            })
+    }
+}
+
+struct QueuedQuery<Name> {
+    query_bytes: OwnedBytes,
+    name: Name,
+    type_hash: Option<u64>,
+    respond_to: oneshot::Sender<RpcResult<OwnedBytes>>,
+}
+
+/// One connection to a server, dialled once and reused for calls to any of its rpcs, where
+/// [call_client] dials for each call
+///
+/// ```rust,ignore
+/// let add_name = RpcClient::new(rpcs::AddName::client());
+/// let mut client = ConnectedClient::new(add_name.connect(addr).await?);
+/// client.call(rpcs::AddName::client(), String::from("Gaspode")).await?;
+/// let names = client.call(rpcs::GetNames::client(), ()).await?;
+/// ```
+pub struct ConnectedClient<I, Name> {
+    transport: Transport<I, Name>,
+}
+
+impl<I: InternalTransport, Name: RpcName> ConnectedClient<I, Name> {
+    pub fn new(transport: Transport<I, Name>) -> Self {
+        Self { transport }
+    }
+
+    /// Call [rpc] with [query] over the connection
+    pub async fn call<Q: RpcType, R: RpcType>(
+        &mut self,
+        rpc: Rpc<Name, Q, R>,
+        query: Q,
+    ) -> RpcResult<R> {
+        RpcClient::new(rpc).call(query, &mut self.transport).await
+    }
+
+    /// The connection, e.g. to make calls with an [RpcClient]'s other methods over it
+    pub fn transport(&mut self) -> &mut Transport<I, Name> {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> Transport<I, Name> {
+        self.transport
     }
 }
 
-/// Basic client call function using the [TpcTransport] internal transport with [TransportConfig::Pickle]
+/// A connection shared between tasks: clones are cheap and all send over the one [Transport],
+/// owned by a dispatcher task which stops once every clone is dropped. Call through it with
+/// [RpcClient::call_shared]. The protocol has no request ids to match responses arriving out of
+/// order, so queries are sent one at a time and a slow rpc holds up those queued behind it
+pub struct SharedTransport<Name> {
+    queue: mpsc::Sender<QueuedQuery<Name>>,
+    config: Arc<TransportConfig>,
+}
+
+impl<Name> Clone for SharedTransport<Name> {
+    fn clone(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<Name: RpcName + Send + Sync + 'static> SharedTransport<Name> {
+    pub fn new(mut transport: Transport<impl InternalTransport + Send + 'static, Name>) -> Self {
+        let config = Arc::new(transport.config.clone());
+        let (queue, mut queued) = mpsc::channel::<QueuedQuery<Name>>(32);
+        tasks::spawn("pirates shared transport", async move {
+            while let Some(query) = queued.recv().await {
+                let result = transport
+                    .send_query_with_type_hash(&query.query_bytes, &query.name, query.type_hash)
+                    .await;
+                // The caller may have stopped waiting, which is fine
+                let _ = query.respond_to.send(result);
+            }
+        });
+        Self { queue, config }
+    }
+
+    async fn send_query(
+        &self,
+        query_bytes: OwnedBytes,
+        name: Name,
+        type_hash: Option<u64>,
+    ) -> RpcResult<OwnedBytes> {
+        let closed = || {
+            RpcError::TransportError(TransportError::SendError(String::from(
+                "Shared connection dispatcher stopped",
+            )))
+        };
+        let (respond_to, response) = oneshot::channel();
+        let query = QueuedQuery {
+            query_bytes,
+            name,
+            type_hash,
+            respond_to,
+        };
+        self.queue.send(query).await.map_err(|_| closed())?;
+        response.await.map_err(|_| closed())?
+    }
+}
+
+/// Basic client call function using the [TpcTransport] internal transport with [TransportConfig::Pickle],
+/// unless overridden by the [ClientEnv]. Dials for each call, see [ConnectedClient] to reuse one
+/// connection
 pub async fn call_client<Name: RpcName, Q: RpcType, R: RpcType>(
     addr: &str,
     q: Q,
     rpc: Rpc<Name, Q, R>,
 ) -> RpcResult<R> {
-    let mut transport = {
-        match tokio::net::TcpStream::connect(addr).await {
-            Ok(client_stream) => {
-                let tcp_transport = TcpTransport::new(client_stream);
-                Ok(Transport::new(tcp_transport, TransportConfig::default()))
-            }
-            Err(e) => Err(e),
+    let rpc_client = RpcClient::new(rpc);
+    let mut transport = rpc_client.connect(addr).await?;
+    rpc_client.call(q, &mut transport).await
+}
+
+/// [call_client], retrying under [retry_policy], see [RpcClient::call_retrying]. Only for
+/// idempotent rpcs
+pub async fn call_client_retrying<Name: RpcName, Q: RpcType, R: RpcType>(
+    addr: &str,
+    q: Q,
+    rpc: Rpc<Name, Q, R>,
+    retry_policy: RetryPolicy,
+) -> RpcResult<R> {
+    let mut rpc_client = RpcClient::new(rpc);
+    rpc_client.set_retry_policy(retry_policy);
+    rpc_client.call_retrying(addr, q).await
+}
+
+/// Bundle the client side of a service's rpcs into one struct, constructed from the server address,
+/// with one async method per rpc. Each rpc must implement [crate::RpcDefinition]
+///
+/// ```rust,ignore
+/// pirates::rpc_client_bundle! {
+///     pub struct NamesClient {
+///         add_name: rpcs::AddName,
+///         get_names: rpcs::GetNames,
+///     }
+/// }
+///
+/// let client = NamesClient::new("127.0.0.1:5959");
+/// client.add_name(String::from("Gaspode the wonder dog")).await?;
+/// let names = client.get_names(()).await?;
+/// ```
+#[macro_export]
+macro_rules! rpc_client_bundle {
+    ($vis:vis struct $client:ident { $($method:ident : $rpc:ty),* $(,)? }) => {
+        $vis struct $client {
+            addr: String,
         }
-    }
-    .map_err(|e| RpcError::TransportError(TransportError::ConnectError(format!("{}", e))))?;
 
-    let rpc_client = RpcClient::new(rpc);
+        impl $client {
+            pub fn new(addr: impl Into<String>) -> Self {
+                Self { addr: addr.into() }
+            }
 
-    rpc_client.call(q, &mut transport).await
+            $(
+                pub async fn $method<Name, State, Q, R>(&self, query: Q) -> $crate::error::RpcResult<R>
+                where
+                    Name: $crate::RpcName,
+                    Q: $crate::RpcType,
+                    R: $crate::RpcType,
+                    $rpc: $crate::RpcDefinition<Name, State, Q, R>,
+                {
+                    let rpc = <$rpc as $crate::RpcDefinition<Name, State, Q, R>>::client();
+                    $crate::call_client(&self.addr, query, rpc).await
+                }
+            )*
+        }
+    };
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::make_hello_world_rpc;
+    use crate::tests::{make_get_i_rpc, make_hello_world_rpc};
     use crate::transport::CannedTestingTransport;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[tokio::test]
     async fn client_test() {
@@ -65,12 +1013,190 @@ mod tests {
         };
         let mut transport = Transport::new(internal_transport, Default::default());
 
-        let rpc_client = RpcClient {
-            rpc: make_hello_world_rpc(),
-        };
+        let rpc_client = RpcClient::new(make_hello_world_rpc());
 
         let result = rpc_client.call("Foo".into(), &mut transport).await.unwrap();
 
         assert_eq!(String::from("Foo-Bar"), result);
     }
+
+    #[tokio::test]
+    async fn client_stats() {
+        let internal_transport = CannedTestingTransport {
+            always_respond_with: "Not a number".to_string(),
+            receive_times: 0,
+        };
+        let mut transport = Transport::new(internal_transport, Default::default());
+
+        let rpc_client = RpcClient::new(make_get_i_rpc());
+        assert_eq!(rpc_client.stats().error_rate(), None);
+        let clone = rpc_client.clone();
+        rpc_client.call((), &mut transport).await.unwrap_err();
+        clone.call((), &mut transport).await.unwrap_err();
+
+        let stats = rpc_client.stats();
+        assert_eq!((stats.calls, stats.errors), (2, 2));
+        assert_eq!(stats.error_rate(), Some(1.0));
+        assert_eq!(stats.latency.count(), 2);
+        assert!(stats.latency.quantile(0.99).is_some());
+    }
+
+    #[tokio::test]
+    async fn client_parse_error() {
+        let internal_transport = CannedTestingTransport {
+            always_respond_with: "Not a number".to_string(),
+            receive_times: 0,
+        };
+        let mut transport = Transport::new(internal_transport, Default::default());
+
+        let rpc_client = RpcClient::new(make_get_i_rpc());
+
+        match rpc_client.call((), &mut transport).await {
+            Err(RpcError::ParseError {
+                expected,
+                received_bytes,
+                ..
+            }) => {
+                assert_eq!(expected, "usize");
+                assert!(received_bytes > 0);
+            }
+            other => panic!("Expected a ParseError, got {:?}", other),
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingEvents {
+        connects: AtomicUsize,
+        retries: AtomicUsize,
+        timeouts: AtomicUsize,
+    }
+    impl ClientEvents for CountingEvents {
+        fn on_connect(&self, _addr: &str) {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+        }
+        fn on_retry(&self, attempt: u32, _error: &RpcError) {
+            assert_eq!(
+                self.retries.fetch_add(1, Ordering::SeqCst) + 1,
+                attempt as usize
+            );
+        }
+        fn on_timeout(&self, _timeout: Duration) {
+            self.timeouts.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_events() {
+        // A server that accepts but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _silent_server = tokio::spawn(async move {
+            let (_stream, _from) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let events = Arc::new(CountingEvents::default());
+        let mut rpc_client = RpcClient::new(make_hello_world_rpc());
+        rpc_client.set_events(events.clone());
+        let mut transport = rpc_client.connect(&addr).await.unwrap();
+        transport.config.rcv_timeout = Duration::from_millis(20);
+        assert!(rpc_client.call("Foo".into(), &mut transport).await.is_err());
+
+        assert_eq!(events.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(events.timeouts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let mut retry_policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.0,
+        };
+        let backoffs: Vec<u128> = [1, 2, 4, 5, 40]
+            .into_iter()
+            .map(|retry| retry_policy.backoff(retry).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![100, 200, 800, 1000, 1000]);
+        retry_policy.jitter = 0.5;
+        for _ in 0..100 {
+            let backoff = retry_policy.backoff(1);
+            assert!(backoff > Duration::from_millis(50) && backoff <= Duration::from_millis(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn call_retrying() {
+        // Nothing listens here once the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refusing = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let events = Arc::new(CountingEvents::default());
+        let mut rpc_client = RpcClient::new(make_hello_world_rpc());
+        rpc_client.set_events(events.clone());
+        rpc_client.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+        match rpc_client.call_retrying(&refusing, "Foo".into()).await {
+            Err(RpcError::TransportError(TransportError::ConnectError(_))) => {}
+            other => panic!("Expected a ConnectError, got {:?}", other),
+        }
+        assert_eq!(events.retries.load(Ordering::SeqCst), 2);
+
+        // Answering with nonsense, which trying again won't fix
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _nonsense_server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            loop {
+                let (mut stream, _from) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let _ = stream.write_all(b"nonsense").await;
+            }
+        });
+        assert!(rpc_client.call_retrying(&addr, "Foo".into()).await.is_err());
+        assert_eq!(events.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(events.retries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn client_env_from_vars() {
+        let env = ClientEnv::from_vars(|name| match name {
+            "PIRATES_CLIENT_ADDR" => Some(String::from("10.0.0.7:5959")),
+            "PIRATES_CLIENT_TIMEOUT_MS" => Some(String::from("250")),
+            "PIRATES_CLIENT_WIRE_FORMAT" => Some(String::from("morse")),
+            _ => None,
+        });
+        assert_eq!(env.addr("127.0.0.1:5959"), "10.0.0.7:5959");
+        assert_eq!(env.timeout, Some(Duration::from_millis(250)));
+        // Unknown, so ignored
+        assert!(env.wire_config.is_none());
+        assert_eq!(
+            ClientEnv::default().addr("127.0.0.1:5959"),
+            "127.0.0.1:5959"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_env_overrides() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _silent_server = tokio::spawn(async move {
+            let (_stream, _from) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let mut rpc_client = RpcClient::new(make_hello_world_rpc());
+        rpc_client.set_env(Some(ClientEnv {
+            addr: Some(addr),
+            timeout: Some(Duration::from_millis(20)),
+            wire_config: None,
+        }));
+        // Nothing listens here, but the env points elsewhere
+        let transport = rpc_client.connect("127.0.0.1:1").await.unwrap();
+        assert_eq!(transport.config.rcv_timeout, Duration::from_millis(20));
+    }
 }
@@ -0,0 +1,562 @@
+//! Declarative server configuration, loaded from TOML and environment variables (Enable the
+//! "config" feature).
+//!
+//! A [ServerConfig] covers what deployments tend to vary: the addresses served on, timeouts,
+//! heartbeats, accept backoff, the peers allowed to connect, the wire format and the TLS
+//! certificate. [ServerConfig::load] reads a TOML file and then applies overrides from
+//! environment variables named after the keys, sections separated by a double underscore, e.g.
+//! `PIRATES_SERVER_TIMEOUTS__IDLE_MS=0`. Values are read as TOML where they parse as such, and as
+//! strings otherwise. The config is validated once loaded, so mistakes surface at startup rather
+//! than on the first connection. Give the variables a prefix of the server's own: any others
+//! sharing it, such as those of a [crate::ClientEnv], would be rejected as unknown keys
+//!
+//! ```toml
+//! listen_on = ["0.0.0.0:5959", "[::]:5959"]
+//! wire_format = "pickle"
+//!
+//! [timeouts]
+//! rcv_ms = 3000
+//! idle_ms = 0  # Never reap idle connections
+//!
+//! [ip_filter]
+//! allow = ["10.0.0.0/8"]
+//! ```
+//!
+//! ```rust,ignore
+//! let config = ServerConfig::load("pirates.toml", "PIRATES_SERVER")?;
+//! let mut server = RpcServer::new(state, config.transport_config()?);
+//! config.configure(&mut server)?;
+//! server.add_rpc(Box::new(rpcs::AddName::server()));
+//! Arc::new(server).serve_config(&config).await?;
+//! ```
+use crate::ip_filter::{IpFilter, IpNet};
+use crate::server::{AcceptBackoff, RpcServer};
+use crate::transport::{HeartbeatConfig, TransportConfig, TransportWireConfig};
+use crate::{RpcName, StoredRpc};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Why a [ServerConfig] could not be loaded or applied
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file at [path] could not be read
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// A file or environment variable is not valid TOML, or doesn't match [ServerConfig]
+    Parse(String),
+    /// The config parsed but makes no sense, e.g. has nothing to listen on
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "Could not read {}: {}", path.display(), error),
+            Self::Parse(reason) => write!(f, "Could not parse config: {}", reason),
+            Self::Invalid(reason) => write!(f, "Invalid config: {}", reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Configuration of an [RpcServer] and its [TransportConfig], see [crate::config]. Anything left
+/// out keeps its default
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to serve on, given as a list or a single string
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen_on: Vec<String>,
+    /// Name of the wire format, as given by [TransportWireConfig::format_name]
+    pub wire_format: Option<String>,
+    /// See [TransportConfig::detect_wire_format]
+    pub detect_wire_format: bool,
+    pub timeouts: Timeouts,
+    pub heartbeat: Option<Heartbeat>,
+    pub accept_backoff: Option<Backoff>,
+    pub ip_filter: IpRanges,
+    pub tls: Option<TlsFiles>,
+    /// See [RpcServer::set_dry_run]
+    pub dry_run: bool,
+    /// See [RpcServer::set_reflection]
+    pub reflection: bool,
+    /// See [RpcServer::set_http_health_check]
+    pub health_check_path: Option<String>,
+    /// See [RpcServer::set_http_metrics]
+    pub metrics_path: Option<String>,
+    /// See [RpcServer::set_lock_timeout], 0 or unset waiting as long as it takes
+    pub lock_timeout_ms: Option<u64>,
+    /// See [TransportConfig::max_message_size], 0 removing the limit
+    pub max_message_size: Option<usize>,
+}
+
+/// Timeouts of [TransportConfig], in milliseconds. For those that are optional 0 disables them
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub rcv_ms: Option<u64>,
+    pub idle_ms: Option<u64>,
+    pub keepalive_ms: Option<u64>,
+    pub connect_ms: Option<u64>,
+    pub write_ms: Option<u64>,
+}
+
+/// [HeartbeatConfig], in milliseconds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Heartbeat {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+/// [AcceptBackoff], in milliseconds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backoff {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Ranges of [IpNet]s for an [IpFilter]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpRanges {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// PEM files of the certificate chain served, and its private key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl ServerConfig {
+    /// The config in the TOML file at [path], overridden by environment variables starting with
+    /// [env_prefix] and an underscore
+    pub fn load(path: impl AsRef<Path>, env_prefix: &str) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let mut value = toml_to_value(&toml)?;
+        merge(&mut value, env_to_value(std::env::vars(), env_prefix));
+        Self::from_value(value)
+    }
+
+    /// The config in [toml]
+    pub fn from_toml_str(toml: &str) -> Result<Self, ConfigError> {
+        Self::from_value(toml_to_value(toml)?)
+    }
+
+    /// The config given by environment variables starting with [prefix] and an underscore alone
+    pub fn from_env(prefix: &str) -> Result<Self, ConfigError> {
+        Self::from_value(env_to_value(std::env::vars(), prefix))
+    }
+
+    fn from_value(value: Value) -> Result<Self, ConfigError> {
+        let config: Self =
+            serde_json::from_value(value).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the config can be applied, as [Self::load] does
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen_on.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "listen_on has no addresses",
+            )));
+        }
+        if self.timeouts.rcv_ms == Some(0) {
+            return Err(ConfigError::Invalid(String::from(
+                "timeouts.rcv_ms can't be 0",
+            )));
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            if heartbeat.interval_ms == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "heartbeat.interval_ms can't be 0",
+                )));
+            }
+        }
+        if let Some(backoff) = &self.accept_backoff {
+            if backoff.initial_ms > backoff.max_ms {
+                return Err(ConfigError::Invalid(format!(
+                    "accept_backoff.initial_ms {} is above max_ms {}",
+                    backoff.initial_ms, backoff.max_ms
+                )));
+            }
+        }
+        #[cfg(not(feature = "transport_tls"))]
+        if self.tls.is_some() {
+            return Err(ConfigError::Invalid(String::from(
+                "tls is configured, but the \"transport_tls\" feature is not enabled",
+            )));
+        }
+        self.wire_config()?;
+        self.ip_filter()?;
+        Ok(())
+    }
+
+    fn wire_config(&self) -> Result<TransportWireConfig, ConfigError> {
+        let wire_format = match &self.wire_format {
+            Some(wire_format) => wire_format,
+            None => return Ok(TransportWireConfig::default()),
+        };
+        TransportWireConfig::from_format_name(wire_format).ok_or_else(|| {
+            let names: Vec<&str> = TransportWireConfig::all()
+                .iter()
+                .map(|wire_config| wire_config.format_name())
+                .collect();
+            ConfigError::Invalid(format!(
+                "Unknown wire_format {}, expected one of {}",
+                wire_format,
+                names.join(", ")
+            ))
+        })
+    }
+
+    /// The [IpFilter] of [Self::ip_filter]'s ranges
+    pub fn ip_filter(&self) -> Result<IpFilter, ConfigError> {
+        let parse = |net: &String| {
+            net.parse::<IpNet>()
+                .map_err(|e| ConfigError::Invalid(format!("ip_filter: {}", e)))
+        };
+        let mut ip_filter = IpFilter::new();
+        for net in &self.ip_filter.allow {
+            ip_filter.allow(parse(net)?);
+        }
+        for net in &self.ip_filter.deny {
+            ip_filter.deny(parse(net)?);
+        }
+        Ok(ip_filter)
+    }
+
+    /// The default [TransportConfig] with this config's wire format, timeouts, heartbeat and
+    /// maximum message size
+    pub fn transport_config(&self) -> Result<TransportConfig, ConfigError> {
+        let mut transport_config = TransportConfig {
+            wire_config: self.wire_config()?,
+            detect_wire_format: self.detect_wire_format,
+            ..Default::default()
+        };
+        let timeouts = &self.timeouts;
+        if let Some(rcv_ms) = timeouts.rcv_ms {
+            transport_config.rcv_timeout = Duration::from_millis(rcv_ms);
+        }
+        let optional = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+        if let Some(idle_ms) = timeouts.idle_ms {
+            transport_config.idle_timeout = optional(idle_ms);
+        }
+        if let Some(keepalive_ms) = timeouts.keepalive_ms {
+            transport_config.keepalive = optional(keepalive_ms);
+        }
+        if let Some(connect_ms) = timeouts.connect_ms {
+            transport_config.connect_timeout = optional(connect_ms);
+        }
+        if let Some(write_ms) = timeouts.write_ms {
+            transport_config.write_timeout = optional(write_ms);
+        }
+        transport_config.heartbeat = self.heartbeat.as_ref().map(|heartbeat| HeartbeatConfig {
+            interval: Duration::from_millis(heartbeat.interval_ms),
+            timeout: Duration::from_millis(heartbeat.timeout_ms),
+        });
+        if let Some(max_message_size) = self.max_message_size {
+            transport_config.max_message_size = (max_message_size > 0).then_some(max_message_size);
+        }
+        Ok(transport_config)
+    }
+
+    /// Apply the server wide settings, accept backoff, ip filter, dry-run mode, health check,
+    /// metrics and lock timeout, to [server]
+    pub fn configure<S, Name, Stored>(
+        &self,
+        server: &mut RpcServer<S, Name, Stored>,
+    ) -> Result<(), ConfigError>
+    where
+        S: Send + 'static,
+        Name: RpcName + Send + Sync + 'static,
+        Stored: StoredRpc<S, Name> + Send + Sync + 'static,
+    {
+        if !self.ip_filter.allow.is_empty() || !self.ip_filter.deny.is_empty() {
+            server.set_ip_filter(self.ip_filter()?);
+        }
+        if let Some(backoff) = &self.accept_backoff {
+            server.set_accept_backoff(AcceptBackoff {
+                initial: Duration::from_millis(backoff.initial_ms),
+                max: Duration::from_millis(backoff.max_ms),
+            });
+        }
+        server.set_dry_run(self.dry_run);
+        server.set_reflection(self.reflection);
+        if let Some(health_check_path) = &self.health_check_path {
+            server.set_http_health_check(health_check_path.clone());
+        }
+        if let Some(metrics_path) = &self.metrics_path {
+            server.set_http_metrics(metrics_path.clone());
+        }
+        if let Some(lock_timeout_ms) = self.lock_timeout_ms.filter(|ms| *ms > 0) {
+            server.set_lock_timeout(Some(Duration::from_millis(lock_timeout_ms)));
+        }
+        Ok(())
+    }
+
+    /// The [crate::tls::TlsServerConfig] of [Self::tls]'s files, if any
+    #[cfg(feature = "transport_tls")]
+    pub fn tls_server_config(&self) -> Result<Option<crate::tls::TlsServerConfig>, ConfigError> {
+        use crate::tls::rustls;
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+        let pem_error =
+            |path: &Path, e| ConfigError::Invalid(format!("tls: {}: {}", path.display(), e));
+        let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(&tls.cert_path, e))?;
+        let key =
+            PrivateKeyDer::from_pem_file(&tls.key_path).map_err(|e| pem_error(&tls.key_path, e))?;
+        let rustls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| ConfigError::Invalid(format!("tls: {}", e)))?;
+        Ok(Some(crate::tls::TlsServerConfig::new(std::sync::Arc::new(
+            rustls,
+        ))))
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+fn toml_to_value(toml: &str) -> Result<Value, ConfigError> {
+    let document = toml
+        .parse::<toml_edit::DocumentMut>()
+        .map_err(|e| ConfigError::Parse(e.to_string()))?;
+    Ok(table_to_value(document.as_table().iter()))
+}
+
+fn table_to_value<'a>(entries: impl Iterator<Item = (&'a str, &'a toml_edit::Item)>) -> Value {
+    let mut map = Map::new();
+    for (key, item) in entries {
+        if let Some(value) = item_to_value(item) {
+            map.insert(key.to_string(), value);
+        }
+    }
+    Value::Object(map)
+}
+
+fn item_to_value(item: &toml_edit::Item) -> Option<Value> {
+    match item {
+        toml_edit::Item::None => None,
+        toml_edit::Item::Value(value) => Some(value_to_value(value)),
+        toml_edit::Item::Table(table) => Some(table_to_value(table.iter())),
+        toml_edit::Item::ArrayOfTables(tables) => Some(Value::Array(
+            tables
+                .iter()
+                .map(|table| table_to_value(table.iter()))
+                .collect(),
+        )),
+    }
+}
+
+fn value_to_value(value: &toml_edit::Value) -> Value {
+    match value {
+        toml_edit::Value::String(s) => Value::from(s.value().as_str()),
+        toml_edit::Value::Integer(i) => Value::from(*i.value()),
+        toml_edit::Value::Float(f) => Value::from(*f.value()),
+        toml_edit::Value::Boolean(b) => Value::from(*b.value()),
+        toml_edit::Value::Datetime(datetime) => Value::from(datetime.value().to_string()),
+        toml_edit::Value::Array(array) => Value::Array(array.iter().map(value_to_value).collect()),
+        toml_edit::Value::InlineTable(table) => Value::Object(
+            table
+                .iter()
+                .map(|(key, value)| (key.to_string(), value_to_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// The config given by those of [vars] named [prefix]_KEY, with KEY being the upper case path
+/// to a value with sections separated by "__"
+fn env_to_value(vars: impl Iterator<Item = (String, String)>, prefix: &str) -> Value {
+    let prefix = format!("{}_", prefix);
+    let mut value = Value::Object(Map::new());
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(&prefix) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+        let parsed = match raw.parse::<toml_edit::Value>() {
+            Ok(parsed) => value_to_value(&parsed),
+            Err(_) => Value::from(raw),
+        };
+        let mut nested = parsed;
+        for key in path.rsplit("__") {
+            let mut map = Map::new();
+            map.insert(key.to_string(), nested);
+            nested = Value::Object(map);
+        }
+        merge(&mut value, nested);
+    }
+    value
+}
+
+/// Merge [overrides] into [value], replacing all but tables, which are merged
+fn merge(value: &mut Value, overrides: Value) {
+    match (value, overrides) {
+        (Value::Object(value), Value::Object(overrides)) => {
+            for (key, overriding) in overrides {
+                match value.get_mut(&key) {
+                    Some(existing) => merge(existing, overriding),
+                    None => {
+                        value.insert(key, overriding);
+                    }
+                }
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+listen_on = ["127.0.0.1:5959", "[::1]:5959"]
+wire_format = "pickle"
+max_message_size = 1048576
+
+[timeouts]
+rcv_ms = 500
+idle_ms = 0
+
+[heartbeat]
+interval_ms = 1000
+timeout_ms = 250
+
+[ip_filter]
+allow = ["10.0.0.0/8"]
+deny = ["10.0.0.7"]
+"#;
+
+    #[test]
+    fn from_toml() {
+        let config = ServerConfig::from_toml_str(TOML).unwrap();
+        assert_eq!(config.listen_on, vec!["127.0.0.1:5959", "[::1]:5959"]);
+        let transport_config = config.transport_config().unwrap();
+        assert_eq!(transport_config.rcv_timeout, Duration::from_millis(500));
+        assert_eq!(transport_config.idle_timeout, None);
+        assert_eq!(transport_config.max_message_size, Some(1024 * 1024));
+        // Left out, so the default
+        assert_eq!(
+            transport_config.write_timeout,
+            Some(Duration::from_secs(10))
+        );
+        let heartbeat = transport_config.heartbeat.unwrap();
+        assert_eq!(heartbeat.interval, Duration::from_secs(1));
+        let ip_filter = config.ip_filter().unwrap();
+        assert!(ip_filter.is_allowed(&"10.1.2.3".parse().unwrap()));
+        assert!(!ip_filter.is_allowed(&"10.0.0.7".parse().unwrap()));
+        assert!(!ip_filter.is_allowed(&"192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let vars = [
+            ("PIRATES_SERVER_LISTEN_ON", "0.0.0.0:6000"),
+            ("PIRATES_SERVER_TIMEOUTS__RCV_MS", "750"),
+            ("PIRATES_SERVER_IP_FILTER__DENY", "[\"10.0.0.8\"]"),
+            ("PIRATES_SERVERX_DRY_RUN", "true"),
+            // Those of clients in the same environment are left to them
+            ("PIRATES_CLIENT_ADDR", "10.0.0.7:5858"),
+            ("PIRATES_CLIENT_TIMEOUT_MS", "250"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut value = toml_to_value(TOML).unwrap();
+        merge(&mut value, env_to_value(vars.into_iter(), "PIRATES_SERVER"));
+        let config = ServerConfig::from_value(value).unwrap();
+        assert_eq!(config.listen_on, vec!["0.0.0.0:6000"]);
+        assert_eq!(config.timeouts.rcv_ms, Some(750));
+        // Sections are merged, not replaced
+        assert_eq!(config.timeouts.idle_ms, Some(0));
+        assert_eq!(config.ip_filter.allow, vec!["10.0.0.0/8"]);
+        assert_eq!(config.ip_filter.deny, vec!["10.0.0.8"]);
+        assert!(!config.dry_run);
+    }
+
+    #[test]
+    fn validation() {
+        let invalid = |toml: &str| match ServerConfig::from_toml_str(toml) {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("Expected an invalid config, got {:?}", other),
+        };
+        assert_eq!(invalid(""), "listen_on has no addresses");
+        assert!(invalid("listen_on = \"a:1\"\nwire_format = \"morse\"")
+            .starts_with("Unknown wire_format morse, expected one of pickle"));
+        assert!(
+            invalid("listen_on = \"a:1\"\n[ip_filter]\nallow = [\"10.0.0.0/33\"]")
+                .contains("Prefix length 33")
+        );
+        assert!(
+            invalid("listen_on = \"a:1\"\n[accept_backoff]\ninitial_ms = 5\nmax_ms = 1")
+                .contains("above max_ms")
+        );
+        match ServerConfig::from_toml_str("listen_on = \"a:1\"\nrcv_timeout = 3") {
+            Err(ConfigError::Parse(reason)) => {
+                assert!(reason.contains("rcv_timeout"), "{}", reason)
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "transport_tls")]
+    #[test]
+    fn tls_files() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("pirates.test")]).unwrap();
+        let dir = std::env::temp_dir().join(format!("pirates-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let mut config = ServerConfig {
+            listen_on: vec![String::from("127.0.0.1:0")],
+            tls: Some(TlsFiles {
+                cert_path,
+                key_path: dir.join("missing.pem"),
+            }),
+            ..Default::default()
+        };
+        assert!(config.tls_server_config().is_err());
+        config.tls.as_mut().unwrap().key_path = key_path;
+        assert!(config.tls_server_config().unwrap().is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
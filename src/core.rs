@@ -1,24 +1,34 @@
 use std::any::Any;
 
-use crate::error::RpcResult;
-use crate::transport::TransportWireConfig;
+use crate::call_trace::{self, Phase};
+use crate::error::{RpcError, RpcResult, StatusCode};
+use crate::streaming::{RequestStream, ResponseStream, StreamRequest, StreamResponse};
+use crate::transport::TransportConfig;
 use crate::{Bytes, OwnedBytes};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::fmt::Display;
+use std::future::Future;
 use std::hash::Hash;
 use std::marker::PhantomData;
-
-pub trait ToFromBytes {
-    fn to_bytes(&self) -> RpcResult<OwnedBytes>;
-    fn of_bytes(b: Bytes) -> RpcResult<Self>
-    where
-        Self: Sized;
-}
+use std::pin::Pin;
 
 pub trait RpcType: Any + Serialize + for<'de> Deserialize<'de> + Clone {}
 
-pub trait RpcName: PartialEq + Eq + Hash + Serialize + DeserializeOwned + Display + Clone {}
+pub trait RpcName: PartialEq + Eq + Hash + Serialize + DeserializeOwned + Display + Clone {
+    /// Marks names in the namespace reserved for pirates' built-in RPCs (see [crate::admin]).
+    /// Leave this as the default for your own names.
+    const RESERVED: bool = false;
+}
+
+/// An [RpcName] that can list all its names, so a server can check every one has an
+/// implementation, see [crate::RpcServer::set_strict_registration]. Derive it for enums of unit
+/// variants with `#[derive(pirates::RpcNameList)]`, or with strum implement it as
+/// `Self::iter().collect()`
+pub trait RpcNameList: RpcName {
+    fn all() -> Vec<Self>;
+}
 
 #[derive(Clone)]
 pub struct Rpc<Name, Q: RpcType, R: RpcType> {
@@ -37,18 +47,85 @@ impl<Name: RpcName, Q: RpcType, R: RpcType> Rpc<Name, Q, R> {
     }
 }
 
-type Implementation<State, Q, R> = Box<dyn Fn(&mut State, Q) -> RpcResult<R>>;
+type Implementation<State, Q, R> = Box<dyn Fn(&mut State, Q) -> RpcResult<R> + Send + Sync>;
+type ReadImplementation<State, Q, R> = Box<dyn Fn(&State, Q) -> RpcResult<R> + Send + Sync>;
+type DeferringImplementation<State, Q, R> =
+    Box<dyn Fn(&mut State, Q) -> RpcResult<(R, Deferred)> + Send + Sync>;
+type AsyncImplementation<State, Q, R> =
+    Box<dyn Fn(&mut State, Q) -> RpcResult<AsyncResponse<R>> + Send + Sync>;
+type StreamingImplementation<State, Q> =
+    Box<dyn Fn(&mut State, Q) -> RpcResult<StreamResponse> + Send + Sync>;
+type UploadingImplementation<State, Q, R> =
+    Box<dyn Fn(&mut State, Q) -> RpcResult<StreamRequest<R>> + Send + Sync>;
+
+/// Work a handler leaves for the server to run once its response has been sent, see
+/// [RpcImpl::new_deferring]
+pub type Deferred = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The response an async rpc goes on to make once the state's lock is released, see
+/// [RpcImpl::new_async]
+pub type AsyncResponse<R> = Pin<Box<dyn Future<Output = RpcResult<R>> + Send>>;
+
+enum Handler<State, Q, R> {
+    Write(Implementation<State, Q, R>),
+    Read(ReadImplementation<State, Q, R>),
+    Deferring(DeferringImplementation<State, Q, R>),
+    Async(AsyncImplementation<State, Q, R>),
+    Streaming(StreamingImplementation<State, Q>),
+    Uploading(UploadingImplementation<State, Q, R>),
+}
 
 pub struct RpcImpl<Name: RpcName, State, Q: RpcType, R: RpcType> {
     pub rpc: Rpc<Name, Q, R>,
-    call: Implementation<State, Q, R>,
+    call: Handler<State, Q, R>,
+    /// Traced on first use, the error kept as a message
+    #[cfg(feature = "type_hash")]
+    type_hash: std::sync::OnceLock<Result<u64, String>>,
 }
 
 impl<Name: RpcName, State, Q: RpcType, R: RpcType> RpcImpl<Name, State, Q, R> {
     pub fn new(name: Name, call: Implementation<State, Q, R>) -> Self {
+        Self::of_handler(name, Handler::Write(call))
+    }
+
+    /// An rpc that only reads the state, so can be served from a snapshot of it without waiting
+    /// on writers, see [crate::RpcServer::enable_snapshots]
+    pub fn new_read(name: Name, call: ReadImplementation<State, Q, R>) -> Self {
+        Self::of_handler(name, Handler::Read(call))
+    }
+
+    /// An rpc that returns [Deferred] work with its response, which the server spawns once the
+    /// response has been sent, so the client isn't kept waiting on work (e.g. notifying others
+    /// of the change) the response doesn't depend on. Deferred work doesn't hold the state's
+    /// lock, and isn't waited for when the server shuts down
+    pub fn new_deferring(name: Name, call: DeferringImplementation<State, Q, R>) -> Self {
+        Self::of_handler(name, Handler::Deferring(call))
+    }
+
+    /// An rpc that reads what it needs of the state under its lock, and returns a future making
+    /// its response once the lock is released, which may await I/O such as other rpcs. The
+    /// server's interceptors and stats see the call end once the future has made its response.
+    ///
+    /// The handler itself, like [StoredRpc::call_of_bytes], is synchronous and can't await while
+    /// holding the state: the state is behind a std [std::sync::Mutex], whose guard a Send future
+    /// can't hold across an await, and holding it there would queue every other call behind the
+    /// slowest handler's I/O
+    pub fn new_async(name: Name, call: AsyncImplementation<State, Q, R>) -> Self {
+        Self::of_handler(name, Handler::Async(call))
+    }
+
+    /// An rpc that reads the rest of its request as a stream of bytes after its query, see
+    /// [crate::streaming]. Call it with [crate::RpcClient::call_uploading]
+    pub fn new_uploading(name: Name, call: UploadingImplementation<State, Q, R>) -> Self {
+        Self::of_handler(name, Handler::Uploading(call))
+    }
+
+    fn of_handler(name: Name, call: Handler<State, Q, R>) -> Self {
         Self {
             rpc: Rpc::new(name),
             call,
+            #[cfg(feature = "type_hash")]
+            type_hash: std::sync::OnceLock::new(),
         }
     }
 
@@ -57,8 +134,29 @@ impl<Name: RpcName, State, Q: RpcType, R: RpcType> RpcImpl<Name, State, Q, R> {
         Q::of_bytes(b)
     }
      */
-    fn call(&self, state: &mut State, q: Q) -> RpcResult<R> {
-        (self.call)(state, q)
+    /// Call the handler, putting any work it deferred in [deferred]
+    fn call(&self, state: &mut State, q: Q, deferred: &Cell<Option<Deferred>>) -> RpcResult<R> {
+        match &self.call {
+            Handler::Write(call) => call(state, q),
+            Handler::Read(call) => call(state, q),
+            Handler::Deferring(call) => {
+                let (response, work) = call(state, q)?;
+                deferred.set(Some(work));
+                Ok(response)
+            }
+            Handler::Async(_) => Err(RpcError::status(
+                StatusCode::FailedPrecondition,
+                format!("{} responds asynchronously", self.rpc.name),
+            )),
+            Handler::Streaming(_) => Err(RpcError::status(
+                StatusCode::FailedPrecondition,
+                format!("{} streams its response", self.rpc.name),
+            )),
+            Handler::Uploading(_) => Err(RpcError::status(
+                StatusCode::FailedPrecondition,
+                format!("{} reads a streamed request", self.rpc.name),
+            )),
+        }
     }
     /*
     fn result_to_bytes(&self, r: R) -> RpcResult<OwnedBytes> {
@@ -67,14 +165,91 @@ impl<Name: RpcName, State, Q: RpcType, R: RpcType> RpcImpl<Name, State, Q, R> {
      */
 }
 
+impl<Name: RpcName, State, Q: RpcType> RpcImpl<Name, State, Q, ()> {
+    /// An rpc that writes its response as a stream of bytes rather than returning it, see
+    /// [crate::streaming]. Call it with [crate::RpcClient::call_streaming]
+    pub fn new_streaming(name: Name, call: StreamingImplementation<State, Q>) -> Self {
+        Self::of_handler(name, Handler::Streaming(call))
+    }
+}
+
 pub trait StoredRpc<State, Name: RpcName> {
+    /// Call the rpc with the query in [bytes], appending the serialised response to
+    /// [response_buffer]. Synchronous, as it is called with the state's lock held, see
+    /// [Self::call_of_bytes_async] for rpcs awaiting I/O
     fn call_of_bytes(
         &self,
         bytes: Bytes,
-        transport_config: &TransportWireConfig,
+        transport_config: &TransportConfig,
         state: &mut State,
-    ) -> RpcResult<OwnedBytes>;
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<()>;
+    /// [Self::call_of_bytes], returning the work the rpc deferred until its response is sent
+    /// rather than spawning it, see [RpcImpl::new_deferring]. Defaults to deferring nothing
+    fn call_of_bytes_deferring(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<Option<Deferred>> {
+        self.call_of_bytes(bytes, transport_config, state, response_buffer)
+            .map(|()| None)
+    }
+    /// [Self::call_of_bytes] on a shared [state], for rpcs that only read it. [None] for those
+    /// that need it mutably, which must be called with [Self::call_of_bytes]
+    fn call_of_bytes_shared(
+        &self,
+        _bytes: Bytes,
+        _transport_config: &TransportConfig,
+        _state: &State,
+        _response_buffer: &mut OwnedBytes,
+    ) -> Option<RpcResult<()>> {
+        None
+    }
+    /// Call an async rpc (see [RpcImpl::new_async]) with the query in [bytes], returning the
+    /// future making its serialised response. [None] for those that aren't
+    fn call_of_bytes_async(
+        &self,
+        _bytes: Bytes,
+        _transport_config: &TransportConfig,
+        _state: &mut State,
+    ) -> Option<RpcResult<AsyncResponse<OwnedBytes>>> {
+        None
+    }
+    /// Call a streaming rpc (see [RpcImpl::new_streaming]) with the query in [bytes], returning
+    /// its response stream. [None] for those that aren't, which must be called with
+    /// [Self::call_of_bytes_deferring]
+    fn call_of_bytes_streaming(
+        &self,
+        _bytes: Bytes,
+        _transport_config: &TransportConfig,
+        _state: &mut State,
+    ) -> Option<RpcResult<ResponseStream>> {
+        None
+    }
+    /// Call an uploading rpc (see [RpcImpl::new_uploading]) with the query in [bytes], returning
+    /// its call waiting on the rest of the request. [None] for those that aren't
+    fn call_of_bytes_uploading(
+        &self,
+        _bytes: Bytes,
+        _transport_config: &TransportConfig,
+        _state: &mut State,
+    ) -> Option<RpcResult<RequestStream>> {
+        None
+    }
+    /// Check the query in [bytes] deserialises, without calling the rpc
+    fn validate_query(&self, bytes: Bytes, transport_config: &TransportConfig) -> RpcResult<()>;
+    /// Whether calls leave the state as it was, so don't advance its version (see
+    /// [crate::RpcServer::state_version]). Defaults to false
+    fn reads_only(&self) -> bool {
+        false
+    }
     fn rpc_name(&self) -> Name;
+    #[cfg(feature = "schema")]
+    fn schema(&self) -> RpcResult<crate::schema::RpcSchema>;
+    #[cfg(feature = "type_hash")]
+    fn type_hash(&self) -> RpcResult<u64>;
 }
 
 impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredRpc<State, Name>
@@ -83,16 +258,220 @@ impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredRpc<State, Name>
     fn call_of_bytes(
         &self,
         input_bytes: Bytes,
-        transport_config: &TransportWireConfig,
+        transport_config: &TransportConfig,
+        state: &mut State,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<()> {
+        let deferred =
+            self.call_of_bytes_deferring(input_bytes, transport_config, state, response_buffer)?;
+        if let Some(deferred) = deferred {
+            crate::tasks::spawn("pirates deferred work", deferred);
+        }
+        Ok(())
+    }
+
+    fn call_of_bytes_deferring(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<Option<Deferred>> {
+        let deferred = Cell::new(None);
+        crate::static_dispatch::call_static(
+            |state, query| self.call(state, query, &deferred),
+            input_bytes,
+            transport_config,
+            state,
+            response_buffer,
+        )?;
+        Ok(deferred.into_inner())
+    }
+
+    fn call_of_bytes_shared(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &State,
+        response_buffer: &mut OwnedBytes,
+    ) -> Option<RpcResult<()>> {
+        let call = match &self.call {
+            Handler::Read(call) => call,
+            Handler::Write(_)
+            | Handler::Deferring(_)
+            | Handler::Async(_)
+            | Handler::Streaming(_)
+            | Handler::Uploading(_) => return None,
+        };
+        // The state is borrowed by the closure instead, so there is none to pass through
+        Some(crate::static_dispatch::call_static(
+            |_: &mut (), query| call(state, query),
+            input_bytes,
+            transport_config,
+            &mut (),
+            response_buffer,
+        ))
+    }
+
+    fn call_of_bytes_async(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+    ) -> Option<RpcResult<AsyncResponse<OwnedBytes>>> {
+        let Handler::Async(call) = &self.call else {
+            return None;
+        };
+        let wire_config = &transport_config.wire_config;
+        let response = call_trace::phase(Phase::Deserialize, || {
+            wire_config.deserialize_payload(input_bytes, transport_config.schema_compatibility)
+        })
+        .map_err(RpcError::from)
+        .and_then(|query| call_trace::phase(Phase::Handler, || call(state, query)));
+        let wire_config = wire_config.clone();
+        Some(response.map(|response| -> AsyncResponse<OwnedBytes> {
+            Box::pin(async move { Ok(wire_config.serialize(&response.await?)?) })
+        }))
+    }
+
+    fn call_of_bytes_streaming(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportConfig,
         state: &mut State,
-    ) -> RpcResult<OwnedBytes> {
-        let query = transport_config.deserialize(input_bytes)?;
-        let result = self.call(state, query)?;
-        let result_bytes = transport_config.serialize(&result)?;
-        Ok(result_bytes)
+    ) -> Option<RpcResult<ResponseStream>> {
+        let Handler::Streaming(call) = &self.call else {
+            return None;
+        };
+        let wire_config = &transport_config.wire_config;
+        let stream = call_trace::phase(Phase::Deserialize, || {
+            wire_config.deserialize_payload(input_bytes, transport_config.schema_compatibility)
+        })
+        .map_err(RpcError::from)
+        .and_then(|query| call_trace::phase(Phase::Handler, || call(state, query)));
+        Some(stream.map(ResponseStream::new))
+    }
+
+    fn call_of_bytes_uploading(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+    ) -> Option<RpcResult<RequestStream>> {
+        let Handler::Uploading(call) = &self.call else {
+            return None;
+        };
+        let wire_config = &transport_config.wire_config;
+        let upload = call_trace::phase(Phase::Deserialize, || {
+            wire_config.deserialize_payload(input_bytes, transport_config.schema_compatibility)
+        })
+        .map_err(RpcError::from)
+        .and_then(|query| call_trace::phase(Phase::Handler, || call(state, query)));
+        Some(upload.map(|upload| RequestStream::new(upload, transport_config)))
+    }
+
+    fn validate_query(&self, bytes: Bytes, transport_config: &TransportConfig) -> RpcResult<()> {
+        crate::static_dispatch::validate_static::<Q>(bytes, transport_config)
+    }
+
+    fn reads_only(&self) -> bool {
+        matches!(self.call, Handler::Read(_))
     }
 
     fn rpc_name(&self) -> Name {
         self.rpc.name.clone()
     }
+
+    #[cfg(feature = "schema")]
+    fn schema(&self) -> RpcResult<crate::schema::RpcSchema> {
+        crate::schema::RpcSchema::of::<Q, R>()
+    }
+
+    #[cfg(feature = "type_hash")]
+    fn type_hash(&self) -> RpcResult<u64> {
+        crate::type_hash::cached_type_hash::<Name, Q, R>(&self.type_hash)
+    }
+}
+
+impl<State, Name: RpcName> StoredRpc<State, Name>
+    for Box<dyn StoredRpc<State, Name> + Send + Sync>
+{
+    fn call_of_bytes(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<()> {
+        (**self).call_of_bytes(bytes, transport_config, state, response_buffer)
+    }
+
+    fn call_of_bytes_deferring(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<Option<Deferred>> {
+        (**self).call_of_bytes_deferring(bytes, transport_config, state, response_buffer)
+    }
+
+    fn call_of_bytes_shared(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &State,
+        response_buffer: &mut OwnedBytes,
+    ) -> Option<RpcResult<()>> {
+        (**self).call_of_bytes_shared(bytes, transport_config, state, response_buffer)
+    }
+
+    fn call_of_bytes_async(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+    ) -> Option<RpcResult<AsyncResponse<OwnedBytes>>> {
+        (**self).call_of_bytes_async(bytes, transport_config, state)
+    }
+
+    fn call_of_bytes_streaming(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+    ) -> Option<RpcResult<ResponseStream>> {
+        (**self).call_of_bytes_streaming(bytes, transport_config, state)
+    }
+
+    fn call_of_bytes_uploading(
+        &self,
+        bytes: Bytes,
+        transport_config: &TransportConfig,
+        state: &mut State,
+    ) -> Option<RpcResult<RequestStream>> {
+        (**self).call_of_bytes_uploading(bytes, transport_config, state)
+    }
+
+    fn validate_query(&self, bytes: Bytes, transport_config: &TransportConfig) -> RpcResult<()> {
+        (**self).validate_query(bytes, transport_config)
+    }
+
+    fn reads_only(&self) -> bool {
+        (**self).reads_only()
+    }
+
+    fn rpc_name(&self) -> Name {
+        (**self).rpc_name()
+    }
+
+    #[cfg(feature = "schema")]
+    fn schema(&self) -> RpcResult<crate::schema::RpcSchema> {
+        (**self).schema()
+    }
+
+    #[cfg(feature = "type_hash")]
+    fn type_hash(&self) -> RpcResult<u64> {
+        (**self).type_hash()
+    }
 }
@@ -0,0 +1,210 @@
+//! Encryption of whole frames under a pre-shared key, for deployments that need confidentiality
+//! but can't run TLS (Enable the "transport_encryption" feature).
+//!
+//! [EncryptedTransport] wraps any [InternalTransport], sealing every frame it sends with
+//! XChaCha20-Poly1305 under the key and a random nonce, and opening every frame it receives.
+//! Unlike [crate::payload_encryption], rpc names and errors are hidden too. Frames sealed by a
+//! client can't be passed off as a server's, or the other way round, but a recorded frame can be
+//! replayed: enable [crate::TransportConfig::sequence_numbers] on both sides to refuse those
+//!
+//! ```rust,ignore
+//! let listener = EncryptedListener::new(TcpListener::bind(addr).await?, shared_key);
+//! tokio::spawn(async move { server.serve_listener(listener).await });
+//! let mut transport = get_names.connect_encrypted(addr, shared_key).await?;
+//! ```
+use crate::auth::Identity;
+use crate::error::RpcResult;
+use crate::listener::Listener;
+use crate::transport::{CodecError, InternalTransport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+const NONCE_LEN: usize = 24;
+
+/// Bytes a sealed frame has over its plaintext: its nonce and tag
+const SEAL_OVERHEAD: usize = NONCE_LEN + 16;
+
+const CLIENT_FRAME: &[u8] = b"pirates client frame";
+const SERVER_FRAME: &[u8] = b"pirates server frame";
+
+/// Implementation of [InternalTransport] sealing the frames of another, see [crate::encryption]
+pub struct EncryptedTransport<T> {
+    inner: T,
+    cipher: XChaCha20Poly1305,
+    /// Bound into the frames sent, the other end's into those received
+    sends: &'static [u8],
+    receives: &'static [u8],
+}
+
+impl<T: InternalTransport + Send> EncryptedTransport<T> {
+    /// The client's end of a connection over [inner], encrypted with [key]
+    pub fn client(inner: T, key: [u8; 32]) -> Self {
+        Self::new(inner, key, CLIENT_FRAME, SERVER_FRAME)
+    }
+
+    /// The server's end of a connection over [inner], encrypted with [key]
+    pub fn server(inner: T, key: [u8; 32]) -> Self {
+        Self::new(inner, key, SERVER_FRAME, CLIENT_FRAME)
+    }
+
+    fn new(inner: T, key: [u8; 32], sends: &'static [u8], receives: &'static [u8]) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            sends,
+            receives,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn seal(&self, frame: Bytes<'_>) -> Result<OwnedBytes, TransportError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: frame,
+            aad: self.sends,
+        };
+        self.cipher
+            .encrypt(&nonce, payload)
+            .map(|ciphertext| [nonce.as_slice(), &ciphertext].concat())
+            .map_err(|_| TransportError::SendError(String::from("Failed to seal the frame")))
+    }
+
+    /// Nothing stays nothing, the end of the connection
+    fn open(&self, sealed: OwnedBytes) -> Result<OwnedBytes, TransportError> {
+        if sealed.is_empty() {
+            return Ok(sealed);
+        }
+        let open_error = |message: &str| {
+            TransportError::DeserialiseError(CodecError {
+                format: "transport_encryption",
+                type_name: "sealed frame",
+                message: message.to_string(),
+            })
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(open_error("Frame is not sealed"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: self.receives,
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| open_error("Frame failed to open, is it sealed with the same key?"))
+    }
+}
+
+#[async_trait]
+impl<T: InternalTransport + Send> InternalTransport for EncryptedTransport<T> {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        let sealed = self.seal(b)?;
+        self.inner.send(&sealed).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        let sealed = self.seal(b)?;
+        let response = self
+            .inner
+            .send_and_wait_for_response(&sealed, timeout)
+            .await?;
+        self.open(response)
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        let sealed = self.inner.receive(timeout).await?;
+        self.open(sealed)
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        self.inner.peer_disconnected()
+    }
+
+    fn peer_identity(&self) -> Option<Identity> {
+        self.inner.peer_identity()
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.inner
+            .set_max_message_size(max_message_size.map(|max| max + SEAL_OVERHEAD));
+    }
+}
+
+/// Serves the connections of another [Listener] encrypted with a pre-shared key, see
+/// [crate::encryption]
+pub struct EncryptedListener<L> {
+    inner: L,
+    key: [u8; 32],
+}
+
+impl<L: Listener> EncryptedListener<L> {
+    pub fn new(inner: L, key: [u8; 32]) -> Self {
+        Self { inner, key }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for EncryptedListener<L>
+where
+    L::Transport: Send,
+{
+    type Stream = L::Stream;
+    type Transport = EncryptedTransport<L::Transport>;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        self.inner.poll_accept(cx)
+    }
+
+    async fn transport(
+        &self,
+        stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<Self::Transport> {
+        let inner = self.inner.transport(stream, transport_config).await?;
+        Ok(EncryptedTransport::server(inner, self.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sealed_frames() {
+        let (client_end, server_end) = crate::memory::pair();
+        let mut client = EncryptedTransport::client(client_end, [7; 32]);
+        let mut server = EncryptedTransport::server(server_end, [7; 32]);
+        client.send(b"secret").await.unwrap();
+        assert_eq!(server.receive(None).await.unwrap(), b"secret");
+
+        let (client_end, mut server_end) = crate::memory::pair();
+        let mut client = EncryptedTransport::client(client_end, [7; 32]);
+        client.send(b"secret").await.unwrap();
+        let sealed = server_end.receive(None).await.unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        // Bound to its key and the end that sent it
+        let server = EncryptedTransport::server(crate::memory::pair().0, [8; 32]);
+        assert!(server.open(sealed.clone()).is_err());
+        let other_client = EncryptedTransport::client(crate::memory::pair().0, [7; 32]);
+        assert!(other_client.open(sealed).is_err());
+    }
+}
@@ -1,19 +1,193 @@
-use crate::transport::TransportError;
+use crate::transport::{payload, TransportError, TransportWireConfig};
+use crate::OwnedBytes;
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
+
+/// The kind of an error, from the same fixed set as gRPC's status codes, giving clients and
+/// interceptors one vocabulary for failures whichever rpc they came from. See [RpcError::code]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StatusCode {
+    Ok,
+    Cancelled,
+    /// Also what errors from servers predating status codes arrive as
+    #[default]
+    Unknown,
+    InvalidArgument,
+    DeadlineExceeded,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    OutOfRange,
+    Unimplemented,
+    Internal,
+    Unavailable,
+    DataLoss,
+    Unauthenticated,
+}
+
+impl StatusCode {
+    /// Whether the same call may succeed if simply tried again. As in gRPC, calls [Self::Aborted]
+    /// are only worth retrying from further back, e.g. after reading the state afresh
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Unavailable | Self::DeadlineExceeded)
+    }
+}
+
+type EncodeDetails =
+    Box<dyn Fn(&TransportWireConfig) -> Result<OwnedBytes, TransportError> + Send + Sync>;
+
+/// The typed details of an [RpcError::Status], encoded in the wire format of the response
+/// carrying them, see [RemoteError::details]
+pub struct ErrorDetails {
+    type_name: &'static str,
+    encode: EncodeDetails,
+}
+
+impl ErrorDetails {
+    pub fn new<D: Serialize + Send + Sync + 'static>(details: D) -> Self {
+        Self {
+            type_name: std::any::type_name::<D>(),
+            encode: Box::new(move |wire_config| wire_config.serialize(&details)),
+        }
+    }
+}
+
+impl Debug for ErrorDetails {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ErrorDetails({})", self.type_name)
+    }
+}
 
 #[derive(Debug)]
 pub enum RpcError {
-    ParseError(serde_pickle::error::Error),
+    /// Received bytes could not be parsed as the [expected] type
+    ParseError {
+        expected: &'static str,
+        received_bytes: usize,
+        reason: String,
+    },
     TransportError(TransportError),
+    /// The server can't take the call right now, but may later
+    Unavailable(String),
+    /// The server is too busy to make the call in time, see [crate::RpcServer::set_lock_timeout]
+    Overloaded(String),
+    /// [rpc] has been taken offline for maintenance, expected to be back [retry_after] from now
+    /// if known, see [crate::RpcServer::set_rpc_maintenance]
+    Maintenance {
+        rpc: String,
+        retry_after: Option<Duration>,
+    },
+    /// An error raised on the server, relayed to the client
+    Remote(RemoteError),
+    /// The server's types for [rpc] differ from the client's, see [crate::schema]
+    SchemaMismatch {
+        rpc: String,
+        reason: String,
+    },
+    /// The client's types for [rpc] hash differently to the server's, see [crate::type_hash]
+    TypeMismatch {
+        rpc: String,
+        client_hash: u64,
+        server_hash: u64,
+    },
+    /// The client has not authenticated, or failed to, see [crate::Authenticator]
+    Unauthenticated(String),
+    /// [identity] (or an unauthenticated client, if [None]) may not call [rpc], see
+    /// [crate::AccessPolicy]
+    PermissionDenied {
+        rpc: String,
+        identity: Option<String>,
+    },
+    /// [identity] has used up its quota, as described by [reason], see [crate::quota]
+    QuotaExceeded {
+        identity: String,
+        reason: String,
+    },
+    /// The server is in dry-run mode and validated the query without calling [rpc], see
+    /// [crate::RpcServer::set_dry_run]
+    DryRun {
+        rpc: String,
+    },
+    /// The response wasn't signed by the server's pinned key, so may have been tampered with, see
+    /// [crate::signing]
+    InvalidSignature(String),
+    /// The call expected the server's state at another version than [current_version], so
+    /// wasn't made, see [crate::RpcClient::call_versioned]
+    Conflict {
+        current_version: u64,
+    },
+    /// A frame was over the [limit] of [crate::TransportConfig::max_message_size] bytes, so
+    /// wasn't sent or read
+    MessageTooLarge {
+        limit: usize,
+    },
+    /// An error with a [StatusCode] of its own choosing, and optionally [details] for the client,
+    /// see [Self::status] and [Self::status_with_details]
+    Status {
+        code: StatusCode,
+        message: String,
+        details: Option<ErrorDetails>,
+    },
     Custom(String),
 }
 
 impl Display for RpcError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::ParseError(pickle) => write!(f, "{}", pickle),
+            Self::ParseError {
+                expected,
+                received_bytes,
+                reason,
+            } => write!(
+                f,
+                "ParseError(expected {} from {} bytes: {})",
+                expected, received_bytes, reason
+            ),
             Self::TransportError(transport_error) => write!(f, "{}", transport_error),
+            Self::Unavailable(s) => write!(f, "Unavailable({})", s),
+            Self::Overloaded(s) => write!(f, "Overloaded({})", s),
+            Self::Maintenance { rpc, retry_after } => match retry_after {
+                Some(retry_after) => {
+                    write!(f, "Maintenance({}, retry after {:?})", rpc, retry_after)
+                }
+                None => write!(f, "Maintenance({})", rpc),
+            },
+            Self::Remote(remote_error) => write!(f, "{}", remote_error),
+            Self::SchemaMismatch { rpc, reason } => {
+                write!(f, "SchemaMismatch({}: {})", rpc, reason)
+            }
+            Self::TypeMismatch {
+                rpc,
+                client_hash,
+                server_hash,
+            } => write!(
+                f,
+                "TypeMismatch({}: client types hash to {:016x}, server types to {:016x})",
+                rpc, client_hash, server_hash
+            ),
+            Self::Unauthenticated(s) => write!(f, "Unauthenticated({})", s),
+            Self::PermissionDenied { rpc, identity } => match identity {
+                Some(identity) => write!(f, "PermissionDenied({} may not call {})", identity, rpc),
+                None => write!(f, "PermissionDenied(unauthenticated, may not call {})", rpc),
+            },
+            Self::QuotaExceeded { identity, reason } => {
+                write!(f, "QuotaExceeded({}: {})", identity, reason)
+            }
+            Self::DryRun { rpc } => write!(f, "DryRun({} validated but not called)", rpc),
+            Self::InvalidSignature(s) => write!(f, "InvalidSignature({})", s),
+            Self::Conflict { current_version } => {
+                write!(f, "Conflict(state is at version {})", current_version)
+            }
+            Self::MessageTooLarge { limit } => {
+                write!(f, "MessageTooLarge(over the {} byte limit)", limit)
+            }
+            Self::Status { code, message, .. } => write!(f, "{:?}({})", code, message),
             Self::Custom(s) => write!(f, "{}", s),
         }
     }
@@ -21,17 +195,157 @@ impl Display for RpcError {
 
 impl Error for RpcError {}
 
-impl From<serde_pickle::Error> for RpcError {
-    fn from(e: serde_pickle::Error) -> Self {
-        Self::ParseError(e)
+impl RpcError {
+    /// An error of kind [code]
+    pub fn status(code: StatusCode, message: impl Into<String>) -> Self {
+        Self::Status {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// An error of kind [code], telling the client more in [details], see [RemoteError::details]
+    pub fn status_with_details<D: Serialize + Send + Sync + 'static>(
+        code: StatusCode,
+        message: impl Into<String>,
+        details: D,
+    ) -> Self {
+        Self::Status {
+            code,
+            message: message.into(),
+            details: Some(ErrorDetails::new(details)),
+        }
+    }
+
+    /// The kind of error this is
+    pub fn code(&self) -> StatusCode {
+        match self {
+            Self::ParseError { .. } => StatusCode::InvalidArgument,
+            Self::TransportError(transport_error) => match transport_error {
+                TransportError::ReceiveTimeout(_) => StatusCode::DeadlineExceeded,
+                TransportError::SerialiseError(_) => StatusCode::Internal,
+                TransportError::DeserialiseError(_) => StatusCode::InvalidArgument,
+                _ => StatusCode::Unavailable,
+            },
+            Self::Unavailable(_) => StatusCode::Unavailable,
+            Self::Overloaded(_) => StatusCode::ResourceExhausted,
+            Self::Maintenance { .. } => StatusCode::Unavailable,
+            Self::Remote(remote_error) => remote_error.code,
+            Self::SchemaMismatch { .. } => StatusCode::FailedPrecondition,
+            Self::TypeMismatch { .. } => StatusCode::FailedPrecondition,
+            Self::Unauthenticated(_) => StatusCode::Unauthenticated,
+            Self::PermissionDenied { .. } => StatusCode::PermissionDenied,
+            Self::QuotaExceeded { .. } => StatusCode::ResourceExhausted,
+            Self::DryRun { .. } => StatusCode::FailedPrecondition,
+            Self::InvalidSignature(_) => StatusCode::DataLoss,
+            Self::Conflict { .. } => StatusCode::Aborted,
+            Self::MessageTooLarge { .. } => StatusCode::ResourceExhausted,
+            Self::Status { code, .. } => *code,
+            Self::Custom(_) => StatusCode::Unknown,
+        }
+    }
+
+    /// Whether the failure is transient (timeouts, temporarily unavailable) such that the same
+    /// call could succeed if tried again, as opposed to permanent (bad request, unknown rpc)
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::ParseError { .. } => false,
+            Self::TransportError(transport_error) => transport_error.is_retryable(),
+            Self::Unavailable(_) => true,
+            Self::Overloaded(_) => true,
+            Self::Maintenance { .. } => true,
+            Self::Remote(remote_error) => remote_error.retryable,
+            Self::SchemaMismatch { .. } => false,
+            Self::TypeMismatch { .. } => false,
+            Self::Unauthenticated(_) => false,
+            Self::PermissionDenied { .. } => false,
+            // Retrying straight away can't help, the quota only recovers in a later window
+            Self::QuotaExceeded { .. } => false,
+            Self::DryRun { .. } => false,
+            Self::InvalidSignature(_) => false,
+            // The same call would conflict again, the caller must read the state afresh first
+            Self::Conflict { .. } => false,
+            Self::MessageTooLarge { .. } => false,
+            Self::Status { code, .. } => code.is_retryable(),
+            Self::Custom(_) => false,
+        }
     }
 }
+
 impl From<TransportError> for RpcError {
     fn from(e: TransportError) -> Self {
         Self::TransportError(e)
     }
 }
 
+/// Wire representation of an [RpcError] raised on the server
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub message: String,
+    pub retryable: bool,
+    #[serde(default)]
+    pub code: StatusCode,
+    /// The [ErrorDetails] of an [RpcError::Status], encoded in the response's wire format
+    #[serde(default, with = "payload::optional")]
+    pub details: Option<OwnedBytes>,
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RemoteError({})", self.message)
+    }
+}
+
+impl RemoteError {
+    /// [e] as sent to the client in [wire_config], details and all
+    pub(crate) fn relay(e: &RpcError, wire_config: &TransportWireConfig) -> Self {
+        let mut remote_error = Self::from(e);
+        if let RpcError::Status {
+            details: Some(details),
+            ..
+        } = e
+        {
+            match (details.encode)(wire_config) {
+                Ok(encoded) => remote_error.details = Some(encoded),
+                Err(encode_error) => warn!(
+                    "Dropping the details of {}, they could not be encoded: {}",
+                    e, encode_error
+                ),
+            }
+        }
+        remote_error
+    }
+
+    /// The details the server sent, as a [D], if it sent any. [wire_config] is the wire format
+    /// the call was made in
+    pub fn details<D: for<'de> Deserialize<'de>>(
+        &self,
+        wire_config: &TransportWireConfig,
+    ) -> Option<RpcResult<D>> {
+        self.details.as_ref().map(|details| {
+            wire_config
+                .deserialize(details)
+                .map_err(RpcError::TransportError)
+        })
+    }
+}
+
+/// Without any details, which need a wire format to be encoded in
+impl From<&RpcError> for RemoteError {
+    fn from(e: &RpcError) -> Self {
+        match e {
+            RpcError::Remote(remote_error) => remote_error.clone(),
+            e => Self {
+                message: e.to_string(),
+                retryable: e.is_retryable(),
+                code: e.code(),
+                details: None,
+            },
+        }
+    }
+}
+
 // TODO: Make this an actual struct and not just a type alias
 pub type RpcResult<A> = Result<A, RpcError>;
 
@@ -41,3 +355,51 @@ pub fn into_rpc_result_transport<T>(result: Result<T, TransportError>) -> RpcRes
         Err(e) => Err(RpcError::TransportError(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_classification() {
+        let timeout = RpcError::TransportError(TransportError::ReceiveTimeout(Duration::ZERO));
+        assert!(timeout.is_retryable());
+        assert!(RpcError::Unavailable("busy".into()).is_retryable());
+        assert!(!RpcError::Custom("Rpc not found: Foo".into()).is_retryable());
+
+        // The classification survives the trip to the client
+        let remote = RpcError::Remote(RemoteError::from(&timeout));
+        assert!(remote.is_retryable());
+        let remote = RpcError::Remote(RemoteError::from(&RpcError::Custom("bad".into())));
+        assert!(!remote.is_retryable());
+    }
+
+    #[test]
+    fn status_codes() {
+        let timeout = RpcError::TransportError(TransportError::ReceiveTimeout(Duration::ZERO));
+        assert_eq!(timeout.code(), StatusCode::DeadlineExceeded);
+        assert_eq!(RpcError::Custom("bad".into()).code(), StatusCode::Unknown);
+        let not_found = RpcError::status(StatusCode::NotFound, "No such name");
+        assert_eq!(not_found.to_string(), "NotFound(No such name)");
+
+        // The code survives the trip to the client, and is read as Unknown from older servers
+        let remote = RpcError::Remote(RemoteError::from(&not_found));
+        assert_eq!(remote.code(), StatusCode::NotFound);
+        let wire_config = TransportWireConfig::default();
+        let old_server = RemoteOnly {
+            message: String::from("bad"),
+            retryable: false,
+        };
+        let remote: RemoteError = wire_config
+            .deserialize(&wire_config.serialize(&old_server).unwrap())
+            .unwrap();
+        assert_eq!((remote.code, remote.details), (StatusCode::Unknown, None));
+    }
+
+    /// [RemoteError] as it was before status codes
+    #[derive(Serialize)]
+    struct RemoteOnly {
+        message: String,
+        retryable: bool,
+    }
+}
@@ -0,0 +1,206 @@
+//! Exactly-once calls: a client sends a call with a request id (see [RequestId] and
+//! [crate::RpcClient::call_idempotent]), reusing it when it retries the call, say after the
+//! response was lost with the connection. A server with [crate::RpcServer::enable_idempotency]
+//! remembers each response by its id in an [IdempotencyStore], and answers retries with it rather
+//! than calling the rpc again. Responses are remembered in memory by default, or can be shared
+//! between servers with a custom store
+//!
+//! ```rust,ignore
+//! server.enable_idempotency(10_000, Duration::from_secs(600));
+//! // Cheap to repeat, and doesn't change the state
+//! server.set_rpc_idempotency(NamesRpc::GetNames, false);
+//!
+//! let request_id = RequestId::new();
+//! let result = match add_name.call_idempotent(name.clone(), &request_id, &mut transport).await {
+//!     Err(e) if e.is_retryable() => {
+//!         let mut transport = add_name.connect(addr).await?;
+//!         add_name.call_idempotent(name, &request_id, &mut transport).await
+//!     }
+//!     result => result,
+//! };
+//! ```
+use crate::OwnedBytes;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Identifies one call across its retries, unique within and between processes
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// An id no other call is made with
+    pub fn new() -> Self {
+        static PROCESS_START: OnceLock<u128> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let process_start = PROCESS_START.get_or_init(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_nanos())
+        });
+        Self(format!(
+            "{:x}-{:x}-{:x}",
+            std::process::id(),
+            process_start,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<&RequestId> for String {
+    fn from(request_id: &RequestId) -> Self {
+        request_id.0.clone()
+    }
+}
+
+/// Who sent a key, keys being scoped to the client's identity and the rpc called
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey {
+    pub identity: Option<String>,
+    pub rpc: String,
+    pub key: String,
+}
+
+/// The response a call made with an [IdempotencyKey] returned, replayed to its duplicates
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RememberedResponse {
+    pub response: OwnedBytes,
+    /// The response is in this wire format, see [crate::TransportWireConfig::format_name]
+    pub format_name: String,
+    /// See [crate::RpcClient::call_versioned]
+    pub version: Option<u64>,
+}
+
+/// Where a server remembers responses by [IdempotencyKey]. The server looks a key up and
+/// remembers its response while holding the state's lock, so a duplicate arriving while the
+/// first call is being made waits for its response
+pub trait IdempotencyStore: Send + Sync {
+    /// The response remembered for [key], if it hasn't been forgotten
+    fn get(&self, key: &IdempotencyKey) -> Option<RememberedResponse>;
+    /// Remember [response] for [key]
+    fn insert(&self, key: IdempotencyKey, response: RememberedResponse);
+}
+
+struct Remembered {
+    response: RememberedResponse,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct Responses {
+    by_key: HashMap<IdempotencyKey, Remembered>,
+    /// Oldest first, so the expired are all at the front
+    order: VecDeque<IdempotencyKey>,
+}
+
+/// The default [IdempotencyStore], remembering the last [capacity] responses in memory, each
+/// forgotten [ttl] after it was made
+pub struct InMemoryIdempotencyStore {
+    capacity: usize,
+    ttl: Duration,
+    responses: Mutex<Responses>,
+}
+
+impl InMemoryIdempotencyStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            responses: Mutex::new(Responses::default()),
+        }
+    }
+}
+
+impl IdempotencyStore for InMemoryIdempotencyStore {
+    fn get(&self, key: &IdempotencyKey) -> Option<RememberedResponse> {
+        self.responses
+            .lock()
+            .unwrap()
+            .by_key
+            .get(key)
+            .filter(|remembered| remembered.at.elapsed() < self.ttl)
+            .map(|remembered| remembered.response.clone())
+    }
+
+    fn insert(&self, key: IdempotencyKey, response: RememberedResponse) {
+        let Responses { by_key, order } = &mut *self.responses.lock().unwrap();
+        while let Some(oldest) = order.front() {
+            let expired = by_key
+                .get(oldest)
+                .is_none_or(|remembered| remembered.at.elapsed() >= self.ttl);
+            if !expired && order.len() < self.capacity {
+                break;
+            }
+            if let Some(oldest) = order.pop_front() {
+                by_key.remove(&oldest);
+            }
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let remembered = Remembered {
+            response,
+            at: Instant::now(),
+        };
+        if by_key.insert(key.clone(), remembered).is_some() {
+            // An expired response under the same key, now the newest
+            order.retain(|existing| *existing != key);
+        }
+        order.push_back(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> IdempotencyKey {
+        IdempotencyKey {
+            identity: None,
+            rpc: String::from("AddName"),
+            key: String::from(key),
+        }
+    }
+
+    fn response(byte: u8) -> RememberedResponse {
+        RememberedResponse {
+            response: vec![byte],
+            format_name: String::from("postcard"),
+            version: None,
+        }
+    }
+
+    #[test]
+    fn bounded_and_expiring() {
+        let store = InMemoryIdempotencyStore::new(2, Duration::from_secs(60));
+        store.insert(key("a"), response(1));
+        store.insert(key("b"), response(2));
+        assert_eq!(store.get(&key("a")), Some(response(1)));
+        store.insert(key("c"), response(3));
+        assert_eq!(store.get(&key("a")), None);
+        assert_eq!(store.get(&key("c")), Some(response(3)));
+
+        let store = InMemoryIdempotencyStore::new(2, Duration::ZERO);
+        store.insert(key("a"), response(1));
+        assert_eq!(store.get(&key("a")), None);
+    }
+
+    #[test]
+    fn request_ids_unique() {
+        assert_ne!(RequestId::new(), RequestId::new());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::auth::Identity;
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::metadata::Metadata;
+use crate::Bytes;
+use log::{log, Level};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A call as seen by an [Interceptor], before dispatch
+pub struct CallInfo<'a, Name: RpcName> {
+    pub name: &'a Name,
+    pub query_bytes: Bytes<'a>,
+    /// Who the client on the connection authenticated as, if it did, see [crate::Authenticator]
+    pub identity: Option<&'a Identity>,
+    /// What the client sent with the query, see [Metadata]
+    pub metadata: &'a Metadata,
+}
+
+/// The outcome of a call as seen by an [Interceptor]
+pub struct CallOutcome<'a> {
+    pub duration: Duration,
+    /// The serialised response, or the error the call failed with
+    pub result: Result<Bytes<'a>, &'a RpcError>,
+}
+
+/// Hooks run by an [crate::RpcServer] around each call to one of its rpcs, in the order they
+/// were added with [crate::RpcServer::add_interceptor]
+pub trait Interceptor<Name: RpcName>: Send + Sync {
+    /// Called before dispatch, returning an error rejects the call with it
+    fn before_call(&self, _call: &CallInfo<Name>) -> RpcResult<()> {
+        Ok(())
+    }
+
+    /// Called once the call has completed, including calls rejected in [Self::before_call]
+    fn after_call(&self, _call: &CallInfo<Name>, _outcome: &CallOutcome) {}
+}
+
+/// A call as seen by a [ClientInterceptor], before it's sent
+pub struct ClientCallInfo<'a, Name: RpcName> {
+    pub name: &'a Name,
+    pub query_bytes: Bytes<'a>,
+    /// Sent along with the query, for interceptors to add to
+    pub metadata: &'a mut Metadata,
+}
+
+/// Hooks run by an [crate::RpcClient] around each call it makes, in the order they were added
+/// with [crate::RpcClient::add_interceptor]: the client's side of an [Interceptor]
+pub trait ClientInterceptor<Name: RpcName>: Send + Sync {
+    /// Called before the query is sent, returning an error fails the call with it unsent
+    fn before_call(&self, _call: &mut ClientCallInfo<Name>) -> RpcResult<()> {
+        Ok(())
+    }
+
+    /// Called once the call has completed, including calls failed in [Self::before_call]
+    fn after_call(&self, _call: &ClientCallInfo<Name>, _outcome: &CallOutcome) {}
+
+    /// The error to fail the call with in place of [error], after every [Self::after_call]
+    fn map_error(&self, _call: &ClientCallInfo<Name>, error: RpcError) -> RpcError {
+        error
+    }
+}
+
+type Redactor = Box<dyn Fn(Bytes) -> String + Send + Sync>;
+
+/// Out of the box logging middleware, recording the name, duration, sizes and outcome of each call.
+/// Payloads are only logged when enabled with [LoggingInterceptor::log_payloads], and rpcs
+/// carrying secrets can be given a redactor with [LoggingInterceptor::redact] to control what
+/// reaches the log
+pub struct LoggingInterceptor<Name: RpcName> {
+    level: Level,
+    log_payloads: bool,
+    redactors: HashMap<Name, Redactor>,
+}
+
+impl<Name: RpcName> LoggingInterceptor<Name> {
+    pub fn new(level: Level) -> Self {
+        Self {
+            level,
+            log_payloads: false,
+            redactors: HashMap::new(),
+        }
+    }
+
+    /// Also log query and response payloads
+    pub fn log_payloads(&mut self, enabled: bool) {
+        self.log_payloads = enabled;
+    }
+
+    /// Log payloads of [name] as rendered by [redactor] rather than as raw bytes
+    pub fn redact(
+        &mut self,
+        name: Name,
+        redactor: impl Fn(Bytes) -> String + Send + Sync + 'static,
+    ) {
+        self.redactors.insert(name, Box::new(redactor));
+    }
+
+    fn render_payload(&self, name: &Name, bytes: Bytes) -> String {
+        match self.redactors.get(name) {
+            Some(redactor) => redactor(bytes),
+            None => format!("{:?}", bytes),
+        }
+    }
+}
+
+impl<Name: RpcName + Send + Sync> Interceptor<Name> for LoggingInterceptor<Name> {
+    fn after_call(&self, call: &CallInfo<Name>, outcome: &CallOutcome) {
+        match outcome.result {
+            Ok(response_bytes) => log!(
+                self.level,
+                "Rpc {} succeeded in {:?}: {} bytes in, {} bytes out",
+                call.name,
+                outcome.duration,
+                call.query_bytes.len(),
+                response_bytes.len()
+            ),
+            Err(e) => log!(
+                self.level,
+                "Rpc {} failed in {:?}: {} bytes in, error: {}",
+                call.name,
+                outcome.duration,
+                call.query_bytes.len(),
+                e
+            ),
+        }
+        if self.log_payloads {
+            log!(
+                self.level,
+                "Rpc {} query: {}",
+                call.name,
+                self.render_payload(call.name, call.query_bytes)
+            );
+            if let Ok(response_bytes) = outcome.result {
+                log!(
+                    self.level,
+                    "Rpc {} response: {}",
+                    call.name,
+                    self.render_payload(call.name, response_bytes)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+    use crate::{RpcServer, TransportConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn redacted_payloads() {
+        let mut interceptor = LoggingInterceptor::new(Level::Info);
+        interceptor.redact(HelloWorldRpcName::HelloWorld, |_| {
+            String::from("<redacted>")
+        });
+        let render = |name| interceptor.render_payload(&name, &[1, 2]);
+        assert_eq!(render(HelloWorldRpcName::HelloWorld), "<redacted>");
+        assert_eq!(render(HelloWorldRpcName::GetI), "[1, 2]");
+    }
+
+    struct RejectAfter {
+        calls: AtomicUsize,
+        allowed: usize,
+    }
+    impl Interceptor<HelloWorldRpcName> for RejectAfter {
+        fn before_call(&self, call: &CallInfo<HelloWorldRpcName>) -> RpcResult<()> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.allowed {
+                Ok(())
+            } else {
+                Err(RpcError::Custom(format!("Too many calls to {}", call.name)))
+            }
+        }
+    }
+
+    #[test]
+    fn interceptor_rejects_call() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_interceptor(Box::new(RejectAfter {
+            calls: AtomicUsize::new(0),
+            allowed: 1,
+        }));
+        let incoming_bytes = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+        assert!(server
+            .call(&incoming_bytes, &HelloWorldRpcName::GetI)
+            .is_ok());
+        assert!(server
+            .call(&incoming_bytes, &HelloWorldRpcName::GetI)
+            .is_err());
+        assert_eq!(server.stats().rpcs["GetI"].errors, 1);
+    }
+}
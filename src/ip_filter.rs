@@ -0,0 +1,153 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of IP addresses in CIDR notation, e.g. "10.0.0.0/8" or "2001:db8::/32". A bare
+/// address is a range of one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(format!(
+                "Prefix length {} is too long for {}",
+                prefix_len, addr
+            ));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Whether [ip] is in this range. IPv4 addresses also match as IPv4-mapped IPv6
+    /// addresses, as accepted by [crate::DualStack::Mapped] sockets
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(bits - prefix_len);
+    net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid address in {}: {}", s, e))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .map_err(|e| format!("Invalid prefix length in {}: {}", s, e))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Which peers an [crate::RpcServer] accepts connections from, see
+/// [crate::RpcServer::set_ip_filter]. A peer in any denied range is refused. Otherwise, if any
+/// ranges are allowed the peer must be in one of them, and if none are every peer is accepted
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, net: IpNet) {
+        self.allowed.push(net);
+    }
+
+    pub fn deny(&mut self, net: IpNet) {
+        self.denied.push(net);
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.denied.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn net_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&ip("10.1.200.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(&ip("2001:db8::1")));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&ip("2001:db8:ffff::1")));
+        assert!(!net.contains(&ip("2001:db9::1")));
+
+        let everything: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("192.168.0.1")));
+        let one: IpNet = "192.168.0.1".parse().unwrap();
+        assert!(one.contains(&ip("192.168.0.1")));
+        assert!(!one.contains(&ip("192.168.0.2")));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("banana/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn filter() {
+        let mut filter = IpFilter::new();
+        assert!(filter.is_allowed(&ip("203.0.113.9")));
+        filter.allow("10.0.0.0/8".parse().unwrap());
+        filter.deny("10.66.0.0/16".parse().unwrap());
+        assert!(filter.is_allowed(&ip("10.1.2.3")));
+        assert!(!filter.is_allowed(&ip("10.66.2.3")));
+        assert!(!filter.is_allowed(&ip("203.0.113.9")));
+    }
+}
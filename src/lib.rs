@@ -5,7 +5,7 @@
 //! the `#[pirates::rpc_definition]` macro can do this for you on an impl that
 //! contains a run and implement function (Enable the "macros" feature)
 //!
-//! ```rust,no_run
+//! ```rust,ignore
 //! # pub struct AddName {}
 //! # pub use pirates_macro_lib::rpc_definition;
 //! #[pirates::rpc_definition]
@@ -20,6 +20,10 @@
 //! }
 //! ```
 //!
+//! Queries and responses can be any serde type. To catch the wrong type being used by mistake,
+//! enable the "explicit_rpc_types" feature, and only types marked with
+//! `#[derive(pirates::RpcType)]` (and std types made of them) will do
+//!
 //! There are two core types these are generic over which you need to define:
 //! 1) Rpc Identifier. Create a type which implements RpcName
 //! ```rust,no_run
@@ -53,7 +57,7 @@
 //! ```rust,ignore
 //! let mut server = RpcServer::new(state.clone());
 //! server.add_rpc(Box::new(rpcs::AddName::server()));
-//! server.serve("127.0.0.1:5959").await;
+//! Arc::new(server).serve("127.0.0.1:5959").await?;
 //! ```
 //!
 //!
@@ -64,31 +68,145 @@
 //! pirates::call_client(addr, name, rpcs::AddName::client()).await;
 //! ```
 
+// So the derive macros' `pirates::` paths resolve in the crate's own tests
+#[cfg(all(test, feature = "macros"))]
+extern crate self as pirates;
+
+pub mod admin;
+mod auth;
+#[cfg(feature = "call_trace")]
+pub mod call_trace;
+#[cfg(not(feature = "call_trace"))]
+mod call_trace;
 mod client;
+#[cfg(feature = "config")]
+pub mod config;
 mod core;
+#[cfg(feature = "transport_encryption")]
+pub mod encryption;
 pub mod error;
+pub mod idempotency;
+mod interceptor;
+mod ip_filter;
+mod listener;
+pub mod memory;
+mod metadata;
+#[cfg(feature = "transport_native_tls")]
+pub mod native_tls;
+#[cfg(feature = "payload_encryption")]
+pub mod payload_encryption;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quota;
+#[cfg(feature = "registration")]
+pub mod registry;
+mod resolver;
 mod rpc_types;
+#[cfg(feature = "schema")]
+pub mod schema;
 mod server;
+#[cfg(feature = "response_signing")]
+pub mod signing;
+mod snapshot;
+mod spans;
+mod state_lock;
+mod static_dispatch;
+mod stats;
+pub mod streaming;
+mod subscription;
+mod tasks;
+#[cfg(feature = "transport_tls")]
+pub mod tls;
 mod transport;
+#[cfg(feature = "type_hash")]
+pub mod type_hash;
+#[cfg(feature = "typescript")]
+pub mod typescript;
+pub mod udp;
+#[cfg(feature = "transport_websocket")]
+pub mod websocket;
 
 pub type Bytes<'a> = &'a [u8];
 pub type OwnedBytes = Vec<u8>;
 
+pub use crate::auth::caller;
+pub use crate::auth::AccessPolicy;
+pub use crate::auth::Authenticator;
+pub use crate::auth::ClientAuthenticator;
+pub use crate::auth::Identity;
+pub use crate::auth::NoAuth;
+pub use crate::auth::TokenAuthenticator;
+pub use crate::auth::TokenCredentials;
 pub use crate::client::call_client;
+pub use crate::client::call_client_retrying;
+pub use crate::client::ClientEnv;
+pub use crate::client::ClientEvents;
+pub use crate::client::ConnectedClient;
+pub use crate::client::RetryPolicy;
 pub use crate::client::RpcClient;
+pub use crate::client::SharedTransport;
+pub use crate::core::AsyncResponse;
+pub use crate::core::Deferred;
 pub use crate::core::Rpc;
 pub use crate::core::RpcImpl;
 pub use crate::core::RpcName;
+pub use crate::core::RpcNameList;
 pub use crate::core::RpcType;
 pub use crate::core::StoredRpc;
+pub use crate::interceptor::CallInfo;
+pub use crate::interceptor::CallOutcome;
+pub use crate::interceptor::ClientCallInfo;
+pub use crate::interceptor::ClientInterceptor;
+pub use crate::interceptor::Interceptor;
+pub use crate::interceptor::LoggingInterceptor;
+pub use crate::ip_filter::IpFilter;
+pub use crate::ip_filter::IpNet;
+pub use crate::listener::Listener;
+pub use crate::metadata::metadata;
+pub use crate::metadata::Metadata;
+pub use crate::resolver::Resolver;
+pub use crate::resolver::SystemResolver;
+pub use crate::server::AcceptBackoff;
+pub use crate::server::Acceptor;
+pub use crate::server::Connections;
+pub use crate::server::DualStack;
 pub use crate::server::RpcServer;
+pub use crate::server::ServerHandle;
+pub use crate::state_lock::PoisonPolicy;
+#[doc(hidden)]
+pub use crate::static_dispatch::call_static;
+#[doc(hidden)]
+pub use crate::static_dispatch::validate_static;
+pub use crate::stats::LatencyHistogram;
+pub use crate::stats::RpcStats;
+pub use crate::stats::ServerSnapshot;
+pub use crate::stats::ServerStats;
+pub use crate::subscription::subscribe;
+pub use crate::subscription::subscribe_streaming;
+pub use crate::subscription::Subscription;
+pub use crate::subscription::SubscriptionConfig;
+pub use crate::subscription::SubscriptionEvent;
+pub use crate::transport::HeartbeatConfig;
 pub use crate::transport::InternalTransport;
+pub use crate::transport::SchemaCompatibility;
+pub use crate::transport::StreamTransport;
+pub use crate::transport::TcpTransport;
 pub use crate::transport::Transport;
+pub use crate::transport::TransportCompat;
 pub use crate::transport::TransportConfig;
 pub use crate::transport::TransportWireConfig;
+#[cfg(unix)]
+pub use crate::transport::UnixTransport;
 
+#[cfg(feature = "registration")]
+#[doc(hidden)]
+pub use inventory;
 #[cfg(feature = "macros")]
 pub use pirates_macro_lib::rpc_definition;
+#[cfg(feature = "macros")]
+pub use pirates_macro_lib::RpcNameList;
+#[cfg(feature = "macros")]
+pub use pirates_macro_lib::RpcType;
 
 pub trait RpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
     fn client() -> Rpc<Name, Q, R>;
@@ -97,17 +215,35 @@ pub trait RpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
 
 #[cfg(test)]
 mod tests {
-    use crate::client::call_client;
-    use crate::core::{Rpc, RpcImpl, RpcName};
-    use crate::error::RpcResult;
-    use crate::server::RpcServer;
-    use crate::transport::{TransportConfig, TransportWireConfig};
+    use crate::admin::{self, AdminQuery, ConfigUpdate, SetMaintenance};
+    use crate::auth::{Identity, TokenAuthenticator, TokenCredentials};
+    use crate::client::{call_client, ConnectedClient, RpcClient, SharedTransport};
+    use crate::core::{AsyncResponse, Deferred, Rpc, RpcImpl, RpcName, RpcNameList};
+    use crate::error::{RpcError, RpcResult, StatusCode};
+    use crate::idempotency::RequestId;
+    use crate::interceptor::{
+        CallInfo, CallOutcome, ClientCallInfo, ClientInterceptor, Interceptor,
+    };
+    use crate::ip_filter::IpFilter;
+    use crate::metadata::Metadata;
+    use crate::server::{AcceptBackoff, Acceptor, DualStack, RpcServer};
+    use crate::state_lock::PoisonPolicy;
+    use crate::streaming::{RequestReader, ResponseWriter, STREAM_CHUNK_SIZE};
+    use crate::subscription::{
+        subscribe, subscribe_streaming, SubscriptionConfig, SubscriptionEvent,
+    };
+    use crate::transport::{
+        HeartbeatConfig, StreamTransport, TcpTransport, Transport, TransportCompat,
+        TransportConfig, TransportError, TransportWireConfig,
+    };
     use crate::RpcDefinition;
     use serde::{Deserialize, Serialize};
     use std::fmt::{Display, Formatter};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
+    #[derive(Clone)]
     pub struct HelloWorldState {
         pub i: usize,
     }
@@ -126,6 +262,29 @@ mod tests {
         }
     }
     impl RpcName for HelloWorldRpcName {}
+    impl RpcNameList for HelloWorldRpcName {
+        fn all() -> Vec<Self> {
+            vec![
+                Self::HelloWorld,
+                Self::GetI,
+                Self::IncrI,
+                Self::MassiveRpc,
+                Self::PreciseRpc,
+            ]
+        }
+    }
+
+    /// Names that no test server knows about
+    #[derive(Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+    pub enum UnknownRpcName {
+        NotOnTheServer,
+    }
+    impl Display for UnknownRpcName {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+    impl RpcName for UnknownRpcName {}
 
     pub fn make_hello_world_rpc() -> Rpc<HelloWorldRpcName, String, String> {
         Rpc::new(HelloWorldRpcName::HelloWorld)
@@ -194,6 +353,7 @@ mod tests {
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[cfg_attr(feature = "explicit_rpc_types", derive(crate::RpcType))]
     pub struct PrecisePayload {
         bulk_bytes: Vec<u32>,
         padding: Vec<bool>,
@@ -238,6 +398,7 @@ mod tests {
                 serde_pickle::DeOptions::new(),
                 serde_pickle::SerOptions::new(),
             ),
+            ..Default::default()
         };
         let mut server = RpcServer::new(Arc::new(Mutex::new(state)), transport_config);
         server.add_rpc(Box::new(make_hello_world_rpc_impl()));
@@ -252,6 +413,160 @@ mod tests {
             .unwrap();
     }
 
+    fn get_i(state: &mut HelloWorldState, _query: ()) -> RpcResult<usize> {
+        Ok(state.i)
+    }
+
+    crate::static_rpcs! {
+        enum StaticHelloWorldRpcs<HelloWorldState, HelloWorldRpcName> {
+            GetI((), usize) => HelloWorldRpcName::GetI, get_i;
+            IncrI((), ()) => HelloWorldRpcName::IncrI, IncrIRpc::implement;
+        }
+    }
+
+    #[test]
+    fn static_dispatch_server() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new_static(state_ref, TransportConfig::default());
+        for rpc in StaticHelloWorldRpcs::ALL {
+            server.add_rpc(*rpc);
+        }
+        let unit = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+        server.call(&unit, &HelloWorldRpcName::IncrI).unwrap();
+        let i_bytes = server.call(&unit, &HelloWorldRpcName::GetI).unwrap();
+        let i: usize = serde_pickle::from_slice(&i_bytes, serde_pickle::DeOptions::new()).unwrap();
+        assert_eq!(i, 4);
+        assert!(server.call(&unit, &HelloWorldRpcName::HelloWorld).is_err());
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.set_lock_timeout(Some(Duration::from_millis(20)));
+        let unit = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+
+        let held = state_ref.lock().unwrap();
+        match server.call(&unit, &HelloWorldRpcName::GetI) {
+            Err(RpcError::Overloaded(_)) => (),
+            other => panic!("Expected Overloaded, got {:?}", other),
+        }
+        drop(held);
+        assert!(server.call(&unit, &HelloWorldRpcName::GetI).is_ok());
+    }
+
+    #[test]
+    fn live_config_updates() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let unit = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+
+        let update = ConfigUpdate {
+            lock_timeout_ms: Some(20),
+            ..ConfigUpdate::default()
+        };
+        server.update_config(&update).unwrap();
+        let invalid = ConfigUpdate {
+            lock_timeout_ms: Some(0),
+            max_log_level: Some(String::from("loud")),
+            ..ConfigUpdate::default()
+        };
+        let e = server.update_config(&invalid).unwrap_err();
+        assert_eq!(e.code(), StatusCode::InvalidArgument);
+
+        // The invalid update changed nothing
+        let held = state_ref.lock().unwrap();
+        match server.call(&unit, &HelloWorldRpcName::GetI) {
+            Err(RpcError::Overloaded(_)) => (),
+            other => panic!("Expected Overloaded, got {:?}", other),
+        }
+        drop(held);
+        assert!(server.call(&unit, &HelloWorldRpcName::GetI).is_ok());
+    }
+
+    #[test]
+    fn poison_policies() {
+        let get_i_after_panic = |poison_policy| {
+            let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+            let mut server = RpcServer::new(state_ref, TransportConfig::default());
+            server.add_rpc(Box::new(make_get_i_rpc_impl()));
+            server.add_rpc(Box::new(RpcImpl::<_, _, (), ()>::new(
+                HelloWorldRpcName::IncrI,
+                Box::new(|state: &mut HelloWorldState, ()| {
+                    state.i += 1;
+                    panic!("Handler bug")
+                }),
+            )));
+            server.set_poison_policy(poison_policy);
+            let unit = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                server.call(&unit, &HelloWorldRpcName::IncrI)
+            }));
+            assert!(panicked.is_err());
+            server
+                .call(&unit, &HelloWorldRpcName::GetI)
+                .map(|bytes| serde_pickle::from_slice::<usize>(&bytes, Default::default()).unwrap())
+        };
+
+        assert_eq!(get_i_after_panic(PoisonPolicy::Continue).unwrap(), 4);
+        let reset = PoisonPolicy::Reset(Box::new(|| HelloWorldState { i: 0 }));
+        assert_eq!(get_i_after_panic(reset).unwrap(), 0);
+        match get_i_after_panic(PoisonPolicy::Shutdown) {
+            Err(RpcError::Unavailable(_)) => (),
+            other => panic!("Expected Unavailable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dry_run_server() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.set_dry_run(true);
+        server.add_read_only(HelloWorldRpcName::GetI);
+        let unit = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+
+        match server.call(&unit, &HelloWorldRpcName::IncrI) {
+            Err(RpcError::DryRun { rpc }) => assert_eq!(rpc, "IncrI"),
+            other => panic!("Expected DryRun, got {:?}", other),
+        }
+        assert_eq!(state_ref.lock().unwrap().i, 3);
+        // Read only rpcs are still called
+        let i_bytes = server.call(&unit, &HelloWorldRpcName::GetI).unwrap();
+        let i: usize = serde_pickle::from_slice(&i_bytes, serde_pickle::DeOptions::new()).unwrap();
+        assert_eq!(i, 3);
+        // Invalid queries fail validation
+        let not_unit = serde_pickle::to_vec(&"foo", serde_pickle::SerOptions::new()).unwrap();
+        match server.call(&not_unit, &HelloWorldRpcName::IncrI) {
+            Err(RpcError::DryRun { .. }) | Ok(_) => panic!("Expected a validation failure"),
+            Err(_) => {}
+        }
+    }
+
+    #[test]
+    fn accept_backoff() {
+        let backoff = AcceptBackoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let mut delays = vec![];
+        let mut delay = None;
+        for _ in 0..5 {
+            delay = Some(backoff.next_delay(delay));
+            delays.push(delay.unwrap().as_millis());
+        }
+        assert_eq!(delays, vec![10, 20, 40, 50, 50]);
+
+        let aborted = std::io::Error::from(std::io::ErrorKind::ConnectionAborted);
+        assert!(AcceptBackoff::is_transient(&aborted));
+        // EMFILE, out of file descriptors
+        let out_of_fds = std::io::Error::from_raw_os_error(24);
+        assert!(!AcceptBackoff::is_transient(&out_of_fds));
+    }
+
     #[tokio::test]
     async fn regular_server() {
         // Server setup
@@ -263,6 +578,7 @@ mod tests {
         server.add_rpc(Box::new(make_hello_world_rpc_impl()));
         server.add_rpc(Box::new(make_get_i_rpc_impl()));
         server.add_rpc(Box::new(IncrIRpc::server()));
+        let server = Arc::new(server);
         let addr = "127.0.0.1:5555";
 
         let hello_world_rpc = make_hello_world_rpc();
@@ -281,6 +597,11 @@ mod tests {
             let r4 = call_client(addr, "bar".into(), hello_world_rpc)
                 .await
                 .unwrap();
+            let unknown_rpc: Rpc<UnknownRpcName, (), ()> = Rpc::new(UnknownRpcName::NotOnTheServer);
+            match call_client(addr, (), unknown_rpc).await {
+                Err(RpcError::Remote(remote_error)) => assert!(!remote_error.retryable),
+                other => panic!("Expected a RemoteError, got {:?}", other),
+            }
             (r1, r2, r3, r4)
         });
 
@@ -310,6 +631,7 @@ mod tests {
         let mut server = RpcServer::new(state_ref, TransportConfig::default());
         server.add_rpc(Box::new(MassiveRpc::server()));
         server.add_rpc(Box::new(PreciseRpc::server()));
+        let server = Arc::new(server);
         let addr = "127.0.0.1:5556";
 
         let massive_rpc_client = MassiveRpc::client();
@@ -343,4 +665,2202 @@ mod tests {
         assert_eq!(slightly_smaller_len, num_bulk);
         // which returns 1286 bytes = 1024 + 262 overhead
     }
+
+    #[tokio::test]
+    async fn idle_connections_reaped() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let transport_config = TransportConfig {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state_ref, transport_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr();
+
+        // Connects and then never sends anything, holding up the server until reaped
+        let _silent = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let i = call_client(&addr.to_string(), (), make_get_i_rpc())
+            .await
+            .unwrap();
+        assert_eq!(i, 3);
+    }
+
+    #[tokio::test]
+    async fn persistent_connection_heartbeats() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_millis(30),
+            timeout: Duration::from_millis(30),
+        };
+        let transport_config = TransportConfig {
+            heartbeat: Some(heartbeat),
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state_ref, transport_config.clone());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+
+        let stream = tokio::net::TcpStream::connect(handle.local_addr())
+            .await
+            .unwrap();
+        let mut transport = Transport::new(TcpTransport::new(stream), transport_config);
+        let incr_i = RpcClient::new(IncrIRpc::client());
+        let get_i = RpcClient::new(make_get_i_rpc());
+
+        incr_i.call((), &mut transport).await.unwrap();
+        // Heartbeats keep the connection open for longer than interval + timeout
+        for _ in 0..4 {
+            tokio::time::sleep(heartbeat.interval / 2).await;
+            transport.heartbeat().await.unwrap();
+        }
+        let i = get_i.call((), &mut transport).await.unwrap();
+        assert_eq!(i, 4);
+
+        // Once silent for interval + timeout, the server reaps the connection
+        tokio::time::sleep((heartbeat.interval + heartbeat.timeout) * 2).await;
+        assert!(transport.heartbeat().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn subscription_resumes_after_drop() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        // A server stays stopped once shut down, so it comes back as a new one on the same state
+        let new_server = || {
+            let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+            server.add_rpc(Box::new(make_get_i_rpc_impl()));
+            Arc::new(server)
+        };
+        let handle = new_server().spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr();
+        let config = SubscriptionConfig {
+            poll_interval: Duration::from_millis(5),
+            reconnect_delay: Duration::from_millis(10),
+            buffer: 1,
+        };
+        let mut subscription = subscribe(
+            addr.to_string(),
+            RpcClient::new(make_get_i_rpc()),
+            (),
+            config,
+        );
+        assert!(matches!(
+            subscription.next().await,
+            Some(SubscriptionEvent::Item(3))
+        ));
+
+        // Stopping the server drops the connection, the subscription picks up once it is back
+        handle.shutdown();
+        assert!(handle.join().await.is_empty());
+        state_ref.lock().unwrap().i = 4;
+        let _handle = new_server().spawn(addr).await.unwrap();
+        let resumed = loop {
+            match subscription.next().await.unwrap() {
+                SubscriptionEvent::Item(3) => continue,
+                SubscriptionEvent::Item(i) => break (false, i),
+                SubscriptionEvent::Gap { .. } => match subscription.next().await.unwrap() {
+                    SubscriptionEvent::Item(i) => break (true, i),
+                    other => panic!("Expected an item after the gap, got {:?}", other),
+                },
+                SubscriptionEvent::Ended { error } => panic!("Ended with {}", error),
+            }
+        };
+        assert_eq!(resumed, (true, 4));
+    }
+
+    #[tokio::test]
+    async fn subscription_ends_on_rpc_error() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        let failing_rpc: RpcImpl<_, HelloWorldState, (), usize> = RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|_state, ()| Err(RpcError::Custom(String::from("No more")))),
+        );
+        server.add_rpc(Box::new(failing_rpc));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let config = SubscriptionConfig {
+            poll_interval: Duration::from_millis(5),
+            reconnect_delay: Duration::from_millis(10),
+            buffer: 1,
+        };
+        let addr = handle.local_addr().to_string();
+        let mut subscription = subscribe(addr, RpcClient::new(make_get_i_rpc()), (), config);
+
+        // Reconnecting wouldn't help, so the error reaches the application and ends it
+        match subscription.next().await {
+            Some(SubscriptionEvent::Ended { error }) => {
+                assert!(error.to_string().contains("No more"), "{}", error)
+            }
+            other => panic!("Expected the subscription to end, got {:?}", other),
+        }
+        assert!(subscription.next().await.is_none());
+        handle.shutdown();
+        assert!(handle.join().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streaming_subscription() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), ()>::new_streaming(
+                HelloWorldRpcName::IncrI,
+                Box::new(|state, ()| {
+                    state.i += 1;
+                    let line = format!("{}\n", state.i);
+                    Ok(Box::new(move |mut writer: ResponseWriter| {
+                        Box::pin(async move { writer.send(line.as_bytes()).await })
+                    }))
+                }),
+            ),
+        ));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let config = SubscriptionConfig {
+            poll_interval: Duration::from_millis(5),
+            reconnect_delay: Duration::from_millis(10),
+            buffer: 1,
+        };
+        let addr = handle.local_addr().to_string();
+        let mut subscription =
+            subscribe_streaming(addr, RpcClient::new(IncrIRpc::client()), (), config);
+
+        // Called again each time its response ends
+        for expected in [b"4\n", b"5\n"] {
+            match subscription.next().await {
+                Some(SubscriptionEvent::Item(chunk)) => assert_eq!(chunk, expected),
+                other => panic!("Expected a chunk, got {:?}", other),
+            }
+        }
+        drop(subscription);
+        handle.shutdown();
+        assert!(handle.join().await.is_empty());
+    }
+
+    #[cfg(feature = "schema")]
+    #[tokio::test]
+    async fn schema_check_on_connect() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().to_string();
+
+        let mut get_i = RpcClient::new(make_get_i_rpc());
+        get_i.set_schema_check(true);
+        let mut transport = get_i.connect(&addr).await.unwrap();
+        assert_eq!(get_i.call((), &mut transport).await.unwrap(), 3);
+        drop(transport);
+
+        // A client built against a different revision of GetI
+        let mut stale_get_i: RpcClient<_, (), String> =
+            RpcClient::new(Rpc::new(HelloWorldRpcName::GetI));
+        stale_get_i.set_schema_check(true);
+        match stale_get_i.connect(&addr).await.err() {
+            Some(RpcError::SchemaMismatch { rpc, reason }) => {
+                assert_eq!(rpc, "GetI");
+                assert!(reason.starts_with("response"), "{}", reason);
+            }
+            other => panic!("Expected a SchemaMismatch, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "type_hash")]
+    #[tokio::test]
+    async fn type_hash_mismatch_rejected() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().to_string();
+
+        assert_eq!(call_client(&addr, (), make_get_i_rpc()).await.unwrap(), 3);
+        // A client built against a different revision of GetI
+        let stale_get_i: Rpc<_, (), String> = Rpc::new(HelloWorldRpcName::GetI);
+        match call_client(&addr, (), stale_get_i).await.err() {
+            Some(RpcError::Remote(remote_error)) => {
+                assert!(remote_error.message.starts_with("TypeMismatch(GetI"));
+                assert!(!remote_error.retryable);
+            }
+            other => panic!("Expected a remote TypeMismatch, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_transport() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().to_string();
+
+        let incr_i = RpcClient::new(IncrIRpc::client());
+        let transport = SharedTransport::new(incr_i.connect(&addr).await.unwrap());
+        let callers: Vec<_> = (0..8)
+            .map(|_| {
+                let (incr_i, transport) = (incr_i.clone(), transport.clone());
+                tokio::spawn(async move { incr_i.call_shared((), &transport).await })
+            })
+            .collect();
+        for caller in callers {
+            caller.await.unwrap().unwrap();
+        }
+        let i = RpcClient::new(make_get_i_rpc())
+            .call_shared((), &transport)
+            .await
+            .unwrap();
+        // Eight increments, all over the one connection
+        assert_eq!(i, 11);
+    }
+
+    /// An address with a port free to serve on, for tests of serve methods binding the address
+    /// themselves rather than with [RpcServer::spawn]. Those tests poll the server first in a
+    /// biased select, so it has bound before the client connects
+    fn unused_addr(ip: &str) -> std::net::SocketAddr {
+        let probe = std::net::TcpListener::bind((ip, 0)).unwrap();
+        probe.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn dual_stack() {
+        for mode in [DualStack::Mapped, DualStack::Separate] {
+            let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+            let mut server = RpcServer::new(state_ref, TransportConfig::default());
+            server.add_rpc(Box::new(make_get_i_rpc_impl()));
+            let server = Arc::new(server);
+            // Free on IPv4 as well, as the probe accepts IPv4 clients too
+            let port = unused_addr("::").port();
+
+            let client_call = async {
+                let v4 = call_client(&format!("127.0.0.1:{}", port), (), make_get_i_rpc()).await;
+                let v6 = call_client(&format!("[::1]:{}", port), (), make_get_i_rpc()).await;
+                (v4.unwrap(), v6.unwrap())
+            };
+
+            let results = tokio::select! {
+                biased;
+                _ = server.serve_dual_stack(port, mode) => unreachable!(),
+                client_output = client_call => client_output,
+            };
+            assert_eq!(results, (3, 3), "{:?}", mode);
+        }
+    }
+
+    /// A server config with a self-signed certificate for "pirates.test", and a client config
+    /// trusting it and expecting that name
+    #[cfg(feature = "transport_tls")]
+    fn tls_configs() -> (crate::tls::TlsServerConfig, crate::tls::TlsClientConfig) {
+        use crate::tls::{rustls, TlsClientConfig, TlsServerConfig};
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("pirates.test")]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key =
+            rustls::pki_types::PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+        let server_tls = TlsServerConfig::new(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.clone()], key)
+                .unwrap(),
+        ));
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client_tls = TlsClientConfig::new(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        // The certificate is for pirates.test, not the address connected to
+        client_tls.server_name = Some(String::from("pirates.test"));
+        (server_tls, client_tls)
+    }
+
+    #[cfg(feature = "transport_tls")]
+    #[tokio::test]
+    async fn tls_server() {
+        let (server_tls, client_tls) = tls_configs();
+
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let addr = unused_addr("127.0.0.1");
+
+        let client_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i
+                .connect_tls(&addr.to_string(), &client_tls)
+                .await
+                .unwrap();
+            let alpn = transport
+                .internal_transport()
+                .alpn_protocol()
+                .map(<[u8]>::to_vec);
+            (get_i.call((), &mut transport).await.unwrap(), alpn)
+        };
+
+        let (i, alpn) = tokio::select! {
+            biased;
+            _ = server.serve_tls(addr, &server_tls) => unreachable!(),
+            client_output = client_call => client_output,
+        };
+        assert_eq!(i, 3);
+        assert_eq!(alpn.as_deref(), Some(crate::tls::ALPN_PROTOCOL));
+    }
+
+    #[cfg(feature = "transport_tls")]
+    #[tokio::test]
+    async fn starttls_upgrade() {
+        let (server_tls, client_tls) = tls_configs();
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let starttls_addr = unused_addr("127.0.0.1");
+
+        let client_call = async {
+            let starttls_addr = starttls_addr.to_string();
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i
+                .connect_starttls(&starttls_addr, &client_tls)
+                .await
+                .unwrap();
+            assert!(transport.internal_transport().is_tls());
+            let upgraded = get_i.call((), &mut transport).await.unwrap();
+            drop(transport);
+            // Clients that don't ask to upgrade carry on in plaintext
+            let plain = call_client(&starttls_addr, (), make_get_i_rpc())
+                .await
+                .unwrap();
+            (upgraded, plain)
+        };
+        let results = tokio::select! {
+            biased;
+            _ = server.serve_starttls(starttls_addr, &server_tls) => unreachable!(),
+            client_output = client_call => client_output,
+        };
+        assert_eq!(results, (3, 3));
+
+        // A server that can't upgrade declines, and the connection stays in plaintext
+        let handle = server.spawn("127.0.0.1:0").await.unwrap();
+        let get_i = RpcClient::new(make_get_i_rpc());
+        let mut transport = get_i
+            .connect_starttls(&handle.local_addr().to_string(), &client_tls)
+            .await
+            .unwrap();
+        assert!(!transport.internal_transport().is_tls());
+        assert_eq!(get_i.call((), &mut transport).await.unwrap(), 3);
+    }
+
+    #[cfg(feature = "transport_encryption")]
+    #[tokio::test]
+    async fn encrypted_transport() {
+        use crate::encryption::EncryptedListener;
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client_call_task = tokio::spawn(async move {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.connect_encrypted(&addr, [9; 32]).await.unwrap();
+            let i = get_i.call((), &mut transport).await.unwrap();
+            let mut transport = get_i.connect_encrypted(&addr, [1; 32]).await.unwrap();
+            assert!(get_i.call((), &mut transport).await.is_err());
+            i
+        });
+
+        let i = tokio::select! {
+            _ = server.serve_listener(EncryptedListener::new(listener, [9; 32])) => unreachable!(),
+            client_output = client_call_task => client_output.unwrap(),
+        };
+        assert_eq!(i, 3);
+    }
+
+    #[cfg(feature = "transport_websocket")]
+    #[tokio::test]
+    async fn websocket_server() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(MassiveRpc::server()));
+        let server = Arc::new(server);
+        let addr = unused_addr("127.0.0.1");
+
+        let client_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i
+                .connect_websocket(&addr.to_string(), "/rpc")
+                .await
+                .unwrap();
+            let i = get_i.call((), &mut transport).await.unwrap();
+            // Long enough for 64 bit frame lengths
+            let massive = RpcClient::new(MassiveRpc::client())
+                .call(100_000, &mut transport)
+                .await
+                .unwrap();
+            (i, massive.len())
+        };
+
+        let results = tokio::select! {
+            biased;
+            _ = server.serve_websocket(addr) => unreachable!(),
+            client_output = client_call => client_output,
+        };
+        assert_eq!(results, (3, 100_000));
+    }
+
+    #[cfg(feature = "transport_tls")]
+    #[tokio::test]
+    async fn mutual_tls() {
+        use crate::auth::caller;
+        use crate::tls::rustls;
+        use crate::tls::{TlsClientConfig, TlsServerConfig};
+        use rustls::server::WebPkiClientVerifier;
+        let certify = |name: &str| {
+            let certified = rcgen::generate_simple_self_signed(vec![String::from(name)]).unwrap();
+            let key =
+                rustls::pki_types::PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(certified.cert.der().clone()).unwrap();
+            (certified.cert.der().clone(), key, Arc::new(roots))
+        };
+        let (server_cert, server_key, server_roots) = certify("pirates.test");
+        let (client_cert, client_key, client_roots) = certify("alice.pirates.test");
+
+        // Clients without a certificate may still authenticate with the handshake
+        let verifier = WebPkiClientVerifier::builder(client_roots)
+            .allow_unauthenticated()
+            .build()
+            .unwrap();
+        let mut server_tls = TlsServerConfig::new(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![server_cert], server_key)
+                .unwrap(),
+        ));
+        let alice_cert = client_cert.clone();
+        server_tls.client_identity = Some(Arc::new(move |cert| {
+            (*cert == alice_cert).then(|| Identity::new("alice"))
+        }));
+        let client_builder = rustls::ClientConfig::builder().with_root_certificates(server_roots);
+        let mut uncertified_client_tls =
+            TlsClientConfig::new(Arc::new(client_builder.clone().with_no_client_auth()));
+        uncertified_client_tls.server_name = Some(String::from("pirates.test"));
+        let mut client_tls = TlsClientConfig::new(Arc::new(
+            client_builder
+                .with_client_auth_cert(vec![client_cert], client_key)
+                .unwrap(),
+        ));
+        client_tls.server_name = Some(String::from("pirates.test"));
+
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|state: &mut HelloWorldState, ()| match caller() {
+                Some(caller) if caller.name == "alice" => Ok(state.i),
+                caller => Err(RpcError::Custom(format!("Not for {:?}", caller))),
+            }),
+        )));
+        server.set_authenticator(Box::new(TokenAuthenticator::new()));
+        let identities = Arc::new(Mutex::new(Vec::new()));
+        server.add_interceptor(Box::new(RecordIdentities(identities.clone())));
+        let server = Arc::new(server);
+        let addr = unused_addr("127.0.0.1");
+
+        let client_call = async {
+            let client_addr = addr.to_string();
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.connect_tls(&client_addr, &client_tls).await.unwrap();
+            let i = get_i.call((), &mut transport).await.unwrap();
+            drop(transport);
+            let mut transport = get_i
+                .connect_tls(&client_addr, &uncertified_client_tls)
+                .await
+                .unwrap();
+            match get_i.call((), &mut transport).await {
+                Err(RpcError::Remote(remote_error)) => {
+                    assert!(remote_error.message.contains("Unauthenticated"))
+                }
+                other => panic!("Expected a RemoteError, got {:?}", other),
+            }
+            i
+        };
+
+        let i = tokio::select! {
+            biased;
+            _ = server.serve_tls(addr, &server_tls) => unreachable!(),
+            client_output = client_call => client_output,
+        };
+        assert_eq!(i, 3);
+        assert_eq!(
+            *identities.lock().unwrap(),
+            vec![Some(String::from("alice"))]
+        );
+    }
+
+    /// Records who each call was made by
+    struct RecordIdentities(Arc<Mutex<Vec<Option<String>>>>);
+    impl Interceptor<HelloWorldRpcName> for RecordIdentities {
+        fn before_call(&self, call: &CallInfo<HelloWorldRpcName>) -> RpcResult<()> {
+            let identity = call.identity.map(|identity| identity.name.clone());
+            self.0.lock().unwrap().push(identity);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn authentication_handshake() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let mut authenticator = TokenAuthenticator::new();
+        authenticator.add_token("s3cret", Identity::new("alice"));
+        server.set_authenticator(Box::new(authenticator));
+        let identities = Arc::new(Mutex::new(Vec::new()));
+        server.add_interceptor(Box::new(RecordIdentities(identities.clone())));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().to_string();
+
+        match call_client(&addr, (), make_get_i_rpc()).await {
+            Err(RpcError::Remote(remote_error)) => {
+                assert!(remote_error.message.contains("Unauthenticated"))
+            }
+            other => panic!("Expected a RemoteError, got {:?}", other),
+        }
+        let mut get_i = RpcClient::new(make_get_i_rpc());
+        get_i.set_authenticator(Arc::new(TokenCredentials::new("guess")));
+        assert!(get_i.connect(&addr).await.is_err());
+        get_i.set_authenticator(Arc::new(TokenCredentials::new("s3cret")));
+        let mut transport = get_i.connect(&addr).await.unwrap();
+        assert_eq!(get_i.call((), &mut transport).await.unwrap(), 3);
+        // Only the authenticated call reached dispatch
+        assert_eq!(
+            *identities.lock().unwrap(),
+            vec![Some(String::from("alice"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn ip_filter_refuses_peers() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let mut ip_filter = IpFilter::new();
+        ip_filter.deny("127.0.0.0/8".parse().unwrap());
+        server.set_ip_filter(ip_filter);
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+
+        let addr = handle.local_addr().to_string();
+        let result = call_client(&addr, (), make_get_i_rpc()).await;
+        // Closed before the query was read
+        assert!(matches!(result, Err(RpcError::TransportError(_))));
+    }
+
+    #[cfg(feature = "config")]
+    #[tokio::test]
+    async fn serve_config() {
+        let addr = unused_addr("127.0.0.1").to_string();
+        let config = crate::config::ServerConfig::from_toml_str(&format!(
+            "listen_on = \"{}\"\n[timeouts]\nrcv_ms = 500\n",
+            addr
+        ))
+        .unwrap();
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, config.transport_config().unwrap());
+        config.configure(&mut server).unwrap();
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+
+        let result = tokio::select! {
+            biased;
+            _ = server.serve_config(&config) => unreachable!(),
+            client_output = call_client(&addr, (), make_get_i_rpc()) => client_output,
+        };
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    crate::rpc_client_bundle! {
+        pub struct HelloWorldClient {
+            incr_i: IncrIRpc,
+            massive: MassiveRpc,
+        }
+    }
+
+    #[tokio::test]
+    async fn client_bundle() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.add_rpc(Box::new(MassiveRpc::server()));
+        let handle = Arc::new(server).spawn("127.0.0.1:0").await.unwrap();
+
+        let client = HelloWorldClient::new(handle.local_addr().to_string());
+        client.incr_i(()).await.unwrap();
+        let massive = client.massive(10).await.unwrap();
+        assert_eq!(massive.len(), 10);
+        assert_eq!(state_ref.lock().unwrap().i, 4);
+    }
+
+    #[tokio::test]
+    async fn snapshot_reads() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new_read(
+            HelloWorldRpcName::GetI,
+            Box::new(|state: &HelloWorldState, ()| Ok(state.i)),
+        )));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.enable_snapshots();
+        state_ref.lock().unwrap().i = 10;
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            let stale = get_i.call((), &mut transport).await.unwrap();
+            incr_i.call((), &mut transport).await.unwrap();
+            let fresh = get_i.call((), &mut transport).await.unwrap();
+            (stale, fresh)
+        };
+        let (stale, fresh) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(stale, 3);
+        assert_eq!(fresh, 11);
+    }
+
+    #[tokio::test]
+    async fn versioned_calls() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new_read(
+            HelloWorldRpcName::GetI,
+            Box::new(|state: &HelloWorldState, ()| Ok(state.i)),
+        )));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            let (i, version) = get_i
+                .call_versioned((), None, &mut transport)
+                .await
+                .unwrap();
+            let ((), after_incr) = incr_i
+                .call_versioned((), Some(version), &mut transport)
+                .await
+                .unwrap();
+            let stale = incr_i
+                .call_versioned((), Some(version), &mut transport)
+                .await;
+            (i, version, after_incr, stale)
+        };
+        let (i, version, after_incr, stale) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!((i, version, after_incr), (3, 0, 1));
+        match stale {
+            Err(RpcError::Conflict { current_version }) => assert_eq!(current_version, 1),
+            other => panic!("Expected a Conflict, got {:?}", other),
+        }
+        assert_eq!(server.state_version(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_metadata() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state: &mut HelloWorldState, name: String| {
+                let greeting = match crate::metadata::metadata("locale").as_deref() {
+                    Some(b"fr") => "Bonjour",
+                    _ => "Hello",
+                };
+                Ok(format!("{} {}", greeting, name))
+            }),
+        )));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let hello = RpcClient::new(make_hello_world_rpc());
+            let mut transport = hello.over_stream(client_stream).await.unwrap();
+            let mut metadata = Metadata::new();
+            metadata.insert(String::from("locale"), b"fr".to_vec());
+            let french = hello
+                .call_with_metadata("Foo".into(), metadata, &mut transport)
+                .await
+                .unwrap();
+            let default = hello.call("Foo".into(), &mut transport).await.unwrap();
+            (french, default)
+        };
+        let (french, default) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(french, "Bonjour Foo");
+        assert_eq!(default, "Hello Foo");
+    }
+
+    #[derive(Default)]
+    struct FrenchClient {
+        calls: AtomicUsize,
+    }
+    impl ClientInterceptor<HelloWorldRpcName> for FrenchClient {
+        fn before_call(&self, call: &mut ClientCallInfo<HelloWorldRpcName>) -> RpcResult<()> {
+            call.metadata.insert(String::from("locale"), b"fr".to_vec());
+            Ok(())
+        }
+        fn after_call(&self, _call: &ClientCallInfo<HelloWorldRpcName>, _outcome: &CallOutcome) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+        }
+        fn map_error(&self, call: &ClientCallInfo<HelloWorldRpcName>, error: RpcError) -> RpcError {
+            RpcError::Custom(format!("{} failed: {}", call.name, error))
+        }
+    }
+
+    #[tokio::test]
+    async fn client_interceptors() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state: &mut HelloWorldState, name: String| {
+                let locale = crate::metadata::metadata("locale").unwrap_or_default();
+                Ok(format!("{} {}", String::from_utf8(locale).unwrap(), name))
+            }),
+        )));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let interceptor = Arc::new(FrenchClient::default());
+
+        let client_calls = async {
+            let mut hello = RpcClient::new(make_hello_world_rpc());
+            hello.add_interceptor(interceptor.clone());
+            let mut transport = hello.over_stream(client_stream).await.unwrap();
+            let hello_response = hello.call("Foo".into(), &mut transport).await.unwrap();
+            // Not served
+            let mut massive = RpcClient::new(MassiveRpc::client());
+            massive.add_interceptor(interceptor.clone());
+            let massive_response = massive.call(3, &mut transport).await;
+            (hello_response, massive_response)
+        };
+        let (hello_response, massive_response) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(hello_response, "fr Foo");
+        match massive_response {
+            Err(RpcError::Custom(message)) => assert!(message.starts_with("MassiveRpc failed")),
+            other => panic!("Expected the rewritten error, got {:?}", other),
+        }
+        assert_eq!(interceptor.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn max_message_size() {
+        let small = TransportConfig {
+            max_message_size: Some(1024),
+            ..TransportConfig::default()
+        };
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(MassiveRpc::server()));
+        let mut small_server = RpcServer::new(state_ref, small.clone());
+        small_server.add_rpc(Box::new(MassiveRpc::server()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let (small_client_stream, small_server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            // Refused by the client receiving it
+            let mut small_client = RpcClient::new(MassiveRpc::client());
+            small_client.set_transport_config(small);
+            let mut transport = small_client.over_stream(client_stream).await.unwrap();
+            let received = small_client.call(1000, &mut transport).await;
+            // Refused by the server sending it, which stays open for smaller responses
+            let massive = RpcClient::new(MassiveRpc::client());
+            let mut transport = massive.over_stream(small_client_stream).await.unwrap();
+            let sent = massive.call(1000, &mut transport).await;
+            let smaller = massive.call(10, &mut transport).await.unwrap();
+            (received, sent, smaller.len())
+        };
+        let (received, sent, smaller) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            _ = small_server.serve_stream(small_server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert!(matches!(
+            received,
+            Err(RpcError::MessageTooLarge { limit: 1024 })
+        ));
+        assert_eq!(sent.unwrap_err().code(), StatusCode::ResourceExhausted);
+        assert_eq!(smaller, 10);
+    }
+
+    #[tokio::test]
+    async fn deferred_work() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (done, mut deferred_done) = tokio::sync::mpsc::unbounded_channel();
+        let released = release.clone();
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), ()>::new_deferring(
+                HelloWorldRpcName::IncrI,
+                Box::new(move |state, ()| {
+                    state.i += 1;
+                    let (released, done, i) = (released.clone(), done.clone(), state.i);
+                    let deferred: Deferred = Box::pin(async move {
+                        // Only released once the client has its response
+                        released.notified().await;
+                        done.send(i).unwrap();
+                    });
+                    Ok(((), deferred))
+                }),
+            ),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = incr_i.over_stream(client_stream).await.unwrap();
+            incr_i.call((), &mut transport).await.unwrap();
+            release.notify_one();
+            deferred_done.recv().await
+        };
+        let deferred_i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            deferred_i = client_calls => deferred_i,
+        };
+        assert_eq!(deferred_i, Some(4));
+    }
+
+    #[tokio::test]
+    async fn async_handlers() {
+        use tokio::sync::Notify;
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        let (called, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let (calling, released) = (called.clone(), release.clone());
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), usize>::new_async(
+                HelloWorldRpcName::GetI,
+                Box::new(move |state, ()| {
+                    calling.notify_one();
+                    let (released, i) = (released.clone(), state.i);
+                    let response: AsyncResponse<usize> = Box::pin(async move {
+                        released.notified().await;
+                        Ok(i)
+                    });
+                    Ok(response)
+                }),
+            ),
+        ));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (get_client_stream, get_server_stream) = tokio::io::duplex(8192);
+        let (incr_client_stream, incr_server_stream) = tokio::io::duplex(8192);
+
+        let get_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.over_stream(get_client_stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        // Made while GetI awaits, which doesn't hold the state's lock
+        let incr_call = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = incr_i.over_stream(incr_client_stream).await.unwrap();
+            called.notified().await;
+            incr_i.call((), &mut transport).await.unwrap();
+            // GetI is still being answered
+            assert_eq!(server.snapshot().in_flight, 1);
+            release.notify_one();
+            // Held open until GetI is answered, so this connection's server carries on
+            std::future::pending::<()>().await;
+        };
+        let got_i = tokio::select! {
+            _ = server.serve_stream(get_server_stream) => unreachable!(),
+            _ = server.serve_stream(incr_server_stream) => unreachable!(),
+            _ = incr_call => unreachable!(),
+            got_i = get_call => got_i,
+        };
+        assert_eq!(got_i.unwrap(), 3);
+        assert_eq!(state_ref.lock().unwrap().i, 4);
+    }
+
+    #[tokio::test]
+    async fn async_handler_outcomes() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), usize>::new_async(
+                HelloWorldRpcName::GetI,
+                Box::new(|_state, ()| {
+                    let response: AsyncResponse<usize> = Box::pin(async {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Err(RpcError::Custom(String::from("Failed after awaiting")))
+                    });
+                    Ok(response)
+                }),
+            ),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        let got_i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            got_i = client_call => got_i,
+        };
+        assert!(got_i.is_err());
+        let stats = &server.stats().rpcs[&HelloWorldRpcName::GetI.to_string()];
+        assert_eq!((stats.calls, stats.errors), (1, 1));
+        // Timed until the future made its response, not until the handler returned it
+        assert!(stats.latency.max_micros >= 20_000);
+    }
+
+    #[tokio::test]
+    async fn streamed_responses() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), ()>::new_streaming(
+                HelloWorldRpcName::IncrI,
+                Box::new(|state, ()| {
+                    state.i += 1;
+                    let byte = state.i as u8;
+                    Ok(Box::new(move |mut writer: ResponseWriter| {
+                        Box::pin(async move {
+                            for _ in 0..3 {
+                                writer.send(&[byte; STREAM_CHUNK_SIZE / 2 + 1]).await?;
+                            }
+                            Ok(())
+                        })
+                    }))
+                }),
+            ),
+        ));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = incr_i.over_stream(client_stream).await.unwrap();
+            let chunks = incr_i.call_streaming((), &mut transport).await.unwrap();
+            let streamed = chunks.collect().await.unwrap();
+            // Left unread, to be discarded before the next call
+            let mut chunks = incr_i.call_streaming((), &mut transport).await.unwrap();
+            let first_chunk = chunks.next().await.unwrap().unwrap();
+            drop(chunks);
+            let i = get_i.call((), &mut transport).await.unwrap();
+            (streamed, first_chunk, i)
+        };
+        let (streamed, first_chunk, i) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(streamed, vec![4; 3 * (STREAM_CHUNK_SIZE / 2 + 1)]);
+        assert!(first_chunk.len() <= STREAM_CHUNK_SIZE);
+        assert!(first_chunk.iter().all(|byte| *byte == 5));
+        assert_eq!(i, 5);
+        // The stream left unread was cancelled
+        let incr_i_stats = &server.stats().rpcs[&HelloWorldRpcName::IncrI.to_string()];
+        assert_eq!((incr_i_stats.calls, incr_i_stats.errors), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn streamed_response_outcomes() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), ()>::new_streaming(
+                HelloWorldRpcName::IncrI,
+                Box::new(|_state, ()| {
+                    Ok(Box::new(|mut writer: ResponseWriter| {
+                        Box::pin(async move {
+                            writer.send(b"The first half").await?;
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            Err(RpcError::Custom(String::from("Failed halfway")))
+                        })
+                    }))
+                }),
+            ),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_call = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = incr_i.over_stream(client_stream).await.unwrap();
+            let chunks = incr_i.call_streaming((), &mut transport).await.unwrap();
+            chunks.collect().await
+        };
+        let streamed = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            streamed = client_call => streamed,
+        };
+        assert!(streamed.is_err());
+        let stats = &server.stats().rpcs[&HelloWorldRpcName::IncrI.to_string()];
+        assert_eq!((stats.calls, stats.errors), (1, 1));
+        assert!(stats.latency.max_micros >= 20_000);
+    }
+
+    #[tokio::test]
+    async fn streamed_requests() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref.clone(), TransportConfig::default());
+        // Counts the request's bytes, stopping once it has [limit] of them
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, usize, usize>::new_uploading(
+                HelloWorldRpcName::GetI,
+                Box::new(|state, limit| {
+                    state.i += 1;
+                    Ok(Box::new(move |mut reader: RequestReader| {
+                        Box::pin(async move {
+                            let mut buffer = vec![0; 1000];
+                            let mut counted = 0;
+                            while counted < limit {
+                                match reader.receive(&mut buffer).await? {
+                                    0 => break,
+                                    read => counted += read,
+                                }
+                            }
+                            Ok(counted)
+                        })
+                    }))
+                }),
+            ),
+        ));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let count = RpcClient::new(Rpc::<_, usize, usize>::new(HelloWorldRpcName::GetI));
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = count.over_stream(client_stream).await.unwrap();
+            let body = vec![7; 3 * STREAM_CHUNK_SIZE / 2];
+            let counted = count
+                .call_uploading(body.len() + 1, &body[..], &mut transport)
+                .await
+                .unwrap();
+            // Answered without the rest of the request once the rpc has read enough
+            let counted_some = count
+                .call_uploading(10, &body[..], &mut transport)
+                .await
+                .unwrap();
+            let not_streamed = count.call(10, &mut transport).await.unwrap_err();
+            let not_uploading = incr_i
+                .call_uploading((), &body[..], &mut transport)
+                .await
+                .unwrap_err();
+            incr_i.call((), &mut transport).await.unwrap();
+            (counted, counted_some, not_streamed, not_uploading)
+        };
+        let (counted, counted_some, not_streamed, not_uploading) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(counted, 3 * STREAM_CHUNK_SIZE / 2);
+        assert!((10..=STREAM_CHUNK_SIZE).contains(&counted_some));
+        assert_eq!(not_streamed.code(), StatusCode::FailedPrecondition);
+        assert_eq!(not_uploading.code(), StatusCode::FailedPrecondition);
+        assert_eq!(state_ref.lock().unwrap().i, 6);
+    }
+
+    #[tokio::test]
+    async fn oneway_calls() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let hello = RpcClient::new(make_hello_world_rpc());
+            let mut transport = incr_i.over_stream(client_stream).await.unwrap();
+            for _ in 0..3 {
+                incr_i.call_oneway((), &mut transport).await.unwrap();
+            }
+            // Not registered, which the client never learns of
+            hello
+                .call_oneway("Foo".into(), &mut transport)
+                .await
+                .unwrap();
+            get_i.call((), &mut transport).await.unwrap()
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i,
+        };
+        assert_eq!(i, 6);
+    }
+
+    #[tokio::test]
+    async fn oneway_acknowledged_on_receipt() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        // Each call waits for a permit, which the client only gives out once it has sent both
+        let permits = Arc::new(tokio::sync::Semaphore::new(0));
+        let handler_permits = permits.clone();
+        server.add_rpc(Box::new(RpcImpl::<_, HelloWorldState, (), ()>::new_async(
+            HelloWorldRpcName::IncrI,
+            Box::new(move |state, ()| {
+                state.i += 1;
+                let permits = handler_permits.clone();
+                Ok(Box::pin(async move {
+                    permits.acquire().await.unwrap().forget();
+                    Ok(())
+                }))
+            }),
+        )));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = incr_i.over_stream(client_stream).await.unwrap();
+            incr_i.call_oneway((), &mut transport).await.unwrap();
+            // Reads the first's acknowledgement, which doesn't wait on its call
+            let second = incr_i.call_oneway((), &mut transport);
+            tokio::time::timeout(Duration::from_secs(5), second)
+                .await
+                .expect("The acknowledgement waited on the call")
+                .unwrap();
+            permits.add_permits(2);
+            get_i.call((), &mut transport).await.unwrap()
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i,
+        };
+        assert_eq!(i, 5);
+    }
+
+    #[tokio::test]
+    async fn streamed_request_values() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        // Sums the values of the request, adding them to the state's
+        server.add_rpc(Box::new(
+            RpcImpl::<_, HelloWorldState, (), usize>::new_uploading(
+                HelloWorldRpcName::GetI,
+                Box::new(|state, ()| {
+                    let i = state.i;
+                    Ok(Box::new(move |mut reader: RequestReader| {
+                        Box::pin(async move {
+                            let mut sum = i;
+                            while let Some(value) = reader.receive_value::<usize>().await? {
+                                sum += value;
+                            }
+                            Ok(sum)
+                        })
+                    }))
+                }),
+            ),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let sum = RpcClient::new(Rpc::<_, (), usize>::new(HelloWorldRpcName::GetI));
+            let mut transport = sum.over_stream(client_stream).await.unwrap();
+            // Spanning many chunks
+            let summed = sum
+                .call_uploading_values((), 0..100_000usize, &mut transport)
+                .await
+                .unwrap();
+            let nothing = sum
+                .call_uploading_values((), Vec::<usize>::new(), &mut transport)
+                .await
+                .unwrap();
+            // The rpc fails to read these as numbers
+            let words = [String::from("one"), String::from("two")];
+            let misread = sum
+                .call_uploading_values((), words, &mut transport)
+                .await
+                .unwrap_err();
+            (summed, nothing, misread)
+        };
+        let (summed, nothing, misread) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        assert_eq!(summed, 3 + (0..100_000).sum::<usize>());
+        assert_eq!(nothing, 3);
+        assert!(misread.to_string().contains("usize"), "{}", misread);
+        let stats = &server.stats().rpcs[&HelloWorldRpcName::GetI.to_string()];
+        assert_eq!((stats.calls, stats.errors), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn status_codes() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::<_, HelloWorldState, (), u64>::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|state, ()| {
+                let message = "i is out of range";
+                Err(RpcError::status_with_details(
+                    StatusCode::OutOfRange,
+                    message,
+                    state.i,
+                ))
+            }),
+        )));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            let out_of_range = get_i.call((), &mut transport).await;
+            let not_served = incr_i.call((), &mut transport).await;
+            (out_of_range, not_served)
+        };
+        let (out_of_range, not_served) = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            calls = client_calls => calls,
+        };
+        match out_of_range {
+            Err(e @ RpcError::Remote(_)) => {
+                assert_eq!(e.code(), StatusCode::OutOfRange);
+                assert!(!e.is_retryable());
+                let RpcError::Remote(remote_error) = e else {
+                    unreachable!()
+                };
+                let details = remote_error.details::<u64>(&TransportWireConfig::default());
+                assert_eq!(details.unwrap().unwrap(), 3);
+            }
+            other => panic!("Expected a RemoteError, got {:?}", other),
+        }
+        let not_served = not_served.unwrap_err();
+        assert_eq!(not_served.code(), StatusCode::Unimplemented);
+        match not_served {
+            RpcError::Remote(remote_error) => assert!(remote_error.details.is_none()),
+            other => panic!("Expected a RemoteError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn idempotent_calls() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.enable_idempotency(16, Duration::from_secs(60));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            for key in ["first", "first", "second"] {
+                incr_i
+                    .call_idempotent((), key, &mut transport)
+                    .await
+                    .unwrap();
+            }
+            get_i.call((), &mut transport).await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i.unwrap(),
+        };
+        assert_eq!(i, 5);
+    }
+
+    #[tokio::test]
+    async fn idempotency_per_rpc() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.enable_idempotency(16, Duration::from_secs(60));
+        server.set_rpc_idempotency(HelloWorldRpcName::IncrI, false);
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            let request_id = RequestId::new();
+            for _ in 0..2 {
+                incr_i
+                    .call_idempotent((), &request_id, &mut transport)
+                    .await
+                    .unwrap();
+            }
+            get_i.call((), &mut transport).await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i.unwrap(),
+        };
+        assert_eq!(i, 5);
+    }
+
+    #[tokio::test]
+    async fn state_dump() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server: RpcServer<_, HelloWorldRpcName> =
+            RpcServer::new(state_ref, TransportConfig::default());
+        server.enable_admin("hunter2");
+        server.set_state_dump(|state: &HelloWorldState| state.i);
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let dump_state = RpcClient::new(admin::dump_state::<usize>());
+            let mut transport = dump_state.over_stream(client_stream).await.unwrap();
+            let bad_token = dump_state
+                .call(AdminQuery::new("guess", ()), &mut transport)
+                .await;
+            assert!(bad_token.is_err());
+            dump_state
+                .call(AdminQuery::new("hunter2", ()), &mut transport)
+                .await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i.unwrap(),
+        };
+        assert_eq!(i, 3);
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs() {
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.enable_admin("hunter2");
+        let server = Arc::new(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client_call_task = tokio::spawn(async move {
+            let addr = &addr;
+            let token = "hunter2";
+            call_client(addr, (), make_get_i_rpc()).await.unwrap();
+            let bad_token = call_client(addr, AdminQuery::new("guess", ()), admin::dump_stats());
+            assert!(!bad_token.await.unwrap_err().is_retryable());
+
+            let maintenance = SetMaintenance {
+                rpc: HelloWorldRpcName::GetI.to_string(),
+                enabled: true,
+                retry_after_ms: Some(60_000),
+            };
+            let set_maintenance = AdminQuery::new(token, maintenance);
+            call_client(addr, set_maintenance, admin::set_maintenance())
+                .await
+                .unwrap();
+            let under_maintenance = call_client(addr, (), make_get_i_rpc()).await;
+            match under_maintenance.unwrap_err() {
+                e @ RpcError::Maintenance {
+                    retry_after: Some(retry_after),
+                    ..
+                } => {
+                    assert!(e.is_retryable());
+                    assert!(retry_after <= Duration::from_secs(60));
+                    assert!(retry_after > Duration::from_secs(50));
+                }
+                e => panic!("Expected Maintenance with a retry after, got {}", e),
+            }
+
+            let stats = call_client(addr, AdminQuery::new(token, ()), admin::dump_stats())
+                .await
+                .unwrap();
+            call_client(addr, AdminQuery::new(token, ()), admin::shutdown())
+                .await
+                .unwrap();
+            stats
+        });
+
+        // serve only returns because of the shutdown call
+        server.serve_listener(listener).await.unwrap();
+        let stats = client_call_task.await.unwrap();
+        let get_i_stats = &stats.rpcs[&HelloWorldRpcName::GetI.to_string()];
+        assert_eq!(get_i_stats.calls, 2);
+        assert_eq!(get_i_stats.errors, 1);
+    }
+
+    #[tokio::test]
+    async fn connection_tasks() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let panicking_rpc: RpcImpl<_, HelloWorldState, (), ()> = RpcImpl::new(
+            HelloWorldRpcName::IncrI,
+            Box::new(|_state, ()| panic!("IncrI panicked")),
+        );
+        server.add_rpc(Box::new(panicking_rpc));
+        server.enable_admin("hunter2");
+        let server = Arc::new(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client_call_task = tokio::spawn(async move {
+            let addr = &addr;
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut held_open = get_i.connect(addr).await.unwrap();
+            assert_eq!(get_i.call((), &mut held_open).await.unwrap(), 3);
+            call_client(addr, (), IncrIRpc::client()).await.unwrap_err();
+            call_client(addr, AdminQuery::new("hunter2", ()), admin::drain())
+                .await
+                .unwrap();
+            held_open
+        });
+
+        // serve returns on the drain, leaving the connection held open to carry on
+        let connections = server.serve_listener(listener).await.unwrap();
+        let held_open = client_call_task.await.unwrap();
+        assert!(!connections.is_empty());
+        drop(held_open);
+        let panicked = connections.join().await;
+        assert_eq!(panicked.len(), 1);
+        assert!(panicked[0].is_panic());
+    }
+
+    #[tokio::test]
+    async fn spawned_server() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+
+        let handle = server.spawn("127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().to_string();
+        let i = call_client(&addr, (), make_get_i_rpc()).await.unwrap();
+        assert_eq!(i, 3);
+        handle.shutdown();
+        assert!(handle.join().await.is_empty());
+        assert!(call_client(&addr, (), make_get_i_rpc()).await.is_err());
+    }
+
+    /// Reads a PROXY protocol (v1) header, recording the client addresses it gives
+    struct ProxyProtocolAcceptor {
+        clients: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Acceptor for ProxyProtocolAcceptor {
+        type Transport = TcpTransport;
+
+        async fn accept(
+            &self,
+            mut tcp_stream: tokio::net::TcpStream,
+            _peer: std::net::SocketAddr,
+        ) -> RpcResult<TcpTransport> {
+            use tokio::io::AsyncReadExt;
+            let mut header = Vec::new();
+            while !header.ends_with(b"\r\n") {
+                let byte = tcp_stream.read_u8().await.map_err(|e| {
+                    RpcError::TransportError(TransportError::ReceiveError(e.to_string()))
+                })?;
+                header.push(byte);
+            }
+            let header = String::from_utf8_lossy(&header).into_owned();
+            match header.split(' ').collect::<Vec<_>>()[..] {
+                ["PROXY", _, client, ..] => {
+                    self.clients.lock().unwrap().push(client.to_string());
+                    Ok(TcpTransport::new(tcp_stream))
+                }
+                _ => Err(RpcError::Custom(format!("Bad PROXY header: {}", header))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_acceptor() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let addr = unused_addr("127.0.0.1");
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let acceptor = ProxyProtocolAcceptor {
+            clients: clients.clone(),
+        };
+
+        let client_call = async {
+            use tokio::io::AsyncWriteExt;
+            let mut tcp_stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tcp_stream
+                .write_all(b"PROXY TCP4 198.51.100.7 10.0.0.1 56324 5573\r\n")
+                .await
+                .unwrap();
+            let mut transport =
+                Transport::new(TcpTransport::new(tcp_stream), TransportConfig::default());
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await
+        };
+        let i = tokio::select! {
+            biased;
+            _ = server.serve_with_acceptor(addr, acceptor) => unreachable!(),
+            i = client_call => i.unwrap(),
+        };
+        assert_eq!(i, 3);
+        assert_eq!(*clients.lock().unwrap(), vec![String::from("198.51.100.7")]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_listener() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let path = std::env::temp_dir().join(format!("pirates-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let client_path = path.clone();
+        let client_call_task = tokio::spawn(async move {
+            let unix_stream = tokio::net::UnixStream::connect(client_path).await.unwrap();
+            let mut transport = Transport::new(
+                crate::transport::UnixTransport::new(unix_stream),
+                TransportConfig::default(),
+            );
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await
+        });
+        let i = tokio::select! {
+            _ = server.serve_listener(listener) => unreachable!(),
+            i = client_call_task => i.unwrap().unwrap(),
+        };
+        assert_eq!(i, 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_transport() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_call = async {
+            let mut transport = Transport::new(
+                StreamTransport::new(client_stream),
+                TransportConfig::default(),
+            );
+            let i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            // Closing the stream ends the server's side
+            drop(transport);
+            i
+        };
+        let (served, i) = tokio::join!(server.serve_stream(server_stream), client_call);
+        served.unwrap();
+        assert_eq!(i.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn memory_transport() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (client_end, server_end) = crate::memory::pair();
+
+        let client_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.over_memory(client_end).await.unwrap();
+            RpcClient::new(IncrIRpc::client())
+                .call((), &mut transport)
+                .await
+                .unwrap();
+            let i = get_i.call((), &mut transport).await;
+            // Dropping the client's end ends the server's side
+            drop(transport);
+            i
+        };
+        let (served, i) = tokio::join!(server.serve_memory(server_end), client_call);
+        served.unwrap();
+        assert_eq!(i.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn udp_transport() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let server = Arc::new(server);
+        let probe = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = probe.local_addr().unwrap();
+        drop(probe);
+
+        let client_call = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i
+                .connect_udp(&addr.to_string(), Duration::from_millis(50))
+                .await
+                .unwrap();
+            RpcClient::new(IncrIRpc::client())
+                .call((), &mut transport)
+                .await
+                .unwrap();
+            get_i.call((), &mut transport).await.unwrap()
+        };
+
+        let i = tokio::select! {
+            biased;
+            _ = server.serve_udp(addr) => unreachable!(),
+            client_output = client_call => client_output,
+        };
+        assert_eq!(i, 4);
+    }
+
+    #[tokio::test]
+    async fn detect_wire_format() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let transport_config = TransportConfig {
+            detect_wire_format: true,
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state_ref, transport_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+
+        for wire_config in TransportWireConfig::all() {
+            let (client_stream, server_stream) = tokio::io::duplex(8192);
+            let client_call = async {
+                let mut get_i = RpcClient::new(make_get_i_rpc());
+                get_i.set_transport_config(TransportConfig {
+                    wire_config: wire_config.clone(),
+                    ..Default::default()
+                });
+                let mut transport = get_i.over_stream(client_stream).await.unwrap();
+                get_i.call((), &mut transport).await
+            };
+            let i = tokio::select! {
+                _ = server.serve_stream(server_stream) => unreachable!(),
+                i = client_call => i,
+            };
+            assert_eq!(i.unwrap(), 3, "{}", wire_config.format_name());
+        }
+    }
+
+    #[tokio::test]
+    async fn health_rpc() {
+        use crate::admin::HealthStatus;
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        // Probes needn't authenticate, nor the server enable admin rpcs
+        server.set_authenticator(Box::new(TokenAuthenticator::new()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_call = async {
+            let health = RpcClient::new(admin::health());
+            let mut transport = health.over_stream(client_stream).await.unwrap();
+            health.call((), &mut transport).await.unwrap()
+        };
+        let status = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            status = client_call => status,
+        };
+        assert_eq!(status, HealthStatus::Serving);
+    }
+
+    #[tokio::test]
+    async fn reflection_rpc() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        async fn reflect(
+            server: &RpcServer<HelloWorldState, HelloWorldRpcName>,
+        ) -> RpcResult<admin::Reflection> {
+            let (client_stream, server_stream) = tokio::io::duplex(8192);
+            let client_call = async {
+                let reflection = RpcClient::new(admin::reflection());
+                let mut transport = reflection.over_stream(client_stream).await.unwrap();
+                reflection.call((), &mut transport).await
+            };
+            tokio::select! {
+                _ = server.serve_stream(server_stream) => unreachable!(),
+                reflection = client_call => reflection,
+            }
+        }
+
+        assert!(reflect(&server).await.is_err());
+        server.set_reflection(true);
+        let reflection = reflect(&server).await.unwrap();
+        assert_eq!(reflection.rpcs, vec!["GetI", "HelloWorld"]);
+        assert_eq!(reflection.wire_format, "pickle");
+    }
+
+    #[tokio::test]
+    async fn http_health_check() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.set_http_health_check("/healthz");
+
+        for (request, expected) in [
+            ("GET /healthz HTTP/1.1", "HTTP/1.1 200 OK\r\n"),
+            ("GET /metrics HTTP/1.1", "HTTP/1.1 404 Not Found\r\n"),
+        ] {
+            let (mut client_stream, server_stream) = tokio::io::duplex(8192);
+            let health_check = async {
+                client_stream
+                    .write_all(format!("{}\r\nHost: pirates\r\n\r\n", request).as_bytes())
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                client_stream.read_to_string(&mut response).await.unwrap();
+                response
+            };
+            let (served, response) = tokio::join!(server.serve_stream(server_stream), health_check);
+            served.unwrap();
+            assert!(response.starts_with(expected), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.set_http_metrics("/metrics");
+        let incoming_bytes = serde_pickle::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+        server
+            .call(&incoming_bytes, &HelloWorldRpcName::GetI)
+            .unwrap();
+
+        let (mut client_stream, server_stream) = tokio::io::duplex(8192);
+        let scrape = async {
+            client_stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: pirates\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client_stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let (served, response) = tokio::join!(server.serve_stream(server_stream), scrape);
+        served.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("# TYPE pirates_calls_total counter\n"));
+        assert!(response.contains("\npirates_calls_total{rpc=\"GetI\"} 1\n"));
+        assert!(response.contains("\npirates_call_duration_seconds_count{rpc=\"GetI\"} 1\n"));
+        assert!(response.contains("\npirates_connections 1\n"));
+    }
+
+    #[tokio::test]
+    async fn client_over_stream() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let mut authenticator = TokenAuthenticator::new();
+        authenticator.add_token("s3cret", Identity::new("alice"));
+        server.set_authenticator(Box::new(authenticator));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_call = async {
+            let mut get_i = RpcClient::new(make_get_i_rpc());
+            get_i.set_authenticator(Arc::new(TokenCredentials::new("s3cret")));
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_call => i.unwrap(),
+        };
+        assert_eq!(i, 3);
+    }
+
+    #[tokio::test]
+    async fn connected_client() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+
+        let client_calls = async {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut client = ConnectedClient::new(get_i.over_stream(client_stream).await.unwrap());
+            client.call(IncrIRpc::client(), ()).await.unwrap();
+            client.call(IncrIRpc::client(), ()).await.unwrap();
+            client.call(make_get_i_rpc(), ()).await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_calls => i.unwrap(),
+        };
+        assert_eq!(i, 5);
+    }
+
+    #[cfg(feature = "payload_encryption")]
+    #[tokio::test]
+    async fn payload_encryption() {
+        use crate::payload_encryption::PayloadKeys;
+        let mut keys = PayloadKeys::new();
+        keys.set_rpc_key(HelloWorldRpcName::GetI.to_string(), [7; 32]);
+        let transport_config = TransportConfig {
+            payload_keys: Some(Arc::new(keys)),
+            ..Default::default()
+        };
+
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, transport_config.clone());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+
+        let call = |transport_config: TransportConfig, stream| async move {
+            let mut get_i = RpcClient::new(make_get_i_rpc());
+            get_i.set_transport_config(transport_config);
+            let mut transport = get_i.over_stream(stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = call(transport_config, client_stream) => i.unwrap(),
+        };
+        assert_eq!(i, 3);
+
+        // A client without the key can't call the rpc
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let result = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            result = call(TransportConfig::default(), client_stream) => result,
+        };
+        match result {
+            Err(RpcError::Remote(remote_error)) => {
+                assert!(remote_error.message.contains("sealed"), "{}", remote_error)
+            }
+            other => panic!("Expected a remote error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "response_signing")]
+    #[tokio::test]
+    async fn signed_responses() {
+        use crate::signing::ed25519_dalek::SigningKey;
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifying_key = signing_key.verifying_key();
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let transport_config = TransportConfig {
+            signing_key: Some(Arc::new(signing_key)),
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state_ref, transport_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+
+        let call = |verifying_key| {
+            let (client_stream, server_stream) = tokio::io::duplex(8192);
+            let client_call = async move {
+                let mut get_i = RpcClient::new(make_get_i_rpc());
+                get_i.set_transport_config(TransportConfig {
+                    verifying_key,
+                    ..Default::default()
+                });
+                let mut transport = get_i.over_stream(client_stream).await.unwrap();
+                get_i.call((), &mut transport).await
+            };
+            let served = server.serve_stream(server_stream);
+            async move {
+                tokio::select! {
+                    _ = served => unreachable!(),
+                    result = client_call => result,
+                }
+            }
+        };
+        assert_eq!(call(Some(verifying_key)).await.unwrap(), 3);
+        // Clients not pinning a key still understand signed responses
+        assert_eq!(call(None).await.unwrap(), 3);
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        match call(Some(other_key)).await {
+            Err(RpcError::InvalidSignature(_)) => {}
+            other => panic!("Expected InvalidSignature, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "call_trace")]
+    #[tokio::test]
+    async fn call_trace() {
+        use crate::call_trace::TraceRecorder;
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let recorder = Arc::new(TraceRecorder::new());
+        server.set_trace_recorder(recorder.clone());
+
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let client_call = async move {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            for _ in 0..2 {
+                assert_eq!(get_i.call((), &mut transport).await.unwrap(), 3);
+            }
+        };
+        tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            () = client_call => {}
+        }
+
+        let mut trace = Vec::new();
+        recorder.write_chrome_trace(&mut trace).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let names: Vec<&str> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["name"].as_str().unwrap())
+            .collect();
+        let call = [
+            "GetI",
+            "receive",
+            "lock wait",
+            "deserialize",
+            "handler",
+            "serialize",
+            "send",
+        ];
+        assert_eq!(names, [call, call].concat());
+        recorder.clear();
+        assert!(recorder.is_empty());
+    }
+
+    #[cfg(feature = "explicit_rpc_types")]
+    #[test]
+    fn explicit_rpc_types() {
+        #[derive(Clone, Serialize, Deserialize, crate::RpcType)]
+        struct Page<T> {
+            items: Vec<T>,
+            next: Option<u64>,
+        }
+        // Compiles only as [Page] and its contents are marked as wire types
+        let rpc: Rpc<HelloWorldRpcName, Page<PrecisePayload>, Page<String>> =
+            Rpc::new(HelloWorldRpcName::GetI);
+        assert_eq!(rpc.name, HelloWorldRpcName::GetI);
+    }
+
+    #[cfg(feature = "registration")]
+    pub struct RegisteredGetI {}
+    #[cfg(feature = "registration")]
+    #[crate::rpc_definition(register)]
+    impl RegisteredGetI {
+        fn name() -> HelloWorldRpcName {
+            HelloWorldRpcName::GetI
+        }
+        fn implement(state: &mut HelloWorldState, _query: ()) -> RpcResult<usize> {
+            Ok(state.i)
+        }
+    }
+
+    #[cfg(feature = "registration")]
+    #[tokio::test]
+    async fn registered_rpcs() {
+        let call = |server: RpcServer<HelloWorldState, HelloWorldRpcName>| async move {
+            let (client_stream, server_stream) = tokio::io::duplex(8192);
+            let client_call = async {
+                let get_i = RpcClient::new(RegisteredGetI::client());
+                let mut transport = get_i.over_stream(client_stream).await.unwrap();
+                get_i.call((), &mut transport).await
+            };
+            tokio::select! {
+                _ = server.serve_stream(server_stream) => unreachable!(),
+                i = client_call => i,
+            }
+        };
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server = RpcServer::with_registered_rpcs(state_ref, TransportConfig::default());
+        assert_eq!(call(server).await.unwrap(), 3);
+        // Servers of other state types don't pick it up
+        let other: RpcServer<(), HelloWorldRpcName> =
+            RpcServer::with_registered_rpcs(Arc::new(Mutex::new(())), TransportConfig::default());
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let client_call = async {
+            let get_i = RpcClient::new(RegisteredGetI::client());
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        let result = tokio::select! {
+            _ = other.serve_stream(server_stream) => unreachable!(),
+            result = client_call => result,
+        };
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn strict_registration() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        assert!(server.missing_rpcs().is_empty());
+        server.set_strict_registration(true);
+        use HelloWorldRpcName::*;
+        assert_eq!(
+            server.missing_rpcs(),
+            vec![&IncrI, &MassiveRpc, &PreciseRpc]
+        );
+
+        let server = Arc::new(server);
+        let e = match server.serve("127.0.0.1:0").await {
+            Err(e) => e,
+            Ok(_) => panic!("Expected the server to refuse to serve"),
+        };
+        assert!(
+            e.to_string().contains("IncrI, MassiveRpc, PreciseRpc"),
+            "{}",
+            e
+        );
+
+        let e = match server.spawn("127.0.0.1:0").await {
+            Err(e) => e,
+            Ok(_) => panic!("Expected the server to refuse to start"),
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(
+            e.to_string().contains("IncrI, MassiveRpc, PreciseRpc"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_address_in_use() {
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server: Arc<RpcServer<_, HelloWorldRpcName>> =
+            Arc::new(RpcServer::new(state_ref, TransportConfig::default()));
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let e = match server.serve(taken.local_addr().unwrap()).await {
+            Err(e) => e,
+            Ok(_) => panic!("Expected the server to refuse to serve"),
+        };
+        assert!(matches!(
+            e,
+            RpcError::TransportError(TransportError::ConnectError(_))
+        ));
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derived_rpc_name_list() {
+        #[derive(Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize, crate::RpcNameList)]
+        enum Names {
+            First,
+            Second,
+        }
+        impl Display for Names {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{:?}", self)
+            }
+        }
+        impl RpcName for Names {}
+        assert_eq!(Names::all(), vec![Names::First, Names::Second]);
+    }
+
+    #[tokio::test]
+    async fn legacy_wire_protocol() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let transport_config = TransportConfig {
+            compat: TransportCompat::V0,
+            ..Default::default()
+        };
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, transport_config.clone());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let client_call = async {
+            let mut get_i = RpcClient::new(make_get_i_rpc());
+            get_i.set_transport_config(transport_config);
+            let mut transport = get_i.over_stream(client_stream).await.unwrap();
+            get_i.call((), &mut transport).await
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = client_call => i.unwrap(),
+        };
+        assert_eq!(i, 3);
+
+        // A query as pirates 0.1 sent it, answered with the bare result
+        #[derive(Serialize)]
+        struct Package<'a> {
+            name_bytes: &'a [u8],
+            query_bytes: &'a [u8],
+        }
+        let wire_config = TransportWireConfig::default();
+        let name_bytes = wire_config.serialize(&HelloWorldRpcName::GetI).unwrap();
+        let query_bytes = wire_config.serialize(&()).unwrap();
+        let package = wire_config
+            .serialize(&Package {
+                name_bytes: &name_bytes,
+                query_bytes: &query_bytes,
+            })
+            .unwrap();
+        let (mut client_stream, server_stream) = tokio::io::duplex(8192);
+        let legacy_call = async {
+            client_stream.write_all(&package).await.unwrap();
+            let mut response = vec![0; 1024];
+            let n = client_stream.read(&mut response).await.unwrap();
+            wire_config.deserialize::<usize>(&response[..n]).unwrap()
+        };
+        let i = tokio::select! {
+            _ = server.serve_stream(server_stream) => unreachable!(),
+            i = legacy_call => i,
+        };
+        assert_eq!(i, 3);
+    }
+
+    #[cfg(feature = "transport_native_tls")]
+    #[tokio::test]
+    async fn native_tls_server() {
+        use crate::native_tls::{native_tls, NativeTlsClientConfig, NativeTlsListener};
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("pirates.test")]).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(
+            certified.cert.pem().as_bytes(),
+            certified.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let root = native_tls::Certificate::from_pem(certified.cert.pem().as_bytes()).unwrap();
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(root)
+            .build()
+            .unwrap();
+        let mut client_tls = NativeTlsClientConfig::new(connector);
+        client_tls.server_name = Some(String::from("pirates.test"));
+
+        let state_ref = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let server = Arc::new(server);
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_listener.local_addr().unwrap().to_string();
+        let listener = NativeTlsListener::new(tcp_listener, acceptor);
+
+        let client_call_task = tokio::spawn(async move {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = get_i.connect_native_tls(&addr, &client_tls).await.unwrap();
+            get_i.call((), &mut transport).await.unwrap()
+        });
+        let i = tokio::select! {
+            _ = server.serve_listener(listener) => unreachable!(),
+            client_output = client_call_task => client_output.unwrap(),
+        };
+        assert_eq!(i, 3);
+    }
 }
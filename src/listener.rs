@@ -0,0 +1,83 @@
+use crate::error::RpcResult;
+use crate::transport::{InternalTransport, TcpTransport, TransportConfig};
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+/// The accept side of a server, serving with [crate::RpcServer::serve_listener]. Implemented for
+/// TCP by [tokio::net::TcpListener], Unix domain sockets by [tokio::net::UnixListener] and TLS
+/// by [crate::tls::TlsListener]
+#[async_trait]
+pub trait Listener: Send + Sync + 'static {
+    /// A connection as accepted, before any handshake
+    type Stream: Send + 'static;
+    type Transport: InternalTransport + Send + 'static;
+
+    /// Poll for the next connection, along with the address of its peer if it is on an IP
+    /// network. Peers without one are let through any [crate::IpFilter]
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Self::Stream, Option<SocketAddr>)>>;
+
+    /// Set up the transport to serve a connection over, run on the connection's own task so slow
+    /// handshakes don't hold up accepting others
+    async fn transport(
+        &self,
+        stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<Self::Transport>;
+}
+
+#[async_trait]
+impl Listener for tokio::net::TcpListener {
+    type Stream = tokio::net::TcpStream;
+    type Transport = TcpTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        tokio::net::TcpListener::poll_accept(self, cx)
+            .map_ok(|(tcp_stream, from)| (tcp_stream, Some(from)))
+    }
+
+    async fn transport(
+        &self,
+        tcp_stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<TcpTransport> {
+        let mut tcp_transport = TcpTransport::new(tcp_stream);
+        if let Some(keepalive) = transport_config.keepalive {
+            tcp_transport.set_keepalive(keepalive)?;
+        }
+        tcp_transport.set_write_timeout(transport_config.write_timeout);
+        Ok(tcp_transport)
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    type Transport = crate::transport::UnixTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        tokio::net::UnixListener::poll_accept(self, cx)
+            .map_ok(|(unix_stream, _)| (unix_stream, None))
+    }
+
+    async fn transport(
+        &self,
+        unix_stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<Self::Transport> {
+        let mut unix_transport = crate::transport::UnixTransport::new(unix_stream);
+        unix_transport.set_write_timeout(transport_config.write_timeout);
+        Ok(unix_transport)
+    }
+}
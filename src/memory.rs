@@ -0,0 +1,76 @@
+//! An in-process transport, for testing clients and servers together without binding sockets.
+//! [pair] makes the two ends of a connection, one served with [crate::RpcServer::serve_memory]
+//! and the other called over with [crate::RpcClient::over_memory]. Each message is handed over
+//! whole on a channel, so unlike a byte stream there's no minimum read size to mind
+//!
+//! ```rust,ignore
+//! let (client_end, server_end) = pirates::memory::pair();
+//! tokio::spawn(async move { server.serve_memory(server_end).await });
+//! let mut transport = get_names.over_memory(client_end).await?;
+//! let names = get_names.call((), &mut transport).await?;
+//! ```
+use crate::transport::{InternalTransport, TransportError};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How many messages one end may send ahead of the other receiving them, before sends wait
+const CHANNEL_CAPACITY: usize = 16;
+
+/// One end of an in-process connection, see [crate::memory]. The connection closes once either
+/// end is dropped
+pub struct MemoryTransport {
+    sender: mpsc::Sender<OwnedBytes>,
+    receiver: mpsc::Receiver<OwnedBytes>,
+}
+
+/// The two ends of a new in-process connection, each receiving what the other sends
+pub fn pair() -> (MemoryTransport, MemoryTransport) {
+    let (a_sender, b_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let (b_sender, a_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    (
+        MemoryTransport {
+            sender: a_sender,
+            receiver: a_receiver,
+        },
+        MemoryTransport {
+            sender: b_sender,
+            receiver: b_receiver,
+        },
+    )
+}
+
+#[async_trait]
+impl InternalTransport for MemoryTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.sender
+            .send(b.to_vec())
+            .await
+            .map_err(|_| TransportError::SendError(String::from("The other end was dropped")))
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    /// Nothing once the other end was dropped, as a stream reads at its end
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        let received = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receiver.recv())
+                .await
+                .map_err(|_| TransportError::ReceiveTimeout(timeout))?,
+            None => self.receiver.recv().await,
+        };
+        Ok(received.unwrap_or_default())
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        self.sender.is_closed()
+    }
+}
@@ -0,0 +1,27 @@
+use crate::OwnedBytes;
+use std::collections::BTreeMap;
+
+/// Values sent alongside a query, by key, for what applies to any rpc rather than belonging in
+/// each one's query type: auth tokens, trace ids, locale hints. Sent with
+/// [crate::RpcClient::call_with_metadata], seen by interceptors as [crate::CallInfo::metadata]
+/// and by handlers with [metadata]
+pub type Metadata = BTreeMap<String, OwnedBytes>;
+
+thread_local! {
+    /// The metadata of the call being handled on this thread, see [metadata]
+    static METADATA: std::cell::RefCell<Metadata> = const { std::cell::RefCell::new(BTreeMap::new()) };
+}
+
+/// The value the call being handled was sent with under [key], if any. Only set while a handler
+/// runs under the state's lock, as with [crate::caller]
+pub fn metadata(key: &str) -> Option<OwnedBytes> {
+    METADATA.with(|metadata| metadata.borrow().get(key).cloned())
+}
+
+/// Run [f], a call sent with [metadata], on this thread
+pub(crate) fn with_metadata<T>(metadata: &Metadata, f: impl FnOnce() -> T) -> T {
+    let previous = METADATA.with(|current| current.replace(metadata.clone()));
+    let t = f();
+    METADATA.with(|current| *current.borrow_mut() = previous);
+    t
+}
@@ -0,0 +1,118 @@
+//! TLS connections using the platform's TLS stack through [tokio_native_tls]: SChannel on
+//! Windows, Secure Transport on macOS and OpenSSL elsewhere (Enable the "transport_native_tls"
+//! feature). For deployments whose policy mandates the OS TLS stack and certificate stores, in
+//! place of the rustls backend in [crate::tls].
+//!
+//! A server serves a [NativeTlsListener] with [crate::RpcServer::serve_listener] and clients
+//! connect with [crate::RpcClient::connect_native_tls]
+//!
+//! ```rust,ignore
+//! let identity = native_tls::Identity::from_pkcs12(&pkcs12, "password")?;
+//! let acceptor = native_tls::TlsAcceptor::new(identity)?;
+//! let listener = NativeTlsListener::new(TcpListener::bind(addr).await?, acceptor);
+//! server.serve_listener(listener).await?;
+//! ```
+use crate::error::{RpcError, RpcResult};
+use crate::listener::Listener;
+use crate::transport::{self, StreamTransport, TransportConfig, TransportError};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+pub use tokio_native_tls::native_tls;
+
+/// [crate::InternalTransport] over a native TLS connection
+pub type NativeTlsTransport = StreamTransport<tokio_native_tls::TlsStream<TcpStream>>;
+
+/// Client side settings for [crate::RpcClient::connect_native_tls]
+#[derive(Clone)]
+pub struct NativeTlsClientConfig {
+    /// Certificate verification and client identity settings
+    pub connector: tokio_native_tls::TlsConnector,
+    /// Name to send with SNI and verify the server's certificate against, the host connected to
+    /// if [None]
+    pub server_name: Option<String>,
+}
+
+impl NativeTlsClientConfig {
+    pub fn new(connector: native_tls::TlsConnector) -> Self {
+        Self {
+            connector: connector.into(),
+            server_name: None,
+        }
+    }
+}
+
+/// A [Listener] accepting native TLS connections. Handshakes must complete within
+/// [TransportConfig::connect_timeout]
+pub struct NativeTlsListener {
+    listener: TcpListener,
+    acceptor: tokio_native_tls::TlsAcceptor,
+}
+
+impl NativeTlsListener {
+    pub fn new(listener: TcpListener, acceptor: native_tls::TlsAcceptor) -> Self {
+        Self {
+            listener,
+            acceptor: acceptor.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for NativeTlsListener {
+    type Stream = TcpStream;
+    type Transport = NativeTlsTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        Listener::poll_accept(&self.listener, cx)
+    }
+
+    async fn transport(
+        &self,
+        tcp_stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<NativeTlsTransport> {
+        if let Some(keepalive) = transport_config.keepalive {
+            transport::set_keepalive(&tcp_stream, keepalive)?;
+        }
+        let accept_fut = self.acceptor.accept(tcp_stream);
+        let tls_stream = match transport_config.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, accept_fut)
+                .await
+                .map_err(|_| handshake_error(format!("Timed out after {:?}", connect_timeout)))?,
+            None => accept_fut.await,
+        }
+        .map_err(|e| handshake_error(e.to_string()))?;
+        let mut native_tls_transport = StreamTransport::new(tls_stream);
+        native_tls_transport.set_write_timeout(transport_config.write_timeout);
+        Ok(native_tls_transport)
+    }
+}
+
+/// Run the client side of the handshake over [tcp_stream], connected to [addr]
+pub(crate) async fn connect(
+    tls_config: &NativeTlsClientConfig,
+    addr: &str,
+    tcp_stream: TcpStream,
+) -> std::io::Result<tokio_native_tls::TlsStream<TcpStream>> {
+    let server_name = match &tls_config.server_name {
+        Some(server_name) => server_name.as_str(),
+        None => crate::resolver::host_of(addr),
+    };
+    tls_config
+        .connector
+        .connect(server_name, tcp_stream)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+fn handshake_error(message: String) -> RpcError {
+    RpcError::TransportError(TransportError::ConnectError(format!(
+        "TLS handshake failed: {}",
+        message
+    )))
+}
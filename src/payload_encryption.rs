@@ -0,0 +1,172 @@
+//! End-to-end encryption of query and response payloads, independent of the transport, so they
+//! stay confidential when frames are relayed through brokers or proxies that can't be trusted
+//! (Enable the "payload_encryption" feature).
+//!
+//! Both sides set the same [PayloadKeys] as [crate::TransportConfig::payload_keys]. Each payload
+//! is sealed with XChaCha20-Poly1305 under its rpc's key and a random nonce, from which a key of
+//! its own is derived for every message. Rpc names, errors and other frames are left in the
+//! clear, and a payload sealed for one rpc or direction can't be passed off as another's
+//!
+//! ```rust,ignore
+//! let mut keys = PayloadKeys::new();
+//! keys.set_default_key(shared_key);
+//! keys.set_rpc_key(RpcId::AddName.to_string(), add_name_key);
+//! transport_config.payload_keys = Some(Arc::new(keys));
+//! ```
+use crate::transport::{CodecError, TransportError};
+use crate::{Bytes, OwnedBytes};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::collections::HashMap;
+
+const NONCE_LEN: usize = 24;
+
+/// Which way a payload travels, bound into its seal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Query,
+    Response,
+}
+
+/// Keys payloads are sealed with, by rpc. Rpcs without a key of their own use the default key,
+/// and are sent unencrypted if there is none
+#[derive(Clone, Default)]
+pub struct PayloadKeys {
+    default: Option<XChaCha20Poly1305>,
+    rpcs: HashMap<String, XChaCha20Poly1305>,
+}
+
+impl std::fmt::Debug for PayloadKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadKeys")
+            .field("default", &self.default.is_some())
+            .field("rpcs", &self.rpcs.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl PayloadKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_default_key(&mut self, key: [u8; 32]) {
+        self.default = Some(XChaCha20Poly1305::new(&key.into()));
+    }
+
+    /// Seal payloads of the rpc whose [std::fmt::Display] form is [rpc] with [key]
+    pub fn set_rpc_key(&mut self, rpc: impl Into<String>, key: [u8; 32]) {
+        self.rpcs
+            .insert(rpc.into(), XChaCha20Poly1305::new(&key.into()));
+    }
+
+    /// Whether payloads of [rpc] are sealed
+    pub(crate) fn seals(&self, rpc: &str) -> bool {
+        self.cipher(rpc).is_some()
+    }
+
+    /// Seal [payload] of [rpc], travelling in [direction], or [None] if [rpc] has no key
+    pub(crate) fn seal(
+        &self,
+        rpc: &str,
+        direction: Direction,
+        payload: Bytes,
+    ) -> Option<Result<OwnedBytes, TransportError>> {
+        let cipher = self.cipher(rpc)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(rpc, direction);
+        let sealed = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &aad,
+                },
+            )
+            .map(|ciphertext| [nonce.as_slice(), &ciphertext].concat())
+            .map_err(|_| seal_error("Failed to seal payload"));
+        Some(sealed)
+    }
+
+    /// Open [sealed] payload of [rpc], travelling in [direction], or [None] if [rpc] has no key
+    pub(crate) fn open(
+        &self,
+        rpc: &str,
+        direction: Direction,
+        sealed: Bytes,
+    ) -> Option<Result<OwnedBytes, TransportError>> {
+        let cipher = self.cipher(rpc)?;
+        if sealed.len() < NONCE_LEN {
+            return Some(Err(seal_error("Payload is not sealed")));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = associated_data(rpc, direction);
+        let opened = cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| seal_error("Payload failed to open, is it sealed with the same key?"));
+        Some(opened)
+    }
+
+    fn cipher(&self, rpc: &str) -> Option<&XChaCha20Poly1305> {
+        self.rpcs.get(rpc).or(self.default.as_ref())
+    }
+}
+
+fn associated_data(rpc: &str, direction: Direction) -> OwnedBytes {
+    let direction: &[u8] = match direction {
+        Direction::Query => b"query:",
+        Direction::Response => b"response:",
+    };
+    [direction, rpc.as_bytes()].concat()
+}
+
+fn seal_error(message: &str) -> TransportError {
+    TransportError::DeserialiseError(CodecError {
+        format: "payload_encryption",
+        type_name: "sealed payload",
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let mut keys = PayloadKeys::new();
+        keys.set_rpc_key("GetI", [7; 32]);
+        assert!(keys.seal("IncrI", Direction::Query, b"plain").is_none());
+
+        let sealed = keys
+            .seal("GetI", Direction::Query, b"secret")
+            .unwrap()
+            .unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        let opened = keys.open("GetI", Direction::Query, &sealed).unwrap();
+        assert_eq!(opened.unwrap(), b"secret");
+        // Bound to its direction and key
+        assert!(keys
+            .open("GetI", Direction::Response, &sealed)
+            .unwrap()
+            .is_err());
+        let mut other_keys = PayloadKeys::new();
+        other_keys.set_default_key([8; 32]);
+        assert!(other_keys
+            .open("GetI", Direction::Query, &sealed)
+            .unwrap()
+            .is_err());
+        // Every message is sealed differently
+        let resealed = keys
+            .seal("GetI", Direction::Query, b"secret")
+            .unwrap()
+            .unwrap();
+        assert_ne!(sealed, resealed);
+    }
+}
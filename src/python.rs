@@ -0,0 +1,364 @@
+//! Python bindings for calling a server's rpcs from Python (Enable the "python" feature).
+//!
+//! [PythonBindings] generates, from the [RpcSchema]s of a set of rpcs, a Python module declaring
+//! their query and response types and a `Client` with a method per rpc. The client speaks the
+//! default [crate::TransportWireConfig::Pickle] wire format with Python's own `pickle`, so needs
+//! nothing beyond the standard library. Structs are `TypedDict`s, enums unions of variant names
+//! and single key dicts, as `pickle` sees them
+//!
+//! ```rust,ignore
+//! let mut bindings = PythonBindings::new();
+//! bindings.add_rpc(&rpcs::AddName::client())?;
+//! bindings.add_rpc(&rpcs::GetNames::client())?;
+//! std::fs::write("tools/names_client.py", bindings.generate())?;
+//! ```
+//!
+//! and then in Python:
+//!
+//! ```python,ignore
+//! with Client("127.0.0.1", 5959) as client:
+//!     client.add_name("Ferris")
+//!     names = client.get_names(None)
+//! ```
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::RpcResult;
+use crate::schema::RpcSchema;
+use crate::transport::TransportWireConfig;
+use serde_reflection::{ContainerFormat, Format, Named, VariantFormat};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// An rpc to generate a client method for
+struct BoundRpc {
+    /// The rpc's name as the server expects it on the wire, pickled
+    name_bytes: Vec<u8>,
+    schema: RpcSchema,
+}
+
+/// Generates Python bindings for the rpcs added to it, see [crate::python]
+#[derive(Default)]
+pub struct PythonBindings {
+    rpcs: BTreeMap<String, BoundRpc>,
+}
+
+impl PythonBindings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate a client method for [rpc], named after the [std::fmt::Display] form of its name
+    pub fn add_rpc<Name, Q, R>(&mut self, rpc: &Rpc<Name, Q, R>) -> RpcResult<()>
+    where
+        Name: RpcName,
+        Q: RpcType,
+        R: RpcType,
+    {
+        let name_bytes = TransportWireConfig::default().serialize(&rpc.name)?;
+        let schema = RpcSchema::of::<Q, R>()?;
+        self.rpcs
+            .insert(rpc.name.to_string(), BoundRpc { name_bytes, schema });
+        Ok(())
+    }
+
+    /// The Python module for the rpcs added so far
+    pub fn generate(&self) -> String {
+        let mut types = BTreeMap::new();
+        for rpc in self.rpcs.values() {
+            types.extend(rpc.schema.registry.iter());
+        }
+        let mut out = String::from(PRELUDE);
+        for (name, container) in types {
+            declare_type(&mut out, name, container);
+        }
+        out.push_str(RUNTIME);
+        for (name, rpc) in &self.rpcs {
+            let _ = write!(
+                out,
+                "\n    def {}(self, query: {}) -> {}:\n        return self._call({}, query)\n",
+                method_name(name),
+                type_of(&rpc.schema.query),
+                type_of(&rpc.schema.response),
+                bytes_literal(&rpc.name_bytes),
+            );
+        }
+        out
+    }
+}
+
+/// Declare the named type [name] as `pickle` loads it. References between types are quoted, so
+/// they may come in any order and be recursive
+fn declare_type(out: &mut String, name: &str, container: &ContainerFormat) {
+    let declared = match container {
+        ContainerFormat::UnitStruct => String::from("None"),
+        ContainerFormat::NewTypeStruct(format) => type_of(format),
+        ContainerFormat::TupleStruct(formats) => tuple_of(formats),
+        ContainerFormat::Struct(fields) => typed_dict(name, fields),
+        ContainerFormat::Enum(variants) if variants.is_empty() => String::from("NoReturn"),
+        ContainerFormat::Enum(variants) => {
+            let variants: Vec<String> = variants
+                .values()
+                .map(|variant| {
+                    let content = match &variant.value {
+                        VariantFormat::Unit => {
+                            return format!("Literal[{}]", string_literal(&variant.name))
+                        }
+                        VariantFormat::NewType(format) => type_of(format),
+                        // Tuple variants are pickled as lists
+                        VariantFormat::Tuple(_) => String::from("List[Any]"),
+                        VariantFormat::Struct(fields) => {
+                            let fields_name = format!("{}_{}", name, variant.name);
+                            let _ = writeln!(
+                                out,
+                                "{} = {}\n",
+                                fields_name,
+                                typed_dict(&fields_name, fields)
+                            );
+                            string_literal(&fields_name)
+                        }
+                        VariantFormat::Variable(_) => String::from("Any"),
+                    };
+                    format!(
+                        "Dict[Literal[{}], {}]",
+                        string_literal(&variant.name),
+                        content
+                    )
+                })
+                .collect();
+            format!("Union[{}]", variants.join(", "))
+        }
+    };
+    let _ = writeln!(out, "{} = {}\n", name, declared);
+}
+
+fn type_of(format: &Format) -> String {
+    match format {
+        Format::Unit => String::from("None"),
+        Format::Bool => String::from("bool"),
+        Format::I8
+        | Format::I16
+        | Format::I32
+        | Format::I64
+        | Format::I128
+        | Format::U8
+        | Format::U16
+        | Format::U32
+        | Format::U64
+        | Format::U128 => String::from("int"),
+        Format::F32 | Format::F64 => String::from("float"),
+        Format::Char | Format::Str => String::from("str"),
+        Format::Bytes => String::from("bytes"),
+        Format::TypeName(name) => string_literal(name),
+        Format::Option(format) => format!("Optional[{}]", type_of(format)),
+        Format::Seq(format) => format!("List[{}]", type_of(format)),
+        Format::TupleArray { content, .. } => format!("Tuple[{}, ...]", type_of(content)),
+        Format::Map { key, value } => format!("Dict[{}, {}]", type_of(key), type_of(value)),
+        Format::Tuple(formats) => tuple_of(formats),
+        Format::Variable(_) => String::from("Any"),
+    }
+}
+
+fn tuple_of(formats: &[Format]) -> String {
+    if formats.is_empty() {
+        return String::from("Tuple[()]");
+    }
+    let formats: Vec<String> = formats.iter().map(type_of).collect();
+    format!("Tuple[{}]", formats.join(", "))
+}
+
+fn typed_dict(name: &str, fields: &[Named<Format>]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| format!("{}: {}", string_literal(&field.name), type_of(&field.value)))
+        .collect();
+    format!(
+        "TypedDict({}, {{{}}})",
+        string_literal(name),
+        fields.join(", ")
+    )
+}
+
+/// The client method for the rpc displayed as [name], e.g. "get_names" for "GetNames"
+fn method_name(name: &str) -> String {
+    let mut method = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 && !method.ends_with('_') {
+                method.push('_');
+            }
+            method.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            method.push(c);
+        } else if !method.ends_with('_') {
+            method.push('_');
+        }
+    }
+    if method.is_empty() || method.starts_with(|c: char| c.is_ascii_digit()) {
+        method.insert(0, '_');
+    }
+    method
+}
+
+fn string_literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(literal, "\\U{:08x}", c as u32);
+            }
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn bytes_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("b\"");
+    for byte in bytes {
+        let _ = write!(literal, "\\x{:02x}", byte);
+    }
+    literal.push('"');
+    literal
+}
+
+const PRELUDE: &str = r#"# Generated by pirates, do not edit
+import pickle
+import socket
+from typing import Any, Dict, List, Literal, NoReturn, Optional, Tuple, TypedDict, Union
+
+"#;
+
+/// Everything a generated client needs besides its types and methods
+const RUNTIME: &str = r#"
+class RpcError(Exception):
+    """An error raised by the server, or by the client failing to make a call. Its code is one
+    of pirates::error::StatusCode, with details if the server sent any"""
+
+    def __init__(self, message: str, retryable: bool, code: str = "Unknown", details: Any = None):
+        super().__init__(message)
+        self.retryable = retryable
+        self.code = code
+        self.details = details
+
+
+class Client:
+    """A connection to a pirates server, making one call at a time"""
+
+    def __init__(self, host: str, port: int, timeout: Optional[float] = None):
+        self._socket = socket.create_connection((host, port), timeout)
+
+    def close(self) -> None:
+        self._socket.close()
+
+    def __enter__(self) -> "Client":
+        return self
+
+    def __exit__(self, *exc_info: Any) -> None:
+        self.close()
+
+    def _receive(self) -> Any:
+        received = b""
+        while True:
+            chunk = self._socket.recv(65536)
+            if not chunk:
+                raise RpcError("Connection closed", True, "Unavailable")
+            received += chunk
+            try:
+                return pickle.loads(received)
+            except (EOFError, pickle.UnpicklingError):
+                # Only part of the reply has arrived
+                continue
+
+    def _call(self, name: bytes, query: Any) -> Any:
+        frame = {
+            "Query": {
+                "name_bytes": name,
+                "query_bytes": pickle.dumps(query),
+                "reserved": False,
+                "type_hash": None,
+            }
+        }
+        self._socket.sendall(pickle.dumps(frame))
+        reply = self._receive()
+        # Signatures are left unchecked, see pirates::signing
+        while isinstance(reply, dict) and "Signed" in reply:
+            reply = pickle.loads(bytes(reply["Signed"]["frame"]))
+        if isinstance(reply, dict):
+            if "Ok" in reply:
+                return pickle.loads(bytes(reply["Ok"]))
+            if "Err" in reply:
+                err = reply["Err"]
+                details = err.get("details")
+                if details is not None:
+                    details = pickle.loads(bytes(details))
+                raise RpcError(
+                    err["message"], err["retryable"], err.get("code", "Unknown"), details
+                )
+            if "DryRun" in reply:
+                rpc = reply["DryRun"]["rpc"]
+                message = f"Server is in dry-run mode, {rpc} was not run"
+                raise RpcError(message, False, "FailedPrecondition")
+            if "Maintenance" in reply:
+                rpc = reply["Maintenance"]["rpc"]
+                retry_after_ms = reply["Maintenance"]["retry_after_ms"]
+                message = f"{rpc} is under maintenance, retry after {retry_after_ms}ms"
+                raise RpcError(message, True, "Unavailable")
+        raise RpcError(f"Unexpected reply: {reply!r}", False, "Internal")
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::HelloWorldRpcName;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "explicit_rpc_types", derive(crate::RpcType))]
+    struct Page {
+        names: Vec<String>,
+        next: Option<Box<Page>>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "explicit_rpc_types", derive(crate::RpcType))]
+    enum Filter {
+        All,
+        Prefix(String),
+        Range { from: u32, to: u32 },
+    }
+
+    #[test]
+    fn generates_types_and_client() {
+        let mut bindings = PythonBindings::new();
+        bindings
+            .add_rpc(&Rpc::<_, Filter, Page>::new(HelloWorldRpcName::GetI))
+            .unwrap();
+        bindings
+            .add_rpc(&Rpc::<_, (), usize>::new(HelloWorldRpcName::IncrI))
+            .unwrap();
+        let generated = bindings.generate();
+        assert!(generated.contains(
+            "Filter_Range = TypedDict(\"Filter_Range\", {\"from\": int, \"to\": int})\n\n\
+             Filter = Union[Literal[\"All\"], Dict[Literal[\"Prefix\"], str], \
+             Dict[Literal[\"Range\"], \"Filter_Range\"]]\n"
+        ));
+        assert!(generated.contains(
+            "Page = TypedDict(\"Page\", {\"names\": List[str], \"next\": Optional[\"Page\"]})\n"
+        ));
+        assert!(generated.contains(
+            "    def get_i(self, query: \"Filter\") -> \"Page\":\n        return self._call(b\"\\x80\\x03"
+        ));
+        assert!(generated.contains("    def incr_i(self, query: None) -> int:\n"));
+    }
+
+    #[test]
+    fn method_names() {
+        assert_eq!(method_name("GetNames"), "get_names");
+        assert_eq!(method_name("admin.stats"), "admin_stats");
+        assert_eq!(method_name("2fa"), "_2fa");
+    }
+}
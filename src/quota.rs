@@ -0,0 +1,270 @@
+//! Per client quotas for multi-tenant servers: limits on the calls and bytes each [Identity] may
+//! use per minute or day, enforced by a [QuotaInterceptor] added with
+//! [crate::RpcServer::add_interceptor]. Usage is counted in fixed windows kept in a
+//! [QuotaStore], in memory by default, or shared between servers with a custom store
+//!
+//! ```rust,ignore
+//! let mut quotas = QuotaInterceptor::new(Quota {
+//!     calls_per_minute: Some(60),
+//!     ..Default::default()
+//! });
+//! quotas.set_quota("partner", Quota { calls_per_day: Some(100_000), ..Default::default() });
+//! server.add_interceptor(Box::new(quotas));
+//! ```
+use crate::auth::Identity;
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::interceptor::{CallInfo, CallOutcome, Interceptor};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Limits on what one identity may use, [None] being unlimited. Bytes count both queries and
+/// responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub calls_per_minute: Option<u64>,
+    pub calls_per_day: Option<u64>,
+    pub bytes_per_day: Option<u64>,
+}
+
+/// What a quota limits, and over how long
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QuotaMetric {
+    CallsPerMinute,
+    CallsPerDay,
+    BytesPerDay,
+}
+
+impl QuotaMetric {
+    fn window_secs(&self) -> u64 {
+        match self {
+            Self::CallsPerMinute => 60,
+            Self::CallsPerDay | Self::BytesPerDay => 24 * 60 * 60,
+        }
+    }
+
+    fn limit(&self, quota: &Quota) -> Option<u64> {
+        match self {
+            Self::CallsPerMinute => quota.calls_per_minute,
+            Self::CallsPerDay => quota.calls_per_day,
+            Self::BytesPerDay => quota.bytes_per_day,
+        }
+    }
+}
+
+/// One counter in a [QuotaStore]: an identity's usage of [metric] in the [window]th window of
+/// the metric's length since the unix epoch
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QuotaKey {
+    pub identity: String,
+    pub metric: QuotaMetric,
+    pub window: u64,
+}
+
+/// Where a [QuotaInterceptor] counts usage
+pub trait QuotaStore: Send + Sync {
+    /// Add [amount] to the counter for [key], returning its new total
+    fn add(&self, key: &QuotaKey, amount: u64) -> u64;
+    /// The counter for [key], without changing it
+    fn get(&self, key: &QuotaKey) -> u64 {
+        self.add(key, 0)
+    }
+}
+
+/// The default [QuotaStore], counting in memory and forgetting counters of past windows
+#[derive(Default)]
+pub struct InMemoryQuotaStore {
+    counters: Mutex<HashMap<QuotaKey, u64>>,
+}
+
+impl QuotaStore for InMemoryQuotaStore {
+    fn add(&self, key: &QuotaKey, amount: u64) -> u64 {
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(key) {
+            // A new window has begun for [key], so drop that which it replaces
+            counters.retain(|existing, _| {
+                existing.identity != key.identity
+                    || existing.metric != key.metric
+                    || existing.window >= key.window
+            });
+        }
+        let total = counters.entry(key.clone()).or_default();
+        *total += amount;
+        *total
+    }
+}
+
+/// Enforces [Quota]s, rejecting calls with [RpcError::QuotaExceeded] once an identity has used
+/// up any of its limits. Clients that haven't authenticated share one allowance
+pub struct QuotaInterceptor {
+    default: Quota,
+    quotas: HashMap<String, Quota>,
+    store: Box<dyn QuotaStore>,
+}
+
+impl QuotaInterceptor {
+    /// Apply [default] to every identity without its own quota
+    pub fn new(default: Quota) -> Self {
+        Self {
+            default,
+            quotas: HashMap::new(),
+            store: Box::new(InMemoryQuotaStore::default()),
+        }
+    }
+
+    /// Apply [quota] to the identity named [identity], rather than the default
+    pub fn set_quota(&mut self, identity: impl Into<String>, quota: Quota) {
+        self.quotas.insert(identity.into(), quota);
+    }
+
+    /// Count usage in [store], rather than in memory
+    pub fn set_store(&mut self, store: Box<dyn QuotaStore>) {
+        self.store = store;
+    }
+
+    fn quota_of(&self, identity: &str) -> &Quota {
+        self.quotas.get(identity).unwrap_or(&self.default)
+    }
+
+    /// The counter of [metric] for [identity] in the current window, and its limit. [None] if
+    /// the identity's use of [metric] is unlimited
+    fn counter(&self, identity: &str, metric: QuotaMetric) -> Option<(QuotaKey, u64)> {
+        let limit = metric.limit(self.quota_of(identity))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let key = QuotaKey {
+            identity: identity.to_string(),
+            metric,
+            window: now / metric.window_secs(),
+        };
+        Some((key, limit))
+    }
+
+    /// Count [usage] against [identity], or none of it and an error if any would exceed its
+    /// limit, so a call rejected by one limit doesn't use up the others
+    fn use_quotas(&self, identity: &str, usage: &[(QuotaMetric, u64)]) -> RpcResult<()> {
+        let counted: Vec<((QuotaKey, u64), u64)> = usage
+            .iter()
+            .filter_map(|(metric, amount)| Some((self.counter(identity, *metric)?, *amount)))
+            .collect();
+        for ((key, limit), amount) in &counted {
+            if self.store.get(key).saturating_add(*amount) > *limit {
+                return Err(RpcError::QuotaExceeded {
+                    identity: identity.to_string(),
+                    reason: format!("{:?} limit of {}", key.metric, limit),
+                });
+            }
+        }
+        for ((key, _), amount) in &counted {
+            self.store.add(key, *amount);
+        }
+        Ok(())
+    }
+}
+
+fn identity_name(identity: Option<&Identity>) -> &str {
+    identity.map_or("", |identity| &identity.name)
+}
+
+impl<Name: RpcName> Interceptor<Name> for QuotaInterceptor {
+    fn before_call(&self, call: &CallInfo<Name>) -> RpcResult<()> {
+        self.use_quotas(
+            identity_name(call.identity),
+            &[
+                (QuotaMetric::CallsPerMinute, 1),
+                (QuotaMetric::CallsPerDay, 1),
+                (QuotaMetric::BytesPerDay, call.query_bytes.len() as u64),
+            ],
+        )
+    }
+
+    fn after_call(&self, call: &CallInfo<Name>, outcome: &CallOutcome) {
+        if let Ok(response_bytes) = outcome.result {
+            // Already sent, so counted whatever the limit, against the identity's next calls
+            let identity = identity_name(call.identity);
+            if let Some((key, _)) = self.counter(identity, QuotaMetric::BytesPerDay) {
+                self.store.add(&key, response_bytes.len() as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+    use crate::tests::HelloWorldRpcName;
+
+    #[test]
+    fn quotas_per_identity() {
+        let mut quotas = QuotaInterceptor::new(Quota {
+            calls_per_minute: Some(2),
+            ..Default::default()
+        });
+        quotas.set_quota(
+            "partner",
+            Quota {
+                bytes_per_day: Some(10),
+                ..Default::default()
+            },
+        );
+        let (alice, partner) = (Identity::new("alice"), Identity::new("partner"));
+        let call = |identity, query_bytes| {
+            quotas.before_call(&CallInfo {
+                name: &HelloWorldRpcName::GetI,
+                query_bytes,
+                identity,
+                metadata: &Metadata::new(),
+            })
+        };
+
+        assert!(call(Some(&alice), &[]).is_ok());
+        assert!(call(Some(&alice), &[]).is_ok());
+        match call(Some(&alice), &[]) {
+            Err(RpcError::QuotaExceeded { identity, .. }) => assert_eq!(identity, "alice"),
+            other => panic!("Expected QuotaExceeded, got {:?}", other),
+        }
+        // Unauthenticated clients have an allowance of their own
+        assert!(call(None, &[]).is_ok());
+
+        // The partner has no call limit, only a byte limit
+        for _ in 0..3 {
+            assert!(call(Some(&partner), &[0; 3]).is_ok());
+        }
+        assert!(call(Some(&partner), &[0; 3]).is_err());
+    }
+
+    #[test]
+    fn rejected_calls_use_no_quota() {
+        let quotas = QuotaInterceptor::new(Quota {
+            calls_per_minute: Some(2),
+            bytes_per_day: Some(4),
+            ..Default::default()
+        });
+        let alice = Identity::new("alice");
+        let call = |query_bytes| {
+            quotas.before_call(&CallInfo {
+                name: &HelloWorldRpcName::GetI,
+                query_bytes,
+                identity: Some(&alice),
+                metadata: &Metadata::new(),
+            })
+        };
+
+        assert!(call(&[0; 3]).is_ok());
+        for _ in 0..3 {
+            match call(&[0; 3]) {
+                Err(RpcError::QuotaExceeded { reason, .. }) => {
+                    assert!(reason.contains("BytesPerDay"), "{}", reason)
+                }
+                other => panic!("Expected QuotaExceeded, got {:?}", other),
+            }
+        }
+        // Those rejected for their bytes didn't count as calls, so there's one call left
+        assert!(call(&[0; 1]).is_ok());
+        assert!(call(&[]).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Rpcs registered where they're defined, with `#[pirates::rpc_definition(register)]`, and
+//! served by every [crate::RpcServer::with_registered_rpcs] of their state and name types, so
+//! none can be forgotten in a list of [crate::RpcServer::add_rpc] calls (Enable the
+//! "registration" feature)
+//!
+//! ```rust,ignore
+//! #[pirates::rpc_definition(register)]
+//! impl AddName {
+//!     fn name() -> RpcId {
+//!         RpcId::AddName
+//!     }
+//!     fn implement(state: &mut ServerState, query: String) -> RpcResult<()> {
+//!         state.names.push(query);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let server = RpcServer::with_registered_rpcs(state_ref, TransportConfig::default());
+//! ```
+use crate::core::{RpcName, RpcType, StoredRpc};
+use crate::RpcDefinition;
+use std::any::Any;
+
+/// An rpc registered with `#[pirates::rpc_definition(register)]`, of any state and name types
+pub struct RpcRegistration {
+    make: fn() -> Box<dyn Any>,
+}
+
+impl RpcRegistration {
+    pub const fn of<D, Name, State, Q, R>() -> Self
+    where
+        D: RpcDefinition<Name, State, Q, R>,
+        Name: RpcName + Send + Sync + 'static,
+        State: 'static,
+        Q: RpcType + Send + Sync,
+        R: RpcType + Send + Sync,
+    {
+        Self {
+            make: make_stored::<D, Name, State, Q, R>,
+        }
+    }
+}
+
+inventory::collect!(RpcRegistration);
+
+fn make_stored<D, Name, State, Q, R>() -> Box<dyn Any>
+where
+    D: RpcDefinition<Name, State, Q, R>,
+    Name: RpcName + Send + Sync + 'static,
+    State: 'static,
+    Q: RpcType + Send + Sync,
+    R: RpcType + Send + Sync,
+{
+    let stored: Box<dyn StoredRpc<State, Name> + Send + Sync> = Box::new(D::server());
+    Box::new(stored)
+}
+
+/// Every registered rpc of state [State] and name [Name]
+pub(crate) fn registered_rpcs<State: 'static, Name: RpcName + 'static>(
+) -> impl Iterator<Item = Box<dyn StoredRpc<State, Name> + Send + Sync>> {
+    inventory::iter::<RpcRegistration>
+        .into_iter()
+        .filter_map(|registration| {
+            (registration.make)()
+                .downcast::<Box<dyn StoredRpc<State, Name> + Send + Sync>>()
+                .ok()
+                .map(|stored| *stored)
+        })
+}
@@ -0,0 +1,188 @@
+use crate::tasks;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+/// Delay before starting an attempt to connect to the next address while the previous one is
+/// still pending, as recommended by RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves the address an [crate::RpcClient] connects to (e.g. "example.com:5959") into the
+/// socket addresses to try, see [crate::RpcClient::set_resolver]
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, addr: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// The default [Resolver], using the system's resolver through [tokio::net::lookup_host]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host(addr).await?.collect())
+    }
+}
+
+/// Resolve [addr] with [resolver] and connect to one of its addresses "happy eyeballs" style:
+/// addresses are tried alternating between IPv6 and IPv4, each attempt starting [attempt_delay]
+/// after the last or as soon as it fails, and the first to connect wins. So an address that
+/// black-holes connections (typically of a family with no route) doesn't stall the others
+pub(crate) async fn connect(
+    resolver: &dyn Resolver,
+    addr: &str,
+    attempt_delay: Duration,
+) -> io::Result<TcpStream> {
+    let addrs = resolver.resolve(addr).await?;
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolved to no addresses", addr),
+        ));
+    }
+    let mut pending = interleave_families(addrs).into_iter();
+    // Dropped on return, aborting any attempts still pending
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(next) => {
+                    spawn_attempt(&mut attempts, next);
+                }
+                None => return Err(last_error.expect("Every attempt failed with an error")),
+            }
+        }
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    last_error = Some(e);
+                    if let Some(next) = pending.next() {
+                        spawn_attempt(&mut attempts, next);
+                    }
+                }
+                Err(e) => last_error = Some(io::Error::other(e)),
+            },
+            _ = tokio::time::sleep(attempt_delay), if pending.len() > 0 => {
+                if let Some(next) = pending.next() {
+                    spawn_attempt(&mut attempts, next);
+                }
+            }
+        }
+    }
+}
+
+fn spawn_attempt(attempts: &mut JoinSet<io::Result<TcpStream>>, addr: SocketAddr) {
+    let name = format!("pirates connect {}", addr);
+    tasks::spawn_in(attempts, &name, attempt(addr));
+}
+
+async fn attempt(addr: SocketAddr) -> io::Result<TcpStream> {
+    TcpStream::connect(addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+}
+
+/// Order [addrs] alternating between address families, starting with the family of the first,
+/// and otherwise keeping the resolver's order
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(first) => first.is_ipv6(),
+        None => return addrs,
+    };
+    let count = addrs.len();
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut ordered = Vec::with_capacity(count);
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// The host part of [addr], e.g. "example.com" of "example.com:5959" or "::1" of "[::1]:5959"
+#[cfg_attr(
+    not(any(feature = "transport_tls", feature = "transport_native_tls")),
+    allow(dead_code)
+)]
+pub(crate) fn host_of(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    #[async_trait]
+    impl Resolver for StaticResolver {
+        async fn resolve(&self, _addr: &str) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::1]:2",
+            "[::1]:3",
+            "127.0.0.1:4",
+            "127.0.0.1:5",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let ports: Vec<u16> = interleave_families(addrs)
+            .iter()
+            .map(|addr| addr.port())
+            .collect();
+        assert_eq!(ports, vec![1, 4, 2, 5, 3]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_later_addresses() {
+        // Nothing listens on the first address, so its attempt is refused
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening_addr = listener.local_addr().unwrap();
+
+        let resolver = StaticResolver(vec![refused_addr, listening_addr]);
+        let stream = connect(&resolver, "pirates.test:5959", Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening_addr);
+    }
+
+    #[test]
+    fn hosts_of_addresses() {
+        assert_eq!(host_of("example.com:5959"), "example.com");
+        assert_eq!(host_of("127.0.0.1:5959"), "127.0.0.1");
+        assert_eq!(host_of("[::1]:5959"), "::1");
+        assert_eq!(host_of("example.com"), "example.com");
+    }
+
+    #[tokio::test]
+    async fn no_addresses() {
+        let resolver = StaticResolver(vec![]);
+        let e = connect(&resolver, "pirates.test:5959", CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,5 +1,69 @@
-use crate::core::RpcType;
+#[cfg(not(feature = "explicit_rpc_types"))]
+mod blanket {
+    use crate::core::RpcType;
+    use serde::{Deserialize, Serialize};
 
-use serde::{Deserialize, Serialize};
+    impl<T> RpcType for T where T: Clone + Serialize + for<'de> Deserialize<'de> + 'static {}
+}
 
-impl<T> RpcType for T where T: Clone + Serialize + for<'de> Deserialize<'de> + 'static {}
+/// With "explicit_rpc_types", only types marked with `#[derive(pirates::RpcType)]` are wire
+/// types, besides these std ones and those composed of wire types
+#[cfg(feature = "explicit_rpc_types")]
+mod explicit {
+    use crate::core::RpcType;
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+    use std::hash::Hash;
+
+    macro_rules! rpc_types {
+        ($($ty:ty),* $(,)?) => {
+            $(impl RpcType for $ty {})*
+        };
+    }
+    rpc_types!(
+        (),
+        bool,
+        char,
+        u8,
+        u16,
+        u32,
+        u64,
+        u128,
+        usize,
+        i8,
+        i16,
+        i32,
+        i64,
+        i128,
+        isize,
+        f32,
+        f64,
+        String,
+        std::time::Duration,
+        std::time::SystemTime,
+        std::net::IpAddr,
+        std::net::SocketAddr,
+        std::path::PathBuf,
+    );
+
+    impl<T: RpcType> RpcType for Option<T> {}
+    impl<T: RpcType> RpcType for Box<T> {}
+    impl<T: RpcType> RpcType for Vec<T> {}
+    impl<T: RpcType, E: RpcType> RpcType for Result<T, E> {}
+    impl<T: RpcType + Ord> RpcType for BTreeSet<T> {}
+    impl<T: RpcType + Eq + Hash> RpcType for HashSet<T> {}
+    impl<K: RpcType + Ord, V: RpcType> RpcType for BTreeMap<K, V> {}
+    impl<K: RpcType + Eq + Hash, V: RpcType> RpcType for HashMap<K, V> {}
+    impl<A: RpcType, B: RpcType> RpcType for (A, B) {}
+    impl<A: RpcType, B: RpcType, C: RpcType> RpcType for (A, B, C) {}
+    impl<A: RpcType, B: RpcType, C: RpcType, D: RpcType> RpcType for (A, B, C, D) {}
+
+    // Those of pirates' own rpcs
+    impl<T: RpcType> RpcType for crate::admin::AdminQuery<T> {}
+    impl RpcType for crate::admin::SetMaintenance {}
+    impl RpcType for crate::admin::ConfigUpdate {}
+    impl RpcType for crate::admin::HealthStatus {}
+    impl RpcType for crate::admin::Reflection {}
+    impl RpcType for crate::stats::ServerStats {}
+    #[cfg(feature = "schema")]
+    impl RpcType for crate::schema::RpcSchema {}
+}
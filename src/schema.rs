@@ -0,0 +1,96 @@
+//! Type schemas of rpc queries and responses, so a client can check it agrees with a server
+//! about them before sending any traffic (Enable the "schema" feature).
+//!
+//! A server with the feature serves the schemas of all its rpcs through the reserved
+//! [AdminRpcName::Schema] rpc, which needs no admin token. A client checks against it with
+//! [crate::RpcClient::check_schema], or on every connect after [crate::RpcClient::set_schema_check]
+//!
+//! ```rust,ignore
+//! let mut rpc_client = RpcClient::new(rpcs::AddName::client());
+//! rpc_client.set_schema_check(true);
+//! // Fails with RpcError::SchemaMismatch if the server's AddName takes something other than a String
+//! let mut transport = rpc_client.connect(addr).await?;
+//! ```
+use crate::admin::AdminRpcName;
+use crate::core::{Rpc, RpcType};
+use crate::error::{RpcError, RpcResult};
+use serde::{Deserialize, Serialize};
+use serde_reflection::{Format, FormatHolder, Registry, Tracer, TracerConfig};
+use std::collections::HashMap;
+
+/// Structure of an rpc's query and response types, traced from their [Deserialize] impls.
+/// Named types (structs and enums) appear by name in [query] and [response] and are described in
+/// [registry]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcSchema {
+    pub query: Format,
+    pub response: Format,
+    pub registry: Registry,
+}
+
+impl RpcSchema {
+    /// Trace the schema of query type [Q] and response type [R]
+    pub fn of<Q: RpcType, R: RpcType>() -> RpcResult<Self> {
+        let trace_error = |e: serde_reflection::Error| {
+            RpcError::Custom(format!(
+                "Could not trace schema of ({}, {}): {}",
+                std::any::type_name::<Q>(),
+                std::any::type_name::<R>(),
+                e
+            ))
+        };
+        let mut tracer = Tracer::new(TracerConfig::default());
+        let (mut query, _) = tracer.trace_simple_type::<Q>().map_err(trace_error)?;
+        let (mut response, _) = tracer.trace_simple_type::<R>().map_err(trace_error)?;
+        query.normalize().map_err(trace_error)?;
+        response.normalize().map_err(trace_error)?;
+        let registry = tracer.registry().map_err(trace_error)?;
+        Ok(Self {
+            query,
+            response,
+            registry,
+        })
+    }
+
+    /// Check this (local) schema against [server]'s, describing the first difference found
+    pub fn check_compatible(&self, server: &Self) -> Result<(), String> {
+        if self.query != server.query {
+            return Err(format!(
+                "query is {:?} locally but {:?} on the server",
+                self.query, server.query
+            ));
+        }
+        if self.response != server.response {
+            return Err(format!(
+                "response is {:?} locally but {:?} on the server",
+                self.response, server.response
+            ));
+        }
+        for (name, local) in &self.registry {
+            match server.registry.get(name) {
+                Some(remote) if remote == local => {}
+                Some(remote) => {
+                    return Err(format!(
+                        "type {} is {:?} locally but {:?} on the server",
+                        name, local, remote
+                    ));
+                }
+                None => return Err(format!("type {} is not used on the server", name)),
+            }
+        }
+        if let Some(name) = server
+            .registry
+            .keys()
+            .find(|name| !self.registry.contains_key(*name))
+        {
+            return Err(format!("type {} is only used on the server", name));
+        }
+        Ok(())
+    }
+}
+
+/// Fetch the [RpcSchema] of every rpc the server serves, keyed by the [std::fmt::Display] form
+/// of their names
+pub fn schema() -> Rpc<AdminRpcName, (), HashMap<String, RpcSchema>> {
+    Rpc::new(AdminRpcName::Schema)
+}
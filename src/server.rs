@@ -1,19 +1,161 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::core::{RpcName, StoredRpc};
-use crate::error::{RpcError, RpcResult};
-use crate::transport::{TcpTransport, Transport, TransportConfig};
-use crate::OwnedBytes;
+use crate::admin::{
+    AdminQuery, AdminRpcName, ConfigUpdate, HealthStatus, Reflection, SetMaintenance,
+};
+use crate::auth::{self, Authenticator, Identity, NoAuth};
+use crate::call_trace::{self, Phase};
+use crate::core::{AsyncResponse, Deferred, RpcName, RpcNameList, StoredRpc};
+use crate::error::{RpcError, RpcResult, StatusCode};
+use crate::idempotency::{
+    IdempotencyKey, IdempotencyStore, InMemoryIdempotencyStore, RememberedResponse,
+};
+use crate::interceptor::{CallInfo, CallOutcome, Interceptor};
+use crate::ip_filter::IpFilter;
+use crate::listener::Listener;
+use crate::memory::MemoryTransport;
+use crate::metadata;
+use crate::snapshot::Snapshots;
+use crate::spans::{self, RpcSpan};
+use crate::state_lock::{PoisonPolicy, StateGuard, StateLock};
+use crate::stats::{Gauges, ServerSnapshot, ServerStats};
+use crate::streaming::{RequestStream, ResponseStream};
+use crate::tasks;
+use crate::transport::{
+    HttpRequest, InternalTransport, QueryOptions, ReceivedFrame, ReceivedName, ReceivedQuery,
+    StreamTransport, Transport, TransportConfig, TransportError, TransportWireConfig, VersionCheck,
+};
+use crate::{Bytes, OwnedBytes};
 use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
 
-pub struct RpcServer<S, Name>
+/// How a stop requested through the admin RPCs should proceed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StopMode {
+    Drain,
+    Shutdown,
+}
+
+/// The settings of a server that can change while it serves, see [ServerHandle::update_config]
+struct LiveSettings {
+    /// Cloned for each connection as it's opened
+    transport_config: RwLock<TransportConfig>,
+    state_lock: StateLock,
+}
+
+impl LiveSettings {
+    fn transport_config(&self) -> TransportConfig {
+        self.transport_config.read().unwrap().clone()
+    }
+
+    /// Apply [update], or none of it if any of it is invalid
+    fn update(&self, update: &ConfigUpdate) -> RpcResult<()> {
+        let invalid = |message: String| RpcError::status(StatusCode::InvalidArgument, message);
+        if update.rcv_timeout_ms == Some(0) {
+            return Err(invalid(String::from("rcv_timeout_ms can't be 0")));
+        }
+        let max_log_level = update
+            .max_log_level
+            .as_deref()
+            .map(|level| {
+                level
+                    .parse::<log::LevelFilter>()
+                    .map_err(|_| invalid(format!("Not a log level: {}", level)))
+            })
+            .transpose()?;
+        info!("Updating the server's config: {:?}", update);
+        // 0 disables an optional duration
+        let optional = |ms: u64| Some(Duration::from_millis(ms)).filter(|d| !d.is_zero());
+        {
+            let mut transport_config = self.transport_config.write().unwrap();
+            if let Some(rcv_ms) = update.rcv_timeout_ms {
+                transport_config.rcv_timeout = Duration::from_millis(rcv_ms);
+            }
+            if let Some(idle_ms) = update.idle_timeout_ms {
+                transport_config.idle_timeout = optional(idle_ms);
+            }
+            if let Some(write_ms) = update.write_timeout_ms {
+                transport_config.write_timeout = optional(write_ms);
+            }
+        }
+        if let Some(lock_ms) = update.lock_timeout_ms {
+            self.state_lock.set_timeout(optional(lock_ms));
+        }
+        if let Some(max_log_level) = max_log_level {
+            log::set_max_level(max_log_level);
+        }
+        Ok(())
+    }
+}
+
+/// What a call leaves besides its response
+#[derive(Default)]
+pub(crate) struct Called {
+    /// The state version the call left, if its [QueryOptions] asked for it
+    pub(crate) version: Option<u64>,
+    /// Work to spawn once the response has been sent, see [crate::RpcImpl::new_deferring]
+    pub(crate) deferred: Option<Deferred>,
+    /// Resolves to the response, see [crate::RpcImpl::new_async]
+    pub(crate) pending: Option<AsyncResponse<OwnedBytes>>,
+    /// Sent in place of the response, see [crate::RpcImpl::new_streaming]
+    pub(crate) stream: Option<ResponseStream>,
+    /// Waiting on the rest of the request, see [crate::RpcImpl::new_uploading]
+    pub(crate) upload: Option<RequestStream>,
+}
+
+/// A call dispatched and not yet recorded, see [RpcServer::call_into]
+pub(crate) struct CallRecord {
+    span: RpcSpan,
+    start: Instant,
+}
+
+/// Serialises the state for [crate::admin::dump_state] into a response buffer
+type StateDump<S> =
+    Box<dyn Fn(&S, &TransportWireConfig, &mut OwnedBytes) -> RpcResult<()> + Send + Sync>;
+
+/// Serves rpcs on [S], the server state. Rpcs are stored as [Stored], boxed trait objects by
+/// default, or the enum generated by [crate::static_rpcs] when made with [RpcServer::new_static]
+pub struct RpcServer<S, Name, Stored = Box<dyn StoredRpc<S, Name> + Send + Sync>>
 where
     Name: RpcName,
 {
     state: Arc<Mutex<S>>,
-    rpcs: HashMap<Name, Box<dyn StoredRpc<S, Name>>>,
-    transport_config: TransportConfig,
+    rpcs: HashMap<Name, Stored>,
+    settings: Arc<LiveSettings>,
+    interceptors: Vec<Box<dyn Interceptor<Name>>>,
+    admin_token: Option<String>,
+    state_dump: Option<StateDump<S>>,
+    snapshots: Option<Snapshots<S>>,
+    poison_policy: PoisonPolicy<S>,
+    /// Only changed while holding the state's lock, see [Self::state_version]
+    state_version: AtomicU64,
+    idempotency: Option<Box<dyn IdempotencyStore>>,
+    /// Rpcs whose calls aren't remembered, even with an idempotency key
+    not_idempotent: HashSet<Name>,
+    authenticator: Option<Box<dyn Authenticator>>,
+    ip_filter: Option<IpFilter>,
+    accept_backoff: AcceptBackoff,
+    dry_run: bool,
+    reflection: bool,
+    health_check_path: Option<String>,
+    metrics_path: Option<String>,
+    read_only: HashSet<Name>,
+    /// Names that must all be implemented before the server will serve
+    required_rpcs: Vec<Name>,
+    #[cfg(feature = "call_trace")]
+    trace_recorder: Option<Arc<call_trace::TraceRecorder>>,
+    stats: Mutex<ServerStats>,
+    gauges: Gauges,
+    /// By rpc name, when each is expected back if known
+    maintenance: Mutex<HashMap<String, Option<Instant>>>,
+    stop: Arc<watch::Sender<Option<StopMode>>>,
 }
 
 impl<S, Name> RpcServer<S, Name>
@@ -21,69 +163,1602 @@ where
     Name: RpcName,
 {
     pub fn new(state: Arc<Mutex<S>>, transport_config: TransportConfig) -> Self {
+        Self::new_static(state, transport_config)
+    }
+
+    /// [RpcServer::new], serving every rpc registered for its state and name types, see
+    /// [crate::registry]
+    #[cfg(feature = "registration")]
+    pub fn with_registered_rpcs(state: Arc<Mutex<S>>, transport_config: TransportConfig) -> Self
+    where
+        S: 'static,
+        Name: 'static,
+    {
+        let mut server = Self::new(state, transport_config);
+        for stored_rpc in crate::registry::registered_rpcs() {
+            server.add_rpc(stored_rpc);
+        }
+        server
+    }
+}
+
+impl<S, Name, Stored> RpcServer<S, Name, Stored>
+where
+    Name: RpcName,
+    Stored: StoredRpc<S, Name>,
+{
+    /// [RpcServer::new] for rpcs dispatched statically, without boxing, see [crate::static_rpcs]
+    pub fn new_static(state: Arc<Mutex<S>>, transport_config: TransportConfig) -> Self {
         Self {
             state,
             rpcs: HashMap::new(),
-            transport_config,
+            settings: Arc::new(LiveSettings {
+                transport_config: RwLock::new(transport_config),
+                state_lock: StateLock::default(),
+            }),
+            interceptors: Vec::new(),
+            admin_token: None,
+            state_dump: None,
+            snapshots: None,
+            poison_policy: PoisonPolicy::default(),
+            state_version: AtomicU64::new(0),
+            idempotency: None,
+            not_idempotent: HashSet::new(),
+            authenticator: None,
+            ip_filter: None,
+            accept_backoff: AcceptBackoff::default(),
+            dry_run: false,
+            reflection: false,
+            health_check_path: None,
+            metrics_path: None,
+            read_only: HashSet::new(),
+            required_rpcs: Vec::new(),
+            #[cfg(feature = "call_trace")]
+            trace_recorder: None,
+            stats: Mutex::new(ServerStats::default()),
+            gauges: Gauges::default(),
+            maintenance: Mutex::new(HashMap::new()),
+            stop: Arc::new(watch::Sender::new(None)),
         }
     }
 
-    pub fn add_rpc(&mut self, stored_rpc: Box<dyn StoredRpc<S, Name>>) {
+    pub fn add_rpc(&mut self, stored_rpc: Stored) {
         let name = stored_rpc.rpc_name();
         self.rpcs.insert(name, stored_rpc);
     }
 
+    /// Run [interceptor] around every call to this server's rpcs. See [Interceptor]
+    pub fn add_interceptor(&mut self, interceptor: Box<dyn Interceptor<Name>>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Require clients to authenticate with [authenticator] before calling any rpcs, see
+    /// [Authenticator]
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// In dry-run mode, queries are deserialised to check they are valid but rpcs are not called,
+    /// other than those added with [Self::add_read_only]. Clients see [RpcError::DryRun] in place
+    /// of a response. Useful for checking wire compatibility against production traffic
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = enabled;
+    }
+
+    /// Answer [crate::admin::reflection] calls, listing the server's rpcs to any client, see
+    /// [Reflection]. Disabled by default
+    pub fn set_reflection(&mut self, enabled: bool) {
+        self.reflection = enabled;
+    }
+
+    /// Answer HTTP GET and HEAD requests for [path] (e.g. "/healthz") on the rpc port with 200 OK
+    /// while serving and 503 once stopping, so load balancers' HTTP health checks can target it.
+    /// Other paths get 404, and without a path HTTP requests are refused by closing the connection
+    pub fn set_http_health_check(&mut self, path: impl Into<String>) {
+        self.health_check_path = Some(path.into());
+    }
+
+    /// Answer HTTP GET and HEAD requests for [path] (e.g. "/metrics") on the rpc port with
+    /// [Self::metrics], for Prometheus to scrape. Other paths are answered as for
+    /// [Self::set_http_health_check]
+    pub fn set_http_metrics(&mut self, path: impl Into<String>) {
+        self.metrics_path = Some(path.into());
+    }
+
+    /// In strict mode, the server refuses to serve while any of [Name]'s rpcs has no
+    /// implementation, catching a name added without its rpc at startup. See [Self::missing_rpcs]
+    pub fn set_strict_registration(&mut self, enabled: bool)
+    where
+        Name: RpcNameList,
+    {
+        self.required_rpcs = if enabled { Name::all() } else { Vec::new() };
+    }
+
+    /// Names required by [Self::set_strict_registration] that have no implementation yet
+    pub fn missing_rpcs(&self) -> Vec<&Name> {
+        self.required_rpcs
+            .iter()
+            .filter(|name| !self.rpcs.contains_key(name))
+            .collect()
+    }
+
+    /// Record the timing of every call's phases with [recorder], see [crate::call_trace]
+    #[cfg(feature = "call_trace")]
+    pub fn set_trace_recorder(&mut self, recorder: Arc<call_trace::TraceRecorder>) {
+        self.trace_recorder = Some(recorder);
+    }
+
+    fn check_registration(&self) -> RpcResult<()> {
+        let missing = self.missing_rpcs();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let missing: Vec<String> = missing.iter().map(|name| name.to_string()).collect();
+        Err(RpcError::Custom(format!(
+            "Refusing to serve, rpcs have no implementation: {}",
+            missing.join(", ")
+        )))
+    }
+
+    /// Declare [rpc] free of side effects, so it is still called in dry-run mode
+    pub fn add_read_only(&mut self, rpc: Name) {
+        self.read_only.insert(rpc);
+    }
+
+    /// Only accept connections from peers allowed by [ip_filter], closing others before reading
+    /// anything from them
+    pub fn set_ip_filter(&mut self, ip_filter: IpFilter) {
+        self.ip_filter = Some(ip_filter);
+    }
+
+    /// Back off accepting connections as [accept_backoff] sets out after errors accepting them
+    pub fn set_accept_backoff(&mut self, accept_backoff: AcceptBackoff) {
+        self.accept_backoff = accept_backoff;
+    }
+
+    /// Serve the reserved admin RPCs in [crate::admin], accepting only calls carrying [token]
+    pub fn enable_admin(&mut self, token: impl Into<String>) {
+        self.admin_token = Some(token.into());
+    }
+
+    /// Serve [crate::admin::dump_state] with the whole state as it is serialised. Like the other
+    /// admin rpcs it needs the token given to [Self::enable_admin], and a dump is as sensitive
+    /// as the state itself
+    pub fn enable_state_dump(&mut self)
+    where
+        S: Serialize,
+    {
+        self.state_dump = Some(Box::new(|state, wire_config, response_buffer| {
+            Ok(wire_config.serialize_into(state, response_buffer)?)
+        }));
+    }
+
+    /// [Self::enable_state_dump] with what [dump] makes of the state, e.g. to leave out secrets
+    /// or where the state isn't [Serialize] itself
+    pub fn set_state_dump<T: Serialize>(&mut self, dump: impl Fn(&S) -> T + Send + Sync + 'static) {
+        self.state_dump = Some(Box::new(move |state, wire_config, response_buffer| {
+            Ok(wire_config.serialize_into(&dump(state), response_buffer)?)
+        }));
+    }
+
+    /// Serve rpcs made with [crate::RpcImpl::new_read] from a snapshot of the state, so heavy
+    /// read traffic never waits on the state's lock. Every other rpc takes the lock as usual and
+    /// then publishes a copy of the state as the new snapshot, so reads may be slightly stale and
+    /// each write costs a clone. Changes made to the state outside the server's rpcs are only
+    /// seen by reads once [Self::refresh_snapshot] is called
+    pub fn enable_snapshots(&mut self)
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        self.snapshots = Some(Snapshots::new(&self.state.lock().unwrap()));
+    }
+
+    /// Take [name] offline (if [enabled]) or bring it back, while the rest of the server carries
+    /// on. Its calls meanwhile fail with [RpcError::Maintenance], telling clients to retry after
+    /// what remains of [retry_after] if given. Also available to operators as
+    /// [crate::admin::set_maintenance]
+    pub fn set_rpc_maintenance(&self, name: &Name, enabled: bool, retry_after: Option<Duration>) {
+        info!(
+            "Setting maintenance for {} to {}, retry after {:?}",
+            name, enabled, retry_after
+        );
+        let mut maintenance = self.maintenance.lock().unwrap();
+        if enabled {
+            let back_at = retry_after.map(|retry_after| Instant::now() + retry_after);
+            maintenance.insert(name.to_string(), back_at);
+        } else {
+            maintenance.remove(&name.to_string());
+        }
+    }
+
+    /// [ServerHandle::update_config], for servers not run with [Self::spawn]
+    pub fn update_config(&self, update: &ConfigUpdate) -> RpcResult<()> {
+        self.settings.update(update)
+    }
+
+    /// Fail calls that wait longer than [timeout] for the state's lock with
+    /// [RpcError::Overloaded], rather than queueing behind a slow handler indefinitely. Each
+    /// timeout is logged with the rpc holding the lock and for how long, to find the handler
+    /// hogging it. [None], the default, waits as long as it takes
+    pub fn set_lock_timeout(&mut self, timeout: Option<Duration>) {
+        self.settings.state_lock.set_timeout(timeout);
+    }
+
+    /// What to do once a handler panics while holding the state's lock, see [PoisonPolicy]
+    pub fn set_poison_policy(&mut self, poison_policy: PoisonPolicy<S>) {
+        self.poison_policy = poison_policy;
+    }
+
+    /// Publish the state as it is now to reads, see [Self::enable_snapshots]
+    pub fn refresh_snapshot(&self) {
+        if let Some(snapshots) = &self.snapshots {
+            if let Ok(state) = self.lock_state(&"refresh_snapshot") {
+                snapshots.publish(&state);
+            }
+        }
+    }
+
+    /// The version of the state, advanced by every call to an rpc that may change it (those not
+    /// made with [crate::RpcImpl::new_read]), for clients making read-modify-write calls with
+    /// [crate::RpcClient::call_versioned]
+    pub fn state_version(&self) -> u64 {
+        self.state_version.load(Ordering::SeqCst)
+    }
+
+    /// Advance the [Self::state_version] after changing the state outside the server's rpcs, so
+    /// clients that read it before the change conflict
+    pub fn bump_state_version(&self) {
+        let _state = self.state.lock().unwrap();
+        self.state_version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Remember the responses of up to [capacity] calls made with
+    /// [crate::RpcClient::call_idempotent] for [ttl], answering calls repeating their key with the
+    /// remembered response instead of calling the rpc again. Only successful calls are
+    /// remembered, so a call that failed can be retried with the same key. See
+    /// [crate::idempotency]
+    pub fn enable_idempotency(&mut self, capacity: usize, ttl: Duration) {
+        self.set_idempotency_store(Box::new(InMemoryIdempotencyStore::new(capacity, ttl)));
+    }
+
+    /// [Self::enable_idempotency], remembering responses in [store] rather than in memory
+    pub fn set_idempotency_store(&mut self, store: Box<dyn IdempotencyStore>) {
+        self.idempotency = Some(store);
+    }
+
+    /// Whether calls to [name] with an idempotency key have their responses remembered, true
+    /// for every rpc by default
+    pub fn set_rpc_idempotency(&mut self, name: Name, enabled: bool) {
+        if enabled {
+            self.not_idempotent.remove(&name);
+        } else {
+            self.not_idempotent.insert(name);
+        }
+    }
+
+    /// Snapshot of the counters and per-rpc latency histograms this server keeps, for feeding
+    /// into whatever monitoring the embedding application uses
+    pub fn stats(&self) -> ServerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Live connection and request gauges, cheap enough to poll
+    pub fn snapshot(&self) -> ServerSnapshot {
+        self.gauges.snapshot()
+    }
+
+    /// [Self::stats] and [Self::snapshot] in the Prometheus text exposition format, see
+    /// [Self::set_http_metrics] to serve them
+    pub fn metrics(&self) -> String {
+        self.stats().to_prometheus(&self.snapshot())
+    }
+
+    /// Status, reason and body answering [request], or [None] to refuse it
+    fn http_response(&self, request: &HttpRequest) -> Option<(u16, &'static str, String)> {
+        if self.metrics_path.as_ref() == Some(&request.path) {
+            return Some((200, "OK", self.metrics()));
+        }
+        let (status, reason, body) = match &self.health_check_path {
+            Some(path) if request.path != *path => (404, "Not Found", "not found"),
+            Some(_) if self.stop.borrow().is_some() => (503, "Service Unavailable", "stopping"),
+            Some(_) => (200, "OK", "ok"),
+            None if self.metrics_path.is_some() => (404, "Not Found", "not found"),
+            None => return None,
+        };
+        Some((status, reason, String::from(body)))
+    }
+
+    #[cfg(test)]
     pub(crate) fn call(
         &self,
         incoming_bytes: &[u8],
         incoming_name: &Name,
     ) -> RpcResult<OwnedBytes> {
+        let mut response_buffer = OwnedBytes::new();
+        let options = QueryOptions::default();
+        let (record, result) = self.call_into(
+            incoming_bytes,
+            incoming_name,
+            None,
+            &options,
+            &self.settings.transport_config(),
+            &mut response_buffer,
+        );
+        let call_info = CallInfo {
+            name: incoming_name,
+            query_bytes: incoming_bytes,
+            identity: None,
+            metadata: &options.metadata,
+        };
+        let outcome = result.as_ref().map(|_| &response_buffer[..]);
+        self.finish_call(record, &call_info, outcome);
+        result?;
+        Ok(response_buffer)
+    }
+
+    /// Call the rpc for a client authenticated as [identity], replacing the contents of
+    /// [response_buffer] with the serialised response. Payloads are in the wire format of
+    /// [transport_config], that of the client's connection. Given a [VersionCheck] in [options],
+    /// returns the state version the call left, along with any work it deferred. The call is
+    /// recorded by passing the [CallRecord] to [Self::finish_call] once its outcome is known,
+    /// which for async rpcs is only once their response is made
+    pub(crate) fn call_into(
+        &self,
+        incoming_bytes: &[u8],
+        incoming_name: &Name,
+        identity: Option<&Identity>,
+        options: &QueryOptions,
+        transport_config: &TransportConfig,
+        response_buffer: &mut OwnedBytes,
+    ) -> (CallRecord, RpcResult<Called>) {
         debug!("Server called by rpc {}", incoming_name);
+        let record = CallRecord {
+            span: RpcSpan::new(incoming_name),
+            start: Instant::now(),
+        };
+        let call_info = CallInfo {
+            name: incoming_name,
+            query_bytes: incoming_bytes,
+            identity,
+            metadata: &options.metadata,
+        };
+        let result = record.span.in_scope(|| {
+            self.interceptors
+                .iter()
+                .try_for_each(|interceptor| interceptor.before_call(&call_info))
+                .and_then(|()| {
+                    response_buffer.clear();
+                    auth::with_caller(identity, || {
+                        metadata::with_metadata(&options.metadata, || {
+                            self.call_rpc(
+                                incoming_bytes,
+                                incoming_name,
+                                identity,
+                                options,
+                                transport_config,
+                                response_buffer,
+                            )
+                        })
+                    })
+                })
+        });
+        (record, result)
+    }
+
+    /// [Self::finish_call] for the call [query] made, if it was to one of the server's rpcs
+    fn finish_received_call(
+        &self,
+        record: Option<CallRecord>,
+        query: &ReceivedQuery<Name>,
+        identity: Option<&Identity>,
+        result: Result<Bytes, &RpcError>,
+    ) {
+        if let (Some(record), ReceivedName::Rpc(name)) = (record, &query.name) {
+            let call_info = CallInfo {
+                name,
+                query_bytes: &query.query_bytes,
+                identity,
+                metadata: &query.options.metadata,
+            };
+            self.finish_call(record, &call_info, result);
+        }
+    }
+
+    /// Tell the interceptors, stats and tracing span of the call [record] how it went
+    pub(crate) fn finish_call(
+        &self,
+        record: CallRecord,
+        call_info: &CallInfo<Name>,
+        result: Result<Bytes, &RpcError>,
+    ) {
+        let outcome = CallOutcome {
+            duration: record.start.elapsed(),
+            result,
+        };
+        for interceptor in &self.interceptors {
+            interceptor.after_call(call_info, &outcome);
+        }
+        record.span.record(outcome.duration, result.map(|_| ()));
+        self.stats.lock().unwrap().record_call(
+            call_info.name.to_string(),
+            result.is_ok(),
+            outcome.duration,
+        );
+    }
+
+    fn call_rpc(
+        &self,
+        incoming_bytes: &[u8],
+        incoming_name: &Name,
+        identity: Option<&Identity>,
+        options: &QueryOptions,
+        transport_config: &TransportConfig,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<Called> {
+        if let Some(back_at) = self
+            .maintenance
+            .lock()
+            .unwrap()
+            .get(&incoming_name.to_string())
+        {
+            let now = Instant::now();
+            return Err(RpcError::Maintenance {
+                rpc: incoming_name.to_string(),
+                retry_after: back_at
+                    .filter(|back_at| *back_at > now)
+                    .map(|back_at| back_at - now),
+            });
+        }
         match self.rpcs.get(incoming_name) {
+            Some(rpc_impl) if self.dry_run && !self.read_only.contains(incoming_name) => {
+                rpc_impl.validate_query(incoming_bytes, transport_config)?;
+                Err(RpcError::DryRun {
+                    rpc: incoming_name.to_string(),
+                })
+            }
             Some(rpc_impl) => {
-                let result_bytes = {
-                    let mut state = self.state.lock().unwrap();
-                    rpc_impl.call_of_bytes(
+                // A snapshot may be behind the version, so versioned calls always take the lock
+                if let (Some(snapshots), None) = (&self.snapshots, options.version_check) {
+                    let snapshot = snapshots.load();
+                    if let Some(result) = rpc_impl.call_of_bytes_shared(
+                        incoming_bytes,
+                        transport_config,
+                        &snapshot,
+                        response_buffer,
+                    ) {
+                        return result.map(|()| Called::default());
+                    }
+                }
+                let queued = self.gauges.queued.enter();
+                let state = call_trace::phase(Phase::LockWait, || self.lock_state(incoming_name));
+                drop(queued);
+                let mut state = state?;
+                let current_version = self.state_version.load(Ordering::SeqCst);
+                // Checked under the lock, so a duplicate sent while the first call is being made
+                // waits for its response
+                let idempotency = self.idempotency_key(incoming_name, identity, options);
+                if let Some((store, key)) = &idempotency {
+                    if let Some(remembered) = store.get(key) {
+                        let version = self.replay(
+                            remembered,
+                            options,
+                            current_version,
+                            transport_config,
+                            response_buffer,
+                        )?;
+                        return Ok(Called {
+                            version,
+                            ..Called::default()
+                        });
+                    }
+                }
+                if let Some(VersionCheck::Expect(expected)) = options.version_check {
+                    if expected != current_version {
+                        return Err(RpcError::Conflict { current_version });
+                    }
+                }
+                let result = if options.streamed_request {
+                    match rpc_impl.call_of_bytes_uploading(
                         incoming_bytes,
-                        &self.transport_config.wire_config,
+                        transport_config,
                         &mut state,
-                    )?
+                    ) {
+                        Some(upload) => upload.map(|upload| Called {
+                            upload: Some(upload),
+                            ..Called::default()
+                        }),
+                        None => Err(RpcError::status(
+                            StatusCode::FailedPrecondition,
+                            format!("{} doesn't read a streamed request", incoming_name),
+                        )),
+                    }
+                } else if let Some(stream) =
+                    rpc_impl.call_of_bytes_streaming(incoming_bytes, transport_config, &mut state)
+                {
+                    stream.map(|stream| Called {
+                        stream: Some(stream),
+                        ..Called::default()
+                    })
+                } else if let Some(pending) =
+                    rpc_impl.call_of_bytes_async(incoming_bytes, transport_config, &mut state)
+                {
+                    pending.map(|pending| Called {
+                        pending: Some(pending),
+                        ..Called::default()
+                    })
+                } else {
+                    rpc_impl
+                        .call_of_bytes_deferring(
+                            incoming_bytes,
+                            transport_config,
+                            &mut state,
+                            response_buffer,
+                        )
+                        .map(|deferred| Called {
+                            deferred,
+                            ..Called::default()
+                        })
                 };
-                Ok(result_bytes)
+                // Advanced even if the call failed, as it may have changed the state regardless
+                let version = if rpc_impl.reads_only() {
+                    current_version
+                } else {
+                    self.state_version.fetch_add(1, Ordering::SeqCst) + 1
+                };
+                if let Some(snapshots) = &self.snapshots {
+                    snapshots.publish(&state);
+                }
+                let version = options.version_check.map(|_| version);
+                // Async, streamed and uploaded responses aren't kept, so their duplicates are
+                // called again
+                if let (Ok(called), Some((store, key))) = (&result, idempotency) {
+                    if called.pending.is_none()
+                        && called.stream.is_none()
+                        && called.upload.is_none()
+                    {
+                        let remembered = RememberedResponse {
+                            response: response_buffer.clone(),
+                            format_name: transport_config.wire_config.format_name().to_string(),
+                            version,
+                        };
+                        store.insert(key, remembered);
+                    }
+                }
+                result.map(|called| Called { version, ..called })
             }
-            None => Err(RpcError::Custom(format!(
-                "Rpc not found: {}",
-                incoming_name
-            ))),
+            None => Err(RpcError::status(
+                StatusCode::Unimplemented,
+                format!("Rpc not found: {}", incoming_name),
+            )),
         }
     }
 
-    async fn handle_connection(&self, tcp_stream: tokio::net::TcpStream) -> RpcResult<()> {
-        debug!("Handling connection: {:?}", tcp_stream);
-        let mut transport = {
-            let async_trans = TcpTransport::new(tcp_stream);
-            Transport::new(async_trans, self.transport_config.clone())
+    /// Where to remember the response to a call with [options], by what key, if it should be
+    fn idempotency_key(
+        &self,
+        name: &Name,
+        identity: Option<&Identity>,
+        options: &QueryOptions,
+    ) -> Option<(&dyn IdempotencyStore, IdempotencyKey)> {
+        let store = self.idempotency.as_deref()?;
+        let key = options.idempotency_key.as_ref()?;
+        if self.not_idempotent.contains(name) {
+            return None;
+        }
+        let key = IdempotencyKey {
+            identity: identity.map(|identity| identity.name.clone()),
+            rpc: name.to_string(),
+            key: key.clone(),
         };
-        let received_query = transport.receive_query().await?;
-        let result_bytes = self
-            .call(&received_query.query_bytes, &received_query.name)
-            .unwrap();
-        transport.respond(&result_bytes).await
+        Some((store, key))
     }
 
-    pub async fn serve(&self, listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display) {
-        info!("Starting server on {}", listen_on);
-        let listener = tokio::net::TcpListener::bind(listen_on).await.unwrap();
+    /// Answer a call repeating an idempotency key with the response [remembered] for it
+    fn replay(
+        &self,
+        remembered: RememberedResponse,
+        options: &QueryOptions,
+        current_version: u64,
+        transport_config: &TransportConfig,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<Option<u64>> {
+        let format_name = transport_config.wire_config.format_name();
+        if remembered.format_name != format_name {
+            return Err(RpcError::Custom(format!(
+                "Idempotency key was first used in the {} wire format, not {}",
+                remembered.format_name, format_name
+            )));
+        }
+        debug!("Replaying the response remembered for an idempotency key");
+        response_buffer.extend_from_slice(&remembered.response);
+        Ok(options
+            .version_check
+            .map(|_| remembered.version.unwrap_or(current_version)))
+    }
+
+    pub(crate) fn call_admin(
+        &self,
+        incoming_bytes: &[u8],
+        incoming_name: &AdminRpcName,
+        transport_config: &TransportConfig,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<()> {
+        response_buffer.clear();
+        debug!("Server called by rpc {}", incoming_name);
+        let token = || {
+            self.admin_token
+                .as_deref()
+                .ok_or_else(|| RpcError::Custom(String::from("Admin rpcs are not enabled")))
+        };
+        match incoming_name {
+            AdminRpcName::Shutdown => {
+                self.admin_body::<()>(incoming_bytes, token()?, transport_config)?;
+                self.request_stop(StopMode::Shutdown);
+                self.admin_response(&(), transport_config, response_buffer)
+            }
+            AdminRpcName::Drain => {
+                self.admin_body::<()>(incoming_bytes, token()?, transport_config)?;
+                self.request_stop(StopMode::Drain);
+                self.admin_response(&(), transport_config, response_buffer)
+            }
+            AdminRpcName::DumpStats => {
+                self.admin_body::<()>(incoming_bytes, token()?, transport_config)?;
+                self.admin_response(&self.stats(), transport_config, response_buffer)
+            }
+            AdminRpcName::DumpState => {
+                self.admin_body::<()>(incoming_bytes, token()?, transport_config)?;
+                let state_dump = self
+                    .state_dump
+                    .as_ref()
+                    .ok_or_else(|| RpcError::Custom(String::from("State dumps are not enabled")))?;
+                let state = self.lock_state(incoming_name)?;
+                state_dump(&state, &transport_config.wire_config, response_buffer)
+            }
+            AdminRpcName::SetMaintenance => {
+                let SetMaintenance {
+                    rpc,
+                    enabled,
+                    retry_after_ms,
+                } = self.admin_body(incoming_bytes, token()?, transport_config)?;
+                let name = self
+                    .rpcs
+                    .keys()
+                    .find(|name| name.to_string() == rpc)
+                    .ok_or_else(|| {
+                        RpcError::status(StatusCode::NotFound, format!("Rpc not found: {}", rpc))
+                    })?;
+                let retry_after = retry_after_ms.map(Duration::from_millis);
+                self.set_rpc_maintenance(name, enabled, retry_after);
+                self.admin_response(&(), transport_config, response_buffer)
+            }
+            AdminRpcName::UpdateConfig => {
+                let update = self.admin_body(incoming_bytes, token()?, transport_config)?;
+                self.update_config(&update)?;
+                self.admin_response(&(), transport_config, response_buffer)
+            }
+            AdminRpcName::Health => {
+                let status = match *self.stop.borrow() {
+                    Some(_) => HealthStatus::Stopping,
+                    None => HealthStatus::Serving,
+                };
+                self.admin_response(&status, transport_config, response_buffer)
+            }
+            AdminRpcName::Reflection => {
+                if !self.reflection {
+                    return Err(RpcError::Custom(String::from("Reflection is not enabled")));
+                }
+                let mut rpcs: Vec<String> = self.rpcs.keys().map(|name| name.to_string()).collect();
+                rpcs.sort();
+                let reflection = Reflection {
+                    rpcs,
+                    wire_format: transport_config.wire_config.format_name().to_string(),
+                };
+                self.admin_response(&reflection, transport_config, response_buffer)
+            }
+            #[cfg(feature = "schema")]
+            AdminRpcName::Schema => {
+                let schemas = self
+                    .rpcs
+                    .iter()
+                    .map(|(name, rpc)| Ok((name.to_string(), rpc.schema()?)))
+                    .collect::<RpcResult<HashMap<_, _>>>()?;
+                self.admin_response(&schemas, transport_config, response_buffer)
+            }
+        }
+    }
+
+    fn admin_body<T: for<'de> serde::Deserialize<'de>>(
+        &self,
+        incoming_bytes: Bytes,
+        token: &str,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<T> {
+        let query: AdminQuery<T> = transport_config.wire_config.deserialize(incoming_bytes)?;
+        query.authorise(token)
+    }
+
+    fn admin_response(
+        &self,
+        response: &impl Serialize,
+        transport_config: &TransportConfig,
+        response_buffer: &mut OwnedBytes,
+    ) -> RpcResult<()> {
+        transport_config
+            .wire_config
+            .serialize_into(response, response_buffer)
+            .map_err(Into::into)
+    }
+
+    /// Reject queries from clients whose types for [name] hash differently to the server's
+    #[cfg(feature = "type_hash")]
+    fn check_type_hash(&self, name: &Name, client_hash: Option<u64>) -> RpcResult<()> {
+        if let (Some(client_hash), Some(rpc_impl)) = (client_hash, self.rpcs.get(name)) {
+            let server_hash = rpc_impl.type_hash()?;
+            if client_hash != server_hash {
+                return Err(RpcError::TypeMismatch {
+                    rpc: name.to_string(),
+                    client_hash,
+                    server_hash,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "type_hash"))]
+    fn check_type_hash(&self, _name: &Name, _client_hash: Option<u64>) -> RpcResult<()> {
+        Ok(())
+    }
+
+    fn request_stop(&self, mode: StopMode) {
+        request_stop(&self.stop, mode);
+    }
+
+    /// Lock the state for a call to [rpc], see [Self::set_lock_timeout]
+    fn lock_state(&self, rpc: &dyn std::fmt::Display) -> RpcResult<StateGuard<'_, S>> {
+        self.settings.state_lock.lock(&self.state, rpc, |poisoned| {
+            self.recover_poisoned(rpc, poisoned)
+        })
+    }
+
+    /// Apply the [PoisonPolicy] to the state's lock, poisoned by a handler that panicked
+    fn recover_poisoned<'a>(
+        &'a self,
+        rpc: &dyn std::fmt::Display,
+        poisoned: PoisonError<MutexGuard<'a, S>>,
+    ) -> RpcResult<MutexGuard<'a, S>> {
+        match &self.poison_policy {
+            PoisonPolicy::Continue => {
+                warn!(
+                    "A handler panicked holding the state, {} continues with it",
+                    rpc
+                );
+                self.state.clear_poison();
+                Ok(poisoned.into_inner())
+            }
+            PoisonPolicy::Reset(factory) => {
+                warn!(
+                    "A handler panicked holding the state, resetting it for {}",
+                    rpc
+                );
+                let mut state = poisoned.into_inner();
+                *state = factory();
+                self.state_version.fetch_add(1, Ordering::SeqCst);
+                self.state.clear_poison();
+                Ok(state)
+            }
+            PoisonPolicy::Shutdown => {
+                error!("A handler panicked holding the state, shutting down");
+                self.request_stop(StopMode::Shutdown);
+                Err(RpcError::Unavailable(String::from(
+                    "The server is shutting down after an rpc panicked",
+                )))
+            }
+        }
+    }
+
+    #[cfg(feature = "transport_tls")]
+    async fn handle_starttls_connection(
+        &self,
+        listener: Arc<TcpListener>,
+        acceptor: &crate::tls::TlsAcceptor,
+        tcp_stream: TcpStream,
+    ) -> RpcResult<()> {
+        let transport_config = self.settings.transport_config();
+        let tcp_transport = listener.transport(tcp_stream, &transport_config).await?;
+        drop(listener);
+        let mut transport = Transport::new(tcp_transport, transport_config.clone());
+        match transport.receive_frame().await {
+            Ok(ReceivedFrame::StartTls) => {
+                transport.respond_start_tls(true).await?;
+                let tcp_stream = transport.into_internal_transport().into_stream();
+                let tls_transport =
+                    crate::tls::accept(acceptor, tcp_stream, &transport_config).await?;
+                let transport = Transport::new(tls_transport, transport_config);
+                self.handle_connection(transport, None).await
+            }
+            first_frame => {
+                debug!("Client did not upgrade to TLS");
+                self.handle_connection(transport, Some(first_frame)).await
+            }
+        }
+    }
+
+    /// Serve the client at the other end of [transport], in the same process, until it drops it,
+    /// see [crate::memory]
+    pub async fn serve_memory(&self, transport: MemoryTransport) -> RpcResult<()> {
+        let transport = Transport::new(transport, self.settings.transport_config());
+        self.handle_connection(transport, None).await
+    }
+
+    /// Serve one client connected over [stream], any duplex byte stream, until it closes it
+    pub async fn serve_stream<T>(&self, stream: T) -> RpcResult<()>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+    {
+        let transport_config = self.settings.transport_config();
+        let mut stream_transport = StreamTransport::new(stream);
+        stream_transport.set_write_timeout(transport_config.write_timeout);
+        let transport = Transport::new(stream_transport, transport_config);
+        self.handle_connection(transport, None).await
+    }
+
+    /// Serve frames from [transport] until the client closes it, starting with [first_frame] if
+    /// that has already been received
+    async fn handle_connection<I: InternalTransport + Send>(
+        &self,
+        mut transport: Transport<I, Name>,
+        mut first_frame: Option<RpcResult<ReceivedFrame<Name>>>,
+    ) -> RpcResult<()> {
+        self.stats.lock().unwrap().connections += 1;
+        let _connection = self.gauges.connections.enter();
+        // Reused for every response on the connection
+        let mut response_buffer = OwnedBytes::new();
+        let authenticator: &dyn Authenticator = self.authenticator.as_deref().unwrap_or(&NoAuth);
+        let mut challenge = None;
+        // Known from the connection itself, or else once the client has authenticated
+        let mut identity = transport.internal_transport().peer_identity();
+        let mut stop = self.stop.subscribe();
+        #[cfg(feature = "call_trace")]
+        let connection_id = self
+            .trace_recorder
+            .as_ref()
+            .map(|recorder| recorder.connection_id());
+        // Connections are persistent, serving frames until the client closes them
         loop {
-            match listener.accept().await {
-                Ok((tcp_stream, _from)) => {
-                    let connection_result = self.handle_connection(tcp_stream).await;
-                    if let Err(e) = connection_result {
-                        warn!("Error handling connection: {}", e);
+            let frame = match first_frame.take() {
+                Some(frame) => frame,
+                None => tokio::select! {
+                    frame = transport.receive_frame() => frame,
+                    _ = stop.wait_for(|stop| *stop == Some(StopMode::Shutdown)) => return Ok(()),
+                },
+            };
+            let received_query = match frame {
+                Ok(ReceivedFrame::Query(received_query)) => received_query,
+                Ok(ReceivedFrame::Heartbeat) => {
+                    transport.respond_heartbeat().await?;
+                    continue;
+                }
+                Ok(ReceivedFrame::StartTls) => {
+                    // Only the first frame of a connection served by [Self::serve_starttls] can
+                    // upgrade it
+                    transport.respond_start_tls(false).await?;
+                    continue;
+                }
+                Ok(ReceivedFrame::AuthStart) => {
+                    let issued = challenge.insert(authenticator.challenge());
+                    transport.respond_auth_challenge(issued).await?;
+                    continue;
+                }
+                Ok(ReceivedFrame::AuthResponse(response)) => {
+                    let authenticated = match &challenge {
+                        Some(challenge) => authenticator.authenticate(challenge, &response),
+                        None => Err(RpcError::Unauthenticated(String::from(
+                            "Authentication response without a challenge",
+                        ))),
+                    };
+                    match authenticated {
+                        Ok(authenticated) => {
+                            info!("Client authenticated as {}", authenticated.name);
+                            identity = Some(authenticated);
+                            transport.respond_authenticated().await?;
+                            continue;
+                        }
+                        Err(e) => {
+                            // Close the connection, so every attempt needs a new one
+                            warn!("Client failed to authenticate: {}", e);
+                            transport.respond(Err(e)).await?;
+                            return Ok(());
+                        }
                     }
                 }
-                Err(e) => error!("TCP Listener error: {}", e),
+                Ok(ReceivedFrame::Http(request)) => {
+                    let Some((status, reason, body)) = self.http_response(&request) else {
+                        warn!("Refusing HTTP {} {}", request.method, request.path);
+                        return Ok(());
+                    };
+                    transport
+                        .respond_http(&request, status, reason, &body)
+                        .await?;
+                    return Ok(());
+                }
+                Ok(ReceivedFrame::Closed) => return Ok(()),
+                Err(RpcError::TransportError(TransportError::ReceiveTimeout(idle))) => {
+                    info!("Reaping connection idle for {:?}", idle);
+                    return Ok(());
+                }
+                Err(RpcError::TransportError(e @ TransportError::OutOfSequence { .. })) => {
+                    // Queries were lost or reordered before reaching us, so the connection can't
+                    // be trusted with more
+                    warn!("Closing connection: {}", e);
+                    transport.respond(Err(RpcError::TransportError(e))).await?;
+                    return Ok(());
+                }
+                Err(RpcError::TransportError(TransportError::DeserialiseError(codec_error))) => {
+                    // Still reply, so the client learns why rather than seeing a dropped connection
+                    let e =
+                        RpcError::Custom(format!("Server could not parse query: {}", codec_error));
+                    warn!("{}", e);
+                    transport.respond(Err(e)).await?;
+                    continue;
+                }
+                Err(e @ RpcError::MessageTooLarge { .. }) => {
+                    // The rest of the frame is still unread, so the connection can't be read on
+                    warn!("Closing connection: {}", e);
+                    transport.respond(Err(e)).await?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            // Until the response has been sent
+            let _in_flight = self.gauges.in_flight.enter();
+            if received_query.options.oneway {
+                // On receipt, so the client's next frame isn't held up by the call
+                transport.acknowledge().await?;
+            }
+            #[cfg(feature = "call_trace")]
+            let mut spans = Vec::new();
+            #[cfg(feature = "call_trace")]
+            if let (Some(received_at), Some(_)) = (transport.received_at, connection_id) {
+                spans.push(call_trace::Span {
+                    phase: Phase::Receive,
+                    start: received_at,
+                    duration: received_at.elapsed(),
+                });
+            }
+            let mut record = None;
+            let mut call = || match &received_query.name {
+                _ if self.authenticator.is_some()
+                    && identity.is_none()
+                    && !matches!(
+                        received_query.name,
+                        ReceivedName::Admin(AdminRpcName::Health)
+                    ) =>
+                {
+                    Err(RpcError::Unauthenticated(String::from(
+                        "Authenticate before calling rpcs",
+                    )))
+                }
+                ReceivedName::Rpc(name) => self
+                    .check_type_hash(name, received_query.type_hash)
+                    .and_then(|()| {
+                        let (call_record, called) = self.call_into(
+                            &received_query.query_bytes,
+                            name,
+                            identity.as_ref(),
+                            &received_query.options,
+                            &transport.config,
+                            &mut response_buffer,
+                        );
+                        record = Some(call_record);
+                        called
+                    }),
+                ReceivedName::Admin(name) => self
+                    .call_admin(
+                        &received_query.query_bytes,
+                        name,
+                        &transport.config,
+                        &mut response_buffer,
+                    )
+                    .map(|()| Called::default()),
+            };
+            #[cfg(feature = "call_trace")]
+            let result = if connection_id.is_some() {
+                let (result, call_spans) = call_trace::traced(call);
+                spans.extend(call_spans);
+                result
+            } else {
+                call()
+            };
+            #[cfg(not(feature = "call_trace"))]
+            let result = call();
+            let (result, called) = match result {
+                Ok(called) => (Ok(()), called),
+                Err(e) => (Err(e), Called::default()),
+            };
+            let Called {
+                version,
+                deferred,
+                pending,
+                stream,
+                upload,
+            } = called;
+            let result = match pending {
+                Some(pending) => pending.await.map(|response| response_buffer = response),
+                None => result,
+            };
+            if let Err(e) = &result {
+                warn!("Rpc call failed: {}", e);
+            }
+            let result = result.map(|()| &response_buffer[..]);
+            // The outcome of a streamed response or request is only known once it has been sent
+            let streamed = (stream.is_some() || upload.is_some()) && !received_query.options.oneway;
+            if !streamed || transport.peer_disconnected() {
+                self.finish_received_call(
+                    record.take(),
+                    &received_query,
+                    identity.as_ref(),
+                    result.as_ref().copied(),
+                );
+            }
+            if transport.peer_disconnected() {
+                info!("Client disconnected before its response was sent, dropping the response");
+                spawn_deferred(deferred);
+                return Ok(());
+            }
+            #[cfg(feature = "call_trace")]
+            let send_start = Instant::now();
+            let responded = match (stream, upload) {
+                // Already acknowledged, and the client isn't waiting to learn how it went, so any
+                // response is dropped
+                _ if received_query.options.oneway => Ok(()),
+                (Some(stream), _) => {
+                    let written = transport.respond_streaming(stream).await;
+                    let outcome = match &written {
+                        Ok(written) => written.as_ref().map(|()| &[][..]),
+                        Err(e) => Err(e),
+                    };
+                    self.finish_received_call(
+                        record.take(),
+                        &received_query,
+                        identity.as_ref(),
+                        outcome,
+                    );
+                    written.map(|_| ())
+                }
+                (None, Some(upload)) => {
+                    let uploaded = transport.respond_uploaded(upload).await;
+                    let outcome = match &uploaded {
+                        Ok(response) => response.as_deref(),
+                        Err(e) => Err(e),
+                    };
+                    self.finish_received_call(
+                        record.take(),
+                        &received_query,
+                        identity.as_ref(),
+                        outcome,
+                    );
+                    uploaded.map(|_| ())
+                }
+                (None, None) => transport.respond_versioned(result, version).await,
+            };
+            // The call was made whether or not its response made it
+            spawn_deferred(deferred);
+            responded?;
+            #[cfg(feature = "call_trace")]
+            if let (Some(recorder), Some(connection_id)) = (&self.trace_recorder, connection_id) {
+                spans.push(call_trace::Span {
+                    phase: Phase::Send,
+                    start: send_start,
+                    duration: send_start.elapsed(),
+                });
+                recorder.record_call(&received_query.name.to_string(), connection_id, &spans);
+            }
+            if *self.stop.borrow() == Some(StopMode::Shutdown) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn accepts_peer(&self, peer: &std::net::SocketAddr) -> bool {
+        match &self.ip_filter {
+            Some(ip_filter) if !ip_filter.is_allowed(&peer.ip()) => {
+                info!("Refusing connection from {}", peer);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+impl<S, Name, Stored> RpcServer<S, Name, Stored>
+where
+    S: Send + 'static,
+    Name: RpcName + Send + Sync + 'static,
+    Stored: StoredRpc<S, Name> + Send + Sync + 'static,
+{
+    /// Serve on [listen_on], each connection on its own task, until stopped through the admin
+    /// rpcs. Returns the [Connections] still open once it stops accepting them, or at once an
+    /// error refusing to serve if [listen_on] can't be bound or [Self::set_strict_registration]
+    /// finds rpcs missing
+    pub async fn serve(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+    ) -> RpcResult<Connections> {
+        info!("Starting server on {}", listen_on);
+        let listener = TcpListener::bind(listen_on).await.map_err(bind_error)?;
+        self.serve_listener(listener).await
+    }
+
+    /// [Self::serve] on any [Listener], e.g. a [tokio::net::UnixListener]
+    pub async fn serve_listener<L: Listener>(
+        self: &Arc<Self>,
+        listener: L,
+    ) -> RpcResult<Connections> {
+        self.serve_listeners(vec![listener]).await
+    }
+
+    /// Bind to [listen_on] and [Self::serve] on a background task, returning a [ServerHandle] to
+    /// stop and wait for it with
+    pub async fn spawn(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+    ) -> std::io::Result<ServerHandle> {
+        self.check_registration().map_err(invalid_input)?;
+        let listener = TcpListener::bind(listen_on).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Spawning server on {}", local_addr);
+        let server = self.clone();
+        let task = tasks::spawn(&format!("pirates server {}", local_addr), async move {
+            // Registration was checked above, so this can't fail
+            server.serve_listener(listener).await.unwrap_or_default()
+        });
+        Ok(ServerHandle {
+            local_addr,
+            settings: self.settings.clone(),
+            stop: self.stop.clone(),
+            task,
+        })
+    }
+
+    /// Serve on [port] to both IPv4 and IPv6 clients, listening as chosen by [mode]. Unlike
+    /// [Self::serve] with "0.0.0.0", which IPv6 clients can't reach
+    pub async fn serve_dual_stack(
+        self: &Arc<Self>,
+        port: u16,
+        mode: DualStack,
+    ) -> RpcResult<Connections> {
+        info!("Starting {:?} dual stack server on port {}", mode, port);
+        let listeners = match mode {
+            DualStack::Mapped => vec![bind_v6(port, false).map_err(bind_error)?],
+            DualStack::Separate => vec![
+                TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, port))
+                    .await
+                    .map_err(bind_error)?,
+                bind_v6(port, true).map_err(bind_error)?,
+            ],
+        };
+        self.serve_listeners(listeners).await
+    }
+
+    /// [Self::serve] over TLS, see [crate::tls]. Handshakes must complete within
+    /// [TransportConfig::connect_timeout]
+    #[cfg(feature = "transport_tls")]
+    pub async fn serve_tls(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+        tls_config: &crate::tls::TlsServerConfig,
+    ) -> RpcResult<Connections> {
+        info!("Starting TLS server on {}", listen_on);
+        let listener = TcpListener::bind(listen_on).await.map_err(bind_error)?;
+        self.serve_listener(crate::tls::TlsListener::new(listener, tls_config))
+            .await
+    }
+
+    /// [Self::serve] over WebSockets, see [crate::websocket]. Handshakes must complete within
+    /// [TransportConfig::connect_timeout]
+    #[cfg(feature = "transport_websocket")]
+    pub async fn serve_websocket(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+    ) -> RpcResult<Connections> {
+        info!("Starting WebSocket server on {}", listen_on);
+        let listener = TcpListener::bind(listen_on).await.map_err(bind_error)?;
+        self.serve_listener(crate::websocket::WebSocketListener::new(listener))
+            .await
+    }
+
+    /// [Self::serve] over UDP, serving each client sending to [listen_on] as a connection of its
+    /// own, see [crate::udp]
+    pub async fn serve_udp(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+    ) -> RpcResult<Connections> {
+        info!("Starting UDP server on {}", listen_on);
+        let socket = tokio::net::UdpSocket::bind(listen_on)
+            .await
+            .map_err(bind_error)?;
+        self.serve_listener(crate::udp::UdpListener::new(socket))
+            .await
+    }
+
+    /// [Self::serve] on every address of [config]'s [crate::config::ServerConfig::listen_on],
+    /// over TLS if it has certificates. An error if any address can't be bound, or the
+    /// certificates loaded
+    #[cfg(feature = "config")]
+    pub async fn serve_config(
+        self: &Arc<Self>,
+        config: &crate::config::ServerConfig,
+    ) -> std::io::Result<Connections> {
+        let mut listeners = Vec::new();
+        for listen_on in &config.listen_on {
+            info!("Starting server on {}", listen_on);
+            listeners.push(TcpListener::bind(listen_on).await?);
+        }
+        #[cfg(feature = "transport_tls")]
+        if let Some(tls_config) = config
+            .tls_server_config()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?
+        {
+            let listeners = listeners
+                .into_iter()
+                .map(|listener| crate::tls::TlsListener::new(listener, &tls_config))
+                .collect();
+            return self.serve_listeners(listeners).await.map_err(invalid_input);
+        }
+        self.serve_listeners(listeners).await.map_err(invalid_input)
+    }
+
+    /// Serve on [listen_on] to clients connecting in plaintext, upgrading the connections of
+    /// those that ask to TLS, see [crate::RpcClient::connect_starttls]. Eases moving a plaintext
+    /// deployment to TLS without changing its port, as clients can upgrade one at a time
+    #[cfg(feature = "transport_tls")]
+    pub async fn serve_starttls(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+        tls_config: &crate::tls::TlsServerConfig,
+    ) -> RpcResult<Connections> {
+        info!("Starting server upgrading to TLS on {}", listen_on);
+        let listener = TcpListener::bind(listen_on).await.map_err(bind_error)?;
+        let acceptor = tls_config.acceptor();
+        self.accept_connections(vec![listener], |server, listener, tcp_stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                server
+                    .handle_starttls_connection(listener, &acceptor, tcp_stream)
+                    .await
+            }
+        })
+        .await
+    }
+
+    /// Serve on [listen_on], setting up each connection accepted with [acceptor], e.g. to read a
+    /// PROXY protocol header or run a handshake of its own before serving the client. Setting up
+    /// must complete within [TransportConfig::connect_timeout]
+    pub async fn serve_with_acceptor<A: Acceptor>(
+        self: &Arc<Self>,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+        acceptor: A,
+    ) -> RpcResult<Connections> {
+        info!("Starting server with a custom acceptor on {}", listen_on);
+        let listener = TcpListener::bind(listen_on).await.map_err(bind_error)?;
+        let acceptor = Arc::new(acceptor);
+        self.accept_connections(vec![listener], |server, _listener, tcp_stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let peer = tcp_stream.peer_addr().map_err(|e| {
+                    RpcError::TransportError(TransportError::ConnectError(e.to_string()))
+                })?;
+                let accept_fut = acceptor.accept(tcp_stream, peer);
+                let transport_config = server.settings.transport_config();
+                let internal_transport = match transport_config.connect_timeout {
+                    Some(connect_timeout) => tokio::time::timeout(connect_timeout, accept_fut)
+                        .await
+                        .map_err(|_| {
+                            RpcError::TransportError(TransportError::ConnectError(format!(
+                                "Timed out after {:?} setting up the connection",
+                                connect_timeout
+                            )))
+                        })??,
+                    None => accept_fut.await?,
+                };
+                let transport = Transport::new(internal_transport, transport_config);
+                server.handle_connection(transport, None).await
+            }
+        })
+        .await
+    }
+
+    async fn serve_listeners<L: Listener>(
+        self: &Arc<Self>,
+        listeners: Vec<L>,
+    ) -> RpcResult<Connections> {
+        self.accept_connections(listeners, |server, listener, stream| async move {
+            let transport_config = server.settings.transport_config();
+            let internal_transport = listener.transport(stream, &transport_config).await?;
+            // So a stopped server's listener closes at once, rather than once its connections do
+            drop(listener);
+            let transport = Transport::new(internal_transport, transport_config);
+            server.handle_connection(transport, None).await
+        })
+        .await
+    }
+
+    /// Accept connections from [listeners] until a stop is requested, serving each on a task of
+    /// its own with [serve_connection]. After a shutdown, waits for the connections to close.
+    /// Refuses to accept any if rpcs are missing, see [Self::set_strict_registration]
+    async fn accept_connections<L, F, Fut>(
+        self: &Arc<Self>,
+        listeners: Vec<L>,
+        serve_connection: F,
+    ) -> RpcResult<Connections>
+    where
+        L: Listener,
+        F: Fn(Arc<Self>, Arc<L>, L::Stream) -> Fut,
+        Fut: Future<Output = RpcResult<()>> + Send + 'static,
+    {
+        self.check_registration()?;
+        let listeners: Vec<Arc<L>> = listeners.into_iter().map(Arc::new).collect();
+        let mut connections = Connections::default();
+        let mut stop = self.stop.subscribe();
+        // Set while backing off after errors accepting connections
+        let mut accept_delay = None;
+        let mut resume_accepting_at = None;
+        loop {
+            tokio::select! {
+                accepted = accept_any(&listeners), if resume_accepting_at.is_none() => match accepted {
+                    Ok((_, (_, Some(from)))) if !self.accepts_peer(&from) => accept_delay = None,
+                    Ok((listener, (stream, from))) => {
+                        accept_delay = None;
+                        debug!("Handling connection from {:?}", from);
+                        let connection = serve_connection(self.clone(), listener, stream);
+                        let name = match from {
+                            Some(from) => format!("pirates connection {}", from),
+                            None => String::from("pirates connection"),
+                        };
+                        let connection = spans::in_connection_span(from, async move {
+                            if let Err(e) = connection.await {
+                                warn!("Error handling connection from {:?}: {}", from, e);
+                            }
+                        });
+                        tasks::spawn_in(&mut connections.tasks, &name, connection);
+                    }
+                    Err(e) if AcceptBackoff::is_transient(&e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                    }
+                    Err(e) => {
+                        let delay = self.accept_backoff.next_delay(accept_delay);
+                        error!("Listener error: {}, pausing accepting for {:?}", e, delay);
+                        accept_delay = Some(delay);
+                        resume_accepting_at = Some(tokio::time::Instant::now() + delay);
+                    }
+                },
+                _ = tokio::time::sleep_until(
+                    resume_accepting_at.unwrap_or_else(tokio::time::Instant::now)
+                ), if resume_accepting_at.is_some() => resume_accepting_at = None,
+                Some(finished) = connections.tasks.join_next() => connections.reap(finished),
+                _ = stop.wait_for(Option::is_some) => break,
+            }
+        }
+        let mode = *self.stop.borrow();
+        info!("Server stopping: {:?}", mode);
+        if mode == Some(StopMode::Shutdown) {
+            // Connections close as soon as the calls they are serving have been answered
+            connections.wait().await;
+        }
+        Ok(connections)
+    }
+}
+
+/// Sets up the connections accepted by [RpcServer::serve_with_acceptor], turning each
+/// [TcpStream] into the [InternalTransport] the client is served over. Any
+/// [TransportConfig::keepalive] or [TransportConfig::write_timeout] is for the acceptor to apply
+#[async_trait::async_trait]
+pub trait Acceptor: Send + Sync + 'static {
+    type Transport: InternalTransport + Send + 'static;
+
+    /// Set up [tcp_stream], accepted from [peer], refusing the connection with an error
+    async fn accept(
+        &self,
+        tcp_stream: TcpStream,
+        peer: std::net::SocketAddr,
+    ) -> RpcResult<Self::Transport>;
+}
+
+/// The connection tasks of a server, returned by [RpcServer::serve] once it stops accepting
+/// connections. Those still open carry on serving until their clients close them, and are
+/// aborted if this is dropped
+#[derive(Default)]
+pub struct Connections {
+    tasks: JoinSet<()>,
+    panicked: Vec<JoinError>,
+}
+
+impl Connections {
+    /// Number of connections still open
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Wait for every connection to close, returning the errors of the tasks that panicked,
+    /// including those that did while the server was accepting connections
+    pub async fn join(mut self) -> Vec<JoinError> {
+        self.wait().await;
+        self.panicked
+    }
+
+    /// Close every connection now, then [Self::join]
+    pub async fn abort(mut self) -> Vec<JoinError> {
+        self.tasks.abort_all();
+        self.join().await
+    }
+
+    async fn wait(&mut self) {
+        while let Some(finished) = self.tasks.join_next().await {
+            self.reap(finished);
+        }
+    }
+
+    fn reap(&mut self, finished: Result<(), JoinError>) {
+        match finished {
+            Ok(()) => {}
+            Err(e) if e.is_panic() => {
+                error!("Connection task panicked: {}", e);
+                self.panicked.push(e);
             }
+            // Aborted
+            Err(_) => {}
+        }
+    }
+}
+
+/// [e] binding a listener as the error of a server refusing to start
+fn bind_error(e: std::io::Error) -> RpcError {
+    TransportError::ConnectError(format!("Could not bind: {}", e)).into()
+}
+
+/// [e] as the error of a server refusing to start
+fn invalid_input(e: RpcError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+}
+
+fn request_stop(stop: &watch::Sender<Option<StopMode>>, mode: StopMode) {
+    info!("Server stop requested: {:?}", mode);
+    stop.send_if_modified(|stop| {
+        // A shutdown is never downgraded to a drain
+        let upgrade = *stop != Some(StopMode::Shutdown) && *stop != Some(mode);
+        if upgrade {
+            *stop = Some(mode);
+        }
+        upgrade
+    });
+}
+
+/// How a server recovers from errors accepting connections, see
+/// [RpcServer::set_accept_backoff]. Errors local to one connection, such as it being reset
+/// before it was accepted, are skipped over. Others, typically running out of file descriptors,
+/// pause accepting for [Self::initial], doubling with each further error up to [Self::max], rather
+/// than spinning on an error that won't clear at once. Any success resets the delay
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcceptBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl AcceptBackoff {
+    /// Delay after an error, given the [previous] delay if accepting has failed since the last
+    /// success
+    pub(crate) fn next_delay(&self, previous: Option<Duration>) -> Duration {
+        match previous {
+            Some(previous) => previous.saturating_mul(2).min(self.max),
+            None => self.initial,
+        }
+    }
+
+    pub(crate) fn is_transient(e: &std::io::Error) -> bool {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::Interrupted
+        )
+    }
+}
+
+/// A server serving on a background task, see [RpcServer::spawn]. Dropping this leaves the
+/// server running
+pub struct ServerHandle {
+    local_addr: std::net::SocketAddr,
+    settings: Arc<LiveSettings>,
+    stop: Arc<watch::Sender<Option<StopMode>>>,
+    task: tokio::task::JoinHandle<Connections>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on, e.g. to find the port picked when binding to port
+    /// 0
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Change the server's timeouts and log level without restarting it, see [ConfigUpdate].
+    /// Fails with [StatusCode::InvalidArgument], changing nothing, if any of [update] is invalid
+    pub fn update_config(&self, update: &ConfigUpdate) -> RpcResult<()> {
+        self.settings.update(update)
+    }
+
+    /// Stop the server as the [crate::admin::shutdown] rpc does, without waiting for it to stop
+    pub fn shutdown(&self) {
+        request_stop(&self.stop, StopMode::Shutdown);
+    }
+
+    /// Wait for the server to stop and its connections to close, returning the errors of any
+    /// tasks that panicked
+    pub async fn join(self) -> Vec<JoinError> {
+        match self.task.await {
+            Ok(connections) => connections.join().await,
+            Err(e) => vec![e],
         }
     }
 }
+
+/// How [RpcServer::serve_dual_stack] listens for IPv4 and IPv6 clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DualStack {
+    /// One IPv6 socket on "[::]" which also accepts IPv4 clients, as IPv4-mapped addresses
+    Mapped,
+    /// Separate sockets on "0.0.0.0" and a v6-only "[::]", for systems whose IPv6 sockets can't
+    /// accept IPv4 clients (e.g. with net.ipv6.bindv6only set)
+    Separate,
+}
+
+fn bind_v6(port: u16, only_v6: bool) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(only_v6)?;
+    // As [tokio::net::TcpListener::bind] does, to allow rebinding while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    let addr = std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Spawn the work left by a call, once the response is sent, see [crate::RpcImpl::new_deferring]
+fn spawn_deferred(deferred: Option<Deferred>) {
+    if let Some(deferred) = deferred {
+        tasks::spawn("pirates deferred work", deferred);
+    }
+}
+
+/// Accept the next connection from whichever of [listeners] receives one first
+async fn accept_any<L: Listener>(
+    listeners: &[Arc<L>],
+) -> std::io::Result<(Arc<L>, (L::Stream, Option<std::net::SocketAddr>))> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let std::task::Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return std::task::Poll::Ready(
+                    accepted.map(|accepted| (listener.clone(), accepted)),
+                );
+            }
+        }
+        std::task::Poll::Pending
+    })
+    .await
+}
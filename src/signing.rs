@@ -0,0 +1,77 @@
+//! Ed25519 signatures on responses, so clients pinning the server's public key detect responses
+//! tampered with or forged in transit, on deployments that can't run full TLS (Enable the
+//! "response_signing" feature).
+//!
+//! A server signing with [crate::TransportConfig::signing_key] signs every response frame
+//! together with the request it answers, so a response can't be passed off as the answer to
+//! another request. A client with [crate::TransportConfig::verifying_key] refuses responses
+//! that aren't signed by that key with [crate::error::RpcError::InvalidSignature]. Responses
+//! are still readable in transit, for confidentiality see [crate::tls]
+//!
+//! ```rust,ignore
+//! // On the server
+//! transport_config.signing_key = Some(Arc::new(SigningKey::from_bytes(&secret_key)));
+//! // On the client, pinning the server's public key
+//! transport_config.verifying_key = Some(VerifyingKey::from_bytes(&public_key)?);
+//! ```
+use crate::error::{RpcError, RpcResult};
+use crate::{Bytes, OwnedBytes};
+pub use ed25519_dalek;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+const CONTEXT: &[u8] = b"pirates response signature";
+
+/// Sign [response], answering [request]
+pub(crate) fn sign(signing_key: &SigningKey, request: Bytes, response: Bytes) -> OwnedBytes {
+    signing_key
+        .sign(&signed_message(request, response))
+        .to_bytes()
+        .to_vec()
+}
+
+/// Check [signature] is [verifying_key]'s of [response], answering [request]
+pub(crate) fn verify(
+    verifying_key: &VerifyingKey,
+    request: Bytes,
+    response: Bytes,
+    signature: Bytes,
+) -> RpcResult<()> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| RpcError::InvalidSignature(format!("Malformed signature: {}", e)))?;
+    verifying_key
+        .verify(&signed_message(request, response), &signature)
+        .map_err(|_| RpcError::InvalidSignature(String::from("Response signature does not match")))
+}
+
+fn signed_message(request: Bytes, response: Bytes) -> OwnedBytes {
+    let mut message = Vec::with_capacity(CONTEXT.len() + 8 + request.len() + response.len());
+    message.extend_from_slice(CONTEXT);
+    message.extend_from_slice(&(request.len() as u64).to_be_bytes());
+    message.extend_from_slice(request);
+    message.extend_from_slice(response);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifying_key = signing_key.verifying_key();
+        let signature = sign(&signing_key, b"request", b"response");
+        assert!(verify(&verifying_key, b"request", b"response", &signature).is_ok());
+
+        // Bound to the request answered, as well as the response
+        assert!(verify(&verifying_key, b"other request", b"response", &signature).is_err());
+        assert!(verify(&verifying_key, b"request", b"tampered", &signature).is_err());
+        // Moving bytes between request and response changes the message
+        assert!(verify(&verifying_key, b"requestr", b"esponse", &signature).is_err());
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        match verify(&other_key, b"request", b"response", &signature) {
+            Err(RpcError::InvalidSignature(_)) => {}
+            other => panic!("Expected InvalidSignature, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Copy-on-write snapshots of a server's state, see [crate::RpcServer::enable_snapshots]
+use std::sync::{Arc, RwLock};
+
+/// The latest snapshot of the state, swapped for a new one after every write. Readers hold the
+/// lock only long enough to clone the [Arc], so never wait on a handler. Erased behind closures,
+/// so only servers with snapshots enabled need a [Sync] state
+pub(crate) struct Snapshots<S> {
+    load: Box<dyn Fn() -> Arc<S> + Send + Sync>,
+    publish: Box<dyn Fn(&S) + Send + Sync>,
+}
+
+impl<S> Snapshots<S> {
+    pub(crate) fn new(state: &S) -> Self
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let current = Arc::new(RwLock::new(Arc::new(state.clone())));
+        let published = current.clone();
+        Self {
+            load: Box::new(move || current.read().unwrap().clone()),
+            publish: Box::new(move |state| {
+                // Copied before swapping, so readers only ever wait on the swap itself
+                let snapshot = Arc::new(state.clone());
+                *published.write().unwrap() = snapshot;
+            }),
+        }
+    }
+
+    pub(crate) fn load(&self) -> Arc<S> {
+        (self.load)()
+    }
+
+    /// Replace the snapshot with a copy of [state]
+    pub(crate) fn publish(&self, state: &S) {
+        (self.publish)(state)
+    }
+}
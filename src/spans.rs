@@ -0,0 +1,135 @@
+//! Tracing spans around what a server does (Enable the "tracing" feature).
+//!
+//! Each connection a server accepts is served in a "connection" span with the client's `peer`
+//! address, and each call to one of its rpcs is dispatched in an "rpc" span within it, recording
+//! the `rpc` name, the `duration_us` it took and its `result`, "ok" or the error. Install a
+//! tracing subscriber to collect them. Without the feature, there are no spans and no cost
+use crate::error::RpcError;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// [future], serving the connection from [peer], in a span of its own
+pub(crate) fn in_connection_span<F: Future>(
+    peer: Option<SocketAddr>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::info_span!("connection", peer = peer.map(tracing::field::display));
+        tracing::Instrument::instrument(future, span)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = peer;
+        future
+    }
+}
+
+/// The span a call is dispatched in, entered only while the handler runs, as entered spans are
+/// bound to their thread. Kept until the call's outcome is known, which may be after awaits
+pub(crate) struct RpcSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl RpcSpan {
+    pub(crate) fn new(rpc: &dyn Display) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::info_span!(
+                "rpc",
+                rpc = %rpc,
+                duration_us = tracing::field::Empty,
+                result = tracing::field::Empty,
+            );
+            Self { span }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = rpc;
+            Self {}
+        }
+    }
+
+    /// Run [f] in the span
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.in_scope(f)
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            f()
+        }
+    }
+
+    /// Record how the call went, once it has
+    pub(crate) fn record(&self, duration: Duration, result: Result<(), &RpcError>) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("duration_us", duration.as_micros() as u64);
+            match result {
+                Ok(()) => self.span.record("result", "ok"),
+                Err(e) => self.span.record("result", tracing::field::display(e)),
+            };
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (duration, result);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Collects the names of the spans made, and the fields recorded on them
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Fields<'a>(&'a mut Vec<String>);
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut recorded = self.0.lock().unwrap();
+            recorded.push(span.metadata().name().to_string());
+            span.record(&mut Fields(&mut recorded));
+            Id::from_u64(recorded.len() as u64)
+        }
+        fn record(&self, _span: &Id, values: &Record<'_>) {
+            values.record(&mut Fields(&mut self.0.lock().unwrap()));
+        }
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, _event: &Event<'_>) {}
+        fn enter(&self, _span: &Id) {}
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn rpc_span() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let span = RpcSpan::new(&"GetI");
+            let e = RpcError::Custom(String::from("Oops"));
+            span.record(Duration::from_micros(7), Err(&e));
+        });
+        let recorded = recorder.0.lock().unwrap();
+        assert_eq!(recorded[..3], ["rpc", "rpc=GetI", "duration_us=7"]);
+        assert!(recorded[3].starts_with("result=") && recorded[3].contains("Oops"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Taking the server state's lock within a timeout, see [crate::RpcServer::set_lock_timeout], and
+//! recovering it once poisoned, see [PoisonPolicy]
+use crate::error::{RpcError, RpcResult};
+use log::warn;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Longest pause between attempts at a contended lock
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+/// What a server does once a handler panics while holding the state's lock, which may leave the
+/// state half changed, see [crate::RpcServer::set_poison_policy]
+#[derive(Default)]
+pub enum PoisonPolicy<S> {
+    /// Log the panic and carry on with the state as the handler left it
+    Continue,
+    /// Replace the state with a fresh one from the factory, and carry on
+    Reset(Box<dyn Fn() -> S + Send + Sync>),
+    /// Fail the call with [RpcError::Unavailable] and shut down, as with
+    /// [crate::admin::shutdown]. The default
+    #[default]
+    Shutdown,
+}
+
+/// Who holds the state's lock, and since when
+struct Holder {
+    rpc: String,
+    since: Instant,
+}
+
+/// How rpcs take the state's lock: waiting as long as it takes, or with a [timeout], failing
+/// with [RpcError::Overloaded] and logging who held it for so long
+#[derive(Default)]
+pub(crate) struct StateLock {
+    /// Changeable while the server runs, see [crate::ServerHandle::update_config]
+    timeout: RwLock<Option<Duration>>,
+    /// Only kept with a [Self::timeout], to report in the log when one is exceeded
+    holder: Mutex<Option<Holder>>,
+}
+
+/// The state, locked for [Holder::rpc]
+pub(crate) struct StateGuard<'a, S> {
+    guard: MutexGuard<'a, S>,
+    holder: Option<&'a Mutex<Option<Holder>>>,
+}
+
+impl StateLock {
+    /// Take the lock within [timeout] from the next call on
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.write().unwrap() = timeout;
+    }
+
+    /// Lock [state] for a call to [rpc], handing the lock to [recover] if it's poisoned
+    pub(crate) fn lock<'a, S>(
+        &'a self,
+        state: &'a Mutex<S>,
+        rpc: &dyn std::fmt::Display,
+        recover: impl FnOnce(PoisonError<MutexGuard<'a, S>>) -> RpcResult<MutexGuard<'a, S>>,
+    ) -> RpcResult<StateGuard<'a, S>> {
+        let timeout = match *self.timeout.read().unwrap() {
+            Some(timeout) => timeout,
+            None => {
+                let guard = match state.lock() {
+                    Ok(guard) => guard,
+                    Err(poisoned) => recover(poisoned)?,
+                };
+                return Ok(StateGuard {
+                    guard,
+                    holder: None,
+                });
+            }
+        };
+        let deadline = Instant::now() + timeout;
+        let mut retry_delay = Duration::from_micros(50);
+        let guard = loop {
+            match state.try_lock() {
+                Ok(guard) => break guard,
+                Err(TryLockError::Poisoned(poisoned)) => break recover(poisoned)?,
+                Err(TryLockError::WouldBlock) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(self.timed_out(rpc, timeout));
+                    }
+                    std::thread::sleep(retry_delay.min(deadline - now));
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        };
+        *self.holder.lock().unwrap() = Some(Holder {
+            rpc: rpc.to_string(),
+            since: Instant::now(),
+        });
+        Ok(StateGuard {
+            guard,
+            holder: Some(&self.holder),
+        })
+    }
+
+    fn timed_out(&self, rpc: &dyn std::fmt::Display, timeout: Duration) -> RpcError {
+        match &*self.holder.lock().unwrap() {
+            Some(holder) => warn!(
+                "{} gave up on the state lock after {:?}, held by {} for {:?}",
+                rpc,
+                timeout,
+                holder.rpc,
+                holder.since.elapsed()
+            ),
+            // Held outside the server's rpcs, e.g. by the embedding application
+            None => warn!(
+                "{} gave up on the state lock after {:?}, held outside the server",
+                rpc, timeout
+            ),
+        }
+        RpcError::Overloaded(format!("The state was not available within {:?}", timeout))
+    }
+}
+
+impl<S> Deref for StateGuard<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.guard
+    }
+}
+
+impl<S> DerefMut for StateGuard<'_, S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.guard
+    }
+}
+
+impl<S> Drop for StateGuard<'_, S> {
+    fn drop(&mut self) {
+        // Before [guard] releases the lock, so the next holder's record is never cleared
+        if let Some(holder) = self.holder {
+            *holder.lock().unwrap() = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unpoisoned<T>(_: PoisonError<T>) -> RpcResult<T> {
+        panic!("The lock isn't poisoned")
+    }
+
+    #[test]
+    fn times_out_while_held() {
+        let state = Mutex::new(0);
+        let state_lock = StateLock::default();
+        state_lock.set_timeout(Some(Duration::from_millis(20)));
+        let held = state_lock.lock(&state, &"IncrI", unpoisoned).unwrap();
+        match state_lock.lock(&state, &"GetI", unpoisoned) {
+            Err(e @ RpcError::Overloaded(_)) => assert!(e.is_retryable()),
+            _ => panic!("Expected Overloaded"),
+        }
+        drop(held);
+        assert!(state_lock.holder.lock().unwrap().is_none());
+        *state_lock.lock(&state, &"IncrI", unpoisoned).unwrap() += 1;
+        assert_eq!(*state.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::call_trace::{self, Phase};
+use crate::core::RpcType;
+use crate::error::RpcResult;
+use crate::transport::TransportConfig;
+use crate::{Bytes, OwnedBytes};
+
+/// Deserialise the query in [bytes], call [implement] with it and serialise the response into
+/// [response_buffer]. Shared by [crate::RpcImpl] and the rpcs generated by [crate::static_rpcs],
+/// where [implement] is a plain function so the call is dispatched statically
+pub fn call_static<State, Q: RpcType, R: RpcType>(
+    implement: impl Fn(&mut State, Q) -> RpcResult<R>,
+    bytes: Bytes,
+    transport_config: &TransportConfig,
+    state: &mut State,
+    response_buffer: &mut OwnedBytes,
+) -> RpcResult<()> {
+    let wire_config = &transport_config.wire_config;
+    let query = call_trace::phase(Phase::Deserialize, || {
+        wire_config.deserialize_payload(bytes, transport_config.schema_compatibility)
+    })?;
+    let result = call_trace::phase(Phase::Handler, || implement(state, query))?;
+    call_trace::phase(Phase::Serialize, || {
+        wire_config.serialize_into(&result, response_buffer)
+    })?;
+    Ok(())
+}
+
+/// Deserialise the query in [bytes] as a [Q] to check it is valid, without calling anything.
+/// Shared like [call_static]
+pub fn validate_static<Q: RpcType>(
+    bytes: Bytes,
+    transport_config: &TransportConfig,
+) -> RpcResult<()> {
+    let wire_config = &transport_config.wire_config;
+    wire_config.deserialize_payload::<Q>(bytes, transport_config.schema_compatibility)?;
+    Ok(())
+}
+
+/// Register a server's rpcs in a generated enum rather than as boxed [crate::StoredRpc]s, so
+/// that each call is dispatched with a match and a direct call to its implementation, with no
+/// boxing or dynamic dispatch. Each rpc is listed with its query and response types, its name and
+/// its implementation function. Serve them with a server made by [crate::RpcServer::new_static]
+///
+/// ```rust,ignore
+/// pirates::static_rpcs! {
+///     pub enum ServerRpcs<ServerState, RpcId> {
+///         AddName(String, ()) => RpcId::AddName, rpcs::AddName::implement;
+///         GetNames((), Vec<String>) => RpcId::GetNames, rpcs::GetNames::implement;
+///     }
+/// }
+///
+/// let mut server = RpcServer::new_static(state.clone(), TransportConfig::default());
+/// for rpc in ServerRpcs::ALL {
+///     server.add_rpc(*rpc);
+/// }
+/// server.serve("127.0.0.1:5959").await?;
+/// ```
+#[macro_export]
+macro_rules! static_rpcs {
+    ($vis:vis enum $rpcs:ident<$state:ty, $name:ty> {
+        $($variant:ident($q:ty, $r:ty) => $rpc_name:expr, $implement:expr);* $(;)?
+    }) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        $vis enum $rpcs {
+            $($variant),*
+        }
+
+        impl $rpcs {
+            /// Every rpc, for adding with [$crate::RpcServer::add_rpc]
+            pub const ALL: &'static [Self] = &[$(Self::$variant),*];
+        }
+
+        impl $crate::StoredRpc<$state, $name> for $rpcs {
+            fn call_of_bytes(
+                &self,
+                bytes: $crate::Bytes,
+                transport_config: &$crate::TransportConfig,
+                state: &mut $state,
+                response_buffer: &mut $crate::OwnedBytes,
+            ) -> $crate::error::RpcResult<()> {
+                match self {
+                    $(Self::$variant => $crate::call_static::<$state, $q, $r>(
+                        $implement,
+                        bytes,
+                        transport_config,
+                        state,
+                        response_buffer,
+                    ),)*
+                }
+            }
+
+            fn validate_query(
+                &self,
+                bytes: $crate::Bytes,
+                transport_config: &$crate::TransportConfig,
+            ) -> $crate::error::RpcResult<()> {
+                match self {
+                    $(Self::$variant => $crate::validate_static::<$q>(bytes, transport_config),)*
+                }
+            }
+
+            fn rpc_name(&self) -> $name {
+                match self {
+                    $(Self::$variant => $rpc_name,)*
+                }
+            }
+
+            $crate::__static_rpcs_schema!($($variant($q, $r)),*);
+            $crate::__static_rpcs_type_hash!($name; $($variant($q, $r)),*);
+        }
+    };
+}
+
+// The optional [crate::StoredRpc] methods are generated by macros picked by this crate's
+// features, as features named in [static_rpcs] itself would be those of the calling crate
+
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static_rpcs_schema {
+    ($($variant:ident($q:ty, $r:ty)),*) => {
+        fn schema(&self) -> $crate::error::RpcResult<$crate::schema::RpcSchema> {
+            match self {
+                $(Self::$variant => $crate::schema::RpcSchema::of::<$q, $r>(),)*
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "schema"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static_rpcs_schema {
+    ($($tt:tt)*) => {};
+}
+
+#[cfg(feature = "type_hash")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static_rpcs_type_hash {
+    ($name:ty; $($variant:ident($q:ty, $r:ty)),*) => {
+        fn type_hash(&self) -> $crate::error::RpcResult<u64> {
+            match self {
+                $(Self::$variant => {
+                    static TYPE_HASH: ::std::sync::OnceLock<Result<u64, String>> =
+                        ::std::sync::OnceLock::new();
+                    $crate::type_hash::cached_type_hash::<$name, $q, $r>(&TYPE_HASH)
+                })*
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "type_hash"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static_rpcs_type_hash {
+    ($($tt:tt)*) => {};
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the [LatencyHistogram] buckets, in microseconds. A final bucket holds
+/// everything slower than the last bound
+pub const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000, 1_000_000, 5_000_000,
+];
+
+/// Fixed-bucket histogram of call latencies, see [LATENCY_BUCKETS_MICROS] for the bucket bounds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// One count per bound in [LATENCY_BUCKETS_MICROS], plus the overflow bucket
+    pub counts: Vec<u64>,
+    pub total_micros: u64,
+    pub max_micros: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; LATENCY_BUCKETS_MICROS.len() + 1],
+            total_micros: 0,
+            max_micros: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        self.counts[bucket] += 1;
+        self.total_micros = self.total_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(Duration::from_micros(self.total_micros / count)),
+        }
+    }
+
+    /// Upper bound of the bucket holding the [quantile] (0.0 to 1.0) latency. Latencies in the
+    /// overflow bucket report the slowest latency seen
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= target {
+                let micros = LATENCY_BUCKETS_MICROS
+                    .get(bucket)
+                    .map_or(self.max_micros, |bound| (*bound).min(self.max_micros));
+                return Some(Duration::from_micros(micros));
+            }
+        }
+        None
+    }
+}
+
+/// Counters kept by an [crate::RpcServer] for a single RPC, or by an [crate::RpcClient] for its
+/// calls, see [crate::RpcClient::stats]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+    pub error_latency: LatencyHistogram,
+}
+
+impl RpcStats {
+    pub(crate) fn record(&mut self, succeeded: bool, latency: Duration) {
+        self.calls += 1;
+        self.latency.record(latency);
+        if !succeeded {
+            self.errors += 1;
+            self.error_latency.record(latency);
+        }
+    }
+
+    /// The fraction of calls that failed, [None] before any were made
+    pub fn error_rate(&self) -> Option<f64> {
+        match self.calls {
+            0 => None,
+            calls => Some(self.errors as f64 / calls as f64),
+        }
+    }
+}
+
+/// Snapshot of the counters kept by an [crate::RpcServer].
+/// [rpcs] is keyed by the [std::fmt::Display] form of each RPC name
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub connections: u64,
+    pub rpcs: HashMap<String, RpcStats>,
+}
+
+impl ServerStats {
+    pub(crate) fn record_call(&mut self, rpc: String, succeeded: bool, latency: Duration) {
+        self.rpcs.entry(rpc).or_default().record(succeeded, latency);
+    }
+
+    /// These stats and the gauges of [snapshot], in the Prometheus text exposition format
+    pub fn to_prometheus(&self, snapshot: &ServerSnapshot) -> String {
+        let mut text = String::new();
+        let gauges = [
+            (
+                "pirates_connections_total",
+                "counter",
+                "Connections accepted",
+                self.connections,
+            ),
+            (
+                "pirates_connections",
+                "gauge",
+                "Connections open",
+                snapshot.connections as u64,
+            ),
+            (
+                "pirates_in_flight",
+                "gauge",
+                "Calls received and not yet answered",
+                snapshot.in_flight as u64,
+            ),
+            (
+                "pirates_queued",
+                "gauge",
+                "Calls waiting to acquire the server state",
+                snapshot.queued as u64,
+            ),
+        ];
+        for (name, kind, help, value) in gauges {
+            prometheus_header(&mut text, name, kind, help);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        let mut rpcs: Vec<_> = self
+            .rpcs
+            .iter()
+            .map(|(rpc, stats)| (prometheus_label(rpc), stats))
+            .collect();
+        rpcs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        prometheus_header(&mut text, "pirates_calls_total", "counter", "Calls by rpc");
+        for (rpc, stats) in &rpcs {
+            let _ = writeln!(
+                text,
+                "pirates_calls_total{{rpc=\"{}\"}} {}",
+                rpc, stats.calls
+            );
+        }
+        prometheus_header(
+            &mut text,
+            "pirates_errors_total",
+            "counter",
+            "Failed calls by rpc",
+        );
+        for (rpc, stats) in &rpcs {
+            let _ = writeln!(
+                text,
+                "pirates_errors_total{{rpc=\"{}\"}} {}",
+                rpc, stats.errors
+            );
+        }
+        let histogram = "pirates_call_duration_seconds";
+        prometheus_header(&mut text, histogram, "histogram", "Call latencies by rpc");
+        for (rpc, stats) in &rpcs {
+            let latency = &stats.latency;
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS_MICROS.iter().zip(&latency.counts) {
+                cumulative += count;
+                let le = *bound as f64 / 1e6;
+                let _ = writeln!(
+                    text,
+                    "{}_bucket{{rpc=\"{}\",le=\"{}\"}} {}",
+                    histogram, rpc, le, cumulative
+                );
+            }
+            let count = latency.count();
+            let sum = latency.total_micros as f64 / 1e6;
+            let _ = writeln!(
+                text,
+                "{}_bucket{{rpc=\"{}\",le=\"+Inf\"}} {}",
+                histogram, rpc, count
+            );
+            let _ = writeln!(text, "{}_sum{{rpc=\"{}\"}} {}", histogram, rpc, sum);
+            let _ = writeln!(text, "{}_count{{rpc=\"{}\"}} {}", histogram, rpc, count);
+        }
+        text
+    }
+}
+
+fn prometheus_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// [value] escaped for a Prometheus label
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Live gauges of what an [crate::RpcServer] is doing right now, see [crate::RpcServer::snapshot].
+/// Cheap enough to poll on every request, e.g. for load-shedding decisions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerSnapshot {
+    /// Connections currently open
+    pub connections: usize,
+    /// Calls received and not yet answered, including [queued] ones. A call is answered once its
+    /// response has been sent, all of it for streamed responses
+    pub in_flight: usize,
+    /// Calls waiting to acquire the server state
+    pub queued: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Gauges {
+    pub connections: Gauge,
+    pub in_flight: Gauge,
+    pub queued: Gauge,
+}
+
+impl Gauges {
+    pub fn snapshot(&self) -> ServerSnapshot {
+        ServerSnapshot {
+            connections: self.connections.get(),
+            in_flight: self.in_flight.get(),
+            queued: self.queued.get(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Gauge(AtomicUsize);
+
+impl Gauge {
+    /// Increment the gauge until the returned guard is dropped
+    pub fn enter(&self) -> GaugeGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for micros in [10, 20, 30, 400, 7_000_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.counts[0], 3);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(50)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_micros(500)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn gauges() {
+        let gauges = Gauges::default();
+        let connection = gauges.connections.enter();
+        {
+            let _in_flight = gauges.in_flight.enter();
+            let _queued = gauges.queued.enter();
+            let expected = ServerSnapshot {
+                connections: 1,
+                in_flight: 1,
+                queued: 1,
+            };
+            assert_eq!(gauges.snapshot(), expected);
+        }
+        drop(connection);
+        assert_eq!(gauges.snapshot(), ServerSnapshot::default());
+    }
+}
@@ -0,0 +1,355 @@
+//! Streaming responses and requests, for rpcs whose responses or queries are too large to build
+//! in memory, such as exports of the whole state, or uploads into it.
+//!
+//! An rpc made with [crate::RpcImpl::new_streaming] reads what it needs of the state under its
+//! lock, and returns a future writing the response's raw bytes to a [ResponseWriter] once the
+//! lock is released. The server sends them on as they're written, in chunks of at most
+//! [STREAM_CHUNK_SIZE] bytes, which the client reads with [crate::RpcClient::call_streaming].
+//! The client asks for each chunk after the first, so a slow reader holds up the writer rather
+//! than the server buffering the response
+//!
+//! ```rust,ignore
+//! let export: RpcImpl<_, ServerState, (), ()> = RpcImpl::new_streaming(
+//!     RpcId::Export,
+//!     Box::new(|state, ()| {
+//!         let names = state.names.clone();
+//!         Ok(Box::new(move |mut writer: ResponseWriter| {
+//!             Box::pin(async move {
+//!                 for name in names {
+//!                     writer.send(format!("{}\n", name).as_bytes()).await?;
+//!                 }
+//!                 Ok(())
+//!             })
+//!         }))
+//!     }),
+//! );
+//!
+//! let mut chunks = export_client.call_streaming((), &mut transport).await?;
+//! while let Some(chunk) = chunks.next().await? {
+//!     file.write_all(&chunk).await?;
+//! }
+//! ```
+//!
+//! The other way round, an rpc made with [crate::RpcImpl::new_uploading] is called with its query
+//! under the lock, and returns a future reading the rest of the request from a [RequestReader]
+//! once the lock is released, resolving to the response. The client sends the request's bytes
+//! with [crate::RpcClient::call_uploading], each chunk once the server asks for it
+//!
+//! ```rust,ignore
+//! let import: RpcImpl<_, ServerState, (), usize> = RpcImpl::new_uploading(
+//!     RpcId::Import,
+//!     Box::new(|state, ()| {
+//!         let imports = state.imports.clone();
+//!         Ok(Box::new(move |mut reader: RequestReader| {
+//!             Box::pin(async move {
+//!                 let mut buffer = vec![0; 4096];
+//!                 let mut imported = 0;
+//!                 loop {
+//!                     let read = reader.receive(&mut buffer).await?;
+//!                     if read == 0 {
+//!                         return Ok(imported);
+//!                     }
+//!                     imports.send(buffer[..read].to_vec()).await;
+//!                     imported += read;
+//!                 }
+//!             })
+//!         }))
+//!     }),
+//! );
+//!
+//! let imported = import_client.call_uploading((), file, &mut transport).await?;
+//! ```
+//!
+//! An uploading rpc can take its request as a stream of values rather than raw bytes, sent with
+//! [crate::RpcClient::call_uploading_values] and read one at a time with
+//! [RequestReader::receive_value], such as the rows of a large dataset
+//!
+//! ```rust,ignore
+//! // In the handler's future
+//! while let Some(row) = reader.receive_value::<Row>().await? {
+//!     imports.send(row).await;
+//! }
+//!
+//! let imported = import_client.call_uploading_values((), rows, &mut transport).await?;
+//! ```
+use crate::core::{RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use crate::transport::{
+    InternalTransport, Transport, TransportConfig, TransportError, TransportWireConfig,
+};
+use crate::OwnedBytes;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+/// The most bytes of a streamed response or request sent in one frame, and buffered between the
+/// connection and the handler
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the response of a streaming rpc, see [crate::streaming]. Writes wait while the
+/// connection is behind, and fail once the client has gone
+pub struct ResponseWriter(DuplexStream);
+
+impl ResponseWriter {
+    /// Write all of [bytes] to the response
+    pub async fn send(&mut self, bytes: &[u8]) -> RpcResult<()> {
+        self.0.write_all(bytes).await.map_err(|e| {
+            RpcError::TransportError(TransportError::SendError(format!(
+                "Could not stream the response: {}",
+                e
+            )))
+        })
+    }
+}
+
+impl AsyncWrite for ResponseWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Writes a streaming rpc's response, once the state's lock is released
+pub type WriteResponse = Pin<Box<dyn Future<Output = RpcResult<()>> + Send>>;
+
+/// What a streaming rpc's handler returns: given the [ResponseWriter], the future writing to it
+pub type StreamResponse = Box<dyn FnOnce(ResponseWriter) -> WriteResponse + Send>;
+
+/// A streaming rpc's response, with the chunks its writer has written so far
+pub struct ResponseStream {
+    pub(crate) write: WriteResponse,
+    /// Ends once [Self::write] is done and has dropped its writer
+    pub(crate) written: DuplexStream,
+}
+
+impl ResponseStream {
+    pub(crate) fn new(stream_response: StreamResponse) -> Self {
+        let (writer, written) = tokio::io::duplex(STREAM_CHUNK_SIZE);
+        Self {
+            write: stream_response(ResponseWriter(writer)),
+            written,
+        }
+    }
+}
+
+/// The chunks of a streamed response as they arrive, see [crate::RpcClient::call_streaming].
+/// Dropped before the last is read, the rest are cancelled before the transport's next call
+pub struct ResponseChunks<'a, I: InternalTransport, Name: RpcName> {
+    transport: &'a mut Transport<I, Name>,
+    rpc: String,
+    /// The number the query was sent with, which each chunk must answer
+    sequence: Option<u64>,
+    /// Received along with the answer to the query
+    first: Option<OwnedBytes>,
+    done: bool,
+}
+
+impl<'a, I: InternalTransport, Name: RpcName> ResponseChunks<'a, I, Name> {
+    pub(crate) fn new(
+        transport: &'a mut Transport<I, Name>,
+        rpc: String,
+        sequence: Option<u64>,
+        first: Option<OwnedBytes>,
+    ) -> Self {
+        Self {
+            transport,
+            rpc,
+            sequence,
+            done: first.is_none(),
+            first,
+        }
+    }
+
+    /// The next chunk of the response, or [None] once it has all been read. Chunks are cut as
+    /// the server sends them, not as the handler wrote them
+    pub async fn next(&mut self) -> RpcResult<Option<OwnedBytes>> {
+        if let Some(first) = self.first.take() {
+            return Ok(Some(first));
+        }
+        if self.done {
+            return Ok(None);
+        }
+        let chunk = self.transport.receive_chunk(&self.rpc, self.sequence).await;
+        self.done = !matches!(chunk, Ok(Some(_)));
+        chunk
+    }
+
+    /// Read the rest of the response into one buffer
+    pub async fn collect(mut self) -> RpcResult<OwnedBytes> {
+        let mut response = OwnedBytes::new();
+        while let Some(chunk) = self.next().await? {
+            response.extend_from_slice(&chunk);
+        }
+        Ok(response)
+    }
+}
+
+impl<I: InternalTransport, Name: RpcName> Drop for ResponseChunks<'_, I, Name> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.transport
+                .abandon_stream(std::mem::take(&mut self.rpc), self.sequence);
+        }
+    }
+}
+
+/// Bytes before each value of a request sent with [crate::RpcClient::call_uploading_values],
+/// holding its length
+const VALUE_PREFIX_LEN: usize = 4;
+
+/// Reads the request of an uploading rpc, see [crate::streaming]. Reads wait for the client to
+/// send more, and end once it has sent it all
+pub struct RequestReader {
+    body: DuplexStream,
+    wire_config: TransportWireConfig,
+    max_message_size: Option<usize>,
+}
+
+impl RequestReader {
+    /// Read some of the request into [buffer], returning how much, 0 once it has all been read
+    pub async fn receive(&mut self, buffer: &mut [u8]) -> RpcResult<usize> {
+        self.body.read(buffer).await.map_err(read_error)
+    }
+
+    /// Read the next value of a request sent with [crate::RpcClient::call_uploading_values],
+    /// [None] once they have all been read. Values over [TransportConfig::max_message_size] are
+    /// refused with [RpcError::MessageTooLarge]
+    pub async fn receive_value<T: RpcType>(&mut self) -> RpcResult<Option<T>> {
+        let mut prefix = [0; VALUE_PREFIX_LEN];
+        let mut read = 0;
+        while read < prefix.len() {
+            match self.receive(&mut prefix[read..]).await? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(read_error("The request ended within a value")),
+                n => read += n,
+            }
+        }
+        let len = u32::from_be_bytes(prefix) as usize;
+        if let Some(limit) = self.max_message_size.filter(|limit| len > *limit) {
+            return Err(RpcError::MessageTooLarge { limit });
+        }
+        let mut value = vec![0; len];
+        self.body.read_exact(&mut value).await.map_err(read_error)?;
+        Ok(Some(self.wire_config.deserialize(&value)?))
+    }
+}
+
+fn read_error(e: impl std::fmt::Display) -> RpcError {
+    RpcError::TransportError(TransportError::ReceiveError(format!(
+        "Could not read the streamed request: {}",
+        e
+    )))
+}
+
+impl AsyncRead for RequestReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+/// The body of a request sent with [crate::RpcClient::call_uploading_values]: each of [values]
+/// after its length, serialised as the server reads up to it
+pub(crate) struct ValuesBody<I> {
+    values: I,
+    wire_config: TransportWireConfig,
+    /// The value being read, with its length
+    buffer: OwnedBytes,
+    read: usize,
+}
+
+impl<I> ValuesBody<I> {
+    pub(crate) fn new(values: I, wire_config: TransportWireConfig) -> Self {
+        Self {
+            values,
+            wire_config,
+            buffer: OwnedBytes::new(),
+            read: 0,
+        }
+    }
+}
+
+impl<I: Iterator + Unpin> AsyncRead for ValuesBody<I>
+where
+    I::Item: Serialize,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let body = &mut *self;
+        // Filling as much of [buf] as there are values for, so small values share chunks
+        while buf.remaining() > 0 {
+            if body.read == body.buffer.len() {
+                let Some(value) = body.values.next() else {
+                    break;
+                };
+                body.buffer.clear();
+                body.buffer.extend_from_slice(&[0; VALUE_PREFIX_LEN]);
+                body.wire_config
+                    .serialize_into(&value, &mut body.buffer)
+                    .map_err(std::io::Error::other)?;
+                let len = u32::try_from(body.buffer.len() - VALUE_PREFIX_LEN)
+                    .map_err(|_| std::io::Error::other("Value too large to stream"))?;
+                body.buffer[..VALUE_PREFIX_LEN].copy_from_slice(&len.to_be_bytes());
+                body.read = 0;
+            }
+            let unread = &body.buffer[body.read..];
+            let n = unread.len().min(buf.remaining());
+            buf.put_slice(&unread[..n]);
+            body.read += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads an uploading rpc's request, once the state's lock is released, resolving to its
+/// response
+pub type ReadRequest<R> = Pin<Box<dyn Future<Output = RpcResult<R>> + Send>>;
+
+/// What an uploading rpc's handler returns: given the [RequestReader], the future reading from it
+pub type StreamRequest<R> = Box<dyn FnOnce(RequestReader) -> ReadRequest<R> + Send>;
+
+/// An uploading rpc's call, waiting on the rest of its request
+pub struct RequestStream {
+    /// Resolves to the serialised response
+    pub(crate) read: ReadRequest<OwnedBytes>,
+    /// Where the request's chunks are written as they arrive, dropped at its end
+    pub(crate) body: DuplexStream,
+}
+
+impl RequestStream {
+    pub(crate) fn new<R: RpcType>(
+        stream_request: StreamRequest<R>,
+        transport_config: &TransportConfig,
+    ) -> Self {
+        let (body, reader) = tokio::io::duplex(STREAM_CHUNK_SIZE);
+        let wire_config = transport_config.wire_config.clone();
+        let read = stream_request(RequestReader {
+            body: reader,
+            wire_config: wire_config.clone(),
+            max_message_size: transport_config.max_message_size,
+        });
+        Self {
+            read: Box::pin(async move { Ok(wire_config.serialize(&read.await?)?) }),
+            body,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::client::RpcClient;
+use crate::core::{RpcName, RpcType};
+use crate::error::RpcError;
+use crate::tasks;
+use crate::transport::{TcpTransport, Transport};
+use crate::OwnedBytes;
+use log::info;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// What a [Subscription] yields
+#[derive(Debug)]
+pub enum SubscriptionEvent<R> {
+    Item(R),
+    /// The connection dropped with [error] and items may have been missed before it was
+    /// re-established. Sent once per drop, before the first item after reconnecting
+    Gap {
+        error: RpcError,
+    },
+    /// The rpc failed with [error], which reconnecting can't fix (see [RpcError::is_retryable]),
+    /// so the subscription has stopped. Always the last event
+    Ended {
+        error: RpcError,
+    },
+}
+
+/// Tuning for [subscribe]
+#[derive(Clone, Copy, Debug)]
+pub struct SubscriptionConfig {
+    /// Pause between polls of the rpc
+    pub poll_interval: Duration,
+    /// Pause between attempts to re-establish a dropped connection
+    pub reconnect_delay: Duration,
+    /// Number of events buffered before polling waits on the application
+    pub buffer: usize,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(500),
+            reconnect_delay: Duration::from_secs(1),
+            buffer: 16,
+        }
+    }
+}
+
+/// A unified stream of responses from a subscribed rpc, see [subscribe]. Dropping it stops the
+/// background task
+pub struct Subscription<R> {
+    events: mpsc::Receiver<SubscriptionEvent<R>>,
+    task: JoinHandle<()>,
+}
+
+impl<R> Subscription<R> {
+    /// Wait for the next event, only [None] if the background task has stopped
+    pub async fn next(&mut self) -> Option<SubscriptionEvent<R>> {
+        self.events.recv().await
+    }
+}
+
+impl<R> Drop for Subscription<R> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Subscribe to [rpc_client]'s rpc on the server at [addr]: the rpc is called with [query] over
+/// one persistent connection every [SubscriptionConfig::poll_interval], and each response is
+/// delivered as a [SubscriptionEvent::Item].
+///
+/// When the connection drops, it is re-established (firing [crate::ClientEvents::on_reconnect])
+/// and the subscription resumes with the same query, with a [SubscriptionEvent::Gap] marking
+/// where items may have been missed. Errors reconnecting can't fix end the subscription with a
+/// [SubscriptionEvent::Ended]. Subscriptions are poll based as the protocol is request and
+/// response only, so rpcs suited to it return what changed since the last call (or block until
+/// something does, within the client's receive timeout). See [subscribe_streaming] for rpcs
+/// streaming their responses
+pub fn subscribe<Name, Q, R>(
+    addr: impl Into<String>,
+    rpc_client: RpcClient<Name, Q, R>,
+    query: Q,
+    config: SubscriptionConfig,
+) -> Subscription<R>
+where
+    Name: RpcName + Send + Sync + 'static,
+    Q: RpcType + Send + Sync,
+    R: RpcType + Send + Sync,
+{
+    let addr = addr.into();
+    let (sender, events) = mpsc::channel(config.buffer.max(1));
+    let mut subscriber = Subscriber::new(addr, rpc_client, config, sender);
+    let task = tasks::spawn(&subscriber.task_name(), async move {
+        while let Some(mut transport) = subscriber.connect().await {
+            loop {
+                match subscriber
+                    .rpc_client
+                    .call(query.clone(), &mut transport)
+                    .await
+                {
+                    Ok(response) => {
+                        if !subscriber.send(SubscriptionEvent::Item(response)).await {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        if !subscriber.dropped(e).await {
+                            return;
+                        }
+                        break;
+                    }
+                }
+                tokio::time::sleep(config.poll_interval).await;
+            }
+            tokio::time::sleep(config.reconnect_delay).await;
+        }
+    });
+    Subscription { events, task }
+}
+
+/// [subscribe] to a streaming rpc (see [crate::RpcImpl::new_streaming]): it is called with
+/// [query] once per connection with [RpcClient::call_streaming], and each chunk of its response
+/// is delivered as a [SubscriptionEvent::Item] as it arrives. Chunks are cut as the server sends
+/// them, so rpcs should frame their items themselves, e.g. one per line. When the response ends
+/// the rpc is called again after [SubscriptionConfig::poll_interval], and when the connection
+/// drops it is re-established and called again, with a [SubscriptionEvent::Gap]
+pub fn subscribe_streaming<Name, Q, R>(
+    addr: impl Into<String>,
+    rpc_client: RpcClient<Name, Q, R>,
+    query: Q,
+    config: SubscriptionConfig,
+) -> Subscription<OwnedBytes>
+where
+    Name: RpcName + Send + Sync + 'static,
+    Q: RpcType + Send + Sync,
+    R: RpcType + Send + Sync,
+{
+    let addr = addr.into();
+    let (sender, events) = mpsc::channel(config.buffer.max(1));
+    let mut subscriber = Subscriber::new(addr, rpc_client, config, sender);
+    let task = tasks::spawn(&subscriber.task_name(), async move {
+        while let Some(mut transport) = subscriber.connect().await {
+            loop {
+                let streamed = subscriber
+                    .rpc_client
+                    .call_streaming(query.clone(), &mut transport)
+                    .await;
+                let result = match streamed {
+                    Ok(mut chunks) => loop {
+                        match chunks.next().await {
+                            Ok(Some(chunk)) => {
+                                if !subscriber.send(SubscriptionEvent::Item(chunk)).await {
+                                    return;
+                                }
+                            }
+                            Ok(None) => break Ok(()),
+                            Err(e) => break Err(e),
+                        }
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    if !subscriber.dropped(e).await {
+                        return;
+                    }
+                    break;
+                }
+                tokio::time::sleep(config.poll_interval).await;
+            }
+            tokio::time::sleep(config.reconnect_delay).await;
+        }
+    });
+    Subscription { events, task }
+}
+
+/// The background task of a [Subscription], (re)connecting and sending its events
+struct Subscriber<Name: RpcName, Q: RpcType, R: RpcType, T> {
+    addr: String,
+    rpc_client: RpcClient<Name, Q, R>,
+    config: SubscriptionConfig,
+    sender: mpsc::Sender<SubscriptionEvent<T>>,
+    /// Why the connection dropped, for the [SubscriptionEvent::Gap] once it's re-established
+    dropped_with: Option<RpcError>,
+}
+
+impl<Name, Q, R, T> Subscriber<Name, Q, R, T>
+where
+    Name: RpcName + Send + Sync + 'static,
+    Q: RpcType + Send + Sync,
+    R: RpcType + Send + Sync,
+{
+    fn new(
+        addr: String,
+        rpc_client: RpcClient<Name, Q, R>,
+        config: SubscriptionConfig,
+        sender: mpsc::Sender<SubscriptionEvent<T>>,
+    ) -> Self {
+        Self {
+            addr,
+            rpc_client,
+            config,
+            sender,
+            dropped_with: None,
+        }
+    }
+
+    fn task_name(&self) -> String {
+        format!("pirates subscription {}", self.addr)
+    }
+
+    /// Send [event] to the application, false once it has dropped the [Subscription]
+    async fn send(&self, event: SubscriptionEvent<T>) -> bool {
+        self.sender.send(event).await.is_ok()
+    }
+
+    /// A connection to the server, retrying until one is made, sending the
+    /// [SubscriptionEvent::Gap] if it replaces one that dropped. [None] once the subscription
+    /// has stopped
+    async fn connect(&mut self) -> Option<Transport<TcpTransport, Name>> {
+        loop {
+            match self.rpc_client.connect(&self.addr).await {
+                Ok(transport) => {
+                    if let Some(error) = self.dropped_with.take() {
+                        info!("Subscription to {} reconnected", self.addr);
+                        self.rpc_client.fire_reconnect(&self.addr);
+                        if !self.send(SubscriptionEvent::Gap { error }).await {
+                            return None;
+                        }
+                    }
+                    return Some(transport);
+                }
+                Err(e) => {
+                    if !self.dropped(e).await {
+                        return None;
+                    }
+                }
+            }
+            tokio::time::sleep(self.config.reconnect_delay).await;
+        }
+    }
+
+    /// Whether the subscription carries on after [e]. Those reconnecting might fix are kept for
+    /// the [SubscriptionEvent::Gap], any other ends it with a [SubscriptionEvent::Ended]
+    async fn dropped(&mut self, e: RpcError) -> bool {
+        if e.is_retryable() || matches!(e, RpcError::TransportError(_)) {
+            info!("Subscription to {} dropped: {}", self.addr, e);
+            self.dropped_with.get_or_insert(e);
+            return true;
+        }
+        info!("Subscription to {} ended: {}", self.addr, e);
+        let _ = self.send(SubscriptionEvent::Ended { error: e }).await;
+        false
+    }
+}
@@ -0,0 +1,51 @@
+//! Spawning of the tasks pirates runs in the background, named for tokio-console (Enable the
+//! "tokio_console" feature, and build with `RUSTFLAGS="--cfg tokio_unstable"` as
+//! console-subscriber requires).
+//!
+//! With both, tasks show up in tokio-console by what they do, e.g. "pirates connection
+//! 10.0.0.7:51234" for the task serving a client, with the call site spawning them. Without
+//! either, tasks are spawned as usual
+use std::future::Future;
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+
+/// [tokio::spawn] [future] as a task named [name]
+#[track_caller]
+pub(crate) fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(tokio_unstable, feature = "tokio_console"))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("Tasks are spawned from within a runtime")
+    }
+    #[cfg(not(all(tokio_unstable, feature = "tokio_console")))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// [JoinSet::spawn] [future] onto [set] as a task named [name]
+#[track_caller]
+pub(crate) fn spawn_in<T, F>(set: &mut JoinSet<T>, name: &str, future: F) -> AbortHandle
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    #[cfg(all(tokio_unstable, feature = "tokio_console"))]
+    {
+        set.build_task()
+            .name(name)
+            .spawn(future)
+            .expect("Tasks are spawned from within a runtime")
+    }
+    #[cfg(not(all(tokio_unstable, feature = "tokio_console")))]
+    {
+        let _ = name;
+        set.spawn(future)
+    }
+}
@@ -0,0 +1,364 @@
+//! TLS connections between clients and servers, over [tokio_rustls] (Enable the
+//! "transport_tls" feature).
+//!
+//! A server serves with [crate::RpcServer::serve_tls] and clients connect with
+//! [crate::RpcClient::connect_tls]. Both sides negotiate [ALPN_PROTOCOL] by default, so pirates
+//! can share a port (e.g. 443) with other protocols behind a load balancer routing on ALPN, and
+//! a client can send a SNI hostname other than the one it connects to, for those routing on SNI.
+//!
+//! To move a plaintext deployment to TLS without changing its port, serve with
+//! [crate::RpcServer::serve_starttls]: connections start in plaintext and are upgraded to TLS
+//! for clients that ask with [crate::RpcClient::connect_starttls], which carry on in plaintext
+//! with servers that can't upgrade
+//!
+//! ```rust,ignore
+//! let mut tls_config = TlsClientConfig::new(Arc::new(rustls_client_config));
+//! // Connect to the load balancer's address, asking to be routed to names.example.com
+//! tls_config.server_name = Some(String::from("names.example.com"));
+//! let mut transport = rpc_client.connect_tls("10.0.0.1:443", &tls_config).await?;
+//! ```
+//!
+//! For mutual TLS, require client certificates with the server's rustls client verifier, and
+//! name who each certificate belongs to with [TlsServerConfig::client_identity]. Connections
+//! presenting one are served as that [Identity] without the authentication handshake, seen by
+//! [crate::AccessPolicy], interceptors and handlers (see [crate::caller])
+//!
+//! ```rust,ignore
+//! let verifier = WebPkiClientVerifier::builder(Arc::new(client_roots)).build()?;
+//! let rustls_server_config = rustls::ServerConfig::builder()
+//!     .with_client_cert_verifier(verifier)
+//!     .with_single_cert(server_certs, server_key)?;
+//! let mut tls_config = TlsServerConfig::new(Arc::new(rustls_server_config));
+//! tls_config.client_identity = Some(Arc::new(|cert| identities.get(cert.as_ref()).cloned()));
+//! ```
+use crate::auth::Identity;
+use crate::error::{RpcError, RpcResult};
+use crate::listener::Listener;
+use crate::transport::{self, InternalTransport, TcpTransport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+pub use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::TlsStream;
+
+/// ALPN protocol identifying pirates traffic
+pub const ALPN_PROTOCOL: &[u8] = b"pirates";
+
+/// Client side TLS settings for [crate::RpcClient::connect_tls]
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    /// Certificate verification and client authentication settings. Its ALPN protocols are
+    /// replaced with [Self::alpn_protocols]
+    pub rustls: Arc<rustls::ClientConfig>,
+    /// Name to send with SNI and verify the server's certificate against, the host connected to
+    /// if [None]. Set when connecting through an address other than the server's name, such as
+    /// that of a SNI-routing load balancer
+    pub server_name: Option<String>,
+    /// Protocols offered with ALPN, most preferred first
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl TlsClientConfig {
+    pub fn new(rustls: Arc<rustls::ClientConfig>) -> Self {
+        Self {
+            rustls,
+            server_name: None,
+            alpn_protocols: vec![ALPN_PROTOCOL.to_vec()],
+        }
+    }
+}
+
+/// Who a client is by the certificate it presented, already verified by the server's rustls
+/// client verifier. [None] leaves the client to authenticate with the handshake
+pub type ClientCertIdentity = Arc<dyn Fn(&CertificateDer<'_>) -> Option<Identity> + Send + Sync>;
+
+/// Server side TLS settings for [crate::RpcServer::serve_tls]
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    /// Certificate and client verification settings. Its ALPN protocols are replaced with
+    /// [Self::alpn_protocols]
+    pub rustls: Arc<rustls::ServerConfig>,
+    /// Protocols accepted with ALPN, most preferred first. Clients offering none of them are
+    /// refused, though clients not using ALPN at all are accepted
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Names the clients presenting certificates, for mutual TLS, see [crate::tls]
+    pub client_identity: Option<ClientCertIdentity>,
+}
+
+impl TlsServerConfig {
+    pub fn new(rustls: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            rustls,
+            alpn_protocols: vec![ALPN_PROTOCOL.to_vec()],
+            client_identity: None,
+        }
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        let mut rustls = (*self.rustls).clone();
+        rustls.alpn_protocols = self.alpn_protocols.clone();
+        TlsAcceptor {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(rustls)),
+            client_identity: self.client_identity.clone(),
+        }
+    }
+}
+
+/// Runs the server side of handshakes with a [TlsServerConfig]
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    acceptor: tokio_rustls::TlsAcceptor,
+    client_identity: Option<ClientCertIdentity>,
+}
+
+/// Implementation of [InternalTransport] over TLS, see [crate::tls]
+pub struct TlsTransport {
+    stream: TlsStream<TcpStream>,
+    write_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+    /// On the server, who the client is by its certificate, see [TlsServerConfig::client_identity]
+    client_identity: Option<Identity>,
+}
+
+impl TlsTransport {
+    /// Fail sends that take longer than [write_timeout], see [TcpTransport::set_write_timeout]
+    ///
+    /// [TcpTransport::set_write_timeout]: crate::TcpTransport::set_write_timeout
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    /// Enable TCP keepalive, probing the peer once the connection has been idle for [idle]
+    pub fn set_keepalive(&self, idle: Duration) -> Result<(), TransportError> {
+        transport::set_keepalive(self.tcp_stream(), idle)
+    }
+
+    /// The protocol agreed with ALPN, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.stream {
+            TlsStream::Client(stream) => stream.get_ref().1.alpn_protocol(),
+            TlsStream::Server(stream) => stream.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    /// The SNI hostname the client sent, only known on the server
+    pub fn sni_hostname(&self) -> Option<&str> {
+        match &self.stream {
+            TlsStream::Client(_) => None,
+            TlsStream::Server(stream) => stream.get_ref().1.server_name(),
+        }
+    }
+
+    /// The certificates the peer presented, its own first, once verified
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match &self.stream {
+            TlsStream::Client(stream) => stream.get_ref().1.peer_certificates(),
+            TlsStream::Server(stream) => stream.get_ref().1.peer_certificates(),
+        }
+    }
+
+    fn tcp_stream(&self) -> &TcpStream {
+        self.stream.get_ref().0
+    }
+}
+
+#[async_trait]
+impl InternalTransport for TlsTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        transport::send_with_timeout(&mut self.stream, b, self.write_timeout).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        transport::receive_with_timeout(&mut self.stream, timeout, self.max_message_size).await
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        transport::peer_disconnected(self.tcp_stream())
+    }
+
+    fn peer_identity(&self) -> Option<Identity> {
+        self.client_identity.clone()
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.max_message_size = max_message_size;
+    }
+}
+
+/// A [Listener] accepting TLS connections, for [crate::RpcServer::serve_listener]. Handshakes
+/// must complete within [TransportConfig::connect_timeout]
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, tls_config: &TlsServerConfig) -> Self {
+        Self {
+            listener,
+            acceptor: tls_config.acceptor(),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for TlsListener {
+    type Stream = TcpStream;
+    type Transport = TlsTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        Listener::poll_accept(&self.listener, cx)
+    }
+
+    async fn transport(
+        &self,
+        tcp_stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<TlsTransport> {
+        accept(&self.acceptor, tcp_stream, transport_config).await
+    }
+}
+
+/// The [InternalTransport] of a connection made with [crate::RpcClient::connect_starttls],
+/// upgraded to TLS if the server agreed
+pub enum MaybeTlsTransport {
+    Plain(TcpTransport),
+    Tls(Box<TlsTransport>),
+}
+
+impl MaybeTlsTransport {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+}
+
+#[async_trait]
+impl InternalTransport for MaybeTlsTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        match self {
+            Self::Plain(transport) => transport.send(b).await,
+            Self::Tls(transport) => transport.send(b).await,
+        }
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        match self {
+            Self::Plain(transport) => transport.send_and_wait_for_response(b, timeout).await,
+            Self::Tls(transport) => transport.send_and_wait_for_response(b, timeout).await,
+        }
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match self {
+            Self::Plain(transport) => transport.receive(timeout).await,
+            Self::Tls(transport) => transport.receive(timeout).await,
+        }
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        match self {
+            Self::Plain(transport) => transport.peer_disconnected(),
+            Self::Tls(transport) => transport.peer_disconnected(),
+        }
+    }
+
+    fn peer_identity(&self) -> Option<Identity> {
+        match self {
+            Self::Plain(transport) => transport.peer_identity(),
+            Self::Tls(transport) => transport.peer_identity(),
+        }
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        match self {
+            Self::Plain(transport) => transport.set_max_message_size(max_message_size),
+            Self::Tls(transport) => transport.set_max_message_size(max_message_size),
+        }
+    }
+}
+
+/// Run the client side of the handshake over [tcp_stream], connected to [addr]
+pub(crate) async fn connect(
+    tls_config: &TlsClientConfig,
+    addr: &str,
+    tcp_stream: TcpStream,
+) -> std::io::Result<TlsTransport> {
+    let server_name = match &tls_config.server_name {
+        Some(server_name) => server_name.clone(),
+        None => crate::resolver::host_of(addr).to_string(),
+    };
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}", e)))?;
+    let mut rustls = (*tls_config.rustls).clone();
+    rustls.alpn_protocols = tls_config.alpn_protocols.clone();
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(rustls))
+        .connect(server_name, tcp_stream)
+        .await?;
+    Ok(TlsTransport {
+        stream: TlsStream::Client(stream),
+        write_timeout: None,
+        max_message_size: None,
+        client_identity: None,
+    })
+}
+
+/// Run the server side of the handshake over [tcp_stream], within
+/// [TransportConfig::connect_timeout], and apply the rest of [transport_config]
+pub(crate) async fn accept(
+    acceptor: &TlsAcceptor,
+    tcp_stream: TcpStream,
+    transport_config: &TransportConfig,
+) -> RpcResult<TlsTransport> {
+    let handshake_error = |message: String| {
+        RpcError::TransportError(TransportError::ConnectError(format!(
+            "TLS handshake failed: {}",
+            message
+        )))
+    };
+    let accept_fut = acceptor.acceptor.accept(tcp_stream);
+    let accepted = match transport_config.connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, accept_fut)
+            .await
+            .map_err(|_| handshake_error(format!("Timed out after {:?}", connect_timeout)))?,
+        None => accept_fut.await,
+    };
+    let stream = accepted.map_err(|e| handshake_error(e.to_string()))?;
+    let client_identity = match (
+        &acceptor.client_identity,
+        stream.get_ref().1.peer_certificates(),
+    ) {
+        (Some(client_identity), Some([cert, ..])) => client_identity(cert),
+        _ => None,
+    };
+    let tls_transport = TlsTransport {
+        stream: TlsStream::Server(stream),
+        write_timeout: transport_config.write_timeout,
+        max_message_size: None,
+        client_identity,
+    };
+    if let Some(keepalive) = transport_config.keepalive {
+        tls_transport.set_keepalive(keepalive)?;
+    }
+    Ok(tls_transport)
+}
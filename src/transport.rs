@@ -1,14 +1,26 @@
+use crate::admin::AdminRpcName;
+use crate::auth::{ClientAuthenticator, Identity};
 use crate::core::RpcName;
-use crate::error::{RpcError, RpcResult};
+use crate::error::{RemoteError, RpcError, RpcResult, StatusCode};
+use crate::metadata::Metadata;
+#[cfg(feature = "payload_encryption")]
+use crate::payload_encryption::{Direction, PayloadKeys};
+#[cfg(feature = "response_signing")]
+use crate::signing::ed25519_dalek::{SigningKey, VerifyingKey};
+use crate::streaming::{RequestStream, ResponseChunks, ResponseStream, STREAM_CHUNK_SIZE};
 
-use crate::transport::TransportError::SerialiseError;
 use crate::{Bytes, OwnedBytes};
 use async_trait::async_trait;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
 use std::marker::PhantomData;
+#[cfg(any(feature = "payload_encryption", feature = "response_signing"))]
+use std::sync::Arc;
 use std::time::Duration;
+#[cfg(feature = "call_trace")]
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 /// Errors specific to transport
 #[derive(Debug)]
@@ -22,9 +34,15 @@ pub enum TransportError {
     /// Error from timeout after waiting some [Duration].
     ReceiveTimeout(Duration),
     // Error when serialising data
-    SerialiseError(String),
+    SerialiseError(CodecError),
     // Error when deserialising data
-    DeserialiseError(String),
+    DeserialiseError(CodecError),
+    /// A query or response numbered [received] arrived when [expected] was due, so one was lost
+    /// or reordered on the way, see [TransportConfig::sequence_numbers]
+    OutOfSequence {
+        expected: u64,
+        received: u64,
+    },
 }
 impl std::fmt::Display for TransportError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -33,21 +51,51 @@ impl std::fmt::Display for TransportError {
             TransportError::ReceiveError(s) => write!(f, "ReceiveError({})", s),
             TransportError::ConnectError(s) => write!(f, "ConnectError({})", s),
             TransportError::ReceiveTimeout(dur) => write!(f, "ReceiveTimeout({:?})", dur),
-            TransportError::SerialiseError(s) => write!(f, "SerialiseError({})", s),
-            TransportError::DeserialiseError(s) => write!(f, "DeserialiseError({})", s),
+            TransportError::SerialiseError(e) => write!(f, "SerialiseError({})", e),
+            TransportError::DeserialiseError(e) => write!(f, "DeserialiseError({})", e),
+            TransportError::OutOfSequence { expected, received } => write!(
+                f,
+                "OutOfSequence(expected {}, received {})",
+                expected, received
+            ),
         }
     }
 }
 impl std::error::Error for TransportError {}
 impl TransportError {
-    fn io_send(e: std::io::Error) -> Self {
-        Self::SendError(format!("{:?}", e))
+    /// Connection level failures are worth retrying, (de)serialisation failures are not
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::SendError(_)
+            | Self::ReceiveError(_)
+            | Self::ConnectError(_)
+            | Self::ReceiveTimeout(_)
+            // Reconnecting starts the sequence afresh
+            | Self::OutOfSequence { .. } => true,
+            Self::SerialiseError(_) | Self::DeserialiseError(_) => false,
+        }
     }
-    fn io_receive(e: std::io::Error) -> Self {
+    pub(crate) fn io_receive(e: std::io::Error) -> Self {
         Self::ReceiveError(format!("{:?}", e))
     }
 }
 
+/// Context for a failure in one of the [TransportWireConfig] codecs, the direction is given by
+/// the [TransportError] variant holding it
+#[derive(Debug)]
+pub struct CodecError {
+    /// Wire format in use, see [TransportWireConfig::format_name]
+    pub format: &'static str,
+    /// Type being (de)serialised
+    pub type_name: &'static str,
+    pub message: String,
+}
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.format, self.type_name, self.message)
+    }
+}
+
 /// The [InternalTransport] trait defines the transport layer for RPCs between client and server
 #[async_trait]
 pub trait InternalTransport {
@@ -67,25 +115,509 @@ pub trait InternalTransport {
 
     /// async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError>;
     async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError>;
+
+    /// Whether the peer is known to have gone away, checked by the server before responding so
+    /// nothing is written to a dead connection. Defaults to false, i.e. unknown
+    fn peer_disconnected(&mut self) -> bool {
+        false
+    }
+
+    /// Who the peer is by the connection itself, such as by its TLS client certificate (see
+    /// [crate::tls]), so it needn't authenticate with the handshake. Defaults to [None]
+    fn peer_identity(&self) -> Option<Identity> {
+        None
+    }
+
+    /// Stop reading a message once it's over [max_message_size] bytes, rather than buffering it
+    /// all, see [TransportConfig::max_message_size]. Applied by [Transport::new]. Transports
+    /// whose messages are in memory already needn't bother, as is the default
+    fn set_max_message_size(&mut self, _max_message_size: Option<usize>) {}
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct TransportPackage<'a> {
-    #[serde(borrow)]
+    #[serde(serialize_with = "payload::serialize")]
     name_bytes: Bytes<'a>,
-    #[serde(borrow)]
+    #[serde(serialize_with = "payload::serialize")]
     query_bytes: Bytes<'a>,
+    /// Set when [name_bytes] is in the reserved namespace, see [RpcName::RESERVED]
+    reserved: bool,
+    /// See [crate::type_hash]
+    type_hash: Option<u64>,
+    /// See [crate::RpcClient::call_versioned]
+    version_check: Option<VersionCheck>,
+    /// See [crate::RpcClient::call_idempotent]
+    idempotency_key: Option<&'a str>,
+    /// See [TransportConfig::sequence_numbers]
+    sequence: Option<u64>,
+    /// See [crate::RpcClient::call_uploading]
+    streamed_request: bool,
+    /// See [Metadata]
+    metadata: &'a Metadata,
+    /// See [crate::RpcClient::call_oneway]
+    oneway: bool,
 }
 #[derive(Serialize, Deserialize)]
 struct TransportPackageOwned {
+    #[serde(with = "payload")]
+    name_bytes: OwnedBytes,
+    #[serde(with = "payload")]
+    query_bytes: OwnedBytes,
+    #[serde(default)]
+    reserved: bool,
+    #[serde(default)]
+    type_hash: Option<u64>,
+    #[serde(default)]
+    version_check: Option<VersionCheck>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    #[serde(default)]
+    sequence: Option<u64>,
+    #[serde(default)]
+    streamed_request: bool,
+    #[serde(default)]
+    metadata: Metadata,
+    #[serde(default)]
+    oneway: bool,
+}
+
+/// What a query asks of the server's state version, see [crate::RpcClient::call_versioned]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum VersionCheck {
+    /// Only report the version the call left the state at
+    Report,
+    /// Fail with [RpcError::Conflict] unless the state is at this version
+    Expect(u64),
+}
+
+/// What a query asks of the server besides calling its rpc
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct QueryOptions {
+    /// See [crate::RpcClient::call_versioned]
+    pub(crate) version_check: Option<VersionCheck>,
+    /// See [crate::RpcClient::call_idempotent]
+    pub(crate) idempotency_key: Option<String>,
+    /// See [crate::RpcClient::call_uploading]
+    pub(crate) streamed_request: bool,
+    /// See [Metadata]
+    pub(crate) metadata: Metadata,
+    /// See [crate::RpcClient::call_oneway]
+    pub(crate) oneway: bool,
+}
+
+/// The query package of [TransportCompat::V0], sent as it is rather than wrapped in a frame
+#[derive(Serialize)]
+struct TransportPackageV0<'a> {
+    name_bytes: Bytes<'a>,
+    query_bytes: Bytes<'a>,
+}
+#[derive(Deserialize)]
+struct TransportPackageV0Owned {
     name_bytes: OwnedBytes,
     query_bytes: OwnedBytes,
 }
 
+/// Everything a client sends is one of these frames: a query, or a heartbeat keeping an idle
+/// connection alive, or a request to upgrade the connection to TLS, or a step of the
+/// authentication handshake (see [crate::Authenticator]), or a request for more of a streamed
+/// response (see [crate::streaming])
+#[derive(Serialize)]
+enum RequestFrame<'a> {
+    Query(TransportPackage<'a>),
+    Heartbeat,
+    #[cfg_attr(not(feature = "transport_tls"), allow(dead_code))]
+    StartTls,
+    AuthStart,
+    AuthResponse(#[serde(serialize_with = "payload::serialize")] Bytes<'a>),
+    /// Answered with the next [ResponseFrame::Chunk] of the response being streamed, or
+    /// [ResponseFrame::StreamEnd] after the last
+    NextChunk,
+    /// Answered with [ResponseFrame::StreamEnd], the rest of the response left unsent. Sent
+    /// while streaming a request, answered with the error that it was cancelled
+    CancelStream,
+    /// A piece of a streamed request, answered with [ResponseFrame::NextUploadChunk] or, once
+    /// the rpc has read all it needs, its response
+    UploadChunk(#[serde(serialize_with = "payload::serialize")] Bytes<'a>),
+    /// Follows the last [Self::UploadChunk] of a streamed request, answered with the response
+    UploadEnd,
+}
+#[derive(Deserialize)]
+enum RequestFrameOwned {
+    Query(TransportPackageOwned),
+    Heartbeat,
+    StartTls,
+    AuthStart,
+    AuthResponse(#[serde(with = "payload")] OwnedBytes),
+    NextChunk,
+    CancelStream,
+    UploadChunk(#[serde(with = "payload")] OwnedBytes),
+    UploadEnd,
+}
+
+/// What the server sends back for each frame: the serialised response, or the error that
+/// the call failed with, or the answer to one of the other request frames. Every reply is
+/// wrapped in one of these, so a client only ever deserialises its response type from an
+/// [ResponsePackage::Ok] payload
+#[derive(Serialize)]
+#[serde(rename = "ResponsePackage")]
+enum ResponseFrame<'a> {
+    Ok(#[serde(serialize_with = "payload::serialize")] Bytes<'a>),
+    Err(RemoteError),
+    Heartbeat,
+    StartTls {
+        accepted: bool,
+    },
+    AuthChallenge(#[serde(serialize_with = "payload::serialize")] Bytes<'a>),
+    Authenticated,
+    /// In place of [Self::Ok] from a server in dry-run mode, see [RpcError::DryRun]
+    DryRun {
+        rpc: String,
+    },
+    /// Another response [frame], wrapped with the server's signature, see [crate::signing]
+    #[cfg_attr(not(feature = "response_signing"), allow(dead_code))]
+    Signed {
+        #[serde(serialize_with = "payload::serialize")]
+        frame: Bytes<'a>,
+        #[serde(serialize_with = "payload::serialize")]
+        signature: Bytes<'a>,
+    },
+    /// In place of [Self::Ok] for a query with a [VersionCheck], with the state [version] the
+    /// call left
+    Versioned {
+        #[serde(serialize_with = "payload::serialize")]
+        payload: Bytes<'a>,
+        version: u64,
+    },
+    /// See [RpcError::Conflict]
+    Conflict {
+        current_version: u64,
+    },
+    /// Another response [frame], answering the query numbered [sequence], see
+    /// [TransportConfig::sequence_numbers]
+    Sequenced {
+        sequence: u64,
+        #[serde(serialize_with = "payload::serialize")]
+        frame: Bytes<'a>,
+    },
+    /// See [RpcError::Maintenance]
+    Maintenance {
+        rpc: String,
+        retry_after_ms: Option<u64>,
+    },
+    /// A piece of a streamed response, see [crate::streaming]
+    Chunk(#[serde(serialize_with = "payload::serialize")] Bytes<'a>),
+    /// Follows the last [Self::Chunk] of a streamed response
+    StreamEnd,
+    /// Asks for the next [RequestFrame::UploadChunk] of a streamed request
+    NextUploadChunk,
+    /// In place of the response to a oneway query, read by the client before its next frame,
+    /// see [crate::RpcClient::call_oneway]
+    Received,
+}
+#[derive(Deserialize)]
+enum ResponsePackage {
+    Ok(#[serde(with = "payload")] OwnedBytes),
+    Err(RemoteError),
+    Heartbeat,
+    #[cfg_attr(not(feature = "transport_tls"), allow(dead_code))]
+    StartTls {
+        accepted: bool,
+    },
+    AuthChallenge(#[serde(with = "payload")] OwnedBytes),
+    Authenticated,
+    DryRun {
+        rpc: String,
+    },
+    #[cfg_attr(not(feature = "response_signing"), allow(dead_code))]
+    Signed {
+        #[serde(with = "payload")]
+        frame: OwnedBytes,
+        #[serde(with = "payload")]
+        signature: OwnedBytes,
+    },
+    Versioned {
+        #[serde(with = "payload")]
+        payload: OwnedBytes,
+        version: u64,
+    },
+    Conflict {
+        current_version: u64,
+    },
+    Sequenced {
+        sequence: u64,
+        #[serde(with = "payload")]
+        frame: OwnedBytes,
+    },
+    Maintenance {
+        rpc: String,
+        retry_after_ms: Option<u64>,
+    },
+    Chunk(#[serde(with = "payload")] OwnedBytes),
+    StreamEnd,
+    NextUploadChunk,
+    Received,
+}
+
+/// (De)serialisation of payloads nested inside packages.
+/// Payloads are written as a sequence of bytes, except for human-readable formats where they are
+/// valid UTF-8 (i.e. nested json), which are written as a string so frames stay readable
+pub(crate) mod payload {
+    use serde::de::{SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt::Formatter;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) if serializer.is_human_readable() => serializer.serialize_str(text),
+            _ => serializer.collect_seq(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct PayloadVisitor;
+        impl<'de> Visitor<'de> for PayloadVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                write!(f, "payload bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(v.as_bytes().to_vec())
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(PayloadVisitor)
+        } else {
+            deserializer.deserialize_seq(PayloadVisitor)
+        }
+    }
+
+    /// As [super::payload], for payloads that may be absent
+    pub mod optional {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        #[derive(Serialize)]
+        struct Payload<'a>(#[serde(serialize_with = "super::serialize")] &'a [u8]);
+
+        #[derive(Deserialize)]
+        struct OwnedPayload(#[serde(deserialize_with = "super::deserialize")] Vec<u8>);
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes.as_deref().map(Payload).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Ok(Option::<OwnedPayload>::deserialize(deserializer)?.map(|OwnedPayload(bytes)| bytes))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::HelloWorldRpcName;
+
+    #[tokio::test]
+    async fn tcp_peer_disconnected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_stream, _from) = listener.accept().await.unwrap();
+        let mut server_transport = TcpTransport::new(server_stream);
+        assert!(!server_transport.peer_disconnected());
+
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(server_transport.peer_disconnected());
+    }
+
+    #[test]
+    fn serialize_into_appends() {
+        let transport_config = TransportWireConfig::default();
+        let mut buffer = vec![0xff];
+        transport_config
+            .serialize_into(&String::from("Foo"), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer[0], 0xff);
+        let bytes = transport_config.serialize(&String::from("Foo")).unwrap();
+        assert_eq!(buffer[1..], bytes[..]);
+    }
+
+    #[tokio::test]
+    async fn tcp_write_timeout() {
+        // A peer that never reads, so the send buffers fill up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_server_stream, _from) = listener.accept().await.unwrap();
+        let mut transport = TcpTransport::new(stalled);
+        transport.set_write_timeout(Some(Duration::from_millis(50)));
+
+        let message = vec![0u8; 64 * 1024 * 1024];
+        match transport.send(&message).await {
+            Err(TransportError::SendError(e)) => {
+                assert!(e.starts_with("Timed out"), "{}", e);
+                assert!(e.contains(&format!("of {} bytes written", message.len())));
+            }
+            other => panic!("Expected a SendError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers() {
+        let (client_stream, server_stream) = tokio::io::duplex(8192);
+        let client_config = TransportConfig {
+            sequence_numbers: true,
+            ..Default::default()
+        };
+        let mut client: Transport<_, HelloWorldRpcName> =
+            Transport::new(StreamTransport::new(client_stream), client_config);
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            StreamTransport::new(server_stream),
+            TransportConfig::default(),
+        );
+        let query_bytes = client.config.wire_config.serialize(&()).unwrap();
+
+        let serve = async {
+            for _ in 0..2 {
+                match server.receive_frame().await {
+                    Ok(ReceivedFrame::Query(_)) => server.respond(Ok(&[])).await.unwrap(),
+                    Ok(_) => panic!("Expected a query"),
+                    Err(e) => {
+                        let message = e.to_string();
+                        server.respond(Err(e)).await.unwrap();
+                        return message;
+                    }
+                }
+            }
+            panic!("Expected a query out of sequence")
+        };
+        let calls = async {
+            client
+                .send_query(&query_bytes, &HelloWorldRpcName::GetI)
+                .await
+                .unwrap();
+            // As if the query numbered 1 was lost on the way
+            client.sequence = 2;
+            client
+                .send_query(&query_bytes, &HelloWorldRpcName::GetI)
+                .await
+        };
+        let (rejected, result) = tokio::join!(serve, calls);
+        assert_eq!(rejected, "OutOfSequence(expected 1, received 2)");
+        assert!(result.unwrap_err().is_retryable());
+    }
+
+    #[test]
+    fn codec_error_context() {
+        let transport_config = TransportWireConfig::default();
+        let bytes = transport_config.serialize(&String::from("Foo")).unwrap();
+        match transport_config.deserialize::<usize>(&bytes) {
+            Err(TransportError::DeserialiseError(codec_error)) => {
+                assert_eq!(codec_error.format, "pickle");
+                assert_eq!(codec_error.type_name, "usize");
+            }
+            _ => panic!("Expected a DeserialiseError"),
+        }
+    }
+
+    #[test]
+    fn detect_wire_format() {
+        for wire_config in TransportWireConfig::all() {
+            for frame in [RequestFrame::Heartbeat, RequestFrame::AuthStart] {
+                let bytes = wire_config.serialize(&frame).unwrap();
+                let detected = TransportWireConfig::detect(&bytes).unwrap();
+                assert_eq!(detected.format_name(), wire_config.format_name());
+            }
+        }
+        assert!(TransportWireConfig::detect(&[]).is_none());
+    }
+
+    #[derive(Serialize)]
+    struct QueryV2 {
+        name: String,
+        limit: u32,
+    }
+    #[derive(Deserialize, Debug)]
+    struct QueryV1 {
+        name: String,
+        nickname: Option<String>,
+    }
+
+    #[test]
+    fn schema_compatibility() {
+        let transport_config = TransportWireConfig::default();
+        let bytes = transport_config
+            .serialize(&QueryV2 {
+                name: String::from("Foo"),
+                limit: 3,
+            })
+            .unwrap();
+        let query: QueryV1 = transport_config
+            .deserialize_payload(&bytes, SchemaCompatibility::Tolerant)
+            .unwrap();
+        assert_eq!(query.name, "Foo");
+        assert_eq!(query.nickname, None);
+
+        match transport_config.deserialize_payload::<QueryV1>(&bytes, SchemaCompatibility::Strict) {
+            Err(TransportError::DeserialiseError(codec_error)) => {
+                assert!(codec_error.message.contains("limit"), "{}", codec_error);
+            }
+            other => panic!("Expected a DeserialiseError, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "transport_debug_json")]
+    #[test]
+    fn debug_json_lines_frame() {
+        let transport_config = TransportWireConfig::DebugJsonLines;
+        let name_bytes = transport_config
+            .serialize(&HelloWorldRpcName::GetI)
+            .unwrap();
+        let query_bytes = transport_config.serialize(&vec![1, 2]).unwrap();
+        let package = TransportPackage {
+            name_bytes: &name_bytes,
+            query_bytes: &query_bytes,
+            reserved: false,
+            type_hash: None,
+            version_check: None,
+            idempotency_key: None,
+            sequence: None,
+            streamed_request: false,
+            metadata: &Metadata::new(),
+            oneway: false,
+        };
+        let mut frame = Vec::new();
+        transport_config
+            .serialize_frame_into(&package, &mut frame)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(frame.clone()).unwrap(),
+            "{\"name_bytes\":\"\\\"GetI\\\"\",\"query_bytes\":\"[1,2]\",\"reserved\":false,\"type_hash\":null,\"version_check\":null,\"idempotency_key\":null,\"sequence\":null,\"streamed_request\":false,\"metadata\":{},\"oneway\":false}\n"
+        );
+        let package2: TransportPackageOwned = transport_config.deserialize(&frame).unwrap();
+        assert_eq!(package2.query_bytes, query_bytes);
+    }
     #[test]
     fn transport_package_round_trip() {
         let name = HelloWorldRpcName::HelloWorld;
@@ -101,6 +633,14 @@ mod tests {
         let package = TransportPackage {
             name_bytes: &name_bytes,
             query_bytes: &query_bytes,
+            reserved: false,
+            type_hash: None,
+            version_check: None,
+            idempotency_key: None,
+            sequence: None,
+            streamed_request: false,
+            metadata: &Metadata::new(),
+            oneway: false,
         };
 
         let package_bytes = transport_config.serialize(&package).unwrap();
@@ -112,13 +652,79 @@ mod tests {
 
         assert_eq!(name, name2);
         assert_eq!(query, query2);
+        assert!(!package2.reserved);
+    }
+}
+
+/// The name of a received query, either one of the server's own RPCs or a reserved one
+pub enum ReceivedName<Name: RpcName> {
+    Rpc(Name),
+    Admin(AdminRpcName),
+}
+
+impl<Name: RpcName> std::fmt::Display for ReceivedName<Name> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rpc(name) => write!(f, "{}", name),
+            Self::Admin(name) => write!(f, "{}", name),
+        }
     }
 }
 
 /// The initial structure handed to the RpcServer, which includes
 pub struct ReceivedQuery<Name: RpcName> {
-    pub name: Name,
+    pub name: ReceivedName<Name>,
     pub query_bytes: OwnedBytes,
+    /// Hash of the client's types for the rpc, if it sent one. See [crate::type_hash]
+    pub type_hash: Option<u64>,
+    pub(crate) options: QueryOptions,
+}
+
+/// A frame received by the server, see [Transport::receive_frame]
+pub enum ReceivedFrame<Name: RpcName> {
+    Query(ReceivedQuery<Name>),
+    /// The client checking the connection is alive, answer with [Transport::respond_heartbeat]
+    Heartbeat,
+    /// The client asking to upgrade the connection to TLS, answer with
+    /// [Transport::respond_start_tls]
+    StartTls,
+    /// The client starting the authentication handshake, answer with
+    /// [Transport::respond_auth_challenge]
+    AuthStart,
+    /// The client's answer to the authentication challenge, answer with
+    /// [Transport::respond_authenticated] or an error
+    AuthResponse(OwnedBytes),
+    /// Not a client but something speaking HTTP, such as a load balancer's health check, answer
+    /// with [Transport::respond_http]
+    Http(HttpRequest),
+    /// The client closed the connection
+    Closed,
+}
+
+/// The request line of an HTTP request received in place of a frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+}
+
+impl HttpRequest {
+    /// The request [frame] starts with, if it's a GET or HEAD. No frame in any wire format starts
+    /// with these methods
+    fn parse(frame: Bytes) -> Option<Self> {
+        if !(frame.starts_with(b"GET ") || frame.starts_with(b"HEAD ")) {
+            return None;
+        }
+        let line_end = frame.iter().position(|b| *b == b'\r' || *b == b'\n')?;
+        let line = std::str::from_utf8(&frame[..line_end]).ok()?;
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            [method, path, version] if version.starts_with("HTTP/") => Some(Self {
+                method: method.to_string(),
+                path: path.to_string(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// Transport for data betweeen client and server, generic over the rpc names and internal transport
@@ -128,6 +734,33 @@ pub struct Transport<I, Name> {
     internal_transport: I,
     name: PhantomData<Name>,
     pub config: TransportConfig,
+    /// Reused for every frame sent over the connection
+    frame_buffer: OwnedBytes,
+    /// The wire format configured, kept once [TransportConfig::detect_wire_format] has switched
+    /// away from it
+    configured_wire_config: Option<TransportWireConfig>,
+    /// The number of the next query sent, or when serving, the next expected, see
+    /// [TransportConfig::sequence_numbers]
+    sequence: u64,
+    /// The number of the query last received, which its response is sent with
+    responding_to: Option<u64>,
+    /// The rpc and query number of a streamed response left unread, see [ResponseChunks], read
+    /// to its end before the next frame is sent
+    unfinished_stream: Option<(String, Option<u64>)>,
+    /// The number of a oneway query sent, if it was numbered, while its acknowledgement is left
+    /// unread, read before the next frame is sent
+    unacknowledged: Option<Option<u64>>,
+    /// The rpc of the query last received, if it was sealed, so its response is sealed too
+    #[cfg(feature = "payload_encryption")]
+    sealed_rpc: Option<String>,
+    /// The frame last received, which the response to it is signed together with
+    #[cfg(feature = "response_signing")]
+    last_request: OwnedBytes,
+    /// When the bytes of the frame last received arrived, the start of its [Phase::Receive]
+    ///
+    /// [Phase::Receive]: crate::call_trace::Phase::Receive
+    #[cfg(feature = "call_trace")]
+    pub(crate) received_at: Option<Instant>,
 }
 
 // TODO: Consider making transport Connected/Disconnected
@@ -137,13 +770,66 @@ pub struct ConnectedTransport<I, Name> {
 }
  */
 
+/// The largest frame sent or received by default, see [TransportConfig::max_message_size]
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
 /// TransportConfig defines various config options for transport handling
 /// [rcv_timeout] is used to protect receiving with a timeout
 /// [wire_config] is for serialising sent data, see the type def for more
+/// [detect_wire_format] has a server decode each frame in whichever wire format it was sent in,
+/// and answer in the same, see [TransportWireConfig::detect]
+/// [idle_timeout] is how long a server waits for a query on an open connection before reaping it
+/// [keepalive] is the idle time before TCP keepalive probes are sent, detecting peers that
+/// vanished without closing the connection
+/// [connect_timeout] bounds how long establishing a connection may take, including any TLS
+/// handshake
+/// [write_timeout] bounds how long sending one message may take, protecting against stalled peers
+/// [heartbeat] enables protocol level heartbeats on idle connections, see [HeartbeatConfig]
+/// [schema_compatibility] is how payloads from other revisions of the rpc types are handled
+/// [payload_keys] encrypts query and response payloads end to end, see [crate::payload_encryption]
+/// [signing_key] signs a server's responses, and [verifying_key] is the key a client requires
+/// them to be signed with, see [crate::signing]
+/// [compat] is the version of the wire protocol spoken, see [TransportCompat]
+/// [sequence_numbers] numbers a client's queries, and has it check each response answers the
+/// query it sent last, detecting responses lost or reordered by a custom [InternalTransport].
+/// Servers check every numbered query follows the one before, rejecting the query and closing
+/// the connection with [TransportError::OutOfSequence] if not. Responses are always received
+/// in the order the queries were sent
+/// [max_message_size] is the largest frame sent or received, in bytes, [DEFAULT_MAX_MESSAGE_SIZE]
+/// by default. Larger frames fail with [RpcError::MessageTooLarge] rather than being buffered,
+/// and a server receiving one closes the connection after saying why. Stream larger responses
+/// and requests, see [crate::streaming]
 #[derive(Clone, Debug)]
 pub struct TransportConfig {
     pub rcv_timeout: Duration,
     pub wire_config: TransportWireConfig,
+    pub detect_wire_format: bool,
+    pub idle_timeout: Option<Duration>,
+    pub keepalive: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    pub schema_compatibility: SchemaCompatibility,
+    pub compat: TransportCompat,
+    pub sequence_numbers: bool,
+    pub max_message_size: Option<usize>,
+    #[cfg(feature = "payload_encryption")]
+    pub payload_keys: Option<Arc<PayloadKeys>>,
+    #[cfg(feature = "response_signing")]
+    pub signing_key: Option<Arc<SigningKey>>,
+    #[cfg(feature = "response_signing")]
+    pub verifying_key: Option<VerifyingKey>,
+}
+
+/// Heartbeats are frames exchanged over an otherwise idle connection to check the peer is still
+/// there, independent of the underlying transport. Clients send one with [Transport::heartbeat]
+/// at least every [interval], and consider the connection dead if the server does not answer
+/// within [timeout]. A server with heartbeats configured reaps connections that send nothing for
+/// [interval] + [timeout], in place of its [TransportConfig::idle_timeout]
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
 }
 
 impl Default for TransportConfig {
@@ -151,6 +837,33 @@ impl Default for TransportConfig {
         Self {
             rcv_timeout: Duration::from_secs(3),
             wire_config: TransportWireConfig::default(),
+            detect_wire_format: false,
+            idle_timeout: Some(Duration::from_secs(60)),
+            keepalive: Some(Duration::from_secs(30)),
+            connect_timeout: Some(Duration::from_secs(5)),
+            write_timeout: Some(Duration::from_secs(10)),
+            heartbeat: None,
+            schema_compatibility: SchemaCompatibility::default(),
+            compat: TransportCompat::default(),
+            sequence_numbers: false,
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            #[cfg(feature = "payload_encryption")]
+            payload_keys: None,
+            #[cfg(feature = "response_signing")]
+            signing_key: None,
+            #[cfg(feature = "response_signing")]
+            verifying_key: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Fail with [RpcError::MessageTooLarge] if a frame of [len] bytes is over
+    /// [Self::max_message_size]
+    pub(crate) fn check_message_size(&self, len: usize) -> RpcResult<()> {
+        match self.max_message_size {
+            Some(limit) if len > limit => Err(RpcError::MessageTooLarge { limit }),
+            _ => Ok(()),
         }
     }
 }
@@ -162,35 +875,206 @@ pub enum TransportWireConfig {
     Pickle(serde_pickle::DeOptions, serde_pickle::SerOptions),
     #[cfg(feature = "transport_postcard")]
     Postcard,
+    /// Every frame is a single line of json, with nested payloads written as strings. For
+    /// development only: frames are readable in logs, diffable in tests and replayable with netcat
+    #[cfg(feature = "transport_debug_json")]
+    DebugJsonLines,
 }
 
-// TODO: Handle unwraps here with some sort of [Serialise/DeserialiseError]
 impl TransportWireConfig {
-    pub(crate) fn serialize(&self, val: &impl Serialize) -> Result<OwnedBytes, TransportError> {
+    /// Short name of the wire format, used in error messages
+    pub fn format_name(&self) -> &'static str {
+        match self {
+            Self::Pickle(_, _) => "pickle",
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => "postcard",
+            #[cfg(feature = "transport_debug_json")]
+            Self::DebugJsonLines => "debug-json-lines",
+        }
+    }
+
+    /// Every wire format enabled, the default first
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::default(),
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard,
+            #[cfg(feature = "transport_debug_json")]
+            Self::DebugJsonLines,
+        ]
+    }
+
+    /// The wire format named [format_name], see [Self::format_name], with its default options
+    pub fn from_format_name(format_name: &str) -> Option<Self> {
+        Self::all()
+            .into_iter()
+            .find(|wire_config| wire_config.format_name() == format_name)
+    }
+
+    /// The wire format [frame] was sent in, by its first byte: pickle frames start with the
+    /// PROTO opcode and json ones with an object or string, leaving postcard for anything else.
+    /// [None] if that format isn't enabled
+    pub fn detect(frame: Bytes) -> Option<Self> {
+        match frame.first() {
+            Some(0x80) => Some(Self::default()),
+            #[cfg(feature = "transport_debug_json")]
+            Some(b'{' | b'"') => Some(Self::DebugJsonLines),
+            #[cfg(not(feature = "transport_debug_json"))]
+            Some(b'{' | b'"') => None,
+            #[cfg(feature = "transport_postcard")]
+            Some(_) => Some(Self::Postcard),
+            _ => None,
+        }
+    }
+
+    fn codec_error<T: ?Sized>(&self, e: impl std::fmt::Debug) -> CodecError {
+        CodecError {
+            format: self.format_name(),
+            type_name: std::any::type_name::<T>(),
+            message: format!("{:?}", e),
+        }
+    }
+
+    pub(crate) fn serialize<T: Serialize>(&self, val: &T) -> Result<OwnedBytes, TransportError> {
+        let mut bytes = OwnedBytes::new();
+        self.serialize_into(val, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// [Self::serialize], appending to [buffer] so that its allocation can be reused
+    pub(crate) fn serialize_into<T: Serialize>(
+        &self,
+        val: &T,
+        buffer: &mut OwnedBytes,
+    ) -> Result<(), TransportError> {
         match self {
-            Self::Pickle(_de_opts, ser_opts) => serde_pickle::ser::to_vec(val, ser_opts.clone())
-                .map_err(|pickle_error| SerialiseError(format!("{:?}", pickle_error))),
+            Self::Pickle(_de_opts, ser_opts) => {
+                serde_pickle::ser::to_writer(buffer, val, ser_opts.clone())
+                    .map_err(|e| TransportError::SerialiseError(self.codec_error::<T>(e)))
+            }
             #[cfg(feature = "transport_postcard")]
-            Self::Postcard => postcard::to_vec(val)
-                .map_err(|postcard_error| SerialiseError(format!("{:?}", postcard_error))),
+            Self::Postcard => postcard::to_extend(val, std::mem::take(buffer))
+                .map(|bytes| *buffer = bytes)
+                .map_err(|e| TransportError::SerialiseError(self.codec_error::<T>(e))),
+            #[cfg(feature = "transport_debug_json")]
+            Self::DebugJsonLines => serde_json::to_writer(buffer, val)
+                .map_err(|e| TransportError::SerialiseError(self.codec_error::<T>(e))),
         }
     }
+
+    /// [Self::serialize_into] for the outermost package sent over the wire, line delimited if
+    /// the format calls for it
+    pub(crate) fn serialize_frame_into<T: Serialize>(
+        &self,
+        val: &T,
+        buffer: &mut OwnedBytes,
+    ) -> Result<(), TransportError> {
+        self.serialize_into(val, buffer)?;
+        #[cfg(feature = "transport_debug_json")]
+        if let Self::DebugJsonLines = self {
+            buffer.push(b'\n');
+        }
+        Ok(())
+    }
     pub(crate) fn deserialize<T: for<'de> Deserialize<'de>>(
         &self,
         bytes: Bytes,
     ) -> Result<T, TransportError> {
         match self {
             Self::Pickle(de_opts, _ser_opts) => {
-                serde_pickle::de::from_slice(bytes, de_opts.clone()).map_err(|pickle_error| {
-                    TransportError::DeserialiseError(format!("{:?}", pickle_error))
-                })
+                serde_pickle::de::from_slice(bytes, de_opts.clone())
+                    .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e)))
             }
             #[cfg(feature = "transport_postcard")]
-            Self::Postcard => postcard::from_bytes(bytes).map_err(|postcard_error| {
-                TransportError::DeserialiseError(format!("{:?}", postcard_error))
-            }),
+            Self::Postcard => postcard::from_bytes(bytes)
+                .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e))),
+            #[cfg(feature = "transport_debug_json")]
+            Self::DebugJsonLines => serde_json::from_slice(bytes)
+                .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e))),
         }
     }
+
+    /// [Self::deserialize] for query and response payloads, checking for data unknown to [T]
+    /// as set by [compatibility]
+    pub(crate) fn deserialize_payload<T: for<'de> Deserialize<'de>>(
+        &self,
+        bytes: Bytes,
+        compatibility: SchemaCompatibility,
+    ) -> Result<T, TransportError> {
+        let mut unknown = Vec::new();
+        let value = match self {
+            Self::Pickle(de_opts, _ser_opts) => {
+                let mut deserializer = serde_pickle::Deserializer::new(bytes, de_opts.clone());
+                serde_ignored::deserialize(&mut deserializer, |path| unknown.push(path.to_string()))
+                    .and_then(|value| deserializer.end().map(|()| value))
+                    .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e)))?
+            }
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => {
+                let (value, rest) = postcard::take_from_bytes(bytes)
+                    .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e)))?;
+                if !rest.is_empty() {
+                    unknown.push(format!("{} trailing bytes", rest.len()));
+                }
+                value
+            }
+            #[cfg(feature = "transport_debug_json")]
+            Self::DebugJsonLines => {
+                let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+                serde_ignored::deserialize(&mut deserializer, |path| unknown.push(path.to_string()))
+                    .and_then(|value| deserializer.end().map(|()| value))
+                    .map_err(|e| TransportError::DeserialiseError(self.codec_error::<T>(e)))?
+            }
+        };
+        if unknown.is_empty() {
+            return Ok(value);
+        }
+        let unknown = unknown.join(", ");
+        match compatibility {
+            SchemaCompatibility::Tolerant => {
+                debug!(
+                    "Skipped data unknown to {}: {}",
+                    std::any::type_name::<T>(),
+                    unknown
+                );
+                Ok(value)
+            }
+            SchemaCompatibility::Strict => Err(TransportError::DeserialiseError(CodecError {
+                format: self.format_name(),
+                type_name: std::any::type_name::<T>(),
+                message: format!("Data unknown to this type: {}", unknown),
+            })),
+        }
+    }
+}
+
+/// Version of the wire protocol spoken, so a fleet can move to a release with a newer protocol
+/// one client or server at a time: set [Self::V0] on the upgraded ones until every peer speaks
+/// [Self::Current], then drop it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportCompat {
+    /// Queries framed and answered with a [ResponsePackage], supporting everything
+    #[default]
+    Current,
+    /// The raw package format of pirates 0.1: a query is its serialised name and payload, and the
+    /// response is the bare serialised result. It has no way to carry anything else, so
+    /// heartbeats, TLS upgrades, authentication, admin rpcs and signing aren't available, and a
+    /// failed call closes the connection in place of relaying its error
+    V0,
+}
+
+/// How strictly payloads are checked against the receiving side's query/response types, so a
+/// client and server built from different revisions of them can interoperate during rolling
+/// upgrades. In either mode, fields missing from a payload are only tolerated where the receiving
+/// type allows it, i.e. [Option] or `#[serde(default)]` fields
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchemaCompatibility {
+    /// Data the receiving type does not know about is skipped: unknown fields in the
+    /// self-describing formats, or trailing bytes (fields appended to a struct) in postcard
+    #[default]
+    Tolerant,
+    /// Data the receiving type does not know about fails deserialisation
+    Strict,
 }
 
 impl Default for TransportWireConfig {
@@ -203,57 +1087,1062 @@ impl Default for TransportWireConfig {
 }
 
 impl<I: InternalTransport, Name: RpcName> Transport<I, Name> {
-    pub fn new(internal_transport: I, transport_config: TransportConfig) -> Self {
+    pub fn new(mut internal_transport: I, transport_config: TransportConfig) -> Self {
+        internal_transport.set_max_message_size(transport_config.max_message_size);
         Self {
             internal_transport,
-            name: PhantomData::default(),
+            name: PhantomData,
             config: transport_config,
+            frame_buffer: OwnedBytes::new(),
+            configured_wire_config: None,
+            sequence: 0,
+            responding_to: None,
+            unfinished_stream: None,
+            unacknowledged: None,
+            #[cfg(feature = "payload_encryption")]
+            sealed_rpc: None,
+            #[cfg(feature = "response_signing")]
+            last_request: OwnedBytes::new(),
+            #[cfg(feature = "call_trace")]
+            received_at: None,
         }
     }
+
+    /// The [InternalTransport] carrying this transport's frames
+    pub fn internal_transport(&self) -> &I {
+        &self.internal_transport
+    }
+
+    pub fn into_internal_transport(self) -> I {
+        self.internal_transport
+    }
+
     pub async fn send_query(
         &mut self,
         query_bytes: Bytes<'_>,
         rpc_name: &Name,
     ) -> RpcResult<OwnedBytes> {
+        self.send_named_query(query_bytes, rpc_name, None).await
+    }
+
+    /// [Self::send_query], with the hash of the client's types for the rpc
+    pub(crate) async fn send_query_with_type_hash(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        type_hash: Option<u64>,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_named_query(query_bytes, rpc_name, type_hash)
+            .await
+    }
+
+    /// [Self::send_query_with_type_hash] with [options], returning the response with the version
+    /// the call left the server's state at if the options have a [VersionCheck]
+    pub(crate) async fn send_query_with_options(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        type_hash: Option<u64>,
+        options: &QueryOptions,
+    ) -> RpcResult<(OwnedBytes, Option<u64>)> {
+        let (result_bytes, version) = self
+            .send_checked_query(query_bytes, rpc_name, type_hash, options)
+            .await?;
+        if options.version_check.is_some() && version.is_none() {
+            return Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected a versioned response, the server may predate versions"),
+            )));
+        }
+        Ok((result_bytes, version))
+    }
+
+    /// [Self::send_query] for one of the reserved rpcs, over this same connection
+    #[cfg(feature = "schema")]
+    pub(crate) async fn send_reserved_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &AdminRpcName,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_named_query(query_bytes, rpc_name, None).await
+    }
+
+    async fn send_named_query<N: RpcName>(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &N,
+        type_hash: Option<u64>,
+    ) -> RpcResult<OwnedBytes> {
+        let (result_bytes, _) = self
+            .send_checked_query(query_bytes, rpc_name, type_hash, &QueryOptions::default())
+            .await?;
+        Ok(result_bytes)
+    }
+
+    async fn send_checked_query<N: RpcName>(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &N,
+        type_hash: Option<u64>,
+        options: &QueryOptions,
+    ) -> RpcResult<(OwnedBytes, Option<u64>)> {
+        let (response, _) = self
+            .send_sequenced_query(query_bytes, rpc_name, type_hash, options)
+            .await?;
+        let (result_bytes, version) = match response {
+            ResponsePackage::Ok(result_bytes) => (result_bytes, None),
+            ResponsePackage::Versioned { payload, version } => (payload, Some(version)),
+            response => return Err(response_error(response)),
+        };
+        #[cfg(feature = "payload_encryption")]
+        let result_bytes = self.open_response(&rpc_name.to_string(), result_bytes)?;
+        Ok((result_bytes, version))
+    }
+
+    /// Send a query without waiting for the call to be made, see [crate::RpcClient::call_oneway]
+    pub(crate) async fn send_oneway_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        type_hash: Option<u64>,
+    ) -> RpcResult<()> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Oneway queries"));
+        }
+        let options = QueryOptions {
+            oneway: true,
+            ..QueryOptions::default()
+        };
+        self.send_sequenced_query(query_bytes, rpc_name, type_hash, &options)
+            .await
+            .map(|_| ())
+    }
+
+    /// Send a query to a streaming rpc (see [crate::streaming]), returning its response's chunks
+    pub(crate) async fn send_streaming_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        type_hash: Option<u64>,
+    ) -> RpcResult<ResponseChunks<'_, I, Name>> {
+        let (response, sequence) = self
+            .send_sequenced_query(query_bytes, rpc_name, type_hash, &QueryOptions::default())
+            .await?;
+        let rpc = rpc_name.to_string();
+        let first = self.chunk_of(&rpc, response)?;
+        Ok(ResponseChunks::new(self, rpc, sequence, first))
+    }
+
+    /// Send a query to an uploading rpc (see [crate::streaming]) followed by the rest of its
+    /// request, read from [body] as the server asks for it, returning its response
+    pub(crate) async fn send_uploading_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        type_hash: Option<u64>,
+        body: &mut (impl AsyncRead + Unpin),
+    ) -> RpcResult<OwnedBytes> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Streamed requests"));
+        }
+        let options = QueryOptions {
+            streamed_request: true,
+            ..QueryOptions::default()
+        };
+        let (mut response, sequence) = self
+            .send_sequenced_query(query_bytes, rpc_name, type_hash, &options)
+            .await?;
+        #[cfg(feature = "payload_encryption")]
+        let rpc = rpc_name.to_string();
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            match response {
+                ResponsePackage::NextUploadChunk => {}
+                #[cfg(feature = "payload_encryption")]
+                ResponsePackage::Ok(result_bytes) => return self.open_response(&rpc, result_bytes),
+                #[cfg(not(feature = "payload_encryption"))]
+                ResponsePackage::Ok(result_bytes) => return Ok(result_bytes),
+                response => return Err(response_error(response)),
+            }
+            let read = match body.read(&mut buffer).await {
+                Ok(read) => read,
+                Err(e) => {
+                    // The server's answer is only the error of being cancelled
+                    self.exchange_frame(&RequestFrame::CancelStream, self.config.rcv_timeout)
+                        .await?;
+                    return Err(RpcError::TransportError(TransportError::SendError(
+                        format!("Could not read the request to stream: {}", e),
+                    )));
+                }
+            };
+            let chunk = &buffer[..read];
+            #[cfg(feature = "payload_encryption")]
+            let sealed_chunk = self
+                .config
+                .payload_keys
+                .as_ref()
+                .and_then(|keys| keys.seal(&rpc, Direction::Query, chunk))
+                .transpose()?;
+            #[cfg(feature = "payload_encryption")]
+            let chunk = sealed_chunk.as_deref().unwrap_or(chunk);
+            let frame = match read {
+                0 => RequestFrame::UploadEnd,
+                _ => RequestFrame::UploadChunk(chunk),
+            };
+            let answer = self.exchange_frame(&frame, self.config.rcv_timeout).await?;
+            response = self.unsequenced(sequence, answer)?;
+        }
+    }
+
+    /// Send a query, returning the response unwrapped from its [ResponsePackage::Sequenced], and
+    /// the number the query was sent with
+    async fn send_sequenced_query<N: RpcName>(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &N,
+        type_hash: Option<u64>,
+        options: &QueryOptions,
+    ) -> RpcResult<(ResponsePackage, Option<u64>)> {
         let name_bytes = self.config.wire_config.serialize(&rpc_name)?;
-        let package = TransportPackage {
+        #[cfg(feature = "payload_encryption")]
+        let payload_keys = self.config.payload_keys.clone();
+        #[cfg(feature = "payload_encryption")]
+        let rpc = rpc_name.to_string();
+        #[cfg(feature = "payload_encryption")]
+        let sealed_query = payload_keys
+            .as_ref()
+            .and_then(|keys| keys.seal(&rpc, Direction::Query, query_bytes))
+            .transpose()?;
+        #[cfg(feature = "payload_encryption")]
+        let query_bytes = sealed_query.as_deref().unwrap_or(query_bytes);
+        let sequence = self.config.sequence_numbers.then(|| {
+            self.sequence += 1;
+            self.sequence - 1
+        });
+        let frame = RequestFrame::Query(TransportPackage {
             name_bytes: &name_bytes,
             query_bytes,
+            reserved: N::RESERVED,
+            type_hash,
+            version_check: options.version_check,
+            idempotency_key: options.idempotency_key.as_deref(),
+            sequence,
+            streamed_request: options.streamed_request,
+            metadata: &options.metadata,
+            oneway: options.oneway,
+        });
+        if options.oneway {
+            self.send_unanswered(&frame, sequence).await?;
+            return Ok((ResponsePackage::Received, sequence));
+        }
+        let response = match self.config.compat {
+            TransportCompat::Current => self.send_frame(&frame, self.config.rcv_timeout).await?,
+            TransportCompat::V0 => self.send_query_v0::<N>(&name_bytes, query_bytes).await?,
         };
-        let package_bytes = self.config.wire_config.serialize(&package)?;
-        debug!(
-            "Transport sending {} Bytes:  {:?}",
-            package_bytes.len(),
-            package_bytes
-        );
-        self.internal_transport
-            .send_and_wait_for_response(&package_bytes, self.config.rcv_timeout)
-            .await
-            .map_err(Into::into)
+        Ok((self.unsequenced(sequence, response)?, sequence))
     }
 
-    pub async fn receive_query(&mut self) -> RpcResult<ReceivedQuery<Name>> {
-        // We receive with no timeout as we want to sit and wait on [internal_transport]
-        match self.internal_transport.receive(None).await {
-            Ok(bytes) => {
-                debug!("Transport {} Bytes:  {:?}", bytes.len(), bytes);
-                let package: TransportPackageOwned = self.config.wire_config.deserialize(&bytes)?;
-                let name = self.config.wire_config.deserialize(&package.name_bytes)?;
-                Ok(ReceivedQuery {
+    /// Unwrap the [response] to the query numbered [expected], if it was numbered
+    fn unsequenced(
+        &self,
+        expected: Option<u64>,
+        response: ResponsePackage,
+    ) -> RpcResult<ResponsePackage> {
+        match (expected, response) {
+            (Some(expected), ResponsePackage::Sequenced { sequence, frame }) => {
+                if sequence != expected {
+                    return Err(RpcError::TransportError(TransportError::OutOfSequence {
+                        expected,
+                        received: sequence,
+                    }));
+                }
+                Ok(self.config.wire_config.deserialize(&frame)?)
+            }
+            (Some(_), ResponsePackage::Err(remote_error)) => Err(RpcError::Remote(remote_error)),
+            (Some(_), _) => Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected a numbered response, the server may predate them"),
+            ))),
+            (None, response) => Ok(response),
+        }
+    }
+
+    /// Open the [result_bytes] of a response from [rpc], if its rpc is sealed
+    #[cfg(feature = "payload_encryption")]
+    fn open_response(&self, rpc: &str, result_bytes: OwnedBytes) -> RpcResult<OwnedBytes> {
+        match self
+            .config
+            .payload_keys
+            .as_ref()
+            .and_then(|keys| keys.open(rpc, Direction::Response, &result_bytes))
+        {
+            Some(opened) => Ok(opened?),
+            None => Ok(result_bytes),
+        }
+    }
+
+    /// The chunk in a frame of the streamed [response] from [rpc], or [None] at its end
+    fn chunk_of(&self, rpc: &str, response: ResponsePackage) -> RpcResult<Option<OwnedBytes>> {
+        match response {
+            #[cfg(feature = "payload_encryption")]
+            ResponsePackage::Chunk(chunk) => Ok(Some(self.open_response(rpc, chunk)?)),
+            #[cfg(not(feature = "payload_encryption"))]
+            ResponsePackage::Chunk(chunk) => {
+                let _ = rpc;
+                Ok(Some(chunk))
+            }
+            ResponsePackage::StreamEnd => Ok(None),
+            ResponsePackage::Ok(_) | ResponsePackage::Versioned { .. } => {
+                Err(RpcError::TransportError(TransportError::ReceiveError(
+                    String::from("Expected a streamed response, the rpc may not stream"),
+                )))
+            }
+            response => Err(response_error(response)),
+        }
+    }
+
+    /// Ask for the next chunk of the response [rpc] is streaming to the query numbered
+    /// [sequence], [None] at its end
+    pub(crate) async fn receive_chunk(
+        &mut self,
+        rpc: &str,
+        sequence: Option<u64>,
+    ) -> RpcResult<Option<OwnedBytes>> {
+        let response = self
+            .exchange_frame(&RequestFrame::NextChunk, self.config.rcv_timeout)
+            .await?;
+        let response = self.unsequenced(sequence, response)?;
+        self.chunk_of(rpc, response)
+    }
+
+    /// Leave the rest of the response [rpc] is streaming to be read before the next frame is
+    /// sent, see [ResponseChunks]
+    pub(crate) fn abandon_stream(&mut self, rpc: String, sequence: Option<u64>) {
+        self.unfinished_stream = Some((rpc, sequence));
+    }
+
+    /// Cancel an abandoned streamed response, so the next frame's response isn't mistaken for
+    /// the rest of it
+    async fn finish_stream(&mut self) -> RpcResult<()> {
+        let (rpc, sequence) = match self.unfinished_stream.take() {
+            Some(unfinished) => unfinished,
+            None => return Ok(()),
+        };
+        let response = self
+            .exchange_frame(&RequestFrame::CancelStream, self.config.rcv_timeout)
+            .await?;
+        match self.unsequenced(sequence, response) {
+            Ok(response) => match self.chunk_of(&rpc, response) {
+                Ok(None) => Ok(()),
+                Ok(Some(_)) => Err(RpcError::TransportError(TransportError::ReceiveError(
+                    String::from("Expected the end of a cancelled response, got more of it"),
+                ))),
+                // The stream ended with an error, which no one is waiting for
+                Err(RpcError::Remote(_)) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Err(RpcError::Remote(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a query in the [TransportCompat::V0] format, where the response is the bare result
+    async fn send_query_v0<N: RpcName>(
+        &mut self,
+        name_bytes: Bytes<'_>,
+        query_bytes: Bytes<'_>,
+    ) -> RpcResult<ResponsePackage> {
+        if N::RESERVED {
+            return Err(v0_unsupported("Admin rpcs"));
+        }
+        let package = TransportPackageV0 {
+            name_bytes,
+            query_bytes,
+        };
+        self.frame_buffer = self.config.wire_config.serialize(&package)?;
+        self.config.check_message_size(self.frame_buffer.len())?;
+        debug!("Transport sending {} Bytes", self.frame_buffer.len());
+        let result_bytes = self
+            .internal_transport
+            .send_and_wait_for_response(&self.frame_buffer, self.config.rcv_timeout)
+            .await?;
+        self.config.check_message_size(result_bytes.len())?;
+        if result_bytes.is_empty() {
+            return Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Connection closed without a response"),
+            )));
+        }
+        let response = ResponsePackage::Ok(result_bytes);
+        #[cfg(feature = "response_signing")]
+        let response = self.verify_response(response)?;
+        Ok(response)
+    }
+
+    /// Send a heartbeat and wait for the server to answer it, returning the round trip time
+    pub async fn heartbeat(&mut self) -> RpcResult<Duration> {
+        let timeout = self
+            .config
+            .heartbeat
+            .map_or(self.config.rcv_timeout, |heartbeat| heartbeat.timeout);
+        let start = std::time::Instant::now();
+        match self.send_frame(&RequestFrame::Heartbeat, timeout).await? {
+            ResponsePackage::Heartbeat => Ok(start.elapsed()),
+            _ => Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected a heartbeat, got a response"),
+            ))),
+        }
+    }
+
+    /// Ask the server to upgrade the connection to TLS, true if it agreed, in which case the TLS
+    /// handshake must follow. Servers predating upgrades can't parse the request and refuse it
+    #[cfg(feature = "transport_tls")]
+    pub(crate) async fn start_tls(&mut self) -> RpcResult<bool> {
+        match self
+            .send_frame(&RequestFrame::StartTls, self.config.rcv_timeout)
+            .await?
+        {
+            ResponsePackage::StartTls { accepted } => Ok(accepted),
+            ResponsePackage::Err(remote_error) => {
+                debug!("Server refused TLS upgrade: {}", remote_error);
+                Ok(false)
+            }
+            _ => Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected the answer to a TLS upgrade, got a response"),
+            ))),
+        }
+    }
+
+    /// Run the client side of the authentication handshake, answering the server's challenge
+    /// with [authenticator]
+    pub(crate) async fn authenticate(
+        &mut self,
+        authenticator: &dyn ClientAuthenticator,
+    ) -> RpcResult<()> {
+        let timeout = self.config.rcv_timeout;
+        let challenge = match self.send_frame(&RequestFrame::AuthStart, timeout).await? {
+            ResponsePackage::AuthChallenge(challenge) => challenge,
+            ResponsePackage::Err(remote_error) => return Err(RpcError::Remote(remote_error)),
+            _ => {
+                return Err(RpcError::TransportError(TransportError::ReceiveError(
+                    String::from("Expected an authentication challenge"),
+                )))
+            }
+        };
+        let response = authenticator.respond(&challenge)?;
+        match self
+            .send_frame(&RequestFrame::AuthResponse(&response), timeout)
+            .await?
+        {
+            ResponsePackage::Authenticated => Ok(()),
+            ResponsePackage::Err(remote_error) => Err(RpcError::Remote(remote_error)),
+            _ => Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected the outcome of authenticating"),
+            ))),
+        }
+    }
+
+    /// Send [frame] and wait for its answer, first cancelling any streamed response left unread
+    /// and reading any acknowledgement of a oneway query
+    async fn send_frame(
+        &mut self,
+        frame: &RequestFrame<'_>,
+        timeout: Duration,
+    ) -> RpcResult<ResponsePackage> {
+        self.finish_stream().await?;
+        self.finish_oneway().await?;
+        self.exchange_frame(frame, timeout).await
+    }
+
+    /// Send the oneway query [frame], numbered [sequence], leaving its acknowledgement to be read
+    /// before the next frame is sent
+    async fn send_unanswered(
+        &mut self,
+        frame: &RequestFrame<'_>,
+        sequence: Option<u64>,
+    ) -> RpcResult<()> {
+        self.finish_stream().await?;
+        self.finish_oneway().await?;
+        self.serialize_frame(frame)?;
+        self.internal_transport.send(&self.frame_buffer).await?;
+        self.unacknowledged = Some(sequence);
+        Ok(())
+    }
+
+    /// Read the acknowledgement of the oneway query last sent, if it is unread. The server sends
+    /// it on receiving the query, before making the call, so this doesn't wait on the call
+    async fn finish_oneway(&mut self) -> RpcResult<()> {
+        let Some(sequence) = self.unacknowledged.take() else {
+            return Ok(());
+        };
+        let response_bytes = self
+            .internal_transport
+            .receive(Some(self.config.rcv_timeout))
+            .await?;
+        let response = self.parse_response_frame(&response_bytes)?;
+        match self.unsequenced(sequence, response)? {
+            ResponsePackage::Received => Ok(()),
+            response => Err(response_error(response)),
+        }
+    }
+
+    /// [Self::send_frame], leaving any abandoned stream unfinished
+    async fn exchange_frame(
+        &mut self,
+        frame: &RequestFrame<'_>,
+        timeout: Duration,
+    ) -> RpcResult<ResponsePackage> {
+        self.serialize_frame(frame)?;
+        let response_bytes = self
+            .internal_transport
+            .send_and_wait_for_response(&self.frame_buffer, timeout)
+            .await?;
+        self.parse_response_frame(&response_bytes)
+    }
+
+    /// Serialise [frame] into [Self::frame_buffer] to be sent
+    fn serialize_frame(&mut self, frame: &RequestFrame<'_>) -> RpcResult<()> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Frames other than queries"));
+        }
+        self.frame_buffer.clear();
+        self.config
+            .wire_config
+            .serialize_frame_into(frame, &mut self.frame_buffer)?;
+        self.config.check_message_size(self.frame_buffer.len())?;
+        debug!("Transport sending {} Bytes", self.frame_buffer.len());
+        Ok(())
+    }
+
+    /// The answer in [response_bytes] to the frame last sent
+    fn parse_response_frame(&self, response_bytes: Bytes) -> RpcResult<ResponsePackage> {
+        self.config.check_message_size(response_bytes.len())?;
+        if response_bytes.is_empty() {
+            return Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Connection closed without a response"),
+            )));
+        }
+        let response = self.config.wire_config.deserialize(response_bytes)?;
+        #[cfg(feature = "response_signing")]
+        let response = self.verify_response(response)?;
+        Ok(response)
+    }
+
+    /// Unwrap a signed [response], requiring it be signed by the pinned
+    /// [TransportConfig::verifying_key] if there is one
+    #[cfg(feature = "response_signing")]
+    fn verify_response(&self, response: ResponsePackage) -> RpcResult<ResponsePackage> {
+        match (response, &self.config.verifying_key) {
+            (ResponsePackage::Signed { frame, signature }, Some(verifying_key)) => {
+                crate::signing::verify(verifying_key, &self.frame_buffer, &frame, &signature)?;
+                Ok(self.config.wire_config.deserialize(&frame)?)
+            }
+            (ResponsePackage::Signed { frame, .. }, None) => {
+                Ok(self.config.wire_config.deserialize(&frame)?)
+            }
+            (_, Some(_)) => Err(RpcError::InvalidSignature(String::from(
+                "Response is not signed",
+            ))),
+            (response, None) => Ok(response),
+        }
+    }
+
+    /// Wait for the next frame from a client
+    pub async fn receive_frame(&mut self) -> RpcResult<ReceivedFrame<Name>> {
+        // We wait on [internal_transport] for as long as the connection may be idle
+        let wait = match self.config.heartbeat {
+            Some(heartbeat) => Some(heartbeat.interval + heartbeat.timeout),
+            None => self.config.idle_timeout,
+        };
+        let bytes = self.internal_transport.receive(wait).await?;
+        debug!("Transport received {} Bytes", bytes.len());
+        self.config.check_message_size(bytes.len())?;
+        #[cfg(feature = "call_trace")]
+        {
+            self.received_at = Some(Instant::now());
+        }
+        if bytes.is_empty() {
+            return Ok(ReceivedFrame::Closed);
+        }
+        if let Some(request) = HttpRequest::parse(&bytes) {
+            return Ok(ReceivedFrame::Http(request));
+        }
+        if self.config.detect_wire_format {
+            self.detect_wire_format(&bytes);
+        }
+        if self.config.compat == TransportCompat::V0 {
+            let package: TransportPackageV0Owned = self.config.wire_config.deserialize(&bytes)?;
+            let name = ReceivedName::Rpc(self.config.wire_config.deserialize(&package.name_bytes)?);
+            #[cfg(feature = "payload_encryption")]
+            let package = self.open_query(
+                &name,
+                TransportPackageOwned {
+                    name_bytes: package.name_bytes,
+                    query_bytes: package.query_bytes,
+                    reserved: false,
+                    type_hash: None,
+                    version_check: None,
+                    idempotency_key: None,
+                    sequence: None,
+                    streamed_request: false,
+                    metadata: Metadata::new(),
+                    oneway: false,
+                },
+            )?;
+            return Ok(ReceivedFrame::Query(ReceivedQuery {
+                name,
+                query_bytes: package.query_bytes,
+                type_hash: None,
+                options: QueryOptions::default(),
+            }));
+        }
+        #[cfg(feature = "response_signing")]
+        if self.config.signing_key.is_some() {
+            self.last_request.clone_from(&bytes);
+        }
+        match self.config.wire_config.deserialize(&bytes)? {
+            RequestFrameOwned::Heartbeat => Ok(ReceivedFrame::Heartbeat),
+            RequestFrameOwned::StartTls => Ok(ReceivedFrame::StartTls),
+            RequestFrameOwned::AuthStart => Ok(ReceivedFrame::AuthStart),
+            RequestFrameOwned::AuthResponse(response) => Ok(ReceivedFrame::AuthResponse(response)),
+            RequestFrameOwned::NextChunk | RequestFrameOwned::CancelStream => {
+                Err(RpcError::TransportError(TransportError::ReceiveError(
+                    String::from("Asked for a chunk with no response being streamed"),
+                )))
+            }
+            RequestFrameOwned::UploadChunk(_) | RequestFrameOwned::UploadEnd => {
+                Err(RpcError::TransportError(TransportError::ReceiveError(
+                    String::from("Sent a chunk with no request being streamed"),
+                )))
+            }
+            RequestFrameOwned::Query(package) => {
+                if let Some(received) = package.sequence {
+                    self.responding_to = Some(received);
+                    if received != self.sequence {
+                        return Err(RpcError::TransportError(TransportError::OutOfSequence {
+                            expected: self.sequence,
+                            received,
+                        }));
+                    }
+                    self.sequence += 1;
+                }
+                let name = if package.reserved {
+                    ReceivedName::Admin(self.config.wire_config.deserialize(&package.name_bytes)?)
+                } else {
+                    ReceivedName::Rpc(self.config.wire_config.deserialize(&package.name_bytes)?)
+                };
+                #[cfg(feature = "payload_encryption")]
+                let package = self.open_query(&name, package)?;
+                Ok(ReceivedFrame::Query(ReceivedQuery {
                     name,
                     query_bytes: package.query_bytes,
+                    type_hash: package.type_hash,
+                    options: QueryOptions {
+                        version_check: package.version_check,
+                        idempotency_key: package.idempotency_key,
+                        streamed_request: package.streamed_request,
+                        metadata: package.metadata,
+                        oneway: package.oneway,
+                    },
+                }))
+            }
+        }
+    }
+
+    /// Switch to the wire format [frame] was sent in, with the configured options if it's the
+    /// configured format. Frames in formats that aren't enabled are left to fail to decode
+    fn detect_wire_format(&mut self, frame: Bytes) {
+        let detected = match TransportWireConfig::detect(frame) {
+            Some(detected) if detected.format_name() != self.config.wire_config.format_name() => {
+                detected
+            }
+            _ => return,
+        };
+        debug!(
+            "Client switched to the {} wire format",
+            detected.format_name()
+        );
+        let configured = self
+            .configured_wire_config
+            .get_or_insert_with(|| self.config.wire_config.clone());
+        self.config.wire_config = if configured.format_name() == detected.format_name() {
+            configured.clone()
+        } else {
+            detected
+        };
+    }
+
+    /// See [InternalTransport::peer_disconnected]
+    pub fn peer_disconnected(&mut self) -> bool {
+        self.internal_transport.peer_disconnected()
+    }
+
+    /// Open the payload of a query to [name], if its rpc is sealed, remembering to seal the
+    /// response
+    #[cfg(feature = "payload_encryption")]
+    fn open_query(
+        &mut self,
+        name: &ReceivedName<Name>,
+        mut package: TransportPackageOwned,
+    ) -> Result<TransportPackageOwned, TransportError> {
+        let rpc = match name {
+            ReceivedName::Rpc(name) => name.to_string(),
+            ReceivedName::Admin(name) => name.to_string(),
+        };
+        self.sealed_rpc = None;
+        let keys = match &self.config.payload_keys {
+            Some(keys) if keys.seals(&rpc) => keys,
+            _ => return Ok(package),
+        };
+        if let Some(opened) = keys.open(&rpc, Direction::Query, &package.query_bytes) {
+            package.query_bytes = opened?;
+        }
+        self.sealed_rpc = Some(rpc);
+        Ok(package)
+    }
+
+    /// Send the outcome of a call back to the client, errors are relayed as a [RemoteError]
+    pub async fn respond(&mut self, result: RpcResult<Bytes<'_>>) -> RpcResult<()> {
+        self.respond_versioned(result, None).await
+    }
+
+    /// [Self::respond], with the state [version] the call left for a query that had a
+    /// [VersionCheck]
+    pub(crate) async fn respond_versioned(
+        &mut self,
+        result: RpcResult<Bytes<'_>>,
+        version: Option<u64>,
+    ) -> RpcResult<()> {
+        // Told to the client in place of a response it would refuse
+        let result = result.and_then(|payload| {
+            self.config.check_message_size(payload.len())?;
+            Ok(payload)
+        });
+        #[cfg(feature = "payload_encryption")]
+        let sealed_response = match (&result, self.sealed_rpc.take(), &self.config.payload_keys) {
+            (Ok(result_bytes), Some(rpc), Some(keys)) => keys
+                .seal(&rpc, Direction::Response, result_bytes)
+                .transpose()?,
+            _ => None,
+        };
+        #[cfg(feature = "payload_encryption")]
+        let result = match &sealed_response {
+            Some(sealed) => Ok(&sealed[..]),
+            None => result,
+        };
+        if self.config.compat == TransportCompat::V0 {
+            return match result {
+                Ok(result_bytes) => self
+                    .internal_transport
+                    .send(result_bytes)
+                    .await
+                    .map_err(RpcError::TransportError),
+                // The client has no way to learn of the error, so is left to see the connection
+                // close
+                Err(e) => Err(e),
+            };
+        }
+        let frame = match (result, version) {
+            (Ok(payload), Some(version)) => ResponseFrame::Versioned { payload, version },
+            (Ok(result_bytes), None) => ResponseFrame::Ok(result_bytes),
+            (Err(RpcError::DryRun { rpc }), _) => ResponseFrame::DryRun { rpc },
+            (Err(RpcError::Conflict { current_version }), _) => {
+                ResponseFrame::Conflict { current_version }
+            }
+            (Err(RpcError::Maintenance { rpc, retry_after }), _) => ResponseFrame::Maintenance {
+                rpc,
+                retry_after_ms: retry_after.map(|retry_after| retry_after.as_millis() as u64),
+            },
+            (Err(e), _) => ResponseFrame::Err(RemoteError::relay(&e, &self.config.wire_config)),
+        };
+        self.send_response(&frame).await
+    }
+
+    /// Answer a oneway query with only its acknowledgement, sent before the call is made, see
+    /// [crate::RpcClient::call_oneway]
+    pub(crate) async fn acknowledge(&mut self) -> RpcResult<()> {
+        #[cfg(feature = "payload_encryption")]
+        self.sealed_rpc.take();
+        self.send_response(&ResponseFrame::Received).await
+    }
+
+    /// Send a streamed [response] back to the client in chunks as its writer writes them, one
+    /// for each [RequestFrame::NextChunk] the client sends, ending with the error the writer
+    /// failed with if it did. Resolves to how the writer did once it's all sent: its error, or
+    /// [StatusCode::Cancelled] if the client cancelled the stream
+    pub(crate) async fn respond_streaming(
+        &mut self,
+        response: ResponseStream,
+    ) -> RpcResult<RpcResult<()>> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Streamed responses"));
+        }
+        #[cfg(feature = "payload_encryption")]
+        let sealed_rpc = self.sealed_rpc.take();
+        let sequence = self.responding_to;
+        let ResponseStream { write, mut written } = response;
+        let this = &mut *self;
+        // Owns [written], so the writer's writes fail rather than wait once the stream is
+        // cancelled or sending has failed. True if cancelled
+        let send = async move {
+            let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+            loop {
+                let read = written.read(&mut buffer).await.map_err(|e| {
+                    RpcError::TransportError(TransportError::SendError(e.to_string()))
+                })?;
+                if read == 0 {
+                    return Ok::<bool, RpcError>(false);
+                }
+                let chunk = &buffer[..read];
+                #[cfg(feature = "payload_encryption")]
+                let sealed_chunk = match (&sealed_rpc, &this.config.payload_keys) {
+                    (Some(rpc), Some(keys)) => {
+                        keys.seal(rpc, Direction::Response, chunk).transpose()?
+                    }
+                    _ => None,
+                };
+                #[cfg(feature = "payload_encryption")]
+                let chunk = sealed_chunk.as_deref().unwrap_or(chunk);
+                this.responding_to = sequence;
+                this.send_response(&ResponseFrame::Chunk(chunk)).await?;
+                if this.receive_stream_request().await? {
+                    return Ok(true);
+                }
+            }
+        };
+        let (written, cancelled) = tokio::join!(write, send);
+        let cancelled = cancelled?;
+        self.responding_to = sequence;
+        match written {
+            Err(e) if !cancelled => {
+                let remote_error = RemoteError::relay(&e, &self.config.wire_config);
+                self.send_response(&ResponseFrame::Err(remote_error))
+                    .await?;
+                Ok(Err(e))
+            }
+            written => {
+                self.send_response(&ResponseFrame::StreamEnd).await?;
+                Ok(match cancelled {
+                    true => Err(RpcError::status(
+                        StatusCode::Cancelled,
+                        "The client cancelled the response",
+                    )),
+                    false => written,
                 })
             }
-            Err(rpc_error) => Err(RpcError::TransportError(rpc_error)),
         }
     }
 
-    pub async fn respond(&mut self, bytes: Bytes<'_>) -> RpcResult<()> {
+    /// Wait for the client to ask for the next chunk of a streamed response, true if it
+    /// cancelled the stream instead
+    async fn receive_stream_request(&mut self) -> RpcResult<bool> {
+        match self.receive_stream_frame().await? {
+            RequestFrameOwned::NextChunk => Ok(false),
+            RequestFrameOwned::CancelStream => Ok(true),
+            _ => Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Expected a request for the next chunk of the response"),
+            ))),
+        }
+    }
+
+    /// Ask the client for the rest of the request [upload] reads, a chunk at a time, then send
+    /// back the response it resolves to. Resolves to that response once it's sent: the reader's
+    /// error, or [StatusCode::Cancelled] if the client cancelled the request
+    pub(crate) async fn respond_uploaded(
+        &mut self,
+        upload: RequestStream,
+    ) -> RpcResult<RpcResult<OwnedBytes>> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Streamed requests"));
+        }
+        // Left for sealing the response
+        #[cfg(feature = "payload_encryption")]
+        let sealed_rpc = self.sealed_rpc.clone();
+        let sequence = self.responding_to;
+        let RequestStream { read, mut body } = upload;
+        let this = &mut *self;
+        // Owns [body], so the reader sees the request end once it has all arrived, or receiving
+        // has failed. True if the client cancelled the request
+        let receive = async move {
+            loop {
+                this.responding_to = sequence;
+                this.send_response(&ResponseFrame::NextUploadChunk).await?;
+                let chunk = match this.receive_stream_frame().await? {
+                    RequestFrameOwned::UploadChunk(chunk) => chunk,
+                    RequestFrameOwned::UploadEnd => return Ok::<bool, RpcError>(false),
+                    RequestFrameOwned::CancelStream => return Ok(true),
+                    _ => {
+                        return Err(RpcError::TransportError(TransportError::ReceiveError(
+                            String::from("Expected the next chunk of the request"),
+                        )))
+                    }
+                };
+                #[cfg(feature = "payload_encryption")]
+                let chunk = match (&sealed_rpc, &this.config.payload_keys) {
+                    (Some(rpc), Some(keys)) => match keys.open(rpc, Direction::Query, &chunk) {
+                        Some(opened) => opened?,
+                        None => chunk,
+                    },
+                    _ => chunk,
+                };
+                if body.write_all(&chunk).await.is_err() {
+                    // The rpc has read all it needs, the client is answered with its response
+                    return Ok(false);
+                }
+            }
+        };
+        let (response, cancelled) = tokio::join!(read, receive);
+        let response = match cancelled? {
+            true => Err(RpcError::status(
+                StatusCode::Cancelled,
+                "The client cancelled the request",
+            )),
+            false => response,
+        };
+        self.responding_to = sequence;
+        match response {
+            Ok(response_bytes) => {
+                self.respond_versioned(Ok(&response_bytes), None).await?;
+                Ok(Ok(response_bytes))
+            }
+            Err(e) => {
+                #[cfg(feature = "payload_encryption")]
+                self.sealed_rpc.take();
+                let remote_error = RemoteError::relay(&e, &self.config.wire_config);
+                self.send_response(&ResponseFrame::Err(remote_error))
+                    .await?;
+                Ok(Err(e))
+            }
+        }
+    }
+
+    /// Wait for the client's next frame of a streamed response or request
+    async fn receive_stream_frame(&mut self) -> RpcResult<RequestFrameOwned> {
+        let bytes = self
+            .internal_transport
+            .receive(Some(self.config.rcv_timeout))
+            .await?;
+        if bytes.is_empty() {
+            return Err(RpcError::TransportError(TransportError::ReceiveError(
+                String::from("Connection closed mid-stream"),
+            )));
+        }
+        self.config.check_message_size(bytes.len())?;
+        #[cfg(feature = "response_signing")]
+        if self.config.signing_key.is_some() {
+            self.last_request.clone_from(&bytes);
+        }
+        Ok(self.config.wire_config.deserialize(&bytes)?)
+    }
+
+    /// Answer a [ReceivedFrame::Heartbeat]
+    pub async fn respond_heartbeat(&mut self) -> RpcResult<()> {
+        self.send_response(&ResponseFrame::Heartbeat).await
+    }
+
+    /// Answer a [ReceivedFrame::StartTls], where if [accepted] the TLS handshake must follow
+    pub async fn respond_start_tls(&mut self, accepted: bool) -> RpcResult<()> {
+        self.send_response(&ResponseFrame::StartTls { accepted })
+            .await
+    }
+
+    /// Answer a [ReceivedFrame::AuthStart] with the authentication [challenge]
+    pub async fn respond_auth_challenge(&mut self, challenge: Bytes<'_>) -> RpcResult<()> {
+        self.send_response(&ResponseFrame::AuthChallenge(challenge))
+            .await
+    }
+
+    /// Answer a [ReceivedFrame::AuthResponse] that authenticated the client
+    pub async fn respond_authenticated(&mut self) -> RpcResult<()> {
+        self.send_response(&ResponseFrame::Authenticated).await
+    }
+
+    /// Answer a [ReceivedFrame::Http] [request] with [status] and a plain text [body], after
+    /// which the connection should be closed
+    pub async fn respond_http(
+        &mut self,
+        request: &HttpRequest,
+        status: u16,
+        reason: &str,
+        body: &str,
+    ) -> RpcResult<()> {
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            reason,
+            body.len()
+        );
+        if request.method != "HEAD" {
+            response.push_str(body);
+        }
         self.internal_transport
-            .send(bytes)
+            .send(response.as_bytes())
             .await
             .map_err(RpcError::TransportError)
     }
+
+    async fn send_response(&mut self, frame: &ResponseFrame<'_>) -> RpcResult<()> {
+        if self.config.compat == TransportCompat::V0 {
+            return Err(v0_unsupported("Responses other than results"));
+        }
+        self.frame_buffer.clear();
+        self.config
+            .wire_config
+            .serialize_frame_into(frame, &mut self.frame_buffer)?;
+        if let Some(sequence) = self.responding_to.take() {
+            let mut sequenced_buffer = OwnedBytes::new();
+            self.config.wire_config.serialize_frame_into(
+                &ResponseFrame::Sequenced {
+                    sequence,
+                    frame: &self.frame_buffer,
+                },
+                &mut sequenced_buffer,
+            )?;
+            self.frame_buffer = sequenced_buffer;
+        }
+        #[cfg(feature = "response_signing")]
+        if let Some(signing_key) = &self.config.signing_key {
+            let signature =
+                crate::signing::sign(signing_key, &self.last_request, &self.frame_buffer);
+            let mut signed_buffer = OwnedBytes::new();
+            self.config.wire_config.serialize_frame_into(
+                &ResponseFrame::Signed {
+                    frame: &self.frame_buffer,
+                    signature: &signature,
+                },
+                &mut signed_buffer,
+            )?;
+            self.frame_buffer = signed_buffer;
+        }
+        self.config.check_message_size(self.frame_buffer.len())?;
+        self.internal_transport
+            .send(&self.frame_buffer)
+            .await
+            .map_err(RpcError::TransportError)
+    }
+}
+
+/// The error a [response] that isn't the one expected stands for
+fn response_error(response: ResponsePackage) -> RpcError {
+    match response {
+        ResponsePackage::Err(remote_error) => RpcError::Remote(remote_error),
+        ResponsePackage::DryRun { rpc } => RpcError::DryRun { rpc },
+        ResponsePackage::Conflict { current_version } => RpcError::Conflict { current_version },
+        ResponsePackage::Maintenance {
+            rpc,
+            retry_after_ms,
+        } => RpcError::Maintenance {
+            rpc,
+            retry_after: retry_after_ms.map(Duration::from_millis),
+        },
+        _ => RpcError::TransportError(TransportError::ReceiveError(String::from(
+            "Expected a response, got the answer to another frame",
+        ))),
+    }
+}
+
+fn v0_unsupported(what: &str) -> RpcError {
+    RpcError::TransportError(TransportError::SendError(format!(
+        "{} can't be sent with TransportCompat::V0",
+        what
+    )))
 }
 
 #[cfg(test)]
@@ -274,10 +2163,14 @@ impl InternalTransport for CannedTestingTransport {
         _b: Bytes<'_>,
         _timeout: Duration,
     ) -> Result<OwnedBytes, TransportError> {
-        Ok(
+        let response_bytes =
             serde_pickle::to_vec(&self.always_respond_with, serde_pickle::SerOptions::new())
-                .unwrap(),
+                .unwrap();
+        Ok(serde_pickle::to_vec(
+            &ResponseFrame::Ok(&response_bytes),
+            serde_pickle::SerOptions::new(),
         )
+        .unwrap())
     }
     async fn receive(&mut self, _timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
         if self.receive_times > 0 {
@@ -294,25 +2187,109 @@ impl InternalTransport for CannedTestingTransport {
     }
 }
 
+/// Implementation of [InternalTransport] over any duplex byte stream, such as an SSH tunnel or
+/// a TLS stream from another library. As over TCP, a message ends with the first read that
+/// doesn't fill the receive buffer, so the stream must hand over at least 1KiB per read
+pub struct StreamTransport<S> {
+    stream: S,
+    write_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+}
+
+impl<S> StreamTransport<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            write_timeout: None,
+            max_message_size: None,
+        }
+    }
+
+    /// Fail sends that take longer than [write_timeout], see [TcpTransport::set_write_timeout]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[async_trait]
+impl<S> InternalTransport for StreamTransport<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        send_with_timeout(&mut self.stream, b, self.write_timeout).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        receive_with_timeout(&mut self.stream, timeout, self.max_message_size).await
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.max_message_size = max_message_size;
+    }
+}
+
+/// [InternalTransport] over a [tokio::net::UnixStream], for clients on the same host as the
+/// server
+#[cfg(unix)]
+pub type UnixTransport = StreamTransport<tokio::net::UnixStream>;
+
 /// Pre-packaged implementation of [InternalTransport] using [tokio::net::TcpStream]
 pub struct TcpTransport {
     stream: tokio::net::TcpStream,
+    write_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
 }
 
 impl TcpTransport {
     pub fn new(stream: tokio::net::TcpStream) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            write_timeout: None,
+            max_message_size: None,
+        }
+    }
+
+    /// Fail sends that take longer than [write_timeout], e.g. to a stalled peer whose receive
+    /// buffer is full. See [TransportConfig::write_timeout]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    /// Enable TCP keepalive, probing the peer once the connection has been idle for [idle]
+    pub fn set_keepalive(&self, idle: Duration) -> Result<(), TransportError> {
+        set_keepalive(&self.stream, idle)
+    }
+
+    pub fn into_stream(self) -> tokio::net::TcpStream {
+        self.stream
     }
 }
 
 #[async_trait]
 impl InternalTransport for TcpTransport {
     async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
-        use tokio::io::AsyncWriteExt;
-        self.stream
-            .write_all(b)
-            .await
-            .map_err(TransportError::io_send)
+        send_with_timeout(&mut self.stream, b, self.write_timeout).await
     }
 
     async fn send_and_wait_for_response(
@@ -325,33 +2302,131 @@ impl InternalTransport for TcpTransport {
     }
 
     async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
-        use tokio::io::AsyncReadExt;
-        // 1024 * 8 = 8192 bits = 256 * u32s
-        let mut buf = [0u8; 1024];
-        let mut return_bytes = Vec::new();
-        loop {
-            let read_fut = self.stream.read(&mut buf);
-            let result = match timeout {
-                Some(timeout_) => match tokio::time::timeout(timeout_, read_fut).await {
-                    Ok(r) => r,
-                    Err(_) => return Err(TransportError::ReceiveTimeout(timeout_)),
-                },
-                None => read_fut.await,
-            };
-            match result {
-                Ok(0) => {
+        receive_with_timeout(&mut self.stream, timeout, self.max_message_size).await
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        peer_disconnected(&self.stream)
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.max_message_size = max_message_size;
+    }
+}
+
+pub(crate) fn set_keepalive(
+    stream: &tokio::net::TcpStream,
+    idle: Duration,
+) -> Result<(), TransportError> {
+    let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+    socket2::SockRef::from(stream)
+        .set_tcp_keepalive(&keepalive)
+        .map_err(|e| TransportError::ConnectError(format!("{:?}", e)))
+}
+
+/// Write all of [b] to [stream], within [write_timeout] if given
+pub(crate) async fn send_with_timeout<W: tokio::io::AsyncWrite + Unpin + Send>(
+    stream: &mut W,
+    b: Bytes<'_>,
+    write_timeout: Option<Duration>,
+) -> Result<(), TransportError> {
+    use tokio::io::AsyncWriteExt;
+    let deadline = write_timeout.map(|write_timeout| tokio::time::Instant::now() + write_timeout);
+    let timed_out = |written: usize| {
+        TransportError::SendError(format!(
+            "Timed out after {:?} with {} of {} bytes written",
+            write_timeout.unwrap_or_default(),
+            written,
+            b.len()
+        ))
+    };
+    let mut written = 0;
+    while written < b.len() {
+        let write_fut = stream.write(&b[written..]);
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, write_fut).await {
+                Ok(r) => r,
+                Err(_) => return Err(timed_out(written)),
+            },
+            None => write_fut.await,
+        };
+        match result {
+            Ok(0) => {
+                return Err(TransportError::SendError(format!(
+                    "Connection closed with {} of {} bytes written",
+                    written,
+                    b.len()
+                )))
+            }
+            Ok(bytes_written) => written += bytes_written,
+            Err(e) => {
+                return Err(TransportError::SendError(format!(
+                    "{:?} with {} of {} bytes written",
+                    e,
+                    written,
+                    b.len()
+                )))
+            }
+        }
+    }
+    // A no-op for plain TCP, but wrapping streams such as TLS may buffer writes
+    let flush_fut = stream.flush();
+    let result = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, flush_fut).await {
+            Ok(r) => r,
+            Err(_) => return Err(timed_out(written)),
+        },
+        None => flush_fut.await,
+    };
+    result.map_err(|e| TransportError::SendError(format!("{:?} flushing", e)))
+}
+
+/// Read one message from [stream], waiting at most [timeout] for each read if given, and stopping
+/// once it's over [max_message_size] bytes
+pub(crate) async fn receive_with_timeout<R: tokio::io::AsyncRead + Unpin + Send>(
+    stream: &mut R,
+    timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+) -> Result<OwnedBytes, TransportError> {
+    use tokio::io::AsyncReadExt;
+    // 1024 * 8 = 8192 bits = 256 * u32s
+    let mut buf = [0u8; 1024];
+    let mut return_bytes = Vec::new();
+    loop {
+        let read_fut = stream.read(&mut buf);
+        let result = match timeout {
+            Some(timeout_) => match tokio::time::timeout(timeout_, read_fut).await {
+                Ok(r) => r,
+                Err(_) => return Err(TransportError::ReceiveTimeout(timeout_)),
+            },
+            None => read_fut.await,
+        };
+        match result {
+            Ok(0) => {
+                return Ok(return_bytes);
+            }
+            Ok(bytes_received) => {
+                return_bytes.extend_from_slice(&buf[0..bytes_received]);
+                let too_large = max_message_size.is_some_and(|max| return_bytes.len() > max);
+                if bytes_received < buf.len() || too_large {
                     return Ok(return_bytes);
                 }
-                Ok(bytes_received) => {
-                    return_bytes.extend_from_slice(&buf[0..bytes_received]);
-                    if bytes_received < buf.len() {
-                        return Ok(return_bytes);
-                    }
-                }
-                Err(e) => {
-                    return Err(TransportError::io_receive(e));
-                }
-            };
-        }
+            }
+            Err(e) => {
+                return Err(TransportError::io_receive(e));
+            }
+        };
+    }
+}
+
+pub(crate) fn peer_disconnected(stream: &tokio::net::TcpStream) -> bool {
+    // Peek without waiting: a closed or reset connection is immediately readable, whereas a
+    // live idle one is not
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut byte = [0u8; 1];
+    let mut buf = tokio::io::ReadBuf::new(&mut byte);
+    match stream.poll_peek(&mut cx, &mut buf) {
+        std::task::Poll::Ready(Ok(0)) | std::task::Poll::Ready(Err(_)) => true,
+        std::task::Poll::Ready(Ok(_)) | std::task::Poll::Pending => false,
     }
 }
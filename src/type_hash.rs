@@ -0,0 +1,56 @@
+//! Stable hashes of the structure of an rpc's name, query and response types (Enable the
+//! "type_hash" feature).
+//!
+//! Clients send the hash with every query, and a server with the feature rejects queries whose
+//! hash differs from that of its own types with [crate::error::RpcError::TypeMismatch], catching
+//! a client and server built from different revisions before they misread each other's payloads.
+//! Lighter than [crate::schema], as no schema is ever exchanged, at the cost of not saying which
+//! types differ. Either side without the feature skips the check
+use crate::core::{RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use serde_reflection::{FormatHolder, Tracer, TracerConfig};
+use std::sync::OnceLock;
+
+/// Hash of the structure of [Name], [Q] and [R] as traced from their [serde::Deserialize] impls,
+/// stable across builds of the same types. Kept to 63 bits, as pickle can only carry integers
+/// that fit an [i64]
+pub fn type_hash<Name: RpcName, Q: RpcType, R: RpcType>() -> RpcResult<u64> {
+    let trace_error = |e: serde_reflection::Error| {
+        RpcError::Custom(format!(
+            "Could not trace types of ({}, {}, {}): {}",
+            std::any::type_name::<Name>(),
+            std::any::type_name::<Q>(),
+            std::any::type_name::<R>(),
+            e
+        ))
+    };
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let mut formats = [
+        tracer.trace_simple_type::<Name>().map_err(trace_error)?.0,
+        tracer.trace_simple_type::<Q>().map_err(trace_error)?.0,
+        tracer.trace_simple_type::<R>().map_err(trace_error)?.0,
+    ];
+    for format in &mut formats {
+        format.normalize().map_err(trace_error)?;
+    }
+    let registry = tracer.registry().map_err(trace_error)?;
+    Ok(fnv1a(format!("{:?}{:?}", formats, registry).as_bytes()) >> 1)
+}
+
+/// [type_hash], traced only on the first call for each [cache]
+#[doc(hidden)]
+pub fn cached_type_hash<Name: RpcName, Q: RpcType, R: RpcType>(
+    cache: &OnceLock<Result<u64, String>>,
+) -> RpcResult<u64> {
+    cache
+        .get_or_init(|| type_hash::<Name, Q, R>().map_err(|e| e.to_string()))
+        .clone()
+        .map_err(RpcError::Custom)
+}
+
+/// 64-bit FNV-1a, used over [std::hash::DefaultHasher] whose output may change between releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
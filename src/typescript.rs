@@ -0,0 +1,341 @@
+//! TypeScript bindings for calling a server's rpcs from a web frontend (Enable the "typescript"
+//! feature).
+//!
+//! [TypeScriptBindings] generates, from the [RpcSchema]s of a set of rpcs, a TypeScript module
+//! declaring their query and response types and a client with a typed method per rpc. The
+//! client speaks the [crate::TransportWireConfig::DebugJsonLines] wire format, sending each frame
+//! as one WebSocket message (served with `RpcServer::serve_websocket`, Enable the
+//! "transport_websocket" feature), or over any other `Connection` the frontend implements
+//!
+//! ```rust,ignore
+//! let mut bindings = TypeScriptBindings::new();
+//! bindings.add_rpc(&rpcs::AddName::client())?;
+//! bindings.add_rpc(&rpcs::GetNames::client())?;
+//! std::fs::write("web/src/names.ts", bindings.generate())?;
+//! ```
+//!
+//! and then in the frontend:
+//!
+//! ```typescript,ignore
+//! const client = new Client(await WebSocketConnection.open("wss://names.example.com/rpc"));
+//! await client.addName("Ferris");
+//! const names: string[] = await client.getNames(null);
+//! ```
+//!
+//! Integers are declared as `number`, so 64 and 128 bit integers beyond 2^53 lose precision in
+//! the frontend
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use crate::schema::RpcSchema;
+use serde_reflection::{ContainerFormat, Format, Named, VariantFormat};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// An rpc to generate a client method for
+struct BoundRpc {
+    /// The rpc's name as the server expects it on the wire, serialised as json
+    name_json: String,
+    schema: RpcSchema,
+}
+
+/// Generates TypeScript bindings for the rpcs added to it, see [crate::typescript]
+#[derive(Default)]
+pub struct TypeScriptBindings {
+    rpcs: BTreeMap<String, BoundRpc>,
+}
+
+impl TypeScriptBindings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate a client method for [rpc], named after the [std::fmt::Display] form of its name
+    pub fn add_rpc<Name, Q, R>(&mut self, rpc: &Rpc<Name, Q, R>) -> RpcResult<()>
+    where
+        Name: RpcName,
+        Q: RpcType,
+        R: RpcType,
+    {
+        let name_json = serde_json::to_string(&rpc.name).map_err(|e| {
+            RpcError::Custom(format!("Could not serialise rpc name {}: {}", rpc.name, e))
+        })?;
+        let schema = RpcSchema::of::<Q, R>()?;
+        self.rpcs
+            .insert(rpc.name.to_string(), BoundRpc { name_json, schema });
+        Ok(())
+    }
+
+    /// The TypeScript module for the rpcs added so far
+    pub fn generate(&self) -> String {
+        let mut types = BTreeMap::new();
+        for rpc in self.rpcs.values() {
+            types.extend(rpc.schema.registry.iter());
+        }
+        let mut out = String::from("// Generated by pirates, do not edit\n\n");
+        for (name, container) in types {
+            declare_type(&mut out, name, container);
+        }
+        out.push_str(RUNTIME);
+        out.push_str("\nexport class Client {\n");
+        out.push_str("  constructor(private readonly connection: Connection) {}\n");
+        for (name, rpc) in &self.rpcs {
+            let _ = write!(
+                out,
+                "\n  {}(query: {}): Promise<{}> {{\n    return call(this.connection, {}, query);\n  }}\n",
+                method_name(name),
+                type_of(&rpc.schema.query),
+                type_of(&rpc.schema.response),
+                string_literal(&rpc.name_json),
+            );
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Declare the named type [name] as it is written in json by serde
+fn declare_type(out: &mut String, name: &str, container: &ContainerFormat) {
+    let declared = match container {
+        ContainerFormat::UnitStruct => String::from("null"),
+        ContainerFormat::NewTypeStruct(format) => type_of(format),
+        ContainerFormat::TupleStruct(formats) => tuple_of(formats),
+        ContainerFormat::Struct(fields) => object_of(fields),
+        ContainerFormat::Enum(variants) if variants.is_empty() => String::from("never"),
+        ContainerFormat::Enum(variants) => variants
+            .values()
+            .map(|variant| {
+                let content = match &variant.value {
+                    VariantFormat::Unit => return string_literal(&variant.name),
+                    VariantFormat::NewType(format) => type_of(format),
+                    VariantFormat::Tuple(formats) => tuple_of(formats),
+                    VariantFormat::Struct(fields) => object_of(fields),
+                    VariantFormat::Variable(_) => String::from("unknown"),
+                };
+                format!("{{ {}: {} }}", property_name(&variant.name), content)
+            })
+            .collect::<Vec<_>>()
+            .join(" | "),
+    };
+    let _ = writeln!(out, "export type {} = {};\n", name, declared);
+}
+
+fn type_of(format: &Format) -> String {
+    match format {
+        Format::Unit => String::from("null"),
+        Format::Bool => String::from("boolean"),
+        Format::I8
+        | Format::I16
+        | Format::I32
+        | Format::I64
+        | Format::I128
+        | Format::U8
+        | Format::U16
+        | Format::U32
+        | Format::U64
+        | Format::U128
+        | Format::F32
+        | Format::F64 => String::from("number"),
+        Format::Char | Format::Str => String::from("string"),
+        Format::Bytes => String::from("number[]"),
+        Format::TypeName(name) => name.clone(),
+        Format::Option(format) => format!("{} | null", type_of(format)),
+        Format::Seq(format)
+        | Format::TupleArray {
+            content: format, ..
+        } => {
+            format!("({})[]", type_of(format))
+        }
+        // Json object keys are always strings, whatever the key type
+        Format::Map { value, .. } => format!("Record<string, {}>", type_of(value)),
+        Format::Tuple(formats) => tuple_of(formats),
+        Format::Variable(_) => String::from("unknown"),
+    }
+}
+
+fn tuple_of(formats: &[Format]) -> String {
+    let formats: Vec<String> = formats.iter().map(type_of).collect();
+    format!("[{}]", formats.join(", "))
+}
+
+fn object_of(fields: &[Named<Format>]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| format!("{}: {}", property_name(&field.name), type_of(&field.value)))
+        .collect();
+    format!("{{ {} }}", fields.join("; "))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_' || first == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+fn property_name(name: &str) -> String {
+    if is_identifier(name) {
+        name.to_string()
+    } else {
+        string_literal(name)
+    }
+}
+
+/// The client method for the rpc displayed as [name], e.g. "getNames" for "GetNames"
+fn method_name(name: &str) -> String {
+    let mut method: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if let Some(first) = method.get_mut(0..1) {
+        first.make_ascii_lowercase();
+    }
+    if !is_identifier(&method) {
+        method.insert(0, '_');
+    }
+    method
+}
+
+fn string_literal(s: &str) -> String {
+    serde_json::to_string(s).expect("Strings always serialise")
+}
+
+/// Everything a generated client needs besides its types and methods
+const RUNTIME: &str = r#"/**
+ * An error raised by the server, or by the client failing to make a call. Its code is one of
+ * pirates::error::StatusCode, with details if the server sent any
+ */
+export class RpcError extends Error {
+  constructor(
+    message: string,
+    readonly retryable: boolean,
+    readonly code: string = "Unknown",
+    readonly details?: unknown,
+  ) {
+    super(message);
+    this.name = "RpcError";
+  }
+}
+
+/** Carries frames to a server and its replies back, one call at a time */
+export interface Connection {
+  request(frame: string): Promise<string>;
+}
+
+/** A Connection sending each frame as a WebSocket message */
+export class WebSocketConnection implements Connection {
+  private pending: Promise<unknown> = Promise.resolve();
+
+  private constructor(private readonly socket: WebSocket) {}
+
+  static open(url: string): Promise<WebSocketConnection> {
+    return new Promise((resolve, reject) => {
+      const socket = new WebSocket(url);
+      socket.onopen = () => resolve(new WebSocketConnection(socket));
+      socket.onerror = () => {
+        reject(new RpcError(`Could not connect to ${url}`, true, "Unavailable"));
+      };
+    });
+  }
+
+  request(frame: string): Promise<string> {
+    // Replies carry no id, so calls take turns
+    const reply = this.pending.then(
+      () =>
+        new Promise<string>((resolve, reject) => {
+          this.socket.onmessage = (event) => resolve(String(event.data));
+          this.socket.onclose = () => reject(new RpcError("Connection closed", true, "Unavailable"));
+          this.socket.send(frame);
+        }),
+    );
+    this.pending = reply.catch(() => undefined);
+    return reply;
+  }
+
+  close(): void {
+    this.socket.close();
+  }
+}
+
+async function call<Q, R>(connection: Connection, name: string, query: Q): Promise<R> {
+  const frame = JSON.stringify({
+    Query: { name_bytes: name, query_bytes: JSON.stringify(query), reserved: false, type_hash: null },
+  });
+  let reply = JSON.parse(await connection.request(frame));
+  // Signatures are left unchecked, see pirates::signing
+  while (typeof reply === "object" && reply !== null && "Signed" in reply) {
+    reply = JSON.parse(reply.Signed.frame);
+  }
+  if (typeof reply === "object" && reply !== null) {
+    if ("Ok" in reply) {
+      return JSON.parse(reply.Ok) as R;
+    }
+    if ("Err" in reply) {
+      const { message, retryable, code, details } = reply.Err;
+      const parsed = details == null ? undefined : JSON.parse(details);
+      throw new RpcError(message, retryable, code ?? "Unknown", parsed);
+    }
+    if ("DryRun" in reply) {
+      const message = `Server is in dry-run mode, ${reply.DryRun.rpc} was not run`;
+      throw new RpcError(message, false, "FailedPrecondition");
+    }
+    if ("Maintenance" in reply) {
+      const { rpc, retry_after_ms } = reply.Maintenance;
+      const message = `${rpc} is under maintenance, retry after ${retry_after_ms}ms`;
+      throw new RpcError(message, true, "Unavailable");
+    }
+  }
+  throw new RpcError(`Unexpected reply: ${JSON.stringify(reply)}`, false, "Internal");
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::HelloWorldRpcName;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "explicit_rpc_types", derive(crate::RpcType))]
+    struct Page {
+        names: Vec<String>,
+        next: Option<u64>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "explicit_rpc_types", derive(crate::RpcType))]
+    enum Filter {
+        All,
+        Prefix(String),
+        Range { from: u32, to: u32 },
+    }
+
+    #[test]
+    fn generates_types_and_client() {
+        let mut bindings = TypeScriptBindings::new();
+        bindings
+            .add_rpc(&Rpc::<_, Filter, Page>::new(HelloWorldRpcName::GetI))
+            .unwrap();
+        bindings
+            .add_rpc(&Rpc::<_, (), usize>::new(HelloWorldRpcName::IncrI))
+            .unwrap();
+        let generated = bindings.generate();
+        assert!(generated.contains(
+            "export type Filter = \"All\" | { Prefix: string } | { Range: { from: number; to: number } };"
+        ));
+        assert!(
+            generated.contains("export type Page = { names: (string)[]; next: number | null };")
+        );
+        assert!(generated.contains(
+            "  getI(query: Filter): Promise<Page> {\n    return call(this.connection, \"\\\"GetI\\\"\", query);"
+        ));
+        assert!(generated.contains("  incrI(query: null): Promise<number> {"));
+    }
+
+    #[test]
+    fn method_names() {
+        assert_eq!(method_name("GetNames"), "getNames");
+        assert_eq!(method_name("admin.stats"), "admin_stats");
+        assert_eq!(method_name("2fa"), "_2fa");
+    }
+}
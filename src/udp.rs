@@ -0,0 +1,364 @@
+//! Serving and calling over UDP, for small latency sensitive rpcs where a TCP handshake costs
+//! more than the call. Each frame is sent as one datagram tagged with the id of the request it
+//! belongs to, so clients can match responses to their requests and ignore those arriving late.
+//!
+//! A client resends its request until the response arrives or its receive timeout is up, and a
+//! server answers a resent request with the response it already sent rather than calling the
+//! rpc again. Frames must fit in a datagram, see [MAX_DATAGRAM_SIZE], so rpcs with large queries
+//! or responses are better served over TCP. Each client is served as a connection of its own,
+//! closed once idle for [crate::TransportConfig::idle_timeout]
+//!
+//! ```rust,ignore
+//! tokio::spawn(async move { server.serve_udp("0.0.0.0:5353").await });
+//! let retransmit_interval = Duration::from_millis(50);
+//! let mut transport = get_name.connect_udp("names.example.com:5353", retransmit_interval).await?;
+//! let name = get_name.call(id, &mut transport).await?;
+//! ```
+use crate::error::RpcResult;
+use crate::listener::Listener;
+use crate::transport::{InternalTransport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// The largest datagram sent or received, request id included
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Bytes of the request id leading each datagram
+const ID_SIZE: usize = 8;
+
+/// How many datagrams from a client are queued for its connection before more are dropped
+const PEER_QUEUE: usize = 16;
+
+fn datagram(id: u64, b: Bytes<'_>) -> Result<OwnedBytes, TransportError> {
+    if ID_SIZE + b.len() > MAX_DATAGRAM_SIZE {
+        return Err(TransportError::SendError(format!(
+            "A {} byte frame doesn't fit in a datagram",
+            b.len()
+        )));
+    }
+    let mut datagram = Vec::with_capacity(ID_SIZE + b.len());
+    datagram.extend_from_slice(&id.to_be_bytes());
+    datagram.extend_from_slice(b);
+    Ok(datagram)
+}
+
+/// The request id and frame of [datagram], [None] for one too short to be from a pirates peer
+fn split_datagram(datagram: &[u8]) -> Option<(u64, &[u8])> {
+    let (id, frame) = datagram.split_first_chunk::<ID_SIZE>()?;
+    Some((u64::from_be_bytes(*id), frame))
+}
+
+/// Client side implementation of [InternalTransport] over UDP, see [crate::udp]
+pub struct UdpTransport {
+    socket: UdpSocket,
+    retransmit_interval: Duration,
+    /// Of the last request sent
+    id: u64,
+}
+
+impl UdpTransport {
+    /// Call over [socket], already connected to the server, resending requests every
+    /// [retransmit_interval] until they're answered
+    pub fn new(socket: UdpSocket, retransmit_interval: Duration) -> Self {
+        Self {
+            socket,
+            retransmit_interval,
+            id: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// The frame of the next datagram answering the last request, ignoring any others
+    async fn receive_answer(&mut self) -> Result<OwnedBytes, TransportError> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let received = self
+                .socket
+                .recv(&mut buffer)
+                .await
+                .map_err(TransportError::io_receive)?;
+            match split_datagram(&buffer[..received]) {
+                Some((id, frame)) if id == self.id => return Ok(frame.to_vec()),
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InternalTransport for UdpTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.id += 1;
+        let datagram = datagram(self.id, b)?;
+        self.socket
+            .send(&datagram)
+            .await
+            .map(|_| ())
+            .map_err(|e| TransportError::SendError(e.to_string()))
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        let resend = datagram(self.id, b)?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let wait_until = deadline.min(tokio::time::Instant::now() + self.retransmit_interval);
+            if let Ok(answer) = tokio::time::timeout_at(wait_until, self.receive_answer()).await {
+                return answer;
+            }
+            if wait_until >= deadline {
+                return Err(TransportError::ReceiveTimeout(timeout));
+            }
+            self.socket
+                .send(&resend)
+                .await
+                .map_err(|e| TransportError::SendError(e.to_string()))?;
+        }
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receive_answer())
+                .await
+                .map_err(|_| TransportError::ReceiveTimeout(timeout))?,
+            None => self.receive_answer().await,
+        }
+    }
+}
+
+/// Server side implementation of [InternalTransport] over UDP, serving one client of a
+/// [UdpListener]
+pub struct UdpPeerTransport {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    datagrams: mpsc::Receiver<OwnedBytes>,
+    /// The id of the request being answered, the highest received. Ids only grow, so those
+    /// below it are of requests already answered
+    answering: u64,
+    /// The last response sent, resent should its request arrive again
+    last_response: Option<(u64, OwnedBytes)>,
+}
+
+impl UdpPeerTransport {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The next request, answering any resent on the way and dropping those arriving after a
+    /// newer one, or nothing once the listener is gone
+    async fn receive_request(&mut self) -> Result<OwnedBytes, TransportError> {
+        while let Some(datagram) = self.datagrams.recv().await {
+            let Some((id, frame)) = split_datagram(&datagram) else {
+                continue;
+            };
+            match &self.last_response {
+                Some((answered, response)) if *answered == id => {
+                    self.socket
+                        .send_to(response, self.peer)
+                        .await
+                        .map_err(|e| TransportError::SendError(e.to_string()))?;
+                }
+                _ if id <= self.answering => continue,
+                _ => {
+                    self.answering = id;
+                    return Ok(frame.to_vec());
+                }
+            }
+        }
+        Ok(OwnedBytes::new())
+    }
+}
+
+#[async_trait]
+impl InternalTransport for UdpPeerTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        let datagram = datagram(self.answering, b)?;
+        self.socket
+            .send_to(&datagram, self.peer)
+            .await
+            .map_err(|e| TransportError::SendError(e.to_string()))?;
+        self.last_response = Some((self.answering, datagram));
+        Ok(())
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receive_request())
+                .await
+                .map_err(|_| TransportError::ReceiveTimeout(timeout))?,
+            None => self.receive_request().await,
+        }
+    }
+}
+
+/// Serves the clients sending datagrams to a [UdpSocket], see [crate::RpcServer::serve_udp].
+/// Each new client is accepted as a connection, and handed the datagrams it sends from then on
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    /// Where the datagrams of each client still being served go
+    peers: Mutex<HashMap<SocketAddr, mpsc::Sender<OwnedBytes>>>,
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl UdpListener {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            peers: Mutex::new(HashMap::new()),
+            buffer: Mutex::new(vec![0u8; MAX_DATAGRAM_SIZE]),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for UdpListener {
+    type Stream = UdpPeerTransport;
+    type Transport = UdpPeerTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        let mut buffer = self.buffer.lock().unwrap();
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buffer);
+            let from = match self.socket.poll_recv_from(cx, &mut read_buf) {
+                Poll::Ready(Ok(from)) => from,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let datagram = read_buf.filled().to_vec();
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(sender) = peers.get(&from).filter(|sender| !sender.is_closed()) {
+                // Dropped like any other datagram when the client sends faster than it's served
+                let _ = sender.try_send(datagram);
+                continue;
+            }
+            peers.retain(|_, sender| !sender.is_closed());
+            let (sender, datagrams) = mpsc::channel(PEER_QUEUE);
+            let _ = sender.try_send(datagram);
+            peers.insert(from, sender);
+            let peer_transport = UdpPeerTransport {
+                socket: self.socket.clone(),
+                peer: from,
+                datagrams,
+                answering: 0,
+                last_response: None,
+            };
+            return Poll::Ready(Ok((peer_transport, Some(from))));
+        }
+    }
+
+    async fn transport(
+        &self,
+        peer_transport: Self::Stream,
+        _transport_config: &TransportConfig,
+    ) -> RpcResult<UdpPeerTransport> {
+        Ok(peer_transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_tagged() {
+        let tagged = datagram(7, b"frame").unwrap();
+        assert_eq!(split_datagram(&tagged), Some((7, &b"frame"[..])));
+        assert_eq!(split_datagram(b"short"), None);
+        assert!(datagram(7, &vec![0; MAX_DATAGRAM_SIZE]).is_err());
+    }
+
+    #[tokio::test]
+    async fn resent_requests_answered_again() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (sender, datagrams) = mpsc::channel(PEER_QUEUE);
+        let mut peer_transport = UdpPeerTransport {
+            socket: Arc::new(server_socket),
+            peer: client_socket.local_addr().unwrap(),
+            datagrams,
+            answering: 0,
+            last_response: None,
+        };
+        sender.send(datagram(1, b"query").unwrap()).await.unwrap();
+        assert_eq!(peer_transport.receive(None).await.unwrap(), b"query");
+        peer_transport.send(b"response").await.unwrap();
+        // The response was lost, so the client sends its query again before the next
+        sender.send(datagram(1, b"query").unwrap()).await.unwrap();
+        sender.send(datagram(2, b"next").unwrap()).await.unwrap();
+        assert_eq!(peer_transport.receive(None).await.unwrap(), b"next");
+
+        let mut buffer = [0u8; 64];
+        for _ in 0..2 {
+            let received = client_socket.recv(&mut buffer).await.unwrap();
+            assert_eq!(
+                split_datagram(&buffer[..received]),
+                Some((1, &b"response"[..]))
+            );
+        }
+        drop(sender);
+        assert!(peer_transport.receive(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_requests_dropped() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (sender, datagrams) = mpsc::channel(PEER_QUEUE);
+        let mut peer_transport = UdpPeerTransport {
+            socket: Arc::new(server_socket),
+            peer: client_socket.local_addr().unwrap(),
+            datagrams,
+            answering: 0,
+            last_response: None,
+        };
+        for (id, query) in [(1, b"first"), (2, b"secnd")] {
+            sender.send(datagram(id, query).unwrap()).await.unwrap();
+            assert_eq!(peer_transport.receive(None).await.unwrap(), query);
+            peer_transport.send(b"response").await.unwrap();
+        }
+        // A resend of the first request, delayed until after the second was answered
+        sender.send(datagram(1, b"first").unwrap()).await.unwrap();
+        sender.send(datagram(3, b"third").unwrap()).await.unwrap();
+        assert_eq!(peer_transport.receive(None).await.unwrap(), b"third");
+
+        let mut buffer = [0u8; 64];
+        for id in [1, 2] {
+            let received = client_socket.recv(&mut buffer).await.unwrap();
+            assert_eq!(
+                split_datagram(&buffer[..received]),
+                Some((id, &b"response"[..]))
+            );
+        }
+        // The stale request was neither taken as a new one nor answered
+        let nothing_more =
+            tokio::time::timeout(Duration::from_millis(50), client_socket.recv(&mut buffer));
+        assert!(nothing_more.await.is_err());
+    }
+}
@@ -0,0 +1,444 @@
+//! Serving and calling over WebSockets (Enable the "transport_websocket" feature), so servers can
+//! be reached through HTTP proxies and load balancers, and by the TypeScript clients generated
+//! with the "typescript" feature straight from a browser.
+//!
+//! Each frame is sent as one binary WebSocket message, or a text message in answer to a client
+//! sending text, as browsers do. Unlike over plain TCP, messages carry their own length, so reads
+//! needn't fill the receive buffer
+//!
+//! ```rust,ignore
+//! tokio::spawn(async move { server.serve_websocket("0.0.0.0:8080").await });
+//! let mut transport = get_names.connect_websocket("names.example.com:8080", "/rpc").await?;
+//! let names = get_names.call((), &mut transport).await?;
+//! ```
+use crate::error::{RpcError, RpcResult};
+use crate::listener::Listener;
+use crate::transport::{self, InternalTransport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Appended to a client's key to make the server's answer to it, see RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest handshake read before giving up on the peer
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Implementation of [InternalTransport] over a WebSocket, see [crate::websocket]
+pub struct WebSocketTransport {
+    stream: BufReader<TcpStream>,
+    write_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+    /// Clients mask what they send, servers expect it masked
+    client: bool,
+    /// Whether the last message received was text, answered in kind
+    text: bool,
+    /// Set once either end has sent a close frame
+    closed: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: OwnedBytes,
+}
+
+impl WebSocketTransport {
+    /// Fail sends that take longer than [write_timeout], see
+    /// [crate::TcpTransport::set_write_timeout]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        self.stream.get_ref()
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: Bytes<'_>) -> Result<(), TransportError> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.client {
+            let mask = random_bytes::<4>()
+                .map_err(|_| TransportError::SendError(String::from("Could not make a mask")))?;
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        transport::send_with_timeout(&mut self.stream, &frame, self.write_timeout).await
+    }
+
+    /// The next frame, or [None] at the end of the stream. Only the first [budget] bytes of its
+    /// payload are read, if given
+    async fn receive_frame(
+        &mut self,
+        budget: Option<usize>,
+    ) -> Result<Option<Frame>, TransportError> {
+        let mut header = [0u8; 2];
+        if self
+            .stream
+            .read(&mut header[..1])
+            .await
+            .map_err(TransportError::io_receive)?
+            == 0
+        {
+            return Ok(None);
+        }
+        self.stream
+            .read_exact(&mut header[1..])
+            .await
+            .map_err(TransportError::io_receive)?;
+        let masked = header[1] & 0x80 != 0;
+        if masked == self.client {
+            return Err(TransportError::ReceiveError(String::from(if self.client {
+                "Servers must not mask their frames"
+            } else {
+                "Clients must mask their frames"
+            })));
+        }
+        let len = match header[1] & 0x7F {
+            126 => self.stream.read_u16().await.map(u64::from),
+            127 => self.stream.read_u64().await,
+            len => Ok(u64::from(len)),
+        }
+        .map_err(TransportError::io_receive)?;
+        let mut mask = [0u8; 4];
+        if masked {
+            self.stream
+                .read_exact(&mut mask)
+                .await
+                .map_err(TransportError::io_receive)?;
+        }
+        let len = usize::try_from(len).map_err(|_| {
+            TransportError::ReceiveError(format!("A {} byte frame is too large", len))
+        })?;
+        let mut payload = vec![0u8; budget.map_or(len, |budget| len.min(budget))];
+        self.stream
+            .read_exact(&mut payload)
+            .await
+            .map_err(TransportError::io_receive)?;
+        if masked {
+            payload
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        Ok(Some(Frame {
+            fin: header[0] & 0x80 != 0,
+            opcode: header[0] & 0x0F,
+            payload,
+        }))
+    }
+
+    /// The next message, answering pings on the way, or nothing once the connection is closed
+    async fn receive_message(&mut self) -> Result<OwnedBytes, TransportError> {
+        let mut message = OwnedBytes::new();
+        while !self.closed {
+            // One byte over the limit, enough to tell it's too large
+            let budget = self
+                .max_message_size
+                .map(|max| (max + 1).saturating_sub(message.len()));
+            let Some(frame) = self.receive_frame(budget).await? else {
+                break;
+            };
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if frame.opcode != OPCODE_CONTINUATION {
+                        self.text = frame.opcode == OPCODE_TEXT;
+                    }
+                    message.extend_from_slice(&frame.payload);
+                    let too_large = self.max_message_size.is_some_and(|max| message.len() > max);
+                    if frame.fin || too_large {
+                        return Ok(message);
+                    }
+                }
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &frame.payload).await?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    self.closed = true;
+                    // The peer may already have gone, having said all it had to
+                    let _ = self.send_frame(OPCODE_CLOSE, &frame.payload).await;
+                }
+                opcode => {
+                    return Err(TransportError::ReceiveError(format!(
+                        "Unknown WebSocket opcode {:#x}",
+                        opcode
+                    )))
+                }
+            }
+        }
+        Ok(OwnedBytes::new())
+    }
+}
+
+#[async_trait]
+impl InternalTransport for WebSocketTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        if self.closed {
+            return Err(TransportError::SendError(String::from(
+                "The WebSocket is closed",
+            )));
+        }
+        let opcode = if self.text {
+            OPCODE_TEXT
+        } else {
+            OPCODE_BINARY
+        };
+        self.send_frame(opcode, b).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receive_message())
+                .await
+                .map_err(|_| TransportError::ReceiveTimeout(timeout))?,
+            None => self.receive_message().await,
+        }
+    }
+
+    fn peer_disconnected(&mut self) -> bool {
+        self.closed || transport::peer_disconnected(self.stream.get_ref())
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.max_message_size = max_message_size;
+    }
+}
+
+/// Accepts WebSocket connections for [crate::RpcServer::serve_websocket], see [crate::websocket].
+/// Handshakes must complete within [TransportConfig::connect_timeout]
+pub struct WebSocketListener {
+    listener: TcpListener,
+}
+
+impl WebSocketListener {
+    pub fn new(listener: TcpListener) -> Self {
+        Self { listener }
+    }
+}
+
+#[async_trait]
+impl Listener for WebSocketListener {
+    type Stream = TcpStream;
+    type Transport = WebSocketTransport;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        Listener::poll_accept(&self.listener, cx)
+    }
+
+    async fn transport(
+        &self,
+        tcp_stream: Self::Stream,
+        transport_config: &TransportConfig,
+    ) -> RpcResult<WebSocketTransport> {
+        accept(tcp_stream, transport_config).await
+    }
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], ring::error::Unspecified> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The server's answer to a client's Sec-WebSocket-Key
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    );
+    base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+}
+
+/// The start line and headers of an HTTP message, header names lowercased
+async fn read_head(
+    stream: &mut BufReader<TcpStream>,
+) -> std::io::Result<(String, Vec<(String, String)>)> {
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = Vec::new();
+        read += stream.read_until(b'\n', &mut line).await?;
+        if read > MAX_HANDSHAKE_SIZE || line.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The WebSocket handshake was cut short or too long",
+            ));
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut lines = lines.into_iter();
+    let start_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    Ok((start_line, headers))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Run the client side of the handshake over [tcp_stream], asking the server at [addr] for
+/// [path]
+pub(crate) async fn connect(
+    addr: &str,
+    path: &str,
+    tcp_stream: TcpStream,
+) -> std::io::Result<WebSocketTransport> {
+    let handshake_error =
+        |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let key = base64::engine::general_purpose::STANDARD.encode(
+        random_bytes::<16>().map_err(|_| handshake_error(String::from("Could not make a key")))?,
+    );
+    let mut stream = BufReader::new(tcp_stream);
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                path, addr, key
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let (status_line, headers) = read_head(&mut stream).await?;
+    if status_line.split_whitespace().nth(1) != Some("101") {
+        return Err(handshake_error(format!(
+            "The server refused the WebSocket: {}",
+            status_line
+        )));
+    }
+    if header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(handshake_error(String::from(
+            "The server answered the WebSocket key wrongly",
+        )));
+    }
+    Ok(WebSocketTransport {
+        stream,
+        write_timeout: None,
+        max_message_size: None,
+        client: true,
+        text: false,
+        closed: false,
+    })
+}
+
+/// Run the server side of the handshake over [tcp_stream], within
+/// [TransportConfig::connect_timeout], and apply the rest of [transport_config]
+pub(crate) async fn accept(
+    tcp_stream: TcpStream,
+    transport_config: &TransportConfig,
+) -> RpcResult<WebSocketTransport> {
+    let handshake_error = |message: String| {
+        RpcError::TransportError(TransportError::ConnectError(format!(
+            "WebSocket handshake failed: {}",
+            message
+        )))
+    };
+    if let Some(keepalive) = transport_config.keepalive {
+        transport::set_keepalive(&tcp_stream, keepalive)?;
+    }
+    let mut stream = BufReader::new(tcp_stream);
+    let read_fut = read_head(&mut stream);
+    let (request_line, headers) = match transport_config.connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, read_fut)
+            .await
+            .map_err(|_| handshake_error(format!("Timed out after {:?}", connect_timeout)))?,
+        None => read_fut.await,
+    }
+    .map_err(|e| handshake_error(e.to_string()))?;
+    let upgrade = header(&headers, "upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    let key = match header(&headers, "sec-websocket-key") {
+        Some(key) if upgrade && request_line.starts_with("GET ") => key,
+        _ => {
+            let _ = stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return Err(handshake_error(format!(
+                "Not a WebSocket upgrade: {}",
+                request_line
+            )));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    transport::send_with_timeout(
+        &mut stream,
+        response.as_bytes(),
+        transport_config.write_timeout,
+    )
+    .await?;
+    Ok(WebSocketTransport {
+        stream,
+        write_timeout: transport_config.write_timeout,
+        max_message_size: None,
+        client: false,
+        text: false,
+        closed: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_from_rfc() {
+        // The example handshake of RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}